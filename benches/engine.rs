@@ -1,6 +1,6 @@
 use criterion::BatchSize::SmallInput;
 use criterion::{criterion_group, criterion_main, Criterion};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{KvStore, KvsReader, KvsWriter, SledKvsEngine};
 use rand::prelude::*;
 use tempfile::TempDir;
 
@@ -104,4 +104,4 @@ fn read_benchmark(c: &mut Criterion) {
 }
 
 criterion_group!(benches, write_benchmark, read_benchmark);
-criterion_main!(benches);
\ No newline at end of file
+criterion_main!(benches);
@@ -0,0 +1,64 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{Client, KvServer, KvStore, Request};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+const ENTRY_COUNT: usize = 100;
+
+// small requests over one connection, so the time is spent decoding and serving them
+fn small_requests(c: &mut Criterion) {
+    let addr = "127.0.0.1:4002";
+
+    let dir = TempDir::new().unwrap();
+    let eng = KvStore::open(dir.path()).unwrap();
+    let server_pool = SharedQueueThreadPool::new(1).unwrap();
+
+    let is_stop = Arc::new(AtomicBool::new(false));
+
+    let mut server = KvServer::new(eng, server_pool, Arc::clone(&is_stop));
+
+    let handle = thread::spawn(move || {
+        server.serve(&addr.to_owned()).unwrap();
+    });
+
+    thread::sleep(Duration::from_secs(1));
+
+    let keys: Vec<String> = (0..ENTRY_COUNT).map(|x| format!("key{}", x)).collect();
+    let mut client = Client::new(addr).unwrap();
+
+    c.bench_function("set_small", |b| {
+        b.iter(|| {
+            for key in keys.iter() {
+                client
+                    .request(&Request::SET(key.clone(), "value".to_owned()))
+                    .unwrap();
+            }
+        })
+    });
+
+    c.bench_function("get_small", |b| {
+        b.iter(|| {
+            for key in keys.iter() {
+                client.request(&Request::GET(key.clone())).unwrap();
+            }
+        })
+    });
+
+    drop(client);
+    is_stop.store(true, Ordering::SeqCst);
+
+    let _ = Client::new(addr);
+
+    handle.join().unwrap();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = small_requests
+}
+criterion_main!(benches);
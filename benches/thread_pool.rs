@@ -42,8 +42,8 @@ fn write_queued_kvstore(c: &mut Criterion) {
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..ENTRY_COUNT {
-                    let key = keys[i].clone();
+                for key in keys.iter() {
+                    let key = key.clone();
                     let value = value.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
@@ -102,17 +102,17 @@ fn read_queued_kvstore(c: &mut Criterion) {
 
             thread::sleep(Duration::from_secs(1));
 
-            for i in 0..ENTRY_COUNT {
+            for key in keys.iter() {
                 let mut write_client = Client::new(addr).unwrap();
-                let key = keys[i].clone();
+                let key = key.clone();
                 let value = value.clone();
                 write_client.request(&Request::SET(key, value)).unwrap();
             }
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..ENTRY_COUNT {
-                    let key = keys[i].clone();
+                for key in keys.iter() {
+                    let key = key.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
                         match Client::new(addr) {
@@ -173,8 +173,8 @@ fn write_rayon_kvstore(c: &mut Criterion) {
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..ENTRY_COUNT {
-                    let key = keys[i].clone();
+                for key in keys.iter() {
+                    let key = key.clone();
                     let value = value.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
@@ -233,17 +233,17 @@ fn read_rayon_kvstore(c: &mut Criterion) {
 
             thread::sleep(Duration::from_secs(1));
 
-            for i in 0..ENTRY_COUNT {
+            for key in keys.iter() {
                 let mut write_client = Client::new(addr).unwrap();
-                let key = keys[i].clone();
+                let key = key.clone();
                 let value = value.clone();
                 write_client.request(&Request::SET(key, value)).unwrap();
             }
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..ENTRY_COUNT {
-                    let key = keys[i].clone();
+                for key in keys.iter() {
+                    let key = key.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
                         match Client::new(addr) {
@@ -304,8 +304,8 @@ fn write_rayon_sledkvengine(c: &mut Criterion) {
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..ENTRY_COUNT {
-                    let key = keys[i].clone();
+                for key in keys.iter() {
+                    let key = key.clone();
                     let value = value.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
@@ -364,17 +364,17 @@ fn read_rayon_sledkvengine(c: &mut Criterion) {
 
             thread::sleep(Duration::from_secs(1));
 
-            for i in 0..ENTRY_COUNT {
+            for key in keys.iter() {
                 let mut write_client = Client::new(addr).unwrap();
-                let key = keys[i].clone();
+                let key = key.clone();
                 let value = value.clone();
                 write_client.request(&Request::SET(key, value)).unwrap();
             }
 
             b.iter(|| {
                 let wg = WaitGroup::new();
-                for i in 0..ENTRY_COUNT {
-                    let key = keys[i].clone();
+                for key in keys.iter() {
+                    let key = key.clone();
                     let wg = wg.clone();
                     client_pool.spawn(move || {
                         match Client::new(addr) {
@@ -0,0 +1,117 @@
+use crate::{KVStoreError, Result};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+/** The CIDR rules deciding which clients a server accepts connections of, checked as each
+connection is accepted, see `KvServer::access_list`. A client matching a deny rule is refused,
+and so is one matching no allow rule if there are any; without rules all clients are accepted.
+
+The list is a handle: clones share the rules, so a clone kept by the caller replaces them
+with `reload` while the server runs.
+# Example
+```
+use kvs::{AccessList, Result};
+
+fn try_main() -> Result<()> {
+    let access = AccessList::new(&["10.0.0.0/8", "127.0.0.1"], &["10.6.0.0/16"])?;
+    assert!(access.is_allowed("10.1.2.3".parse().unwrap()));
+    assert!(!access.is_allowed("10.6.2.3".parse().unwrap()));
+    assert!(!access.is_allowed("192.168.1.1".parse().unwrap()));
+    access.reload(&[], &["10.0.0.0/8"])?;
+    assert!(access.is_allowed("192.168.1.1".parse().unwrap()));
+    Ok(())
+}
+```
+ */
+#[derive(Clone, Debug, Default)]
+pub struct AccessList {
+    rules: Arc<RwLock<AccessRules>>,
+}
+
+#[derive(Debug, Default)]
+struct AccessRules {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    /// Parse the rules, each a CIDR like `10.0.0.0/8` or `fd00::/8`, or a single address.
+    pub fn new<S: AsRef<str>>(allow: &[S], deny: &[S]) -> Result<AccessList> {
+        let access = AccessList::default();
+        access.reload(allow, deny)?;
+        Ok(access)
+    }
+
+    /// Replace the rules, for the connections accepted from now on. The rules are kept as
+    /// they are if any of the new ones is not a CIDR.
+    pub fn reload<S: AsRef<str>>(&self, allow: &[S], deny: &[S]) -> Result<()> {
+        let rules = AccessRules {
+            allow: parse_all(allow)?,
+            deny: parse_all(deny)?,
+        };
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+
+    /// whether a client connecting from `ip` is accepted
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        let rules = self.rules.read().unwrap();
+        if rules.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        rules.allow.is_empty() || rules.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// a network of addresses sharing their first `prefix` bits with `network`
+#[derive(Debug)]
+struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    fn parse(rule: &str) -> Result<Cidr> {
+        let invalid =
+            || KVStoreError::InvalidConfig(format!("{} is not a CIDR like 10.0.0.0/8", rule));
+        let (network, prefix) = match rule.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (rule, None),
+        };
+        let network: IpAddr = network.trim().parse().map_err(|_| invalid())?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.trim().parse().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+        Ok(Cidr { network, prefix })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn parse_all<S: AsRef<str>>(rules: &[S]) -> Result<Vec<Cidr>> {
+    rules
+        .iter()
+        .map(|rule| Cidr::parse(rule.as_ref()))
+        .collect()
+}
@@ -0,0 +1,113 @@
+use crate::Request;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// the queue wait above which the lowest-priority requests are shed by default
+pub const DEFAULT_MAX_QUEUE_WAIT: Duration = Duration::from_millis(100);
+/// how long a measured wait stands for the queue without newer measurements
+const WAIT_MEMORY: Duration = Duration::from_secs(1);
+
+/// How much a request matters to its client when the server is overloaded, lowest first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Priority {
+    /// scans and the other admin requests, which are expensive and can wait
+    Low,
+    /// reads and writes
+    Normal,
+    /// requests finishing work already admitted, like commits, or changing the session
+    High,
+}
+
+impl Priority {
+    pub(crate) fn of(request: &Request) -> Priority {
+        match request {
+            Request::SCAN(..)
+            | Request::FLUSH
+            | Request::DBSTATS
+            | Request::TOPKEYS(..)
+            | Request::CHECKPOINT
+            | Request::CUTOVER => Priority::Low,
+            Request::GET(..)
+            | Request::SET(..)
+            | Request::RM(..)
+            | Request::SETBIT(..)
+            | Request::GETBIT(..)
+            | Request::BITCOUNT(..)
+            | Request::PFADD(..)
+            | Request::PFCOUNT(..)
+            | Request::GETRANGE(..)
+            | Request::SETRANGE(..)
+            | Request::JSONSET(..)
+            | Request::JSONGET(..)
+            | Request::BATCH(..)
+            | Request::BEGIN => Priority::Normal,
+            Request::COMMIT
+            | Request::ROLLBACK
+            | Request::SELECT(..)
+            | Request::WATCH(..)
+            | Request::TOPOLOGY
+            | Request::CLIENTLIST
+            | Request::CLIENTKILL(..)
+            | Request::LEASE(..)
+            | Request::PROMOTE
+            | Request::HEALTH
+            | Request::DRAIN(..)
+            | Request::INFO
+            | Request::REPLICATE(..) => Priority::High,
+            Request::TRACED(_, request)
+            | Request::TAGGED(_, request)
+            | Request::RETRYABLE(_, _, request)
+            | Request::DEADLINE(_, request)
+            | Request::AFTER(_, request)
+            | Request::TRANSFORM(_, request) => Priority::of(request),
+        }
+    }
+}
+
+/// Tracks how long jobs wait in the queues of the thread pools, and sheds requests while
+/// the wait is too long so that the ones admitted are still served in time: low-priority
+/// requests once it exceeds the maximum, normal ones once it exceeds twice the maximum.
+pub(crate) struct AdmissionController {
+    max_wait: Duration,
+    /// the moving average of the waits, and when it was last updated
+    wait: Mutex<Option<(Duration, Instant)>>,
+}
+
+impl AdmissionController {
+    pub(crate) fn new(max_wait: Duration) -> Self {
+        AdmissionController {
+            max_wait,
+            wait: Mutex::new(None),
+        }
+    }
+
+    /// a job started after waiting `waited` in the queue of its pool
+    pub(crate) fn record_wait(&self, waited: Duration) {
+        let mut wait = self.wait.lock().unwrap();
+        let average = match *wait {
+            Some((average, updated)) if updated.elapsed() < WAIT_MEMORY => {
+                (average * 7 + waited) / 8
+            }
+            _ => waited,
+        };
+        *wait = Some((average, Instant::now()));
+    }
+
+    /// the recent queue wait, zero if no job started for a while
+    pub(crate) fn queue_wait(&self) -> Duration {
+        match *self.wait.lock().unwrap() {
+            Some((average, updated)) if updated.elapsed() < WAIT_MEMORY => average,
+            _ => Duration::ZERO,
+        }
+    }
+
+    /// whether to run a request, or to shed it as the server is overloaded
+    pub(crate) fn admit(&self, request: &Request) -> bool {
+        let wait = self.queue_wait();
+        match Priority::of(request) {
+            Priority::Low => wait <= self.max_wait,
+            Priority::Normal => wait <= self.max_wait * 2,
+            Priority::High => true,
+        }
+    }
+}
@@ -1,11 +1,57 @@
 use clap::{arg, command, ArgMatches, SubCommand};
-use kvs::{Client, Request, Result};
+use kvs::{Client, KVStoreError, Request, Reshard, Result, Transform};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use serde_json::json;
+use std::path::PathBuf;
 use std::string::String;
+use std::time::Duration;
 use std::{env, process};
 
+/// the commands of the REPL, completed with tab
+const REPL_COMMANDS: &[&str] = &[
+    "set", "get", "rm", "scan", "flush", "dbstats", "topkeys", "select", "connect", "use",
+    "servers", "help", "quit", "exit",
+];
+
+const REPL_HELP: &str = "\
+set KEY VALUE     set the value of a key
+get KEY           print the value of a key
+rm KEY            remove a key
+scan PATTERN      print the pairs whose key matches a glob pattern
+flush             remove all keys of the database
+dbstats           print the number of keys of the database and their size
+topkeys [N]       print the N most read and most written keys
+select N          switch to the logical database N
+connect IPPORT    connect to another server and switch to it
+use N|IPPORT      switch to a connection listed by servers
+servers           list the connections
+help              print this help
+quit              leave
+Words with spaces are quoted with \"\", and \\ escapes the next character.";
+
 fn main() {
     let matches = command!()
         .name("kvs-Client")
+        .arg(
+            arg!(--db <N> "The logical database to use")
+                .global(true)
+                .required(false)
+                .default_value("0")
+                .value_parser(clap::value_parser!(u32)),
+        )
+        .arg(
+            arg!(--output <FORMAT> "How to print results: plain text, json for scripts, or an aligned table")
+                .global(true)
+                .required(false)
+                .default_value("plain")
+                .value_parser(["plain", "json", "table"]),
+        )
         .subcommand(
             SubCommand::with_name("set")
                 .about("Set the value of a string key to a string. Return an error if the value is not written successfully.")
@@ -17,6 +63,7 @@ fn main() {
             SubCommand::with_name("get")
                 .about("Get the string value of a string key. If the key does not exist, return None. Return an error if the value is not read successfully.")
                 .arg(arg!(<KEY>))
+                .arg(arg!(--pointer <POINTER> "Only get the field of a JSON value at a JSON pointer like /user/name").required(false))
                 .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
         )
         .subcommand(
@@ -25,38 +72,839 @@ fn main() {
                 .arg(arg!(<KEY>))
                 .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
         )
+        .subcommand(
+            SubCommand::with_name("setbit")
+                .about("Set the bit at OFFSET of the bitmap stored as the value of KEY to BIT, growing it as needed. Prints the bit before.")
+                .arg(arg!(<KEY>))
+                .arg(arg!(<OFFSET>).value_parser(clap::value_parser!(u64)))
+                .arg(arg!(<BIT>).value_parser(["0", "1"]))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("getbit")
+                .about("Print the bit at OFFSET of the bitmap stored as the value of KEY.")
+                .arg(arg!(<KEY>))
+                .arg(arg!(<OFFSET>).value_parser(clap::value_parser!(u64)))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("bitcount")
+                .about("Print the number of set bits of the bitmap stored as the value of KEY.")
+                .arg(arg!(<KEY>))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("pfadd")
+                .about("Add the ELEMENTs to the HyperLogLog sketch stored as the value of KEY. Prints 1 if its estimate may have changed and 0 otherwise.")
+                .arg(arg!(<KEY>))
+                .arg(arg!(<ELEMENT>...))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("pfcount")
+                .about("Print the estimated number of distinct elements added to the HyperLogLog sketches of the KEYs together.")
+                .arg(arg!(<KEY>...))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("getrange")
+                .about("Print LENGTH bytes of the value of KEY from the byte OFFSET on, fewer past the end of the value.")
+                .arg(arg!(<KEY>))
+                .arg(arg!(<OFFSET>).value_parser(clap::value_parser!(u64)))
+                .arg(arg!(<LENGTH>).value_parser(clap::value_parser!(u64)))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("setrange")
+                .about("Overwrite the bytes of the value of KEY from the byte OFFSET on with DATA, padding it with zero bytes up to OFFSET. Prints the new length of the value.")
+                .arg(arg!(<KEY>))
+                .arg(arg!(<OFFSET>).value_parser(clap::value_parser!(u64)))
+                .arg(arg!(<DATA>))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("jsonset")
+                .about("Set the field at the JSON pointer POINTER of the JSON document stored as the value of KEY to the JSON text JSON, e.g. /user/name '\"bob\"'. A missing key is an empty object.")
+                .arg(arg!(<KEY>))
+                .arg(arg!(<POINTER>))
+                .arg(arg!(<JSON>))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("jsonget")
+                .about("Print the field at the JSON pointer POINTER of the JSON document stored as the value of KEY as JSON text.")
+                .arg(arg!(<KEY>))
+                .arg(arg!(<POINTER>))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("Print all key/value pairs whose key matches a glob pattern like user:*:profile.")
+                .arg(arg!(<PATTERN>))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("flush")
+                .about("Remove all keys of the database.")
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("cutover")
+                .about("Switch the reads of a server migrating to another engine over to the new engine, after copying the keys it lacks.")
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("promote")
+                .about("Make the follower at --addr the leader once the lease of its old leader expired, fencing the old leader off. Prints the epoch of the promotion.")
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("health")
+                .about("Print the health of the server, serving, or draining while it drains its connections.")
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Print the instance id of the store of the server, the store replicating to it, the revision it applied and its health.")
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("drain")
+                .about("Drain the connections of the server, e.g. before maintenance: the connections open now are served for GRACE milliseconds, while the others are answered with a draining error.")
+                .arg(arg!([GRACE]).default_value("10000").value_parser(clap::value_parser!(u64)))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("split")
+                .about("Move the keys from FROM_KEY on, in every database, from the shard at --addr to the shard at --to, printing the progress to stderr. The keys are removed from --addr once the copy matches them.")
+                .arg(arg!(<FROM_KEY>))
+                .arg(arg!(--to <IPPORT>))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("merge")
+                .about("Move all keys of the shard at --addr to the shard at --to, printing the progress to stderr. The keys are removed from --addr once the copy matches them.")
+                .arg(arg!(--to <IPPORT>))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("dbstats")
+                .about("Print the number of keys of the database and their size in bytes.")
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("topkeys")
+                .about("Print the N most read and most written keys with their approximate access counts.")
+                .arg(arg!([N]).default_value("10").value_parser(clap::value_parser!(usize)))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("client-list")
+                .about("Print the connections of the server with their age and idle time in seconds, requests, bytes received and sent, last request and database.")
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("client-kill")
+                .about("Close the connection of the server with the id ID, as printed by client-list.")
+                .arg(arg!(<ID>).value_parser(clap::value_parser!(u64)))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Print the writes of keys starting with PREFIX as they happen, after replaying those after revision --since the server retains. With --output table the events are printed like with plain.")
+                .arg(arg!(<PREFIX>))
+                .arg(arg!(--since <REVISION>).required(false).default_value("0").value_parser(clap::value_parser!(u64)))
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
+        .subcommand(
+            SubCommand::with_name("repl")
+                .about("Run commands interactively over one connection, with history and tab completion. Type help for the commands.")
+                .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000")),
+        )
         .get_matches();
-    if let Err(err) = send_request(matches) {
-        eprintln!("{:?}", err);
+    let output = Output::of(&matches);
+    if let Err(err) = send_request(&matches, output) {
+        match output {
+            Output::Json => eprintln!("{}", json!({ "error": err.to_string() })),
+            _ => eprintln!("{:?}", err),
+        }
         process::exit(-1);
     }
 }
 
-fn send_request(matches: ArgMatches) -> Result<()> {
+/// the format of the results of the commands, chosen with `--output`
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Output {
+    Plain,
+    Json,
+    Table,
+}
+
+impl Output {
+    fn of(matches: &ArgMatches) -> Output {
+        match matches.get_one::<String>("output").map(String::as_str) {
+            Some("json") => Output::Json,
+            Some("table") => Output::Table,
+            _ => Output::Plain,
+        }
+    }
+}
+
+fn send_request(matches: &ArgMatches, output: Output) -> Result<()> {
     match matches.subcommand() {
         Some(("set", sub_matches)) => {
-            let addr = sub_matches.get_one::<String>("addr").unwrap();
             let key = sub_matches.get_one::<String>("KEY").unwrap();
             let value = sub_matches.get_one::<String>("VALUE").unwrap();
-            let mut client = Client::new(addr)?;
+            let mut client = connect(sub_matches)?;
             client.request(&Request::SET(key.to_owned(), value.to_owned()))?;
         }
         Some(("get", sub_matches)) => {
-            let addr = sub_matches.get_one::<String>("addr").unwrap();
             let key = sub_matches.get_one::<String>("KEY").unwrap();
-            let mut client = Client::new(addr)?;
-            match client.request(&Request::GET(key.to_owned()))? {
-                None => println!("Key not found"),
-                Some(value) => println!("{}", value),
+            let mut client = connect(sub_matches)?;
+            let value = match sub_matches.get_one::<String>("pointer") {
+                Some(pointer) => {
+                    client.get_transformed(key, Transform::JsonPointer(pointer.to_owned()))?
+                }
+                None => client.request(&Request::GET(key.to_owned()))?,
+            };
+            match (output, value) {
+                (Output::Json, value) => println!("{}", json!({ "key": key, "value": value })),
+                (Output::Table, value) => print_table(
+                    &["KEY", "VALUE"],
+                    vec![vec![key.to_owned(), value.unwrap_or_default()]],
+                ),
+                (Output::Plain, None) => println!("Key not found"),
+                (Output::Plain, Some(value)) => println!("{}", value),
             };
         }
         Some(("rm", sub_matches)) => {
-            let addr = sub_matches.get_one::<String>("addr").unwrap();
             let key = sub_matches.get_one::<String>("KEY").unwrap();
-            let mut client = Client::new(addr)?;
+            let mut client = connect(sub_matches)?;
             client.request(&Request::RM(key.to_owned()))?;
         }
+        Some(("setbit", sub_matches)) => {
+            let key = sub_matches.get_one::<String>("KEY").unwrap();
+            let offset = sub_matches.get_one::<u64>("OFFSET").unwrap();
+            let bit = sub_matches.get_one::<String>("BIT").unwrap() == "1";
+            let old = connect(sub_matches)?.setbit(key, *offset, bit)?;
+            println!("{}", old as u8);
+        }
+        Some(("getbit", sub_matches)) => {
+            let key = sub_matches.get_one::<String>("KEY").unwrap();
+            let offset = sub_matches.get_one::<u64>("OFFSET").unwrap();
+            println!("{}", connect(sub_matches)?.getbit(key, *offset)? as u8);
+        }
+        Some(("bitcount", sub_matches)) => {
+            let key = sub_matches.get_one::<String>("KEY").unwrap();
+            println!("{}", connect(sub_matches)?.bitcount(key)?);
+        }
+        Some(("pfadd", sub_matches)) => {
+            let key = sub_matches.get_one::<String>("KEY").unwrap();
+            let elements: Vec<&str> = sub_matches
+                .get_many::<String>("ELEMENT")
+                .unwrap()
+                .map(String::as_str)
+                .collect();
+            println!("{}", connect(sub_matches)?.pfadd(key, &elements)? as u8);
+        }
+        Some(("pfcount", sub_matches)) => {
+            let keys: Vec<&str> = sub_matches
+                .get_many::<String>("KEY")
+                .unwrap()
+                .map(String::as_str)
+                .collect();
+            println!("{}", connect(sub_matches)?.pfcount(&keys)?);
+        }
+        Some(("getrange", sub_matches)) => {
+            let key = sub_matches.get_one::<String>("KEY").unwrap();
+            let offset = sub_matches.get_one::<u64>("OFFSET").unwrap();
+            let length = sub_matches.get_one::<u64>("LENGTH").unwrap();
+            println!("{}", connect(sub_matches)?.getrange(key, *offset, *length)?);
+        }
+        Some(("setrange", sub_matches)) => {
+            let key = sub_matches.get_one::<String>("KEY").unwrap();
+            let offset = sub_matches.get_one::<u64>("OFFSET").unwrap();
+            let data = sub_matches.get_one::<String>("DATA").unwrap();
+            println!("{}", connect(sub_matches)?.setrange(key, *offset, data)?);
+        }
+        Some(("jsonset", sub_matches)) => {
+            let key = sub_matches.get_one::<String>("KEY").unwrap();
+            let pointer = sub_matches.get_one::<String>("POINTER").unwrap();
+            let field = sub_matches.get_one::<String>("JSON").unwrap();
+            connect(sub_matches)?.json_set(key, pointer, field)?;
+        }
+        Some(("jsonget", sub_matches)) => {
+            let key = sub_matches.get_one::<String>("KEY").unwrap();
+            let pointer = sub_matches.get_one::<String>("POINTER").unwrap();
+            match connect(sub_matches)?.json_get(key, pointer)? {
+                Some(field) => println!("{}", field),
+                None => println!("Field not found"),
+            }
+        }
+        Some(("scan", sub_matches)) => {
+            let pattern = sub_matches.get_one::<String>("PATTERN").unwrap();
+            let mut client = connect(sub_matches)?;
+            let pairs = client.scan_match(pattern)?;
+            match output {
+                Output::Json => {
+                    let pairs: Vec<_> = pairs
+                        .iter()
+                        .map(|(key, value)| json!({ "key": key, "value": value }))
+                        .collect();
+                    println!("{}", json!(pairs));
+                }
+                Output::Table => print_table(
+                    &["KEY", "VALUE"],
+                    pairs
+                        .into_iter()
+                        .map(|(key, value)| vec![key, value])
+                        .collect(),
+                ),
+                Output::Plain => {
+                    for (key, value) in pairs {
+                        println!("{} {}", key, value);
+                    }
+                }
+            }
+        }
+        Some(("flush", sub_matches)) => {
+            connect(sub_matches)?.request(&Request::FLUSH)?;
+        }
+        Some(("cutover", sub_matches)) => {
+            connect(sub_matches)?.cutover()?;
+        }
+        Some(("promote", sub_matches)) => {
+            println!("{}", connect(sub_matches)?.promote()?);
+        }
+        Some(("health", sub_matches)) => {
+            println!("{}", connect(sub_matches)?.health()?);
+        }
+        Some(("info", sub_matches)) => {
+            let info = connect(sub_matches)?.info()?;
+            let instance_id = info.instance_id.unwrap_or_default();
+            let replicating_from = info.replicating_from.unwrap_or_default();
+            match output {
+                Output::Json => println!(
+                    "{}",
+                    json!({
+                        "instance_id": instance_id,
+                        "replicating_from": replicating_from,
+                        "revision": info.revision,
+                        "health": info.health,
+                    })
+                ),
+                Output::Table => print_table(
+                    &["INSTANCE", "REPLICATING FROM", "REVISION", "HEALTH"],
+                    vec![vec![
+                        instance_id,
+                        replicating_from,
+                        info.revision.to_string(),
+                        info.health,
+                    ]],
+                ),
+                Output::Plain => {
+                    println!("instance_id: {}", instance_id);
+                    println!("replicating_from: {}", replicating_from);
+                    println!("revision: {}", info.revision);
+                    println!("health: {}", info.health);
+                }
+            }
+        }
+        Some(("drain", sub_matches)) => {
+            let grace = sub_matches.get_one::<u64>("GRACE").unwrap();
+            connect(sub_matches)?.drain(Duration::from_millis(*grace))?;
+        }
+        Some(("split", sub_matches)) => {
+            let addr = sub_matches.get_one::<String>("addr").unwrap();
+            let to = sub_matches.get_one::<String>("to").unwrap();
+            let from_key = sub_matches.get_one::<String>("FROM_KEY").unwrap();
+            reshard(Reshard::split(addr, to, from_key), output)?;
+        }
+        Some(("merge", sub_matches)) => {
+            let addr = sub_matches.get_one::<String>("addr").unwrap();
+            let to = sub_matches.get_one::<String>("to").unwrap();
+            reshard(Reshard::merge(addr, to), output)?;
+        }
+        Some(("dbstats", sub_matches)) => {
+            let stats = connect(sub_matches)?.database_stats()?;
+            match output {
+                Output::Json => println!("{}", json!(stats)),
+                Output::Table => print_table(
+                    &["DATABASE", "KEYS", "BYTES"],
+                    vec![vec![
+                        stats.database.to_string(),
+                        stats.keys.to_string(),
+                        stats.bytes.to_string(),
+                    ]],
+                ),
+                Output::Plain => println!(
+                    "database {}: {} keys, {} bytes",
+                    stats.database, stats.keys, stats.bytes
+                ),
+            }
+        }
+        Some(("client-list", sub_matches)) => {
+            let clients = connect(sub_matches)?.client_list()?;
+            match output {
+                Output::Json => {
+                    let clients: Vec<_> = clients
+                        .iter()
+                        .map(|client| {
+                            json!({
+                                "id": client.id,
+                                "addr": client.addr,
+                                "age": client.age.as_secs_f64(),
+                                "idle": client.idle.as_secs_f64(),
+                                "commands": client.commands,
+                                "bytes_in": client.bytes_in,
+                                "bytes_out": client.bytes_out,
+                                "last_command": client.last_command,
+                                "database": client.database,
+                            })
+                        })
+                        .collect();
+                    println!("{}", json!(clients));
+                }
+                Output::Table => print_table(
+                    &[
+                        "ID", "ADDR", "AGE", "IDLE", "CMDS", "IN", "OUT", "LAST", "DB",
+                    ],
+                    clients
+                        .iter()
+                        .map(|client| {
+                            vec![
+                                client.id.to_string(),
+                                client.addr.clone(),
+                                client.age.as_secs().to_string(),
+                                client.idle.as_secs().to_string(),
+                                client.commands.to_string(),
+                                client.bytes_in.to_string(),
+                                client.bytes_out.to_string(),
+                                client.last_command.clone().unwrap_or_default(),
+                                client.database.to_string(),
+                            ]
+                        })
+                        .collect(),
+                ),
+                Output::Plain => {
+                    for client in clients {
+                        println!(
+                            "id={} addr={} age={} idle={} cmds={} in={} out={} last={} db={}",
+                            client.id,
+                            client.addr,
+                            client.age.as_secs(),
+                            client.idle.as_secs(),
+                            client.commands,
+                            client.bytes_in,
+                            client.bytes_out,
+                            client.last_command.unwrap_or_default(),
+                            client.database
+                        );
+                    }
+                }
+            }
+        }
+        Some(("client-kill", sub_matches)) => {
+            let id = sub_matches.get_one::<u64>("ID").unwrap();
+            connect(sub_matches)?.client_kill(*id)?;
+        }
+        Some(("topkeys", sub_matches)) => {
+            let n = sub_matches.get_one::<usize>("N").unwrap();
+            let top = connect(sub_matches)?.top_keys(*n)?;
+            let kinds = [("reads", top.reads), ("writes", top.writes)];
+            match output {
+                Output::Json => {
+                    let mut object = json!({});
+                    for (kind, keys) in kinds {
+                        let keys: Vec<_> = keys
+                            .iter()
+                            .map(|(key, count)| json!({ "key": key, "count": count }))
+                            .collect();
+                        object[kind] = json!(keys);
+                    }
+                    println!("{}", object);
+                }
+                Output::Table => print_table(
+                    &["KIND", "KEY", "COUNT"],
+                    kinds
+                        .into_iter()
+                        .flat_map(|(kind, keys)| {
+                            keys.into_iter().map(move |(key, count)| {
+                                vec![kind.to_owned(), key, count.to_string()]
+                            })
+                        })
+                        .collect(),
+                ),
+                Output::Plain => {
+                    for (kind, keys) in kinds {
+                        println!("{}:", kind);
+                        for (key, count) in keys {
+                            println!("{} {}", key, count);
+                        }
+                    }
+                }
+            }
+        }
+        Some(("watch", sub_matches)) => {
+            let prefix = sub_matches.get_one::<String>("PREFIX").unwrap();
+            let since = sub_matches.get_one::<u64>("since").unwrap();
+            // until the server closes the connection
+            for event in connect(sub_matches)?.watch(prefix, *since)? {
+                let event = event?;
+                match (output, &event.value) {
+                    (Output::Json, _) => println!("{}", json!(event)),
+                    (_, Some(value)) => println!("{} SET {} {}", event.revision, event.key, value),
+                    (_, None) => println!("{} RM {}", event.revision, event.key),
+                }
+            }
+        }
+        Some(("repl", sub_matches)) => run_repl(sub_matches)?,
         _ => process::exit(-1),
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// move keys between shards, printing the progress to stderr and the result to stdout
+fn reshard(reshard: Reshard, output: Output) -> Result<()> {
+    let progress = reshard
+        .on_progress(|progress| {
+            eprintln!(
+                "scanned {} keys, copied {}, removed {}",
+                progress.scanned_keys, progress.copied_keys, progress.removed_keys
+            )
+        })
+        .run()?;
+    match output {
+        Output::Json => println!("{}", json!(progress)),
+        Output::Table => print_table(
+            &["REVISION", "SCANNED", "COPIED", "REMOVED"],
+            vec![vec![
+                progress.revision.to_string(),
+                progress.scanned_keys.to_string(),
+                progress.copied_keys.to_string(),
+                progress.removed_keys.to_string(),
+            ]],
+        ),
+        Output::Plain => println!(
+            "moved {} keys at revision {}",
+            progress.removed_keys, progress.revision
+        ),
+    }
+    Ok(())
+}
+
+/// print rows under a header with the columns aligned, without padding the last column
+fn print_table(header: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = header.iter().map(|title| title.chars().count()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let header = header.iter().map(|title| title.to_string()).collect();
+    for row in std::iter::once(header).chain(rows) {
+        let last = row.len() - 1;
+        let cells: Vec<String> = row
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| match i == last {
+                true => cell.to_owned(),
+                false => format!("{:width$}", cell, width = widths[i]),
+            })
+            .collect();
+        println!("{}", cells.join("  "));
+    }
+}
+
+/// connect to the server at `--addr` and select the database of `--db`
+fn connect(matches: &ArgMatches) -> Result<Client> {
+    let addr = matches.get_one::<String>("addr").unwrap();
+    let database = *matches.get_one::<u32>("db").unwrap();
+    let mut client = Client::new(addr)?;
+    if database != 0 {
+        client.select(database)?;
+    }
+    Ok(client)
+}
+
+/// read commands with a line editor until `quit` or the end of the input,
+/// keeping the history in `~/.kvs_history`
+fn run_repl(matches: &ArgMatches) -> Result<()> {
+    let addr = matches.get_one::<String>("addr").unwrap();
+    let database = *matches.get_one::<u32>("db").unwrap();
+    let mut repl = Repl {
+        connections: vec![ReplConnection::open(addr, database)?],
+        current: 0,
+    };
+    let mut editor = Editor::<ReplHelper, DefaultHistory>::new().map_err(readline_error)?;
+    editor.set_helper(Some(ReplHelper));
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(".kvs_history"));
+    if let Some(history) = &history {
+        // there is none before the first session
+        let _ = editor.load_history(history);
+    }
+
+    loop {
+        let line = match editor.readline(&repl.prompt()) {
+            Ok(line) => line,
+            // ctrl-c discards the line
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(readline_error(err)),
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        editor
+            .add_history_entry(line.as_str())
+            .map_err(readline_error)?;
+        match repl.execute(&line) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => println!("(error) {}", err),
+        }
+    }
+    if let Some(history) = &history {
+        if let Err(err) = editor.save_history(history) {
+            eprintln!("can not save the history to {}: {}", history.display(), err);
+        }
+    }
+    Ok(())
+}
+
+fn readline_error(err: ReadlineError) -> KVStoreError {
+    KVStoreError::CommonStringError(err.to_string())
+}
+
+/// a connection of the REPL with the database it selected
+struct ReplConnection {
+    addr: String,
+    database: u32,
+    client: Client,
+}
+
+impl ReplConnection {
+    fn open(addr: &str, database: u32) -> Result<Self> {
+        let mut client = Client::new(addr)?;
+        if database != 0 {
+            client.select(database)?;
+        }
+        Ok(ReplConnection {
+            addr: addr.to_owned(),
+            database,
+            client,
+        })
+    }
+}
+
+struct Repl {
+    connections: Vec<ReplConnection>,
+    current: usize,
+}
+
+impl Repl {
+    /// the address of the current connection, with its database unless it is 0
+    fn prompt(&self) -> String {
+        let connection = &self.connections[self.current];
+        match connection.database {
+            0 => format!("{}> ", connection.addr),
+            database => format!("{}[{}]> ", connection.addr, database),
+        }
+    }
+
+    /// run the command of a line and print the response, returning false to leave
+    fn execute(&mut self, line: &str) -> Result<bool> {
+        let words = split_words(line)?;
+        let words: Vec<&str> = words.iter().map(String::as_str).collect();
+        let connection = &mut self.connections[self.current];
+        let client = &mut connection.client;
+        match words.as_slice() {
+            ["set", key, value] => {
+                client.request(&Request::SET((*key).to_owned(), (*value).to_owned()))?;
+                println!("OK");
+            }
+            ["get", key] => match client.request(&Request::GET((*key).to_owned()))? {
+                Some(value) => println!("{:?}", value),
+                None => println!("(nil)"),
+            },
+            ["rm", key] => {
+                client.request(&Request::RM((*key).to_owned()))?;
+                println!("OK");
+            }
+            ["scan", pattern] => {
+                let pairs = client.scan_match(pattern)?;
+                if pairs.is_empty() {
+                    println!("(empty)");
+                }
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    println!("{}) {:?} => {:?}", i + 1, key, value);
+                }
+            }
+            ["flush"] => {
+                client.request(&Request::FLUSH)?;
+                println!("OK");
+            }
+            ["dbstats"] => {
+                let stats = client.database_stats()?;
+                println!(
+                    "database {}: {} keys, {} bytes",
+                    stats.database, stats.keys, stats.bytes
+                );
+            }
+            ["topkeys"] | ["topkeys", _] => {
+                let n = match words.get(1) {
+                    Some(n) => parse_number(n)?,
+                    None => 10,
+                };
+                let top = client.top_keys(n)?;
+                for (title, keys) in [("reads", top.reads), ("writes", top.writes)] {
+                    println!("{}:", title);
+                    for (i, (key, count)) in keys.iter().enumerate() {
+                        println!("  {}) {:?} {}", i + 1, key, count);
+                    }
+                }
+            }
+            ["select", database] => {
+                let database = parse_number(database)?;
+                client.select(database)?;
+                connection.database = database;
+                println!("OK");
+            }
+            ["connect", addr] => {
+                self.connections.push(ReplConnection::open(addr, 0)?);
+                self.current = self.connections.len() - 1;
+                println!("connected to {}", addr);
+            }
+            ["use", target] => {
+                let index = self
+                    .connections
+                    .iter()
+                    .position(|connection| connection.addr == *target);
+                self.current = match index.or_else(|| {
+                    target
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|n| (1..=self.connections.len()).contains(n))
+                        .map(|n| n - 1)
+                }) {
+                    Some(index) => index,
+                    None => {
+                        return Err(KVStoreError::CommonStringError(format!(
+                            "no connection {}, see servers",
+                            target
+                        )))
+                    }
+                };
+                println!("using {}", self.connections[self.current].addr);
+            }
+            ["servers"] => {
+                for (i, connection) in self.connections.iter().enumerate() {
+                    let marker = if i == self.current { '*' } else { ' ' };
+                    println!(
+                        "{}{}) {} database {}",
+                        marker,
+                        i + 1,
+                        connection.addr,
+                        connection.database
+                    );
+                }
+            }
+            ["help"] => println!("{}", REPL_HELP),
+            ["quit"] | ["exit"] => return Ok(false),
+            [command, ..] if REPL_COMMANDS.contains(command) => {
+                return Err(KVStoreError::CommonStringError(format!(
+                    "wrong number of arguments for {}, see help",
+                    command
+                )))
+            }
+            [command, ..] => {
+                return Err(KVStoreError::CommonStringError(format!(
+                    "unknown command {}, see help",
+                    command
+                )))
+            }
+            [] => {}
+        }
+        Ok(true)
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(word: &str) -> Result<T> {
+    word.parse()
+        .map_err(|_| KVStoreError::CommonStringError(format!("{} is not a number", word)))
+}
+
+/// split a line into words at whitespace, where double quotes keep whitespace in a word
+/// and `\` escapes the next character, with `\n` and `\t` for a newline and a tab
+fn split_words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quoted = false;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                let escaped = match chars.next() {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some(escaped) => escaped,
+                    None => {
+                        return Err(KVStoreError::CommonStringError(
+                            "nothing to escape at the end of the line".to_owned(),
+                        ))
+                    }
+                };
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            '"' => {
+                quoted = !quoted;
+                // "" is an empty word
+                word.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !quoted => words.extend(word.take()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quoted {
+        return Err(KVStoreError::CommonStringError(
+            "unbalanced quotes".to_owned(),
+        ));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+/// completes the command at the start of a line
+struct ReplHelper;
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = line[..pos].trim_start();
+        if before.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+        let candidates = REPL_COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(before))
+            .map(|command| command.to_string())
+            .collect();
+        Ok((pos - before.len(), candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
@@ -0,0 +1,136 @@
+use clap::{arg, command, ArgAction, ArgMatches};
+use kvs::{KVStoreError, KvProxy, Result, DEFAULT_VIRTUAL_NODES};
+use log::{info, warn, LevelFilter};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// how often the shards file is checked for changes
+const SHARDS_FILE_INTERVAL: Duration = Duration::from_secs(1);
+
+fn main() -> Result<()> {
+    let matches = command!()
+        .name("kvs-proxy")
+        .about("Route the requests of kvs clients to kvs-server shards by a consistent hash of their keys.")
+        .arg(arg!(--addr <IPPORT>).required(false).default_value("127.0.0.1:4000"))
+        .arg(
+            arg!(--shard <IPPORT> "a kvs-server holding a shard of the keys")
+                .required(false)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(--"shards-file" <FILE> "read the shards from FILE, one IPPORT per line, and again whenever it changes")
+                .required(false),
+        )
+        .arg(
+            arg!(--"virtual-nodes" <N> "the points of each shard on the hash ring")
+                .required(false)
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .get_matches();
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+
+    let shards_file = matches.get_one::<String>("shards-file").map(PathBuf::from);
+    let shards = match &shards_file {
+        Some(path) => read_shards(path)?,
+        None => matches
+            .get_many::<String>("shard")
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect(),
+    };
+    let is_stop = Arc::new(AtomicBool::new(false));
+    stop_on_signal(Arc::clone(&is_stop))?;
+    let proxy = KvProxy::new(shards, Arc::clone(&is_stop))?.virtual_nodes(virtual_nodes(&matches));
+    if let Some(path) = shards_file {
+        watch_shards_file(proxy.clone(), path, is_stop);
+    }
+    let addr = matches.get_one::<String>("addr").unwrap();
+    info!(
+        "kvs-proxy {} serving {} for {:?}",
+        env!("CARGO_PKG_VERSION"),
+        addr,
+        proxy.shards()
+    );
+    proxy.serve(addr)
+}
+
+fn virtual_nodes(matches: &ArgMatches) -> usize {
+    matches
+        .get_one::<usize>("virtual-nodes")
+        .copied()
+        .unwrap_or(DEFAULT_VIRTUAL_NODES)
+}
+
+/// the shards listed in a file, skipping blank lines and `#` comments
+fn read_shards(path: &PathBuf) -> Result<Vec<String>> {
+    let shards: Vec<String> = fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect();
+    if shards.is_empty() {
+        return Err(KVStoreError::CommonStringError(format!(
+            "no shards in {}",
+            path.display()
+        )));
+    }
+    Ok(shards)
+}
+
+/// route requests to the shards of the file each time it is modified, keeping the current
+/// shards if it can not be read
+fn watch_shards_file(proxy: KvProxy, path: PathBuf, is_stop: Arc<AtomicBool>) {
+    let modified =
+        |path: &PathBuf| -> Option<SystemTime> { fs::metadata(path).ok()?.modified().ok() };
+    let mut last_modified = modified(&path);
+    thread::spawn(move || {
+        while !is_stop.load(Ordering::SeqCst) {
+            thread::sleep(SHARDS_FILE_INTERVAL);
+            let now_modified = modified(&path);
+            if now_modified == last_modified {
+                continue;
+            }
+            last_modified = now_modified;
+            let result = read_shards(&path).and_then(|shards| {
+                if shards != proxy.shards() {
+                    proxy.set_shards(shards)?;
+                }
+                Ok(())
+            });
+            if let Err(err) = result {
+                warn!(
+                    "Keep the current shards, {} is invalid: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    });
+}
+
+/// stop serving on SIGTERM or SIGINT
+#[cfg(unix)]
+fn stop_on_signal(is_stop: Arc<AtomicBool>) -> Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!("Received signal {}, stopping", signal);
+            is_stop.store(true, Ordering::SeqCst);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn stop_on_signal(_is_stop: Arc<AtomicBool>) -> Result<()> {
+    Ok(())
+}
@@ -1,25 +1,98 @@
-use clap::{arg, command, ArgMatches};
-use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
-use kvs::{EngineType, KVStoreError, KvServer, KvStore, KvsEngine, Result, SledKvsEngine};
-use log::{info, LevelFilter};
+use clap::{arg, command, ArgAction, ArgMatches, Command};
+use env_logger::{Target, WriteStyle};
+use kvs::thread_pool::{
+    NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, ThreadPoolBuilder,
+};
+use kvs::trace::OtlpExporter;
+use kvs::{
+    systemd_listeners, AccessList, Drain, EngineType, KVStoreError, KvServer, KvStore, KvsEngine,
+    MigratingEngine, ReadOnlyServing, Result, RollingFile, ServerConfig, ServerRole, SledKvsEngine,
+    SnapshotEngine, ThreadPoolKind,
+};
+use log::{error, info, warn};
+use std::fs;
+use std::io;
+use std::net::TcpListener;
+use std::panic;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicBool;
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{env, process};
 
+/// how long `kvs-server stop` waits for the server to exit, after its shutdown grace period
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
 fn main() -> Result<()> {
-    env_logger::builder().filter_level(LevelFilter::Info).init();
     let matches = command!()
         .name("kvs-server")
         .arg(
-            arg!(--addr <IPPORT>)
+            arg!(--config <FILE> "read the configuration from a TOML file")
                 .required(false)
-                .default_value("127.0.0.1:4000"),
+                .global(true),
+        )
+        .arg(
+            arg!(--set <ASSIGNMENT> "override any option of the configuration file, like thread_pool.threads=8")
+                .required(false)
+                .action(ArgAction::Append)
+                .global(true),
+        )
+        .arg(
+            arg!(--"check-config" "validate the configuration and exit without serving")
+                .action(ArgAction::SetTrue),
         )
+        .arg(arg!(--addr <IPPORT>).required(false))
         .arg(
             arg!(--engine <ENGINENAME>)
                 .required(false)
                 .value_parser(["kvs", "sled"]),
         )
+        .arg(arg!(--"data-dir" <DIR>).required(false))
+        .arg(arg!(--"replicate-to" <IPPORT>).required(false))
+        .arg(
+            arg!(--"migrate-to" <ENGINENAME> "write to ENGINENAME as well until a cutover switches the reads to it")
+                .required(false)
+                .value_parser(["kvs", "sled"]),
+        )
+        .arg(
+            arg!(--"bootstrap-from" <IPPORT> "copy all keys from the server at IPPORT before serving")
+                .required(false),
+        )
+        .arg(
+            arg!(--role <ROLE> "take the writes of clients, or only apply the writes replicated from the primary")
+                .required(false)
+                .value_parser(["primary", "follower"]),
+        )
+        .arg(
+            arg!(--primary <IPPORT> "the leader of a follower, which clients are sent to for writes")
+                .required(false),
+        )
+        .arg(
+            arg!(--snapshot <DIR> "serve the frozen store directory DIR read-only instead of the data directory")
+                .required(false),
+        )
+        .arg(arg!(--daemonize "run in the background").action(ArgAction::SetTrue))
+        .arg(
+            arg!(--"pid-file" <FILE> "write the process ID to FILE while serving")
+                .required(false)
+                .global(true),
+        )
+        .arg(
+            arg!(--"upgrade-socket" <PATH> "hand the listeners off to a server taking over at PATH")
+                .required(false),
+        )
+        .arg(
+            arg!(--"take-over" "take the listeners over from the server at the upgrade socket")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"log-file" <FILE> "append the logs to FILE, rolling it over as configured")
+                .required(false),
+        )
+        .subcommand(
+            Command::new("stop").about("Stop the server whose process ID is in the PID file"),
+        )
         .get_matches();
     if let Err(err) = init(matches) {
         eprintln!("{:?}", err);
@@ -28,28 +101,168 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+/// read the configuration file, if any, and override it with the environment and then the flags
+/// shared by all subcommands, `--set` before the flags of single options
+fn load_config(matches: &ArgMatches) -> Result<ServerConfig> {
+    let mut config = match matches.get_one::<String>("config") {
+        Some(path) => ServerConfig::load(path)?,
+        None => ServerConfig::default(),
+    };
+    // variables which are not unicode can not be options
+    config.apply_env(
+        env::vars_os().filter_map(|(name, value)| {
+            Some((name.into_string().ok()?, value.into_string().ok()?))
+        }),
+    )?;
+    for assignment in matches.get_many::<String>("set").into_iter().flatten() {
+        config.apply_override(assignment)?;
+    }
+    if let Some(pid_file) = matches.get_one::<String>("pid-file") {
+        config.pid_file = Some(PathBuf::from(pid_file));
+    }
+    Ok(config)
+}
+
+/// override the configuration with the flags of serving
+fn apply_flags(config: &mut ServerConfig, matches: &ArgMatches) {
+    if let Some(addr) = matches.get_one::<String>("addr") {
+        config.listen = vec![addr.clone()];
+    }
+    if let Some(engine) = matches.get_one::<String>("engine") {
+        config.engine = Some(engine.clone());
+    }
+    if let Some(data_dir) = matches.get_one::<String>("data-dir") {
+        config.data_dir = PathBuf::from(data_dir);
+    }
+    if let Some(remote) = matches.get_one::<String>("replicate-to") {
+        config.replicate_to = Some(remote.clone());
+    }
+    if let Some(engine) = matches.get_one::<String>("migrate-to") {
+        config.migrate_to = Some(engine.clone());
+    }
+    if let Some(remote) = matches.get_one::<String>("bootstrap-from") {
+        config.bootstrap_from = Some(remote.clone());
+    }
+    if let Some(role) = matches.get_one::<String>("role") {
+        config.role = match role.as_str() {
+            "follower" => ServerRole::Follower,
+            _ => ServerRole::Primary,
+        };
+    }
+    if let Some(primary) = matches.get_one::<String>("primary") {
+        config.primary = Some(primary.clone());
+    }
+    if let Some(dir) = matches.get_one::<String>("snapshot") {
+        config.snapshot = Some(PathBuf::from(dir));
+    }
+    if matches.get_flag("daemonize") {
+        config.daemonize = true;
+    }
+    if let Some(path) = matches.get_one::<String>("upgrade-socket") {
+        config.upgrade_socket = Some(PathBuf::from(path));
+    }
+    if let Some(log_file) = matches.get_one::<String>("log-file") {
+        config.log.file = Some(PathBuf::from(log_file));
+    }
+}
+
 fn init(matches: ArgMatches) -> Result<()> {
-    let addr = matches.get_one::<String>("addr").unwrap();
-    let engine_type = judge_engine(matches.get_one::<String>("engine").cloned())?;
+    if let Some(stop_matches) = matches.subcommand_matches("stop") {
+        return stop(&load_config(stop_matches)?);
+    }
+    let mut config = load_config(&matches)?;
+    apply_flags(&mut config, &matches);
+    config.validate()?;
+    if matches.get_flag("check-config") {
+        println!("configuration is valid");
+        return Ok(());
+    }
+    // open the log file before forking so that errors are reported to the terminal
+    let log_file = match config.log_file() {
+        Some(path) => Some(RollingFile::open(
+            path,
+            config.log.rotation(),
+            config.log.retain,
+        )?),
+        None => None,
+    };
+    // fork before any thread is started
+    if config.daemonize {
+        daemonize()?;
+    }
+    init_logger(&config, log_file)?;
+    let access = config.access_list()?;
+    reload_on_signal(matches.clone(), access.clone())?;
+    let _exporter = match &config.trace.otlp_endpoint {
+        Some(endpoint) => Some(OtlpExporter::install(endpoint, &config.trace.service_name)?),
+        None => None,
+    };
+    let taking_over = matches.get_flag("take-over");
+    let activated = if taking_over {
+        Some(take_over(&config)?)
+    } else {
+        systemd_listeners()?
+    };
+    let engine_type = match &config.migrate_to {
+        _ if config.snapshot.is_some() => EngineType::KvStore,
+        // the engine migrated from is the other one, whose directory already exists
+        Some(new) if new == "sled" => EngineType::KvStore,
+        Some(_) => EngineType::SledKvsEngine,
+        None => judge_engine(&config.data_dir, config.engine.clone())?,
+    };
 
     info!("Version: [{}]", env!("CARGO_PKG_VERSION"));
-    info!("Addr: [{}]", addr);
+    if activated.is_none() {
+        info!("Addr: [{}]", config.listen.join(", "));
+    }
     info!("Engine: [{}]", engine_type);
+    if let Some(dir) = &config.snapshot {
+        info!("Snapshot: [{}]", dir.display());
+    }
+    if let Some(new) = &config.migrate_to {
+        info!("Migrate to: [{}]", new);
+    }
+    if let Some(remote) = &config.replicate_to {
+        info!("Replicate to: [{}]", remote);
+    }
+    if let Some(remote) = &config.bootstrap_from {
+        info!("Bootstrap from: [{}]", remote);
+    }
 
-    match engine_type {
-        EngineType::KvStore => run_server(
-            KvStore::open(env::current_dir()?.join(EngineType::KvStore.to_string()))?,
-            addr,
-        ),
-        EngineType::SledKvsEngine => run_server(
-            SledKvsEngine::open(env::current_dir()?.join(EngineType::SledKvsEngine.to_string()))?,
-            addr,
-        ),
+    let _pid_file = match &config.pid_file {
+        Some(path) => Some(PidFile::create(path, taking_over)?),
+        None => None,
+    };
+    let kvs_path = config.data_dir.join(EngineType::KvStore.to_string());
+    let sled_path = config.data_dir.join(EngineType::SledKvsEngine.to_string());
+    let open_kvs = || {
+        let mut builder = KvStore::builder();
+        if let Some(max_disk_size) = config.limits.max_disk_size {
+            builder = builder.max_disk_size(max_disk_size);
+        }
+        builder.open(&kvs_path)
+    };
+    if let Some(dir) = &config.snapshot {
+        let engine = ReadOnlyServing::new(SnapshotEngine::open(dir)?);
+        return run_with_pool(engine, activated, access, &config);
+    }
+    match (engine_type, config.migrate_to.is_some()) {
+        (EngineType::KvStore, false) => run_with_pool(open_kvs()?, activated, access, &config),
+        (EngineType::SledKvsEngine, false) => {
+            run_with_pool(SledKvsEngine::open(&sled_path)?, activated, access, &config)
+        }
+        (EngineType::KvStore, true) => {
+            let engine = MigratingEngine::new(open_kvs()?, SledKvsEngine::open(&sled_path)?);
+            run_with_pool(engine, activated, access, &config)
+        }
+        (EngineType::SledKvsEngine, true) => {
+            let engine = MigratingEngine::new(SledKvsEngine::open(&sled_path)?, open_kvs()?);
+            run_with_pool(engine, activated, access, &config)
+        }
     }
 }
 
-fn judge_engine(engine: Option<String>) -> Result<EngineType> {
-    let dir = env::current_dir()?;
+fn judge_engine(dir: &Path, engine: Option<String>) -> Result<EngineType> {
     match engine {
         None => {
             if dir.join(EngineType::SledKvsEngine.to_string()).exists() {
@@ -73,12 +286,350 @@ fn judge_engine(engine: Option<String>) -> Result<EngineType> {
     }
 }
 
-fn run_server<E: KvsEngine>(engine: E, addr: &String) -> Result<()> {
-    let mut server = KvServer::new(
-        engine,
-        SharedQueueThreadPool::new(num_cpus::get())?,
-        Arc::new(AtomicBool::new(false)),
-    );
-    server.serve(addr)?;
+fn run_with_pool<E: KvsEngine>(
+    engine: E,
+    listeners: Option<Vec<TcpListener>>,
+    access: AccessList,
+    config: &ServerConfig,
+) -> Result<()> {
+    match config.thread_pool.kind {
+        ThreadPoolKind::Naive => {
+            let pool: NaiveThreadPool = start_pool(config)?;
+            run_server(engine, pool, listeners, access, config)
+        }
+        ThreadPoolKind::SharedQueue => {
+            let pool: SharedQueueThreadPool = start_pool(config)?;
+            run_server(engine, pool, listeners, access, config)
+        }
+        ThreadPoolKind::Rayon => {
+            let pool: RayonThreadPool = start_pool(config)?;
+            run_server(engine, pool, listeners, access, config)
+        }
+    }
+}
+
+fn start_pool<P: ThreadPool>(config: &ServerConfig) -> Result<P> {
+    start_labelled_pool(config, config.thread_pool.threads, "kvs-conn")
+}
+
+fn start_labelled_pool<P: ThreadPool>(
+    config: &ServerConfig,
+    threads: usize,
+    label: &str,
+) -> Result<P> {
+    let mut builder = ThreadPoolBuilder::new(threads)
+        .label(label)
+        .supervised(config.thread_pool.supervised);
+    if let Some(affinity) = config.cpu_affinity()? {
+        builder = builder.affinity(affinity);
+    }
+    builder.build()
+}
+
+/// serve on `listeners`, or on the configured addresses if the sockets were not passed in
+fn run_server<E: KvsEngine, P: ThreadPool + Send + Sync + 'static>(
+    engine: E,
+    pool: P,
+    listeners: Option<Vec<TcpListener>>,
+    access: AccessList,
+    config: &ServerConfig,
+) -> Result<()> {
+    let listeners = match listeners {
+        Some(listeners) => listeners,
+        None => config
+            .listen
+            .iter()
+            .map(TcpListener::bind)
+            .collect::<io::Result<_>>()?,
+    };
+    let is_stop = Arc::new(AtomicBool::new(false));
+    let drain = Drain::default();
+    stop_on_signal(
+        Arc::clone(&is_stop),
+        drain.clone(),
+        Duration::from_millis(config.shutdown_grace),
+    )?;
+    // dropped after the server, once the engine is closed
+    let _handoff = match &config.upgrade_socket {
+        Some(path) => Some(hand_off(path, &listeners, Arc::clone(&is_stop))?),
+        None => None,
+    };
+    // closed once the server stopped serving, so the next start trusts its data files
+    let closing = engine.clone();
+    let mut server = KvServer::new(engine, pool, is_stop)
+        .watch_history(config.watch_history)
+        .databases(config.databases)
+        .compression(config.compression)
+        .disable_commands(&config.disabled_commands)
+        .access_list(access)
+        .drain(drain)
+        .memcached_listeners(
+            config
+                .memcached_listen
+                .iter()
+                .map(TcpListener::bind)
+                .collect::<io::Result<_>>()?,
+        );
+    let pools = &config.thread_pool;
+    if pools.read_threads > 0 {
+        server = server.request_pools::<P>(
+            start_labelled_pool(config, pools.read_threads, "kvs-read")?,
+            start_labelled_pool(config, pools.write_threads, "kvs-write")?,
+            start_labelled_pool(config, pools.admin_threads, "kvs-admin")?,
+        );
+    }
+    if config.limits.slow_client_timeout > 0 {
+        server = server.slow_clients(
+            config.limits.max_watch_backlog,
+            Duration::from_secs(config.limits.slow_client_timeout),
+        );
+    }
+    if config.dedup.enabled {
+        server = server.dedup_cache(config.dedup.size, Duration::from_secs(config.dedup.ttl));
+    }
+    if config.admission.enabled {
+        server = server.admission_control(Duration::from_millis(config.admission.max_queue_wait));
+    }
+    if let Some(addr) = config.cluster_addr() {
+        server = server.cluster(config.cluster.shards.clone(), addr);
+    }
+    if let Some(primary) = &config.primary {
+        server = server.follower(primary);
+    }
+    if let Some(remote) = &config.bootstrap_from {
+        server.bootstrap_from(remote)?;
+    }
+    let _replicator = match &config.replicate_to {
+        Some(remote) => Some(server.replicate_to(remote, config.data_dir.join("replication"))?),
+        None => None,
+    };
+    let _lease = match &config.replicate_to {
+        Some(remote) if config.leader_lease > 0 => {
+            Some(server.leader_lease(remote, Duration::from_millis(config.leader_lease)))
+        }
+        _ => None,
+    };
+    let _failover = (config.failover_timeout > 0)
+        .then(|| server.failover_after(Duration::from_millis(config.failover_timeout)));
+    let _metrics = match &config.metrics.statsd_addr {
+        Some(addr) => Some(server.push_metrics(
+            addr,
+            &config.metrics.prefix,
+            Duration::from_secs(config.metrics.flush_interval),
+        )?),
+        None => None,
+    };
+    server.serve_listeners(listeners)?;
+    closing.close()?;
+    info!("Stopped");
+    Ok(())
+}
+
+/// the PID file of a running server, removed when the server stops
+struct PidFile(PathBuf);
+
+impl PidFile {
+    /// write the PID file, replacing the one of a running server only if `taking_over` from it
+    fn create(path: &Path, taking_over: bool) -> Result<Self> {
+        if path.exists() {
+            let pid = read_pid(path)?;
+            if is_running(pid) && !taking_over {
+                return Err(KVStoreError::CommonStringError(format!(
+                    "kvs-server is already running as process {}",
+                    pid
+                )));
+            }
+            warn!("Replace the stale PID file {:?} of process {}", path, pid);
+        }
+        fs::write(path, format!("{}\n", process::id()))?;
+        Ok(PidFile(path.to_owned()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // a server which took over has written its own
+        if read_pid(&self.0).ok() != Some(process::id() as i32) {
+            return;
+        }
+        if let Err(err) = fs::remove_file(&self.0) {
+            warn!("Can not remove the PID file {:?}: {}", self.0, err);
+        }
+    }
+}
+
+fn read_pid(path: &Path) -> Result<i32> {
+    let content = fs::read_to_string(path).map_err(|err| {
+        KVStoreError::CommonStringError(format!("can not read {}: {}", path.display(), err))
+    })?;
+    content.trim().parse().map_err(|_| {
+        KVStoreError::CommonStringError(format!("{} holds no process ID", path.display()))
+    })
+}
+
+/// signal the server of the PID file to stop and wait until it removed the file
+fn stop(config: &ServerConfig) -> Result<()> {
+    let path = config.pid_file.as_ref().ok_or_else(|| {
+        KVStoreError::CommonStringError("kvs-server stop needs a PID file".to_owned())
+    })?;
+    let pid = read_pid(path)?;
+    terminate(pid)?;
+    let timeout = STOP_TIMEOUT + Duration::from_millis(config.shutdown_grace);
+    let start = Instant::now();
+    while path.exists() && is_running(pid) {
+        if start.elapsed() > timeout {
+            return Err(KVStoreError::CommonStringError(format!(
+                "process {} did not stop within {:?}",
+                pid, timeout
+            )));
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    println!("kvs-server process {} stopped", pid);
+    Ok(())
+}
+
+/// log to stderr, or to the log file if there is one
+fn init_logger(config: &ServerConfig, log_file: Option<RollingFile>) -> Result<()> {
+    let mut builder = env_logger::builder();
+    builder.filter_level(config.log_level()?);
+    if let Some(log_file) = log_file {
+        builder
+            .target(Target::Pipe(Box::new(log_file)))
+            .write_style(WriteStyle::Never);
+        // a panic message would go to stderr only
+        panic::set_hook(Box::new(|info| error!("{}", info)));
+    }
+    builder.init();
+    Ok(())
+}
+
+/// detach from the terminal, whose output is discarded from then on
+#[cfg(unix)]
+fn daemonize() -> Result<()> {
+    daemonize::Daemonize::new()
+        .working_directory(env::current_dir()?)
+        .start()
+        .map_err(|err| KVStoreError::CommonStringError(format!("can not daemonize: {}", err)))
+}
+
+/// stop serving on SIGTERM or SIGINT, once the connections were drained for `grace`
+#[cfg(unix)]
+fn stop_on_signal(is_stop: Arc<AtomicBool>, drain: Drain, grace: Duration) -> Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+    use std::sync::atomic::Ordering;
+
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!("Received signal {}, stopping", signal);
+            if !grace.is_zero() {
+                info!("Draining the connections for {:?}", grace);
+                drain.start(grace);
+                thread::sleep(grace);
+            }
+            is_stop.store(true, Ordering::SeqCst);
+        }
+    });
+    Ok(())
+}
+
+/// reload the access rules from the configuration on SIGHUP
+#[cfg(unix)]
+fn reload_on_signal(matches: ArgMatches, access: AccessList) -> Result<()> {
+    use signal_hook::consts::SIGHUP;
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGHUP])?;
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            let reloaded = load_config(&matches)
+                .and_then(|config| access.reload(&config.access.allow, &config.access.deny));
+            match reloaded {
+                Ok(()) => info!("Reloaded the access rules"),
+                Err(err) => warn!("Kept the access rules, can not reload them: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// take the listeners over from the server handing them off at the upgrade socket
+#[cfg(unix)]
+fn take_over(config: &ServerConfig) -> Result<Vec<TcpListener>> {
+    let path = config.upgrade_socket.as_ref().ok_or_else(|| {
+        KVStoreError::CommonStringError("--take-over needs an upgrade socket".to_owned())
+    })?;
+    kvs::take_over_listeners(path)
+}
+
+/// hand the listeners off to a server taking over at `path`
+#[cfg(unix)]
+fn hand_off(
+    path: &Path,
+    listeners: &[TcpListener],
+    is_stop: Arc<AtomicBool>,
+) -> Result<kvs::ListenerHandoff> {
+    kvs::ListenerHandoff::start(path, listeners, is_stop)
+}
+
+#[cfg(unix)]
+fn terminate(pid: i32) -> Result<()> {
+    // SAFETY: kill has no memory safety requirements
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_running(pid: i32) -> bool {
+    // SAFETY: signal 0 only checks that the process exists
+    let result = unsafe { libc::kill(pid, 0) };
+    // a process of another user can not be signalled but exists
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn daemonize() -> Result<()> {
+    Err(KVStoreError::CommonStringError(
+        "daemonizing is only supported on Unix".to_owned(),
+    ))
+}
+
+#[cfg(not(unix))]
+fn stop_on_signal(_is_stop: Arc<AtomicBool>, _drain: Drain, _grace: Duration) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn reload_on_signal(_matches: ArgMatches, _access: AccessList) -> Result<()> {
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(not(unix))]
+fn take_over(_config: &ServerConfig) -> Result<Vec<TcpListener>> {
+    Err(KVStoreError::CommonStringError(
+        "taking over listeners is only supported on Unix".to_owned(),
+    ))
+}
+
+#[cfg(not(unix))]
+fn hand_off(_path: &Path, _listeners: &[TcpListener], _is_stop: Arc<AtomicBool>) -> Result<()> {
+    Err(KVStoreError::CommonStringError(
+        "handing off listeners is only supported on Unix".to_owned(),
+    ))
+}
+
+#[cfg(not(unix))]
+fn terminate(_pid: i32) -> Result<()> {
+    Err(KVStoreError::CommonStringError(
+        "kvs-server stop is only supported on Unix".to_owned(),
+    ))
+}
+
+#[cfg(not(unix))]
+fn is_running(_pid: i32) -> bool {
+    false
+}
@@ -0,0 +1,70 @@
+use clap::{arg, command, ArgMatches, Command, SubCommand};
+use kvs::{KvStore, KvStoreBuilder, Result};
+use serde_json::json;
+use std::process;
+use std::time::Duration;
+
+fn main() {
+    let matches = command!()
+        .name("kvs")
+        .subcommand_required(true)
+        .subcommand(store_args(
+            SubCommand::with_name("verify")
+                .about("Validate a store offline without changing it: its manifest, the records of its data files and the index rebuilt from them. Print a JSON report and exit with 1 if problems are found."),
+        ))
+        .subcommand(store_args(
+            SubCommand::with_name("trim")
+                .about("Compact a store which is not open elsewhere into as few data files as possible and print its disk usage before and after as JSON. Keys in a cold tier move to the hot tier, as no key was read since the store was opened."),
+        ))
+        .get_matches();
+    match run(&matches) {
+        Ok(code) => process::exit(code),
+        Err(err) => {
+            eprintln!("{}", json!({ "error": err.to_string() }));
+            process::exit(-1);
+        }
+    }
+}
+
+/// the arguments locating the directories of a store
+fn store_args(command: Command<'static>) -> Command<'static> {
+    command
+        .arg(arg!(<DIR> "The directory the store is opened at"))
+        .arg(
+            arg!(--"data-dir" <DIR> "Another directory holding data files of the store")
+                .required(false)
+                .action(clap::ArgAction::Append),
+        )
+        .arg(arg!(--"cold-dir" <DIR> "The cold tier directory of the store").required(false))
+}
+
+/// a builder for the store located by `store_args`
+fn builder(matches: &ArgMatches) -> KvStoreBuilder {
+    let mut builder = KvStore::builder();
+    for dir in matches.get_many::<String>("data-dir").into_iter().flatten() {
+        builder = builder.data_dir(dir);
+    }
+    if let Some(dir) = matches.get_one::<String>("cold-dir") {
+        builder = builder.cold_tier(dir, Duration::MAX);
+    }
+    builder
+}
+
+/// run the subcommand and return the exit code
+fn run(matches: &ArgMatches) -> Result<i32> {
+    match matches.subcommand() {
+        Some(("verify", sub_matches)) => {
+            let dir = sub_matches.get_one::<String>("DIR").unwrap();
+            let report = builder(sub_matches).verify(dir)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(if report.is_ok() { 0 } else { 1 })
+        }
+        Some(("trim", sub_matches)) => {
+            let dir = sub_matches.get_one::<String>("DIR").unwrap();
+            let report = builder(sub_matches).trim(dir)?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            Ok(0)
+        }
+        _ => unreachable!("a subcommand is required"),
+    }
+}
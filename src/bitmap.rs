@@ -0,0 +1,64 @@
+use crate::{KVStoreError, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+/// the highest bit offset of a bitmap, so a single request can not grow one beyond 512 MiB
+pub(crate) const MAX_BIT_OFFSET: u64 = (1 << 32) - 1;
+
+/// The bytes of the bitmap stored as the value of `key`, base64 encoded as the engines store
+/// strings; a missing key is an empty bitmap. Bit 0 is the most significant bit of byte 0.
+fn decode(key: &str, value: Option<String>) -> Result<Vec<u8>> {
+    match value {
+        Some(value) => BASE64
+            .decode(value)
+            .map_err(|_| KVStoreError::NotABitmap(key.to_owned())),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// the bit at `offset` of the bitmap stored as `value`, unset beyond its end
+pub(crate) fn get_bit(key: &str, value: Option<String>, offset: u64) -> Result<bool> {
+    let bytes = decode(key, value)?;
+    Ok(bytes
+        .get((offset / 8) as usize)
+        .is_some_and(|byte| byte & mask(offset) != 0))
+}
+
+/// Set the bit at `offset` of the bitmap stored as `value`, growing it with unset bits as
+/// needed. Return the new value and the bit before.
+pub(crate) fn set_bit(
+    key: &str,
+    value: Option<String>,
+    offset: u64,
+    bit: bool,
+) -> Result<(String, bool)> {
+    if offset > MAX_BIT_OFFSET {
+        return Err(KVStoreError::CommonStringError(format!(
+            "bit offset {} is beyond the largest bitmap, {} bits",
+            offset,
+            MAX_BIT_OFFSET + 1
+        )));
+    }
+    let mut bytes = decode(key, value)?;
+    let index = (offset / 8) as usize;
+    if bytes.len() <= index {
+        bytes.resize(index + 1, 0);
+    }
+    let old = bytes[index] & mask(offset) != 0;
+    if bit {
+        bytes[index] |= mask(offset);
+    } else {
+        bytes[index] &= !mask(offset);
+    }
+    Ok((BASE64.encode(bytes), old))
+}
+
+/// the number of set bits of the bitmap stored as `value`
+pub(crate) fn count(key: &str, value: Option<String>) -> Result<u64> {
+    let bytes = decode(key, value)?;
+    Ok(bytes.iter().map(|byte| byte.count_ones() as u64).sum())
+}
+
+fn mask(offset: u64) -> u8 {
+    0x80 >> (offset % 8)
+}
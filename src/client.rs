@@ -1,33 +1,754 @@
-use crate::{KVStoreError, Request, Response, Result};
-use serde::Deserialize;
-use serde_json::de::IoRead;
-use serde_json::Deserializer;
-use std::io::{BufReader, BufWriter, Write};
-use std::net::TcpStream;
-
-/// a tcp client which can connect to kvs-server
+use crate::cluster::ClusterClient;
+use crate::frame::{self, FrameWriter, FLAG_LZ4};
+use crate::integrity;
+use crate::random;
+use crate::trace::TraceContext;
+use crate::{
+    ClientInfo, DatabaseStats, KVStoreError, Request, Response, Result, ServerInfo, TopKeys,
+    Transform, WatchEvent,
+};
+use log::warn;
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+/// the most requests of a pipeline written before reading their responses, so the responses
+/// the server can not send while the client is still writing stay within the socket buffers
+const PIPELINE_WINDOW: usize = 256;
+
+/// a tcp client which can connect to kvs-server and send any number of requests over its connection
 pub struct Client {
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
-    writer: BufWriter<TcpStream>,
+    reader: BufReader<TcpStream>,
+    writer: FrameWriter<BufWriter<TcpStream>>,
+    trace_parent: Option<TraceContext>,
+    timeout: Option<Duration>,
+    integrity: bool,
+    /// the consistency token of the session, see `read_your_writes`
+    token: Option<u64>,
 }
 
 impl Client {
-    /// init a client
+    /// init a client, offering the server to compress large frames
     pub fn new(addr: &str) -> Result<Client> {
-        let stream = TcpStream::connect(addr)?;
+        let (_, reader, writer) = connect(addr)?;
         Ok(Client {
-            reader: Deserializer::from_reader(BufReader::new(stream.try_clone()?)),
-            writer: BufWriter::new(stream),
+            reader,
+            writer,
+            trace_parent: None,
+            timeout: None,
+            integrity: false,
+            token: None,
         })
     }
 
+    /// send the requests as part of the trace of `parent`, so the spans of the server are its
+    /// children
+    pub fn trace_parent(mut self, parent: TraceContext) -> Self {
+        self.trace_parent = Some(parent);
+        self
+    }
+
+    /// Give the server `timeout` to answer each request, see `Request::DEADLINE`. A request
+    /// which runs out of it fails with an error instead of finishing late.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Store each value set through the client with a checksum of its key and value, and
+    /// verify it on every get and scan, failing with `IntegrityMismatch` if the value was
+    /// changed anywhere between the two clients: on the wire, in the server or on disk.
+    /// Values stored without a checksum are read as they are.
+    pub fn integrity(mut self, enabled: bool) -> Self {
+        self.integrity = enabled;
+        self
+    }
+
+    /// Read your own writes from any server: send each request with the consistency token of
+    /// the writes the client has seen, starting with `token`, e.g. the `token` of another
+    /// client of the same session, and 0 for a new one. A server, e.g. a replica lagging
+    /// behind its primary, answers once it applied them, failing with `NotCaughtUp` if it
+    /// does not in time.
+    pub fn read_your_writes(mut self, token: u64) -> Self {
+        self.token = Some(token);
+        self
+    }
+
+    /// the consistency token of the writes the client has seen, if it reads its own writes
+    pub fn token(&self) -> Option<u64> {
+        self.token
+    }
+
+    /// start a batch of requests which are sent together, see `Pipeline`
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+
     /// perform a request
     pub fn request(&mut self, request: &Request) -> Result<Option<String>> {
-        serde_json::to_writer(&mut self.writer, request)?;
-        self.writer.flush()?;
-        match Response::deserialize(&mut self.reader)? {
+        match self.send(request)? {
+            Response::Ok(value) => self.open(request, value),
+            Response::Err(err) => Err(KVStoreError::CommonStringError(err)),
+            Response::Failed(err) => Err(KVStoreError::Remote(err)),
+            response => Err(KVStoreError::UnexpectedResponse(format!("{:?}", response))),
+        }
+    }
+
+    /// Set a key to a value once, however many times it is sent with `token`.
+    /// A write retried with the token it was first sent with, over any connection,
+    /// is answered without being applied again by a server with `KvServer::dedup_cache`.
+    pub fn set_idempotent(
+        &mut self,
+        token: &IdempotencyToken,
+        key: &str,
+        value: &str,
+    ) -> Result<()> {
+        let set = Request::SET(key.to_owned(), value.to_owned());
+        self.request(&token.attach(set))?;
+        Ok(())
+    }
+
+    /// Remove a key once, however many times it is sent with `token`, see `set_idempotent`.
+    /// A retry of a removal which succeeded does not fail with a missing key.
+    pub fn remove_idempotent(&mut self, token: &IdempotencyToken, key: &str) -> Result<()> {
+        self.request(&token.attach(Request::RM(key.to_owned())))?;
+        Ok(())
+    }
+
+    /// Set the bit at `offset` of the bitmap stored as the value of `key` on the server,
+    /// growing it as needed, and return the bit before. Bitmaps are stored base64 encoded,
+    /// bit 0 first as the most significant bit of the first byte, and a missing key is an
+    /// empty bitmap.
+    pub fn setbit(&mut self, key: &str, offset: u64, bit: bool) -> Result<bool> {
+        let old = self.request(&Request::SETBIT(key.to_owned(), offset, bit))?;
+        parse_bit(old)
+    }
+
+    /// get the bit at `offset` of the bitmap stored as the value of `key`, see `setbit`
+    pub fn getbit(&mut self, key: &str, offset: u64) -> Result<bool> {
+        let bit = self.request(&Request::GETBIT(key.to_owned(), offset))?;
+        parse_bit(bit)
+    }
+
+    /// count the set bits of the bitmap stored as the value of `key`, see `setbit`
+    pub fn bitcount(&mut self, key: &str) -> Result<u64> {
+        let count = self
+            .request(&Request::BITCOUNT(key.to_owned()))?
+            .unwrap_or_default();
+        count
+            .parse()
+            .map_err(|_| KVStoreError::UnexpectedResponse(count))
+    }
+
+    /// Add `elements` to the HyperLogLog sketch stored as the value of `key` on the server,
+    /// creating it if the key is missing, and return whether its estimate may have changed.
+    /// A sketch takes about 5 KiB however many elements it counts.
+    pub fn pfadd(&mut self, key: &str, elements: &[&str]) -> Result<bool> {
+        let elements = elements.iter().map(|element| element.to_string()).collect();
+        let changed = self.request(&Request::PFADD(key.to_owned(), elements))?;
+        parse_bit(changed)
+    }
+
+    /// Estimate the number of distinct elements added to the sketches of `keys` together,
+    /// with a standard error of about 1.6%, see `pfadd`.
+    pub fn pfcount(&mut self, keys: &[&str]) -> Result<u64> {
+        let keys = keys.iter().map(|key| key.to_string()).collect();
+        let count = self.request(&Request::PFCOUNT(keys))?.unwrap_or_default();
+        count
+            .parse()
+            .map_err(|_| KVStoreError::UnexpectedResponse(count))
+    }
+
+    /// Get `length` bytes of the value of `key` from the byte `offset` on, fewer past the end
+    /// of the value and none if the key is missing, without transferring the whole value.
+    /// The range must not start or end inside of a character.
+    pub fn getrange(&mut self, key: &str, offset: u64, length: u64) -> Result<String> {
+        let range = self.request(&Request::GETRANGE(key.to_owned(), offset, length))?;
+        Ok(range.unwrap_or_default())
+    }
+
+    /// Overwrite the bytes of the value of `key` from the byte `offset` on with `data`,
+    /// padding the value with zero bytes up to `offset` if it is shorter or missing, and
+    /// return the new length of the value. The range must not start or end inside of a
+    /// character.
+    pub fn setrange(&mut self, key: &str, offset: u64, data: &str) -> Result<u64> {
+        let length = self
+            .request(&Request::SETRANGE(key.to_owned(), offset, data.to_owned()))?
+            .unwrap_or_default();
+        length
+            .parse()
+            .map_err(|_| KVStoreError::UnexpectedResponse(length))
+    }
+
+    /// Set the field at the JSON pointer `pointer`, like `/user/emails/0`, of the JSON
+    /// document stored as the value of `key` to the JSON text `field`. The parent of the field
+    /// must exist, except for a missing key, which is an empty object; the empty pointer
+    /// replaces the whole document. The server only appends the change to its log.
+    pub fn json_set(&mut self, key: &str, pointer: &str, field: &str) -> Result<()> {
+        let request = Request::JSONSET(key.to_owned(), pointer.to_owned(), field.to_owned());
+        self.request(&request)?;
+        Ok(())
+    }
+
+    /// Get the field at the JSON pointer `pointer` of the JSON document stored as the value of
+    /// `key` as JSON text, None if the key is missing or the document lacks the field.
+    pub fn json_get(&mut self, key: &str, pointer: &str) -> Result<Option<String>> {
+        self.request(&Request::JSONGET(key.to_owned(), pointer.to_owned()))
+    }
+
+    /// Get the value of `key` transformed by the server, e.g. only a field of a big JSON
+    /// value, None if the key is missing or its value lacks the part.
+    pub fn get_transformed(&mut self, key: &str, transform: Transform) -> Result<Option<String>> {
+        let get = Request::GET(key.to_owned());
+        self.request(&Request::TRANSFORM(transform, Box::new(get)))
+    }
+
+    /// Get all key/value pairs whose key matches a glob pattern with the values transformed by
+    /// the server, leaving out the pairs whose value lacks the part.
+    pub fn scan_transformed(
+        &mut self,
+        pattern: &str,
+        transform: Transform,
+    ) -> Result<Vec<(String, String)>> {
+        let scan = Request::SCAN(pattern.to_owned());
+        match self.send(&Request::TRANSFORM(transform, Box::new(scan)))? {
+            Response::Pairs(pairs) => Ok(pairs),
+            Response::Err(err) => Err(KVStoreError::CommonStringError(err)),
+            Response::Failed(err) => Err(KVStoreError::Remote(err)),
+            response => Err(KVStoreError::UnexpectedResponse(format!("{:?}", response))),
+        }
+    }
+
+    /// get all key/value pairs whose key matches a glob pattern
+    pub fn scan_match(&mut self, pattern: &str) -> Result<Vec<(String, String)>> {
+        match self.send(&Request::SCAN(pattern.to_owned()))? {
+            Response::Pairs(pairs) if self.integrity => pairs
+                .into_iter()
+                .map(|(key, value)| Ok((key.clone(), integrity::open(&key, value)?)))
+                .collect(),
+            Response::Pairs(pairs) => Ok(pairs),
+            Response::Err(err) => Err(KVStoreError::CommonStringError(err)),
+            Response::Failed(err) => Err(KVStoreError::Remote(err)),
+            response => Err(KVStoreError::UnexpectedResponse(format!("{:?}", response))),
+        }
+    }
+
+    /// switch the connection to the logical database `database`, for all later requests
+    pub fn select(&mut self, database: u32) -> Result<()> {
+        self.request(&Request::SELECT(database))?;
+        Ok(())
+    }
+
+    /// Begin a transaction of the connection. Its reads see the keys as they are now and its
+    /// writes are applied together by `commit`, failing if another client wrote a key it
+    /// reads or writes in the meantime.
+    pub fn begin(&mut self) -> Result<()> {
+        self.request(&Request::BEGIN)?;
+        Ok(())
+    }
+
+    /// apply the writes of the transaction together
+    pub fn commit(&mut self) -> Result<()> {
+        self.request(&Request::COMMIT)?;
+        Ok(())
+    }
+
+    /// discard the writes of the transaction
+    pub fn rollback(&mut self) -> Result<()> {
+        self.request(&Request::ROLLBACK)?;
+        Ok(())
+    }
+
+    /// switch the reads of a server migrating to another engine over to the new one, see
+    /// `MigratingEngine`
+    pub fn cutover(&mut self) -> Result<()> {
+        self.request(&Request::CUTOVER)?;
+        Ok(())
+    }
+
+    /// Promote a follower to leader once the lease of its old leader expired, see
+    /// `KvServer::leader_lease`, returning the epoch of the promotion.
+    pub fn promote(&mut self) -> Result<u64> {
+        let epoch = self.request(&Request::PROMOTE)?.unwrap_or_default();
+        epoch
+            .parse()
+            .map_err(|_| KVStoreError::UnexpectedResponse(epoch))
+    }
+
+    /// get the health of the server, `serving`, or `draining` while it drains its connections,
+    /// see `Drain`
+    pub fn health(&mut self) -> Result<String> {
+        Ok(self.request(&Request::HEALTH)?.unwrap_or_default())
+    }
+
+    /// get what the server tells about itself, e.g. the instance id of its store
+    pub fn info(&mut self) -> Result<ServerInfo> {
+        match self.send(&Request::INFO)? {
+            Response::Info(info) => Ok(info),
+            Response::Err(err) => Err(KVStoreError::CommonStringError(err)),
+            Response::Failed(err) => Err(KVStoreError::Remote(err)),
+            response => Err(KVStoreError::UnexpectedResponse(format!("{:?}", response))),
+        }
+    }
+
+    /// Drain the connections of the server, serving those open now for `grace`, e.g. before
+    /// taking it down for maintenance, see `Drain`.
+    pub fn drain(&mut self, grace: Duration) -> Result<()> {
+        self.request(&Request::DRAIN(grace.as_millis() as u64))?;
+        Ok(())
+    }
+
+    /// get the shards of the cluster the server belongs to, empty if it is not part of one,
+    /// see `KvServer::cluster`
+    pub fn topology(&mut self) -> Result<Vec<String>> {
+        match self.send(&Request::TOPOLOGY)? {
+            Response::Topology(shards) => Ok(shards),
+            Response::Err(err) => Err(KVStoreError::CommonStringError(err)),
+            Response::Failed(err) => Err(KVStoreError::Remote(err)),
+            response => Err(KVStoreError::UnexpectedResponse(format!("{:?}", response))),
+        }
+    }
+
+    /// connect to a cluster of servers, fetching its topology from the first of `seeds`
+    /// which answers, see `ClusterClient`
+    pub fn connect_cluster(seeds: &[&str]) -> Result<ClusterClient> {
+        ClusterClient::connect(seeds)
+    }
+
+    /// get the number of keys and their size in the selected database
+    pub fn database_stats(&mut self) -> Result<DatabaseStats> {
+        match self.send(&Request::DBSTATS)? {
+            Response::DatabaseStats(stats) => Ok(stats),
+            Response::Err(err) => Err(KVStoreError::CommonStringError(err)),
+            Response::Failed(err) => Err(KVStoreError::Remote(err)),
+            response => Err(KVStoreError::UnexpectedResponse(format!("{:?}", response))),
+        }
+    }
+
+    /// get the `n` most read and most written keys with their approximate access counts
+    pub fn top_keys(&mut self, n: usize) -> Result<TopKeys> {
+        match self.send(&Request::TOPKEYS(n))? {
+            Response::TopKeys(top) => Ok(top),
+            Response::Err(err) => Err(KVStoreError::CommonStringError(err)),
+            Response::Failed(err) => Err(KVStoreError::Remote(err)),
+            response => Err(KVStoreError::UnexpectedResponse(format!("{:?}", response))),
+        }
+    }
+
+    /// list the kvs protocol connections of the server with their statistics, oldest first
+    pub fn client_list(&mut self) -> Result<Vec<ClientInfo>> {
+        match self.send(&Request::CLIENTLIST)? {
+            Response::Clients(clients) => Ok(clients),
+            Response::Err(err) => Err(KVStoreError::CommonStringError(err)),
+            Response::Failed(err) => Err(KVStoreError::Remote(err)),
+            response => Err(KVStoreError::UnexpectedResponse(format!("{:?}", response))),
+        }
+    }
+
+    /// Close the connection of the server with the id `id`, see `client_list`. A request it
+    /// is running finishes, but its response is not sent.
+    pub fn client_kill(&mut self, id: u64) -> Result<()> {
+        self.request(&Request::CLIENTKILL(id))?;
+        Ok(())
+    }
+
+    /// Watch the changes of keys starting with `prefix` after `since_revision`.
+    /// The server first replays the changes it retains, then streams new ones as they happen,
+    /// so a client which reconnects with the last revision it has seen misses nothing.
+    pub fn watch(mut self, prefix: &str, since_revision: u64) -> Result<Watcher> {
+        self.write(&Request::WATCH(prefix.to_owned(), since_revision))?;
+        Ok(Watcher {
+            reader: self.reader,
+        })
+    }
+
+    /// verify and strip the checksum of the value a get is answered with
+    fn open(&self, request: &Request, value: Option<String>) -> Result<Option<String>> {
+        match (value, integrity::read_key(request)) {
+            (Some(value), Some(key)) if self.integrity => integrity::open(key, value).map(Some),
+            (value, _) => Ok(value),
+        }
+    }
+
+    /// Read all keys of all databases as of one revision of the server, handing them to
+    /// `apply` in batches, and return the revision, see `Request::CHECKPOINT`.
+    pub fn checkpoint<F>(&mut self, mut apply: F) -> Result<u64>
+    where
+        F: FnMut(Vec<(String, String)>) -> Result<()>,
+    {
+        self.write(&Request::CHECKPOINT)?;
+        loop {
+            match self.read()? {
+                Response::Pairs(pairs) => apply(pairs)?,
+                Response::Revision(revision) => return Ok(revision),
+                Response::Err(err) => return Err(KVStoreError::CommonStringError(err)),
+                Response::Failed(err) => return Err(KVStoreError::Remote(err)),
+                response => {
+                    return Err(KVStoreError::UnexpectedResponse(format!("{:?}", response)))
+                }
+            }
+        }
+    }
+
+    pub(crate) fn send(&mut self, request: &Request) -> Result<Response> {
+        self.write(request)?;
+        self.read()
+    }
+
+    fn read(&mut self) -> Result<Response> {
+        match frame::read_frame(&mut self.reader)?.ok_or_else(connection_closed)? {
+            Response::Token(token, response) => {
+                if let Some(seen) = self.token.as_mut() {
+                    *seen = (*seen).max(token);
+                }
+                Ok(*response)
+            }
+            response => Ok(response),
+        }
+    }
+
+    fn write(&mut self, request: &Request) -> Result<()> {
+        self.encode(request)?;
+        self.writer.flush()
+    }
+
+    /// buffer a request without sending it
+    fn encode(&mut self, request: &Request) -> Result<()> {
+        let sealed = self.integrity.then(|| integrity::seal_request(request));
+        let request = sealed.as_ref().unwrap_or(request);
+        let after = match self.token {
+            Some(token) if !matches!(request, Request::WATCH(..) | Request::CHECKPOINT) => {
+                Some(Request::AFTER(token, Box::new(request.clone())))
+            }
+            _ => None,
+        };
+        let request = after.as_ref().unwrap_or(request);
+        let deadline = match self.timeout {
+            // a watch streams until the connection is closed and a checkpoint as long as it takes
+            Some(timeout) if !matches!(request, Request::WATCH(..) | Request::CHECKPOINT) => Some(
+                Request::DEADLINE(timeout.as_millis() as u64, Box::new(request.clone())),
+            ),
+            _ => None,
+        };
+        let request = deadline.as_ref().unwrap_or(request);
+        match self.trace_parent {
+            Some(parent) => {
+                let traced = Request::TRACED(parent.to_string(), Box::new(request.clone()));
+                self.writer.write(&traced)
+            }
+            None => self.writer.write(request),
+        }
+    }
+}
+
+/// the connection of a client with its reading and writing halves
+type Connection = (
+    TcpStream,
+    BufReader<TcpStream>,
+    FrameWriter<BufWriter<TcpStream>>,
+);
+
+/// connect to a server and agree on compression
+fn connect(addr: &str) -> Result<Connection> {
+    let mut stream = TcpStream::connect(addr)?;
+    let flags = frame::client_handshake(&mut stream, FLAG_LZ4)?;
+    let reader = BufReader::new(stream.try_clone()?);
+    let writer = FrameWriter::new(BufWriter::new(stream.try_clone()?), flags);
+    Ok((stream, reader, writer))
+}
+
+/// Requests sent over the connection of a `Client` in one write, with their responses read
+/// afterwards in order, so a batch costs one round trip instead of one per request
+pub struct Pipeline<'a> {
+    client: &'a mut Client,
+    requests: Vec<Request>,
+}
+
+impl Pipeline<'_> {
+    /// add setting the value of a key
+    pub fn set(mut self, key: &str, value: &str) -> Self {
+        self.requests
+            .push(Request::SET(key.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// add getting the value of a key
+    pub fn get(mut self, key: &str) -> Self {
+        self.requests.push(Request::GET(key.to_owned()));
+        self
+    }
+
+    /// add removing a key
+    pub fn remove(mut self, key: &str) -> Self {
+        self.requests.push(Request::RM(key.to_owned()));
+        self
+    }
+
+    /// Send the requests and return their results in the order they were added, like
+    /// `Client::request` returns them. A failed request only fails its own result;
+    /// the outer error is for the connection. Large pipelines are sent in windows of
+    /// `PIPELINE_WINDOW` requests, one round trip each.
+    pub fn execute(self) -> Result<Vec<Result<Option<String>>>> {
+        let mut results = Vec::with_capacity(self.requests.len());
+        for window in self.requests.chunks(PIPELINE_WINDOW) {
+            for request in window {
+                self.client.encode(request)?;
+            }
+            self.client.writer.flush()?;
+            for request in window {
+                results.push(match self.client.read()? {
+                    Response::Ok(value) => self.client.open(request, value),
+                    Response::Err(err) => Err(KVStoreError::CommonStringError(err)),
+                    Response::Failed(err) => Err(KVStoreError::Remote(err)),
+                    response => Err(KVStoreError::UnexpectedResponse(format!("{:?}", response))),
+                });
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// a stream of changes returned by `Client::watch`, which ends when the server closes the connection
+pub struct Watcher {
+    reader: BufReader<TcpStream>,
+}
+
+impl Iterator for Watcher {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match frame::read_frame(&mut self.reader) {
+            Ok(Some(Response::Event(event))) => Some(Ok(event)),
+            Ok(Some(Response::Err(err))) => Some(Err(KVStoreError::CommonStringError(err))),
+            Ok(Some(Response::Failed(err))) => Some(Err(KVStoreError::Remote(err))),
+            Ok(Some(response)) => Some(Err(KVStoreError::UnexpectedResponse(format!(
+                "{:?}",
+                response
+            )))),
+            Ok(None) => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// the requests of a `MultiplexClient` waiting for their responses by id,
+/// or None once the connection is closed
+type PendingRequests = Arc<Mutex<Option<HashMap<u64, SyncSender<Response>>>>>;
+
+/// A client whose connection is shared by any number of threads. Every request is tagged
+/// with an id, so the requests of all threads are in flight at once, the server runs them
+/// concurrently, and each response reaches its request whatever order it arrives in.
+pub struct MultiplexClient {
+    stream: TcpStream,
+    writer: Mutex<FrameWriter<BufWriter<TcpStream>>>,
+    next_id: AtomicU64,
+    pending: PendingRequests,
+}
+
+impl MultiplexClient {
+    /// connect to a server, reading its responses on a thread of the client
+    pub fn new(addr: &str) -> Result<MultiplexClient> {
+        let (stream, reader, writer) = connect(addr)?;
+        let pending: PendingRequests = Arc::new(Mutex::new(Some(HashMap::new())));
+        let reader_pending = Arc::clone(&pending);
+        thread::spawn(move || read_tagged_responses(reader, reader_pending));
+        Ok(MultiplexClient {
+            writer: Mutex::new(writer),
+            stream,
+            next_id: AtomicU64::new(0),
+            pending,
+        })
+    }
+
+    /// perform a request
+    pub fn request(&self, request: &Request) -> Result<Option<String>> {
+        match self.send(request)? {
             Response::Ok(value) => Ok(value),
             Response::Err(err) => Err(KVStoreError::CommonStringError(err)),
+            Response::Failed(err) => Err(KVStoreError::Remote(err)),
+            response => Err(KVStoreError::UnexpectedResponse(format!("{:?}", response))),
+        }
+    }
+
+    /// get all key/value pairs whose key matches a glob pattern
+    pub fn scan_match(&self, pattern: &str) -> Result<Vec<(String, String)>> {
+        match self.send(&Request::SCAN(pattern.to_owned()))? {
+            Response::Pairs(pairs) => Ok(pairs),
+            Response::Err(err) => Err(KVStoreError::CommonStringError(err)),
+            Response::Failed(err) => Err(KVStoreError::Remote(err)),
+            response => Err(KVStoreError::UnexpectedResponse(format!("{:?}", response))),
+        }
+    }
+
+    fn send(&self, request: &Request) -> Result<Response> {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = mpsc::sync_channel(1);
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(id, sender),
+            None => return Err(connection_closed()),
+        };
+        let tagged = Request::TAGGED(id, Box::new(request.clone()));
+        let written = self.writer.lock().unwrap().send(&tagged);
+        if let Err(err) = written {
+            if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                pending.remove(&id);
+            }
+            return Err(err);
+        }
+        receiver.recv().map_err(|_| connection_closed())
+    }
+}
+
+impl Drop for MultiplexClient {
+    fn drop(&mut self) {
+        // ends the thread reading the responses
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/// The identity of a write which makes it safe to retry, see `Client::set_idempotent`.
+/// Generate one token per write and reuse it for every attempt of that write.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdempotencyToken {
+    client_id: String,
+    request_id: u64,
+}
+
+impl IdempotencyToken {
+    /// a token unique among all tokens of all clients
+    pub fn generate() -> IdempotencyToken {
+        static CLIENT_ID: OnceLock<String> = OnceLock::new();
+        let client_id = CLIENT_ID.get_or_init(|| format!("{:016x}", random::random_u64()));
+        IdempotencyToken {
+            client_id: client_id.clone(),
+            request_id: random::random_u64(),
+        }
+    }
+
+    fn attach(&self, request: Request) -> Request {
+        Request::RETRYABLE(self.client_id.clone(), self.request_id, Box::new(request))
+    }
+}
+
+/// A client which retries a request on a new connection when its connection fails.
+/// Every request is sent as a `Request::RETRYABLE` with the id of the client and the same
+/// request id on each attempt, so a server with `KvServer::dedup_cache` applies it once even
+/// if only its response was lost. A new connection starts in database 0.
+pub struct RetryingClient {
+    addr: String,
+    client_id: String,
+    next_request_id: u64,
+    client: Option<Client>,
+    retries: u32,
+    integrity: bool,
+}
+
+impl RetryingClient {
+    /// init a client identified by `client_id`, which must be unique among the clients of
+    /// the server; the connection is opened by the first request
+    pub fn new(addr: &str, client_id: &str) -> RetryingClient {
+        RetryingClient {
+            addr: addr.to_owned(),
+            client_id: client_id.to_owned(),
+            next_request_id: 0,
+            client: None,
+            retries: 3,
+            integrity: false,
+        }
+    }
+
+    /// how many times a request is retried after its connection failed, 3 by default
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// verify the values the client reads against checksums stored with them, see
+    /// `Client::integrity`
+    pub fn integrity(mut self, enabled: bool) -> Self {
+        self.integrity = enabled;
+        self
+    }
+
+    /// perform a request, retrying it if the connection fails or the error is retryable
+    pub fn request(&mut self, request: &Request) -> Result<Option<String>> {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+        let retryable = Request::RETRYABLE(
+            self.client_id.clone(),
+            request_id,
+            Box::new(request.clone()),
+        );
+        let mut attempt = 0;
+        loop {
+            match self.attempt(&retryable) {
+                Err(err) if err.is_retryable() && attempt < self.retries => {
+                    warn!("Retrying request {} after: {}", request_id, err);
+                    // the connection is fine if the server answered
+                    if !matches!(err.root(), KVStoreError::Remote(_)) {
+                        self.client = None;
+                    }
+                    attempt += 1;
+                    thread::sleep(Duration::from_millis(50 << attempt));
+                }
+                result => return result,
+            }
         }
     }
-}
\ No newline at end of file
+
+    fn attempt(&mut self, request: &Request) -> Result<Option<String>> {
+        if self.client.is_none() {
+            self.client = Some(Client::new(&self.addr)?.integrity(self.integrity));
+        }
+        self.client.as_mut().unwrap().request(request)
+    }
+}
+
+/// hand the responses of a connection to their requests until it is closed,
+/// then fail the requests still waiting
+fn read_tagged_responses(mut reader: BufReader<TcpStream>, pending: PendingRequests) {
+    loop {
+        match frame::read_frame(&mut reader) {
+            Ok(Some(Response::Tagged(id, response))) => {
+                let sender = pending
+                    .lock()
+                    .unwrap()
+                    .as_mut()
+                    .and_then(|pending| pending.remove(&id));
+                if let Some(sender) = sender {
+                    let _ = sender.send(*response);
+                }
+            }
+            Ok(Some(response)) => warn!(
+                "Untagged response {:?} on a multiplexed connection",
+                response
+            ),
+            Ok(None) | Err(_) => break,
+        }
+    }
+    pending.lock().unwrap().take();
+}
+
+/// a bit or a flag answered by the server as `0` or `1`
+fn parse_bit(bit: Option<String>) -> Result<bool> {
+    match bit.as_deref() {
+        Some("0") => Ok(false),
+        Some("1") => Ok(true),
+        _ => Err(KVStoreError::UnexpectedResponse(format!("{:?}", bit))),
+    }
+}
+
+fn connection_closed() -> KVStoreError {
+    KVStoreError::Io(io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        "the connection to the server is closed",
+    ))
+}
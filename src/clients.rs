@@ -0,0 +1,186 @@
+use crate::{KVStoreError, Result};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A connection of a client to a server, as listed by `Client::client_list`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// the id of the connection, for `Client::client_kill`
+    pub id: u64,
+    /// the address the client connected from
+    pub addr: String,
+    /// how long ago the client connected
+    pub age: Duration,
+    /// how long ago the client sent its last request, or connected if it sent none
+    pub idle: Duration,
+    /// the number of requests the client sent
+    pub commands: u64,
+    /// the bytes received from the client
+    pub bytes_in: u64,
+    /// the bytes sent to the client
+    pub bytes_out: u64,
+    /// the name of the last request the client sent, see `Request::name`
+    pub last_command: Option<String>,
+    /// the logical database the connection selected
+    pub database: u32,
+}
+
+/// The kvs protocol connections a server is serving, listed by `Request::CLIENTLIST`
+#[derive(Default)]
+pub(crate) struct ClientRegistry {
+    last_id: AtomicU64,
+    clients: DashMap<u64, Arc<Connection>>,
+}
+
+impl ClientRegistry {
+    /// track a connection until the returned handle is dropped
+    pub(crate) fn register(self: &Arc<Self>, stream: &TcpStream) -> Result<ConnectionHandle> {
+        let id = self.last_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let now = Instant::now();
+        let connection = Arc::new(Connection {
+            id,
+            addr: stream.peer_addr()?.to_string(),
+            stream: stream.try_clone()?,
+            connected: now,
+            commands: AtomicU64::new(0),
+            bytes_in: Arc::new(AtomicU64::new(0)),
+            bytes_out: Arc::new(AtomicU64::new(0)),
+            database: AtomicU32::new(0),
+            last_command: Mutex::new((None, now)),
+        });
+        self.clients.insert(id, Arc::clone(&connection));
+        Ok(ConnectionHandle {
+            registry: Arc::clone(self),
+            connection,
+        })
+    }
+
+    /// the connections, oldest first
+    pub(crate) fn list(&self) -> Vec<ClientInfo> {
+        let mut clients: Vec<ClientInfo> = self
+            .clients
+            .iter()
+            .map(|entry| entry.value().info())
+            .collect();
+        clients.sort_unstable_by_key(|client| client.id);
+        clients
+    }
+
+    /// close the connection with the id `id`, so its handler stops at its next read
+    pub(crate) fn kill(&self, id: u64) -> Result<()> {
+        let connection = match self.clients.get(&id) {
+            Some(entry) => Arc::clone(entry.value()),
+            None => return Err(KVStoreError::ClientNotFound(id)),
+        };
+        connection.stream.shutdown(Shutdown::Both)?;
+        Ok(())
+    }
+}
+
+/// the statistics of a connection
+pub(crate) struct Connection {
+    id: u64,
+    addr: String,
+    stream: TcpStream,
+    connected: Instant,
+    commands: AtomicU64,
+    bytes_in: Arc<AtomicU64>,
+    bytes_out: Arc<AtomicU64>,
+    database: AtomicU32,
+    /// the name of the last request and when it was received
+    last_command: Mutex<(Option<&'static str>, Instant)>,
+}
+
+impl Connection {
+    /// count a request received from the client
+    pub(crate) fn command(&self, name: &'static str) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+        *self.last_command.lock().unwrap() = (Some(name), Instant::now());
+    }
+
+    pub(crate) fn select(&self, database: u32) {
+        self.database.store(database, Ordering::Relaxed);
+    }
+
+    /// the stream of the connection, counting the bytes received and sent over it
+    pub(crate) fn counted(&self, stream: TcpStream) -> CountedStream {
+        CountedStream {
+            stream,
+            bytes_in: Arc::clone(&self.bytes_in),
+            bytes_out: Arc::clone(&self.bytes_out),
+        }
+    }
+
+    fn info(&self) -> ClientInfo {
+        let (last_command, last_active) = *self.last_command.lock().unwrap();
+        ClientInfo {
+            id: self.id,
+            addr: self.addr.clone(),
+            age: self.connected.elapsed(),
+            idle: last_active.elapsed(),
+            commands: self.commands.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            last_command: last_command.map(str::to_owned),
+            database: self.database.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// a registered connection, removed from its registry once dropped
+pub(crate) struct ConnectionHandle {
+    registry: Arc<ClientRegistry>,
+    connection: Arc<Connection>,
+}
+
+impl std::ops::Deref for ConnectionHandle {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        &self.connection
+    }
+}
+
+impl Drop for ConnectionHandle {
+    fn drop(&mut self) {
+        self.registry.clients.remove(&self.connection.id);
+    }
+}
+
+/// a stream counting the bytes read from and written to it for the statistics of its connection
+pub(crate) struct CountedStream {
+    stream: TcpStream,
+    bytes_in: Arc<AtomicU64>,
+    bytes_out: Arc<AtomicU64>,
+}
+
+impl Read for CountedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.stream.read(buf)?;
+        self.bytes_in.fetch_add(read as u64, Ordering::Relaxed);
+        Ok(read)
+    }
+}
+
+impl Write for CountedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.stream.write(buf)?;
+        self.bytes_out.fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        let written = self.stream.write_vectored(bufs)?;
+        self.bytes_out.fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
@@ -0,0 +1,219 @@
+use crate::{Client, KVStoreError, Request, Result};
+use log::info;
+use std::collections::{BTreeMap, HashMap};
+
+/// the points each shard gets on the hash ring by default
+pub const DEFAULT_VIRTUAL_NODES: usize = 128;
+
+/// the most times a request is sent again after the topology changed under it
+const MAX_REDIRECTS: usize = 3;
+
+/// The shards of a cluster, each at `virtual_nodes` points of a consistent hash ring.
+/// A key is served by the first shard at or after its hash, so a change of the shards only
+/// moves the keys of the ring ranges which changed hands.
+pub(crate) struct HashRing {
+    pub(crate) shards: Vec<String>,
+    points: BTreeMap<u64, usize>,
+}
+
+impl HashRing {
+    pub(crate) fn new(shards: Vec<String>, virtual_nodes: usize) -> HashRing {
+        let mut points = BTreeMap::new();
+        for (index, shard) in shards.iter().enumerate() {
+            for node in 0..virtual_nodes {
+                points.insert(hash(format!("{}#{}", shard, node).as_bytes()), index);
+            }
+        }
+        HashRing { shards, points }
+    }
+
+    /// the first shard at or after the hash of `key`, wrapping around
+    pub(crate) fn shard_of(&self, key: &str) -> Option<&str> {
+        let point = hash(key.as_bytes());
+        self.points
+            .range(point..)
+            .chain(&self.points)
+            .next()
+            .map(|(_, index)| self.shards[*index].as_str())
+    }
+}
+
+/// The place of a server in a cluster, see `KvServer::cluster`
+pub(crate) struct ClusterTopology {
+    ring: HashRing,
+    /// the address of the server among the shards
+    addr: String,
+}
+
+impl ClusterTopology {
+    pub(crate) fn new(shards: Vec<String>, addr: String) -> ClusterTopology {
+        ClusterTopology {
+            ring: HashRing::new(shards, DEFAULT_VIRTUAL_NODES),
+            addr,
+        }
+    }
+
+    pub(crate) fn shards(&self) -> &[String] {
+        &self.ring.shards
+    }
+
+    /// the error for a request whose key another shard serves
+    pub(crate) fn check(&self, request: &Request) -> Option<KVStoreError> {
+        let owner = self.ring.shard_of(key_of(request)?)?;
+        (owner != self.addr).then(|| KVStoreError::Moved(owner.to_owned()))
+    }
+}
+
+/// A client of a cluster of kvs-servers, each holding a shard of the keys, see
+/// `Client::connect_cluster`.
+///
+/// Requests with a key go straight to the shard serving it, found on the same consistent hash
+/// ring as `KvServer::cluster` and `KvProxy` use. The shards are fetched from any reachable
+/// node, and again whenever a shard answers that another one serves a key, or can not be
+/// reached; the request is then sent again to the shard of the new topology.
+pub struct ClusterClient {
+    seeds: Vec<String>,
+    ring: HashRing,
+    connections: HashMap<String, Client>,
+}
+
+impl ClusterClient {
+    /// fetch the topology from the first of `seeds` which answers
+    pub(crate) fn connect(seeds: &[&str]) -> Result<ClusterClient> {
+        let mut client = ClusterClient {
+            seeds: seeds.iter().map(|seed| seed.to_string()).collect(),
+            ring: HashRing::new(Vec::new(), DEFAULT_VIRTUAL_NODES),
+            connections: HashMap::new(),
+        };
+        client.refresh_topology()?;
+        Ok(client)
+    }
+
+    /// the addresses of the shards
+    pub fn shards(&self) -> &[String] {
+        &self.ring.shards
+    }
+
+    /// perform a request with a key on the shard serving it
+    pub fn request(&mut self, request: &Request) -> Result<Option<String>> {
+        let key = key_of(request).ok_or_else(|| {
+            KVStoreError::CommonStringError(format!(
+                "a {} request has no key to route it by",
+                request.name()
+            ))
+        })?;
+        let mut redirects = 0;
+        loop {
+            let shard = self.ring.shard_of(key).unwrap().to_owned();
+            match self
+                .connection(&shard)
+                .and_then(|client| client.request(request))
+            {
+                Err(err) if redirects < MAX_REDIRECTS && is_stale(&err) => {
+                    self.connections.remove(&shard);
+                    redirects += 1;
+                    info!(
+                        "Refresh the cluster topology after {} answered: {}",
+                        shard, err
+                    );
+                    self.refresh_topology()?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// get all key/value pairs of all shards whose key matches a glob pattern, in key order
+    pub fn scan_match(&mut self, pattern: &str) -> Result<Vec<(String, String)>> {
+        let mut pairs = Vec::new();
+        for shard in self.ring.shards.clone() {
+            pairs.extend(self.connection(&shard)?.scan_match(pattern)?);
+        }
+        pairs.sort_unstable();
+        Ok(pairs)
+    }
+
+    /// Fetch the shards from the first node which answers, trying the known shards before the
+    /// seeds. A node which is not part of a cluster is the only shard.
+    pub fn refresh_topology(&mut self) -> Result<()> {
+        let mut last_err = None;
+        let nodes: Vec<String> = self
+            .ring
+            .shards
+            .iter()
+            .chain(&self.seeds)
+            .cloned()
+            .collect();
+        for node in nodes {
+            let topology = Client::new(&node).and_then(|mut client| client.topology());
+            match topology {
+                Ok(shards) => {
+                    let shards = if shards.is_empty() {
+                        vec![node]
+                    } else {
+                        shards
+                    };
+                    self.connections.retain(|shard, _| shards.contains(shard));
+                    self.ring = HashRing::new(shards, DEFAULT_VIRTUAL_NODES);
+                    return Ok(());
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            KVStoreError::CommonStringError("a cluster client needs a seed".to_owned())
+        }))
+    }
+
+    /// the connection to a shard, opened when first needed
+    fn connection(&mut self, shard: &str) -> Result<&mut Client> {
+        if !self.connections.contains_key(shard) {
+            self.connections
+                .insert(shard.to_owned(), Client::new(shard)?);
+        }
+        Ok(self.connections.get_mut(shard).unwrap())
+    }
+}
+
+/// whether a request failed because the topology it was routed by is out of date
+fn is_stale(err: &KVStoreError) -> bool {
+    err.code() == KVStoreError::Moved(String::new()).code()
+        || matches!(err.root(), KVStoreError::Io(_))
+}
+
+/// the key of a request served by a single shard
+pub(crate) fn key_of(request: &Request) -> Option<&str> {
+    match request {
+        Request::GET(key)
+        | Request::SET(key, _)
+        | Request::RM(key)
+        | Request::SETBIT(key, ..)
+        | Request::GETBIT(key, _)
+        | Request::BITCOUNT(key)
+        | Request::PFADD(key, _)
+        | Request::GETRANGE(key, ..)
+        | Request::SETRANGE(key, ..)
+        | Request::JSONSET(key, ..)
+        | Request::JSONGET(key, _) => Some(key),
+        Request::PFCOUNT(keys) if keys.len() == 1 => Some(&keys[0]),
+        Request::TRACED(_, request)
+        | Request::RETRYABLE(_, _, request)
+        | Request::DEADLINE(_, request)
+        | Request::AFTER(_, request)
+        | Request::TRANSFORM(_, request) => key_of(request),
+        _ => None,
+    }
+}
+
+/// 64-bit FNV-1a, stable across builds and platforms unlike the hasher of the standard
+/// library, with the finalizer of MurmurHash3 so similar keys land far apart on the ring
+pub(crate) fn hash(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
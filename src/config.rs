@@ -0,0 +1,823 @@
+use crate::proto::COMMANDS;
+use crate::thread_pool::CpuAffinity;
+use crate::watch::DEFAULT_WATCH_HISTORY;
+use crate::DEFAULT_MAX_QUEUE_WAIT;
+use crate::{AccessList, KVStoreError, Result, Rotation};
+use crate::{DEFAULT_DATABASES, DEFAULT_DEDUP_CACHE_SIZE, DEFAULT_DEDUP_CACHE_TTL};
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// the prefix of the environment variables overriding the configuration
+const ENV_PREFIX: &str = "KVS_";
+/// the default size a log file is rolled over at
+const DEFAULT_LOG_MAX_SIZE: u64 = 64 * 1024 * 1024;
+/// the default number of rolled over log files kept
+const DEFAULT_LOG_RETAIN: usize = 7;
+/// the default seconds between pushes of metrics
+const DEFAULT_METRICS_FLUSH_INTERVAL: u64 = 10;
+
+/** The configuration of `kvs-server`, usually read from a TOML file.
+Every field has a default, so a file only lists what it changes.
+
+`kvs-server` resolves every option from, in order of precedence: its flags, of which
+`--set` overrides any option (see `apply_override`), the `KVS_*` environment variables (see
+`apply_env`), the configuration file and the defaults.
+# Example
+```
+use kvs::{Result, ServerConfig};
+
+fn try_main() -> Result<()> {
+    let config = ServerConfig::parse(
+        r#"
+        listen = ["127.0.0.1:4000", "127.0.0.1:4001"]
+        engine = "kvs"
+
+        [thread_pool]
+        kind = "rayon"
+        threads = 8
+        "#,
+    )?;
+    config.validate()?;
+    assert_eq!(config.thread_pool.threads, 8);
+    Ok(())
+}
+```
+ */
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// the addresses to listen on
+    pub listen: Vec<String>,
+    /// the addresses to serve the memcached text protocol on, none by default
+    pub memcached_listen: Vec<String>,
+    /// the storage engine, `kvs` or `sled`, or None to use the one found in the data directory
+    pub engine: Option<String>,
+    /// the directory holding the data of the engine
+    pub data_dir: PathBuf,
+    /// the server to push all writes to
+    pub replicate_to: Option<String>,
+    /// the server to copy all keys from before serving, see `KvServer::bootstrap_from`
+    pub bootstrap_from: Option<String>,
+    /// whether the server takes the writes of clients, or is a read-only replica of `primary`
+    pub role: ServerRole,
+    /// the leader of a follower, which clients are sent to for writes, see `KvServer::follower`
+    pub primary: Option<String>,
+    /// the milliseconds of the lease a primary renews at the server it replicates to and only
+    /// takes writes while holding, see `KvServer::leader_lease`; 0, the default, takes writes
+    /// without a lease
+    pub leader_lease: u64,
+    /// the milliseconds without a lease renewal after which a follower promotes itself, see
+    /// `KvServer::failover_after`; 0, the default, waits for a `PROMOTE` command
+    pub failover_timeout: u64,
+    /// the engine to migrate to, writing to both engines until the `CUTOVER` command switches
+    /// the reads over, see `MigratingEngine`
+    pub migrate_to: Option<String>,
+    /// a frozen copy of the directory of a KvStore, e.g. a restored backup, to serve read-only
+    /// instead of `data_dir` for analytics or to check the restore, see `SnapshotEngine`
+    pub snapshot: Option<PathBuf>,
+    /// the number of writes kept for watchers which reconnect
+    pub watch_history: usize,
+    /// the number of logical databases clients can `SELECT`
+    pub databases: u32,
+    /// let clients compress large frames with LZ4, to save bandwidth between datacenters
+    pub compression: bool,
+    /// the commands the server answers with an error instead of running them, like `FLUSH`,
+    /// see `KvServer::disable_commands`
+    pub disabled_commands: Vec<String>,
+    /// run in the background, detached from the terminal
+    pub daemonize: bool,
+    /// the file the process ID is written to while the server runs, used by `kvs-server stop`
+    pub pid_file: Option<PathBuf>,
+    /// the Unix socket the server hands its listeners off at to a new server process started
+    /// with `--take-over`, for upgrades without downtime
+    pub upgrade_socket: Option<PathBuf>,
+    /// the milliseconds a server stopped by a signal drains its connections for before it
+    /// stops, see `Drain`; 0, the default, stops at once
+    pub shutdown_grace: u64,
+    /// the pool of threads serving connections
+    pub thread_pool: ThreadPoolConfig,
+    /// limits on the resources the server uses
+    pub limits: LimitsConfig,
+    /// the log output of the server
+    pub log: LogConfig,
+    /// the export of request spans to an OpenTelemetry collector
+    pub trace: TraceConfig,
+    /// the push of metrics to a StatsD server
+    pub metrics: MetricsConfig,
+    /// the cache answering retried requests, see `KvServer::dedup_cache`
+    pub dedup: DedupConfig,
+    /// the shedding of requests under overload, see `KvServer::admission_control`
+    pub admission: AdmissionConfig,
+    /// the cluster the server holds a shard of, see `KvServer::cluster`
+    pub cluster: ClusterConfig,
+    /// the clients whose connections are accepted, see `KvServer::access_list`
+    pub access: AccessConfig,
+    /// TLS for client connections, which this server does not support yet
+    pub tls: Option<TlsConfig>,
+    /// authentication of clients, which this server does not support yet
+    pub auth: Option<AuthConfig>,
+}
+
+/// The part a server plays among the servers replicating the same keys
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ServerRole {
+    /// takes the writes of clients
+    #[default]
+    Primary,
+    /// only applies the writes replicated from its primary
+    Follower,
+}
+
+/// The pool of threads serving connections
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThreadPoolConfig {
+    /// the thread pool implementation
+    pub kind: ThreadPoolKind,
+    /// the number of threads, by default one per CPU
+    pub threads: usize,
+    /// whether to replace the workers whose connection panicked instead of catching the
+    /// panic, see `ThreadPool::supervised`
+    pub supervised: bool,
+    /// the threads of the pool running reads, see `KvServer::request_pools`; 0, the default,
+    /// runs requests on the threads of their connections
+    pub read_threads: usize,
+    /// the threads of the pool running writes
+    pub write_threads: usize,
+    /// the threads of the pool running scans and the other admin requests
+    pub admin_threads: usize,
+    /// the cores to pin the threads of the pools to: `spread`, `compact` or core IDs
+    /// separated by commas, see `CpuAffinity`; not pinned by default
+    pub affinity: Option<String>,
+}
+
+/// The thread pool implementations of `kvs::thread_pool`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ThreadPoolKind {
+    /// `NaiveThreadPool`, a new thread for every connection
+    Naive,
+    /// `SharedQueueThreadPool`
+    #[default]
+    SharedQueue,
+    /// `RayonThreadPool`
+    Rayon,
+}
+
+/// Limits on the resources the server uses
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// the most bytes the data files of the `kvs` engine may take, see `KvStoreBuilder::max_disk_size`
+    pub max_disk_size: Option<u64>,
+    /// the seconds after which a client falling behind reading its responses is disconnected,
+    /// see `KvServer::slow_clients`; 0, the default, never disconnects clients
+    pub slow_client_timeout: u64,
+    /// the most bytes of events a watcher may fall behind by for `slow_client_timeout`,
+    /// 16 MiB by default
+    pub max_watch_backlog: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_disk_size: None,
+            slow_client_timeout: 0,
+            max_watch_backlog: 16 << 20,
+        }
+    }
+}
+
+/// The log output of the server
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// the most verbose level logged: off, error, warn, info, debug or trace
+    pub level: String,
+    /// the file logs are appended to instead of stderr,
+    /// by default `kvs-server.log` in the data directory for a daemonized server
+    pub file: Option<PathBuf>,
+    /// when the log file is rolled over
+    pub rotation: LogRotation,
+    /// the most bytes a log file takes before it is rolled over by size
+    pub max_size: u64,
+    /// the number of rolled over log files kept
+    pub retain: usize,
+}
+
+/// When the log file is rolled over, see `Rotation`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    /// never
+    Never,
+    /// once it reaches `max_size`
+    Size,
+    /// every day
+    Daily,
+}
+
+/// The export of request spans, see `trace::OtlpExporter`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TraceConfig {
+    /// the OTLP/HTTP endpoint of the collector, like `http://127.0.0.1:4318`, or None not to trace
+    pub otlp_endpoint: Option<String>,
+    /// the name the spans are reported under
+    pub service_name: String,
+}
+
+impl Default for TraceConfig {
+    fn default() -> Self {
+        TraceConfig {
+            otlp_endpoint: None,
+            service_name: "kvs-server".to_owned(),
+        }
+    }
+}
+
+/// The push of metrics, see `StatsdSink`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// the StatsD server metrics are pushed to over UDP, like `127.0.0.1:8125`, or None not to
+    pub statsd_addr: Option<String>,
+    /// the prefix of the metric names
+    pub prefix: String,
+    /// the seconds between pushes
+    pub flush_interval: u64,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            statsd_addr: None,
+            prefix: "kvs".to_owned(),
+            flush_interval: DEFAULT_METRICS_FLUSH_INTERVAL,
+        }
+    }
+}
+
+/// The cache answering retried requests, see `KvServer::dedup_cache`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupConfig {
+    /// whether to cache the responses of retryable requests
+    pub enabled: bool,
+    /// the number of responses cached
+    pub size: usize,
+    /// the seconds a response is cached for
+    pub ttl: u64,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        DedupConfig {
+            enabled: false,
+            size: DEFAULT_DEDUP_CACHE_SIZE,
+            ttl: DEFAULT_DEDUP_CACHE_TTL.as_secs(),
+        }
+    }
+}
+
+/// The shedding of requests under overload, see `KvServer::admission_control`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdmissionConfig {
+    /// whether to shed requests while the thread pools are overloaded
+    pub enabled: bool,
+    /// the milliseconds jobs may wait for a thread before requests are shed
+    pub max_queue_wait: u64,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        AdmissionConfig {
+            enabled: false,
+            max_queue_wait: DEFAULT_MAX_QUEUE_WAIT.as_millis() as u64,
+        }
+    }
+}
+
+/// The cluster a server holds a shard of, see `KvServer::cluster`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// the addresses clients reach the shards at, none if the server is not part of a cluster
+    pub shards: Vec<String>,
+    /// the address of this server among the shards, by default the first listen address
+    pub addr: Option<String>,
+}
+
+/// The clients whose connections are accepted, by CIDRs like `10.0.0.0/8`, see `AccessList`.
+/// `kvs-server` reloads them on SIGHUP.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    /// the networks of the clients accepted, all by default
+    pub allow: Vec<String>,
+    /// the networks of the clients refused, even if allowed
+    pub deny: Vec<String>,
+}
+
+/// TLS for client connections
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// the PEM file of the server certificate chain
+    pub cert: PathBuf,
+    /// the PEM file of the private key
+    pub key: PathBuf,
+}
+
+/// Authentication of clients
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct AuthConfig {
+    /// the tokens clients may authenticate with
+    pub tokens: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen: vec!["127.0.0.1:4000".to_owned()],
+            memcached_listen: Vec::new(),
+            engine: None,
+            data_dir: PathBuf::from("."),
+            replicate_to: None,
+            bootstrap_from: None,
+            role: ServerRole::default(),
+            primary: None,
+            leader_lease: 0,
+            failover_timeout: 0,
+            migrate_to: None,
+            snapshot: None,
+            watch_history: DEFAULT_WATCH_HISTORY,
+            databases: DEFAULT_DATABASES,
+            compression: true,
+            disabled_commands: Vec::new(),
+            daemonize: false,
+            pid_file: None,
+            upgrade_socket: None,
+            shutdown_grace: 0,
+            thread_pool: ThreadPoolConfig::default(),
+            limits: LimitsConfig::default(),
+            log: LogConfig::default(),
+            trace: TraceConfig::default(),
+            metrics: MetricsConfig::default(),
+            dedup: DedupConfig::default(),
+            admission: AdmissionConfig::default(),
+            cluster: ClusterConfig::default(),
+            access: AccessConfig::default(),
+            tls: None,
+            auth: None,
+        }
+    }
+}
+
+impl Default for ThreadPoolConfig {
+    fn default() -> Self {
+        ThreadPoolConfig {
+            kind: ThreadPoolKind::default(),
+            threads: num_cpus::get(),
+            supervised: false,
+            read_threads: 0,
+            write_threads: 0,
+            admin_threads: 0,
+            affinity: None,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_owned(),
+            file: None,
+            rotation: LogRotation::Size,
+            max_size: DEFAULT_LOG_MAX_SIZE,
+            retain: DEFAULT_LOG_RETAIN,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Read the configuration from a TOML file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|err| {
+            KVStoreError::InvalidConfig(format!("can not read {}: {}", path.display(), err))
+        })?;
+        Self::parse(&text)
+    }
+
+    /// Parse the configuration from TOML.
+    pub fn parse(text: &str) -> Result<Self> {
+        toml::from_str(text).map_err(|err| KVStoreError::InvalidConfig(err.to_string()))
+    }
+
+    /// Override the options set by `KVS_*` environment variables in `vars`, e.g. `env::vars()`.
+    /// Each option is named after its path in the file, upper-cased, with `_` between the parts:
+    /// `KVS_LISTEN` and `KVS_MEMCACHED_LISTEN` (addresses separated by commas), `KVS_ENGINE`, `KVS_DATA_DIR`, `KVS_REPLICATE_TO`, `KVS_BOOTSTRAP_FROM`,
+    /// `KVS_ROLE`, `KVS_PRIMARY`, `KVS_LEADER_LEASE`, `KVS_FAILOVER_TIMEOUT`, `KVS_MIGRATE_TO`, `KVS_SNAPSHOT`, `KVS_WATCH_HISTORY`, `KVS_DATABASES`, `KVS_COMPRESSION`, `KVS_DISABLED_COMMANDS` (separated by commas), `KVS_DAEMONIZE`, `KVS_PID_FILE`,
+    /// `KVS_UPGRADE_SOCKET`, `KVS_SHUTDOWN_GRACE`, `KVS_THREAD_POOL_KIND`, `KVS_THREAD_POOL_THREADS`,
+    /// `KVS_THREAD_POOL_SUPERVISED`, `KVS_THREAD_POOL_READ_THREADS`,
+    /// `KVS_THREAD_POOL_WRITE_THREADS`, `KVS_THREAD_POOL_ADMIN_THREADS`,
+    /// `KVS_THREAD_POOL_AFFINITY`,
+    /// `KVS_LIMITS_MAX_DISK_SIZE`, `KVS_LIMITS_SLOW_CLIENT_TIMEOUT`,
+    /// `KVS_LIMITS_MAX_WATCH_BACKLOG`, `KVS_LOG_LEVEL`, `KVS_LOG_FILE`, `KVS_LOG_ROTATION`,
+    /// `KVS_LOG_MAX_SIZE`, `KVS_LOG_RETAIN`, `KVS_TRACE_OTLP_ENDPOINT`, `KVS_TRACE_SERVICE_NAME`,
+    /// `KVS_METRICS_STATSD_ADDR`, `KVS_METRICS_PREFIX`, `KVS_METRICS_FLUSH_INTERVAL`,
+    /// `KVS_DEDUP_ENABLED`, `KVS_DEDUP_SIZE`, `KVS_DEDUP_TTL`, `KVS_ADMISSION_ENABLED`,
+    /// `KVS_ADMISSION_MAX_QUEUE_WAIT`, `KVS_CLUSTER_SHARDS` (separated by commas), `KVS_CLUSTER_ADDR`,
+    /// `KVS_ACCESS_ALLOW`, `KVS_ACCESS_DENY` (CIDRs separated by commas),
+    /// `KVS_TLS_CERT`, `KVS_TLS_KEY` and `KVS_AUTH_TOKENS` (separated by commas).
+    /// Other `KVS_*` variables are rejected like unknown keys of the file, e.g. misspelled
+    /// options; variables without the prefix are ignored.
+    pub fn apply_env<I: IntoIterator<Item = (String, String)>>(&mut self, vars: I) -> Result<()> {
+        let mut tls_cert = None;
+        let mut tls_key = None;
+        for (name, value) in vars {
+            let option = match name.strip_prefix(ENV_PREFIX) {
+                Some(option) => option,
+                None => continue,
+            };
+            match option {
+                "LISTEN" => self.listen = split_list(&value),
+                "MEMCACHED_LISTEN" => self.memcached_listen = split_list(&value),
+                "ENGINE" => self.engine = Some(value),
+                "DATA_DIR" => self.data_dir = PathBuf::from(value),
+                "REPLICATE_TO" => self.replicate_to = Some(value),
+                "BOOTSTRAP_FROM" => self.bootstrap_from = Some(value),
+                "ROLE" => {
+                    self.role = match value.as_str() {
+                        "primary" => ServerRole::Primary,
+                        "follower" => ServerRole::Follower,
+                        _ => {
+                            return Err(KVStoreError::InvalidConfig(format!(
+                                "{}={} is neither primary nor follower",
+                                name, value
+                            )))
+                        }
+                    }
+                }
+                "PRIMARY" => self.primary = Some(value),
+                "SNAPSHOT" => self.snapshot = Some(PathBuf::from(value)),
+                "LEADER_LEASE" => self.leader_lease = parse_env(&name, &value)?,
+                "FAILOVER_TIMEOUT" => self.failover_timeout = parse_env(&name, &value)?,
+                "MIGRATE_TO" => self.migrate_to = Some(value),
+                "WATCH_HISTORY" => self.watch_history = parse_env(&name, &value)?,
+                "DATABASES" => self.databases = parse_env(&name, &value)?,
+                "COMPRESSION" => self.compression = parse_env(&name, &value)?,
+                "DISABLED_COMMANDS" => self.disabled_commands = split_list(&value),
+                "DAEMONIZE" => self.daemonize = parse_env(&name, &value)?,
+                "PID_FILE" => self.pid_file = Some(PathBuf::from(value)),
+                "UPGRADE_SOCKET" => self.upgrade_socket = Some(PathBuf::from(value)),
+                "SHUTDOWN_GRACE" => self.shutdown_grace = parse_env(&name, &value)?,
+                "THREAD_POOL_KIND" => {
+                    self.thread_pool.kind = match value.as_str() {
+                        "naive" => ThreadPoolKind::Naive,
+                        "shared_queue" => ThreadPoolKind::SharedQueue,
+                        "rayon" => ThreadPoolKind::Rayon,
+                        _ => {
+                            return Err(KVStoreError::InvalidConfig(format!(
+                                "{}={} is not naive, shared_queue or rayon",
+                                name, value
+                            )))
+                        }
+                    }
+                }
+                "THREAD_POOL_THREADS" => self.thread_pool.threads = parse_env(&name, &value)?,
+                "THREAD_POOL_SUPERVISED" => self.thread_pool.supervised = parse_env(&name, &value)?,
+                "THREAD_POOL_READ_THREADS" => {
+                    self.thread_pool.read_threads = parse_env(&name, &value)?
+                }
+                "THREAD_POOL_WRITE_THREADS" => {
+                    self.thread_pool.write_threads = parse_env(&name, &value)?
+                }
+                "THREAD_POOL_ADMIN_THREADS" => {
+                    self.thread_pool.admin_threads = parse_env(&name, &value)?
+                }
+                "THREAD_POOL_AFFINITY" => self.thread_pool.affinity = Some(value),
+                "LIMITS_MAX_DISK_SIZE" => {
+                    self.limits.max_disk_size = Some(parse_env(&name, &value)?)
+                }
+                "LIMITS_SLOW_CLIENT_TIMEOUT" => {
+                    self.limits.slow_client_timeout = parse_env(&name, &value)?
+                }
+                "LIMITS_MAX_WATCH_BACKLOG" => {
+                    self.limits.max_watch_backlog = parse_env(&name, &value)?
+                }
+                "LOG_LEVEL" => self.log.level = value,
+                "LOG_FILE" => self.log.file = Some(PathBuf::from(value)),
+                "LOG_ROTATION" => {
+                    self.log.rotation = match value.as_str() {
+                        "never" => LogRotation::Never,
+                        "size" => LogRotation::Size,
+                        "daily" => LogRotation::Daily,
+                        _ => {
+                            return Err(KVStoreError::InvalidConfig(format!(
+                                "{}={} is not never, size or daily",
+                                name, value
+                            )))
+                        }
+                    }
+                }
+                "LOG_MAX_SIZE" => self.log.max_size = parse_env(&name, &value)?,
+                "LOG_RETAIN" => self.log.retain = parse_env(&name, &value)?,
+                "TRACE_OTLP_ENDPOINT" => self.trace.otlp_endpoint = Some(value),
+                "TRACE_SERVICE_NAME" => self.trace.service_name = value,
+                "METRICS_STATSD_ADDR" => self.metrics.statsd_addr = Some(value),
+                "METRICS_PREFIX" => self.metrics.prefix = value,
+                "METRICS_FLUSH_INTERVAL" => self.metrics.flush_interval = parse_env(&name, &value)?,
+                "DEDUP_ENABLED" => self.dedup.enabled = parse_env(&name, &value)?,
+                "DEDUP_SIZE" => self.dedup.size = parse_env(&name, &value)?,
+                "DEDUP_TTL" => self.dedup.ttl = parse_env(&name, &value)?,
+                "ADMISSION_ENABLED" => self.admission.enabled = parse_env(&name, &value)?,
+                "ADMISSION_MAX_QUEUE_WAIT" => {
+                    self.admission.max_queue_wait = parse_env(&name, &value)?
+                }
+                "CLUSTER_SHARDS" => self.cluster.shards = split_list(&value),
+                "CLUSTER_ADDR" => self.cluster.addr = Some(value),
+                "ACCESS_ALLOW" => self.access.allow = split_list(&value),
+                "ACCESS_DENY" => self.access.deny = split_list(&value),
+                "TLS_CERT" => tls_cert = Some(PathBuf::from(value)),
+                "TLS_KEY" => tls_key = Some(PathBuf::from(value)),
+                "AUTH_TOKENS" => {
+                    self.auth = Some(AuthConfig {
+                        tokens: split_list(&value),
+                    })
+                }
+                _ => {
+                    return Err(KVStoreError::InvalidConfig(format!(
+                        "{} is not a configuration option",
+                        name
+                    )))
+                }
+            }
+        }
+        match (tls_cert, tls_key, &mut self.tls) {
+            (None, None, _) => {}
+            (cert, key, Some(tls)) => {
+                tls.cert = cert.unwrap_or_else(|| tls.cert.clone());
+                tls.key = key.unwrap_or_else(|| tls.key.clone());
+            }
+            (Some(cert), Some(key), None) => self.tls = Some(TlsConfig { cert, key }),
+            _ => {
+                return Err(KVStoreError::InvalidConfig(
+                    "KVS_TLS_CERT and KVS_TLS_KEY must be set together".to_owned(),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Override an option as `kvs-server --set` does: `assignment` is the path of the option
+    /// in the file and a TOML value, like `thread_pool.threads=8` or
+    /// `listen=["127.0.0.1:4000"]`. A value which is not TOML is taken as a string, like
+    /// `log.level=debug`, so strings looking like numbers or booleans need quotes.
+    /// Unknown options are rejected like unknown keys of the file.
+    pub fn apply_override(&mut self, assignment: &str) -> Result<()> {
+        let invalid = |reason: String| {
+            KVStoreError::InvalidConfig(format!("--set {}: {}", assignment, reason))
+        };
+        let (path, text) = assignment
+            .split_once('=')
+            .ok_or_else(|| invalid("is not OPTION=VALUE".to_owned()))?;
+        let value = match toml::from_str::<toml::value::Table>(&format!("value = {}", text)) {
+            Ok(mut table) if table.len() == 1 => table.remove("value").unwrap(),
+            _ => toml::Value::String(text.to_owned()),
+        };
+
+        let mut config = toml::Value::try_from(&*self).map_err(|err| invalid(err.to_string()))?;
+        let mut parts: Vec<&str> = path.trim().split('.').collect();
+        let option = parts.pop().unwrap_or_default();
+        let mut section = config.as_table_mut().unwrap();
+        for part in parts {
+            section = section
+                .entry(part.to_owned())
+                .or_insert_with(|| toml::Value::Table(toml::value::Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| invalid(format!("{} is not a section", part)))?;
+        }
+        section.insert(option.to_owned(), value);
+        *self = config.try_into().map_err(|err| invalid(err.to_string()))?;
+        Ok(())
+    }
+
+    /// Check the configuration for values the server can not run with.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |message: String| Err(KVStoreError::InvalidConfig(message));
+        if self.listen.is_empty() {
+            return invalid("listen needs at least one address".to_owned());
+        }
+        for addr in self.listen.iter().chain(&self.memcached_listen) {
+            if addr.parse::<SocketAddr>().is_err() {
+                return invalid(format!("listen address {} is not an IP:PORT", addr));
+            }
+        }
+        if let Some(engine) = &self.engine {
+            if engine != "kvs" && engine != "sled" {
+                return invalid(format!("engine {} is neither kvs nor sled", engine));
+            }
+        }
+        if let Some(engine) = &self.migrate_to {
+            if engine != "kvs" && engine != "sled" {
+                return invalid(format!("migrate_to {} is neither kvs nor sled", engine));
+            }
+            if self.engine.as_ref() == Some(engine) {
+                return invalid(format!("migrate_to {} is the engine migrated from", engine));
+            }
+        }
+        if let Some(remote) = &self.replicate_to {
+            if remote.parse::<SocketAddr>().is_err() {
+                return invalid(format!("replicate_to {} is not an IP:PORT", remote));
+            }
+        }
+        if let Some(remote) = &self.bootstrap_from {
+            if remote.parse::<SocketAddr>().is_err() {
+                return invalid(format!("bootstrap_from {} is not an IP:PORT", remote));
+            }
+        }
+        match (self.role, &self.primary) {
+            (ServerRole::Follower, None) => {
+                return invalid("a follower needs the address of its primary".to_owned())
+            }
+            (ServerRole::Follower, Some(primary)) if primary.parse::<SocketAddr>().is_err() => {
+                return invalid(format!("primary {} is not an IP:PORT", primary));
+            }
+            (ServerRole::Primary, Some(_)) => {
+                return invalid("primary is only set for a follower".to_owned())
+            }
+            _ => {}
+        }
+        if self.leader_lease > 0
+            && (self.role != ServerRole::Primary || self.replicate_to.is_none())
+        {
+            return invalid("leader_lease needs a primary with replicate_to".to_owned());
+        }
+        if self.failover_timeout > 0 && self.role != ServerRole::Follower {
+            return invalid("failover_timeout is only set for a follower".to_owned());
+        }
+        if self.snapshot.is_some() {
+            if self.engine.as_deref() == Some("sled") {
+                return invalid("snapshot serves a kvs data directory, not sled".to_owned());
+            }
+            if self.replicate_to.is_some()
+                || self.bootstrap_from.is_some()
+                || self.migrate_to.is_some()
+                || self.role == ServerRole::Follower
+            {
+                return invalid(
+                    "snapshot takes no writes, so no replicate_to, bootstrap_from, migrate_to or follower role"
+                        .to_owned(),
+                );
+            }
+        }
+        if self.databases == 0 {
+            return invalid("databases must be at least 1".to_owned());
+        }
+        for name in &self.disabled_commands {
+            if !COMMANDS.contains(&name.to_ascii_uppercase().as_str()) {
+                return invalid(format!("disabled command {} is not a command", name));
+            }
+        }
+        if self.thread_pool.threads == 0 {
+            return invalid("thread_pool.threads must be at least 1".to_owned());
+        }
+        let pool = &self.thread_pool;
+        let request_pools = [pool.read_threads, pool.write_threads, pool.admin_threads];
+        if request_pools.contains(&0) && request_pools.iter().any(|threads| *threads > 0) {
+            return invalid(
+                "thread_pool.read_threads, write_threads and admin_threads must be set together"
+                    .to_owned(),
+            );
+        }
+        self.cpu_affinity()?;
+        self.log_level()?;
+        if self.log.max_size == 0 {
+            return invalid("log.max_size must be at least 1".to_owned());
+        }
+        if let Some(endpoint) = &self.trace.otlp_endpoint {
+            if !endpoint.starts_with("http://") {
+                return invalid(format!(
+                    "trace.otlp_endpoint {} is not an http:// URL",
+                    endpoint
+                ));
+            }
+        }
+        if let Some(addr) = &self.metrics.statsd_addr {
+            if addr.parse::<SocketAddr>().is_err() {
+                return invalid(format!("metrics.statsd_addr {} is not an IP:PORT", addr));
+            }
+        }
+        if self.metrics.flush_interval == 0 {
+            return invalid("metrics.flush_interval must be at least 1".to_owned());
+        }
+        if self.dedup.enabled && (self.dedup.size == 0 || self.dedup.ttl == 0) {
+            return invalid("dedup.size and dedup.ttl must be at least 1".to_owned());
+        }
+        if self.admission.enabled && self.admission.max_queue_wait == 0 {
+            return invalid("admission.max_queue_wait must be at least 1".to_owned());
+        }
+        if let Some(addr) = self.cluster_addr() {
+            for shard in &self.cluster.shards {
+                if shard.parse::<SocketAddr>().is_err() {
+                    return invalid(format!("cluster shard {} is not an IP:PORT", shard));
+                }
+            }
+            if !self.cluster.shards.contains(addr) {
+                return invalid(format!(
+                    "cluster.addr {} is not one of cluster.shards",
+                    addr
+                ));
+            }
+        }
+        self.access_list()?;
+        if self.tls.is_some() {
+            return invalid("tls is not supported by this server yet".to_owned());
+        }
+        if self.auth.is_some() {
+            return invalid("auth is not supported by this server yet".to_owned());
+        }
+        Ok(())
+    }
+
+    /// The file logs go to, if they do not go to stderr.
+    pub fn log_file(&self) -> Option<PathBuf> {
+        match &self.log.file {
+            Some(file) => Some(file.clone()),
+            None if self.daemonize => Some(self.data_dir.join("kvs-server.log")),
+            None => None,
+        }
+    }
+
+    /// The rules of `access` deciding which clients the server accepts.
+    pub fn access_list(&self) -> Result<AccessList> {
+        AccessList::new(&self.access.allow, &self.access.deny)
+    }
+
+    /// The cores the configured thread pools are pinned to, if any.
+    pub fn cpu_affinity(&self) -> Result<Option<CpuAffinity>> {
+        self.thread_pool
+            .affinity
+            .as_deref()
+            .map(|affinity| {
+                affinity.parse().map_err(|_| {
+                    KVStoreError::InvalidConfig(format!(
+                        "thread_pool.affinity {} is neither spread, compact nor core IDs",
+                        affinity
+                    ))
+                })
+            })
+            .transpose()
+    }
+
+    /// The address of the server among the shards of its cluster, or None if it is not part
+    /// of one.
+    pub fn cluster_addr(&self) -> Option<&String> {
+        if self.cluster.shards.is_empty() {
+            return None;
+        }
+        self.cluster.addr.as_ref().or(self.listen.first())
+    }
+
+    /// The configured log level.
+    pub fn log_level(&self) -> Result<LevelFilter> {
+        self.log.level.parse().map_err(|_| {
+            KVStoreError::InvalidConfig(format!("log.level {} is not a log level", self.log.level))
+        })
+    }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn parse_env<T: FromStr>(name: &str, value: &str) -> Result<T> {
+    value.parse().map_err(|_| {
+        KVStoreError::InvalidConfig(format!("{}={} is not a valid value", name, value))
+    })
+}
+
+impl LogConfig {
+    /// The rotation of the log file.
+    pub fn rotation(&self) -> Rotation {
+        match self.rotation {
+            LogRotation::Never => Rotation::Never,
+            LogRotation::Size => Rotation::Size(self.max_size),
+            LogRotation::Daily => Rotation::Daily,
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use crate::Response;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// the requests cached by default
+pub const DEFAULT_DEDUP_CACHE_SIZE: usize = 10_000;
+/// how long responses are cached by default
+pub const DEFAULT_DEDUP_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// a request of a client, as numbered by the client
+type RequestKey = (String, u64);
+
+/// What the cache knows about a request
+pub(crate) enum Lookup {
+    /// nothing, it is run now and its response is cached with `ResponseCache::finish`
+    New,
+    /// it is being run for an earlier attempt
+    InFlight,
+    /// it was run, with this response
+    Done(Response),
+}
+
+/// The responses of recent retryable requests by client and request id, so a request
+/// retried after its response was lost is answered again instead of applied twice
+pub(crate) struct ResponseCache {
+    capacity: usize,
+    ttl: Duration,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    /// the response of every request, or None while it runs
+    responses: HashMap<RequestKey, Option<Response>>,
+    /// the requests with the time they were first seen, oldest first
+    order: VecDeque<(RequestKey, Instant)>,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        ResponseCache {
+            capacity,
+            ttl,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+
+    /// look a request up, marking it in flight if it is new
+    pub(crate) fn begin(&self, client_id: &str, request_id: u64) -> Lookup {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        while let Some((key, seen)) = state.order.front() {
+            if state.order.len() < self.capacity && now.duration_since(*seen) < self.ttl {
+                break;
+            }
+            let key = key.clone();
+            state.order.pop_front();
+            state.responses.remove(&key);
+        }
+
+        let key = (client_id.to_owned(), request_id);
+        match state.responses.get(&key) {
+            Some(Some(response)) => Lookup::Done(response.clone()),
+            Some(None) => Lookup::InFlight,
+            None => {
+                state.responses.insert(key.clone(), None);
+                state.order.push_back((key, now));
+                Lookup::New
+            }
+        }
+    }
+
+    /// cache the response of a request `begin` returned `Lookup::New` for,
+    /// unless it was evicted while it ran
+    pub(crate) fn finish(&self, client_id: &str, request_id: u64, response: &Response) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(cached) = state.responses.get_mut(&(client_id.to_owned(), request_id)) {
+            *cached = Some(response.clone());
+        }
+    }
+}
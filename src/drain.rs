@@ -0,0 +1,74 @@
+use crate::{KVStoreError, Result};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// what `Request::HEALTH` is answered with by a server serving requests
+pub(crate) const SERVING: &str = "serving";
+/// what `Request::HEALTH` is answered with by a draining server
+pub(crate) const DRAINING: &str = "draining";
+
+/** Whether a server is draining its connections, e.g. for a graceful shutdown or maintenance,
+see `KvServer::drain`. While draining, `Request::HEALTH` is answered with `draining` so load
+balancers shift the traffic away, the connections open before the drain began keep being
+served for a grace period, and all other requests fail with `KVStoreError::Draining`.
+
+The drain is a handle: clones share the state, so a clone kept by the caller starts and ends
+a drain while the server runs.
+# Example
+```
+use kvs::Drain;
+use std::time::Duration;
+
+let drain = Drain::default();
+assert!(!drain.is_draining());
+drain.start(Duration::from_secs(5));
+assert!(drain.is_draining());
+drain.resume();
+assert!(!drain.is_draining());
+```
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Drain {
+    /// when the drain began and how long the connections open before get to finish
+    state: Arc<Mutex<Option<(Instant, Duration)>>>,
+}
+
+impl Drain {
+    /// Begin draining, letting the connections open now be served for `grace`. A server
+    /// draining already keeps the time it began at, with the new grace period.
+    pub fn start(&self, grace: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let began = state.map_or_else(Instant::now, |(began, _)| began);
+        *state = Some((began, grace));
+    }
+
+    /// serve all requests again
+    pub fn resume(&self) {
+        *self.state.lock().unwrap() = None;
+    }
+
+    /// whether the server is draining
+    pub fn is_draining(&self) -> bool {
+        self.state.lock().unwrap().is_some()
+    }
+
+    /// what `Request::HEALTH` is answered with
+    pub(crate) fn health(&self) -> &'static str {
+        if self.is_draining() {
+            DRAINING
+        } else {
+            SERVING
+        }
+    }
+
+    /// fail a request of a connection opened at `connected` unless the server serves it:
+    /// it is not draining, or the connection was open before and its grace period lasts
+    pub(crate) fn check(&self, connected: Instant) -> Result<()> {
+        match *self.state.lock().unwrap() {
+            Some((began, grace)) if connected >= began || began.elapsed() >= grace => {
+                Err(KVStoreError::Draining)
+            }
+            _ => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use super::backup::BackupTarget;
+use super::dirs::DataDirs;
+use crate::Result;
+use log::{info, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// the prefix of the names of the archived log files
+const ARCHIVE_PREFIX: &str = "wal/";
+/// how long the archiver waits for a sealed log file before checking whether the store is gone
+const ARCHIVE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// how long the archiver waits before trying a failed upload again
+const ARCHIVE_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// the place the sealed log files of a store are archived to
+#[derive(Clone)]
+pub(super) struct ArchiveHook(pub(super) Arc<dyn BackupTarget + Send + Sync>);
+
+impl fmt::Debug for ArchiveHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ArchiveHook")
+    }
+}
+
+/// The progress of archiving the sealed log files of a KvStore, see `KvStoreBuilder::archive_to`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ArchiveStats {
+    /// number of log files archived since the store was opened
+    pub archived_files: u64,
+    /// size of the log files archived since the store was opened in bytes
+    pub archived_bytes: u64,
+    /// number of sealed log files waiting to be archived
+    pub pending_files: u64,
+    /// how long the oldest sealed log file has been waiting to be archived
+    pub lag: Duration,
+    /// the error of the last failed upload, cleared by the next successful one
+    pub last_error: Option<String>,
+}
+
+/// Uploads the log files of a store to a `BackupTarget` in a background thread as soon as
+/// they are sealed. A sealed log file is pinned until it is archived, so a compaction in the
+/// meantime only deletes it afterwards.
+pub(super) struct Archiver {
+    target: Arc<dyn BackupTarget + Send + Sync>,
+    dirs: Arc<DataDirs>,
+    state: Mutex<ArchiveState>,
+    sealed: Condvar,
+}
+
+#[derive(Default)]
+struct ArchiveState {
+    /// file number -> the path of a sealed log file and when it was sealed
+    pending: BTreeMap<u64, (PathBuf, Instant)>,
+    archived_files: u64,
+    archived_bytes: u64,
+    last_error: Option<String>,
+}
+
+impl Archiver {
+    /// Start archiving to `hook`, first the sealed `log_files` which are not archived yet,
+    /// e.g. because the store was closed before they were.
+    pub(super) fn start(
+        hook: &ArchiveHook,
+        dirs: &Arc<DataDirs>,
+        log_files: &[u64],
+    ) -> Result<Arc<Archiver>> {
+        let archiver = Arc::new(Archiver {
+            target: Arc::clone(&hook.0),
+            dirs: Arc::clone(dirs),
+            state: Mutex::new(ArchiveState::default()),
+            sealed: Condvar::new(),
+        });
+        let archived: HashSet<String> = archiver.target.list(ARCHIVE_PREFIX)?.into_iter().collect();
+        for &file_number in log_files {
+            if !archived.contains(&object_name(file_number)) {
+                archiver.seal(file_number);
+            }
+        }
+        let weak = Arc::downgrade(&archiver);
+        thread::spawn(move || archive_loop(weak));
+        Ok(archiver)
+    }
+
+    /// archive a log file no write goes to anymore
+    pub(super) fn seal(&self, file_number: u64) {
+        self.dirs.pin(&[file_number]);
+        let path = self.dirs.file_path(file_number);
+        let mut state = self.state.lock().unwrap();
+        state.pending.insert(file_number, (path, Instant::now()));
+        self.sealed.notify_one();
+    }
+
+    pub(super) fn stats(&self) -> ArchiveStats {
+        let state = self.state.lock().unwrap();
+        ArchiveStats {
+            archived_files: state.archived_files,
+            archived_bytes: state.archived_bytes,
+            pending_files: state.pending.len() as u64,
+            lag: state
+                .pending
+                .values()
+                .map(|(_, sealed_at)| sealed_at.elapsed())
+                .max()
+                .unwrap_or_default(),
+            last_error: state.last_error.clone(),
+        }
+    }
+
+    /// the oldest sealed log file, waiting a while for one
+    fn next(&self) -> Option<(u64, PathBuf)> {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self
+            .sealed
+            .wait_timeout_while(state, ARCHIVE_POLL_INTERVAL, |state| {
+                state.pending.is_empty()
+            })
+            .unwrap();
+        let (file_number, (path, _)) = state.pending.iter().next()?;
+        Some((*file_number, path.clone()))
+    }
+
+    fn upload(&self, file_number: u64, path: &PathBuf) -> Result<()> {
+        let put = || -> Result<u64> {
+            let mut file = File::open(path)?;
+            let length = file.metadata()?.len();
+            self.target.put(&object_name(file_number), &mut file)?;
+            Ok(length)
+        };
+        let result = put();
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(length) => {
+                state.pending.remove(&file_number);
+                state.archived_files += 1;
+                state.archived_bytes += length;
+                state.last_error = None;
+                drop(state);
+                self.dirs.unpin(&[file_number]);
+                info!("Archived log file {}", file_number);
+                Ok(())
+            }
+            Err(err) => {
+                state.last_error = Some(err.to_string());
+                Err(err)
+            }
+        }
+    }
+}
+
+/// upload the sealed log files one after another until the store is dropped
+fn archive_loop(archiver: Weak<Archiver>) {
+    loop {
+        let archiver = match archiver.upgrade() {
+            Some(archiver) => archiver,
+            None => return,
+        };
+        let (file_number, path) = match archiver.next() {
+            Some(next) => next,
+            None => continue,
+        };
+        if let Err(err) = archiver.upload(file_number, &path) {
+            warn!("Archiving log file {} failed: {}", file_number, err);
+            drop(archiver);
+            thread::sleep(ARCHIVE_RETRY_INTERVAL);
+        }
+    }
+}
+
+/// the name of an archived log file, padded so the names sort like the file numbers
+fn object_name(file_number: u64) -> String {
+    format!("{}data_{:020}.txt", ARCHIVE_PREFIX, file_number)
+}
@@ -0,0 +1,168 @@
+use crate::{KVStoreError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// the object which marks a backup as complete, written after all of its data files
+const MANIFEST_OBJECT: &str = "MANIFEST";
+
+/// A place a KvStore can back its data files up to, such as a directory or an object store.
+///
+/// Objects are named like `<backup id>/data_1.txt` and are never modified once put,
+/// so an S3-compatible bucket maps onto PutObject, GetObject and ListObjectsV2.
+pub trait BackupTarget {
+    /// Store `data` as the object `name`.
+    fn put(&self, name: &str, data: &mut dyn Read) -> Result<()>;
+    /// Read the object `name`.
+    fn get(&self, name: &str) -> Result<Box<dyn Read>>;
+    /// Return the names of all objects starting with `prefix` in ascending order.
+    fn list(&self, prefix: &str) -> Result<Vec<String>>;
+}
+
+/// A BackupTarget which keeps the objects as files under a root directory
+#[derive(Clone, Debug)]
+pub struct DirBackupTarget {
+    root: PathBuf,
+}
+
+impl DirBackupTarget {
+    /// Use `root` as the backup directory, creating it if needed.
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(DirBackupTarget { root })
+    }
+}
+
+impl BackupTarget for DirBackupTarget {
+    fn put(&self, name: &str, data: &mut dyn Read) -> Result<()> {
+        let path = self.root.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // a crash must not leave a truncated object behind
+        let tmp_path = path.with_extension("partial");
+        let mut file = File::create(&tmp_path)?;
+        io::copy(data, &mut file)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    fn get(&self, name: &str) -> Result<Box<dyn Read>> {
+        Ok(Box::new(File::open(self.root.join(name))?))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        list_files(&self.root, "", &mut names)?;
+        names.retain(|name| name.starts_with(prefix) && !name.ends_with(".partial"));
+        names.sort();
+        Ok(names)
+    }
+}
+
+fn list_files(dir: &Path, prefix: &str, names: &mut Vec<String>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir() {
+            list_files(&entry.path(), &format!("{}/", name), names)?;
+        } else {
+            names.push(name);
+        }
+    }
+    Ok(())
+}
+
+/// The description of a complete backup
+///
+/// A full backup copies all data files. An incremental backup only copies the data written
+/// since its parent, so restoring it replays the chain of backups from the last full one.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BackupManifest {
+    /// the id of the backup, increasing with the time it was taken
+    pub id: String,
+    /// when the backup was taken in milliseconds since the unix epoch
+    pub created_at: u64,
+    /// the backup this one is based on, or None for a full backup
+    pub parent: Option<String>,
+    /// the sequence number of the last write in the backup
+    pub sequence: u64,
+    /// the sequence number at the last compaction, before which single writes are not retained
+    pub compacted_sequence: u64,
+    /// the first data file written after the last compaction
+    pub first_log_file: u64,
+    /// the numbers of all data files of the store at the time of the backup
+    pub file_numbers: Vec<u64>,
+    /// the data copied by this backup
+    pub files: Vec<BackupFile>,
+}
+
+/// A range of a data file copied by a backup
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BackupFile {
+    /// the number of the data file
+    pub file_number: u64,
+    /// where the copied range starts in the data file
+    pub offset: u64,
+    /// the number of bytes copied
+    pub length: u64,
+    /// the name of the object holding the data
+    pub object: String,
+}
+
+impl BackupManifest {
+    fn object_name(id: &str) -> String {
+        format!("{}/{}", id, MANIFEST_OBJECT)
+    }
+
+    /// Store the manifest, which completes the backup.
+    pub(super) fn put(&self, target: &dyn BackupTarget) -> Result<()> {
+        let data = serde_json::to_vec(self)?;
+        target.put(&Self::object_name(&self.id), &mut data.as_slice())
+    }
+
+    /// Read the manifest of a complete backup.
+    pub fn get(target: &dyn BackupTarget, id: &str) -> Result<Self> {
+        let name = Self::object_name(id);
+        if !target.list(&name)?.contains(&name) {
+            return Err(KVStoreError::BackupNotFound(id.to_owned()));
+        }
+        Ok(serde_json::from_reader(target.get(&name)?)?)
+    }
+
+    /// Return the backups which restoring this one replays, from the last full backup to this one.
+    pub fn chain(&self, target: &dyn BackupTarget) -> Result<Vec<Self>> {
+        let mut chain = vec![self.clone()];
+        while let Some(parent) = chain.last().unwrap().parent.clone() {
+            chain.push(Self::get(target, &parent)?);
+        }
+        chain.reverse();
+        Ok(chain)
+    }
+
+    /// Return how many bytes of each data file the chain of this backup holds.
+    pub(super) fn backed_up_lengths(&self, target: &dyn BackupTarget) -> Result<HashMap<u64, u64>> {
+        let mut lengths = HashMap::new();
+        for manifest in self.chain(target)? {
+            for file in manifest.files {
+                lengths.insert(file.file_number, file.offset + file.length);
+            }
+        }
+        lengths.retain(|file_number, _| self.file_numbers.contains(file_number));
+        Ok(lengths)
+    }
+
+    /// Return the manifests of all complete backups, oldest first.
+    pub fn list(target: &dyn BackupTarget) -> Result<Vec<Self>> {
+        target
+            .list("")?
+            .iter()
+            .filter_map(|name| name.strip_suffix(&format!("/{}", MANIFEST_OBJECT)))
+            .map(|id| Self::get(target, id))
+            .collect()
+    }
+}
@@ -0,0 +1,277 @@
+use crate::Result;
+use log::{debug, warn};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// the alignment of buffers, sizes and offsets which direct I/O requires
+const ALIGNMENT: usize = 4096;
+/// the size of the aligned buffer, a multiple of `ALIGNMENT`
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// a data file opened for appending, bypassing the page cache if direct I/O is enabled
+pub(crate) enum SegmentFile {
+    Buffered(File),
+    Direct(DirectFile),
+}
+
+impl SegmentFile {
+    /// open `path` for appending, creating it if needed.
+    /// Direct I/O falls back to buffered writes where the platform or file system lacks it.
+    pub(crate) fn open(path: &Path, direct_io: bool) -> Result<Self> {
+        if direct_io {
+            match DirectFile::open(path) {
+                Ok(file) => return Ok(SegmentFile::Direct(file)),
+                Err(err) => warn!("Can not use direct I/O for {:?}: {}", path, err),
+            }
+        }
+        Ok(SegmentFile::Buffered(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        ))
+    }
+
+    /// Reserve `size` bytes for the file without changing its length, so appends do not have to
+    /// allocate blocks. The file system may not support it, and direct I/O releases the space at
+    /// the next flush.
+    pub(crate) fn preallocate(&self, size: u64) {
+        if let Err(err) = fallocate(self.file(), size) {
+            debug!("Can not preallocate {} bytes: {}", size, err);
+        }
+    }
+
+    /// release the space reserved beyond the end of the file
+    pub(crate) fn seal(&mut self) -> Result<()> {
+        self.flush()?;
+        let len = self.seek(SeekFrom::End(0))?;
+        self.file().set_len(len)?;
+        Ok(())
+    }
+
+    fn file(&self) -> &File {
+        match self {
+            SegmentFile::Buffered(file) => file,
+            SegmentFile::Direct(file) => &file.file,
+        }
+    }
+
+    /// make the written data durable
+    pub(crate) fn sync_all(&mut self) -> Result<()> {
+        match self {
+            SegmentFile::Buffered(file) => file.sync_all()?,
+            SegmentFile::Direct(file) => {
+                file.flush()?;
+                file.file.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for SegmentFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            SegmentFile::Buffered(file) => file.write(buf),
+            SegmentFile::Direct(file) => file.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SegmentFile::Buffered(file) => file.flush(),
+            SegmentFile::Direct(file) => file.flush(),
+        }
+    }
+}
+
+impl Seek for SegmentFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            SegmentFile::Buffered(file) => file.seek(pos),
+            SegmentFile::Direct(file) => match pos {
+                SeekFrom::End(0) => Ok(file.len()),
+                _ => Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "direct I/O files only support appending",
+                )),
+            },
+        }
+    }
+}
+
+/// tell the kernel that `len` bytes at `offset` of the file will be read soon
+pub(crate) fn readahead(file: &File, offset: u64, len: u64) {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        // SAFETY: the file descriptor is open for the duration of the call
+        let result = unsafe {
+            libc::posix_fadvise(
+                file.as_raw_fd(),
+                offset as libc::off_t,
+                len as libc::off_t,
+                libc::POSIX_FADV_WILLNEED,
+            )
+        };
+        if result != 0 {
+            debug!(
+                "Can not advise reading ahead: {}",
+                io::Error::from_raw_os_error(result)
+            );
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (file, offset, len);
+}
+
+#[cfg(target_os = "linux")]
+fn fallocate(file: &File, size: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: the file descriptor is open for writing for the duration of the call
+    let result = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_KEEP_SIZE,
+            0,
+            size as libc::off_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn fallocate(_file: &File, _size: u64) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "preallocation is only supported on Linux",
+    ))
+}
+
+/// An append-only file opened with O_DIRECT
+///
+/// Writes are collected in an aligned buffer which is written in whole blocks. Flushing writes
+/// the last partial block padded with zeros and then truncates the file to its real length, so the
+/// block is written again by the next flush.
+pub(crate) struct DirectFile {
+    file: File,
+    /// `BUFFER_SIZE` bytes starting at `start` are aligned
+    memory: Vec<u8>,
+    start: usize,
+    /// the aligned offset in the file the buffer is written to
+    block_offset: u64,
+    /// the number of bytes in the buffer
+    len: usize,
+}
+
+impl DirectFile {
+    #[cfg(target_os = "linux")]
+    fn open(path: &Path) -> Result<Self> {
+        use std::os::unix::fs::{FileExt, OpenOptionsExt};
+
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(path)?;
+        let memory = vec![0; BUFFER_SIZE + ALIGNMENT];
+        let start = memory.as_ptr().align_offset(ALIGNMENT);
+        let mut direct = DirectFile {
+            file,
+            memory,
+            start,
+            block_offset: 0,
+            len: 0,
+        };
+
+        // the partial block at the end of an existing file is written again by the first flush
+        let file_len = direct.file.metadata()?.len();
+        direct.block_offset = file_len - file_len % ALIGNMENT as u64;
+        direct.len = (file_len - direct.block_offset) as usize;
+        if direct.len > 0 {
+            let block_offset = direct.block_offset;
+            let len = direct.len;
+            File::open(path)?.read_exact_at(&mut direct.buffer()[..len], block_offset)?;
+        }
+        Ok(direct)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn open(_path: &Path) -> Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "O_DIRECT is only supported on Linux",
+        )
+        .into())
+    }
+
+    fn buffer(&mut self) -> &mut [u8] {
+        &mut self.memory[self.start..self.start + BUFFER_SIZE]
+    }
+
+    fn len(&self) -> u64 {
+        self.block_offset + self.len as u64
+    }
+
+    /// write the first `size` bytes of the buffer, a multiple of `ALIGNMENT`, at `block_offset`
+    #[cfg(target_os = "linux")]
+    fn write_blocks(&mut self, size: usize) -> io::Result<()> {
+        use std::os::unix::fs::FileExt;
+
+        let block_offset = self.block_offset;
+        let buffer = &self.memory[self.start..self.start + size];
+        self.file.write_all_at(buffer, block_offset)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn write_blocks(&mut self, _size: usize) -> io::Result<()> {
+        unreachable!("direct files are only opened on Linux")
+    }
+}
+
+impl Write for DirectFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.len;
+        let count = buf.len().min(BUFFER_SIZE - len);
+        self.buffer()[len..len + count].copy_from_slice(&buf[..count]);
+        self.len += count;
+        if self.len == BUFFER_SIZE {
+            self.write_blocks(BUFFER_SIZE)?;
+            self.block_offset += BUFFER_SIZE as u64;
+            self.len = 0;
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.len == 0 {
+            return Ok(());
+        }
+        let len = self.len;
+        let padded = len.next_multiple_of(ALIGNMENT);
+        self.buffer()[len..padded].fill(0);
+        self.write_blocks(padded)?;
+        self.file.set_len(self.len())?;
+
+        // only the partial block is kept to be written again
+        let full = len - len % ALIGNMENT;
+        if full > 0 {
+            self.buffer().copy_within(full..len, 0);
+            self.block_offset += full as u64;
+            self.len -= full;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DirectFile {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            warn!("Can not flush a direct I/O file: {}", err);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use super::manifest::sync_dir;
+use crate::{KVStoreError, Result};
+use log::warn;
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read_dir, remove_file};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+/// How a KvStore chooses the directory of a new data file
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PlacementPolicy {
+    /// spread data files over the directories in turn
+    #[default]
+    RoundRobin,
+    /// put each data file into the directory with the most available space
+    MostFreeSpace,
+}
+
+/// the storage tier a data file lives on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Tier {
+    /// the fast directories which receive all new writes
+    Hot,
+    /// the slower directory which compaction moves rarely read data to
+    Cold,
+}
+
+/// the set of directories which hold the data files of a KvStore
+pub(crate) struct DataDirs {
+    /// the hot directories followed by the cold directory, if any
+    dirs: Vec<PathBuf>,
+    hot_dirs: usize,
+    policy: PlacementPolicy,
+    /// file number -> index of the directory containing it
+    locations: RwLock<HashMap<u64, usize>>,
+    pins: Mutex<Pins>,
+}
+
+/// the data files read by snapshots, which are only deleted once no snapshot reads them
+#[derive(Default)]
+struct Pins {
+    /// file number -> the number of snapshots reading it
+    readers: HashMap<u64, usize>,
+    /// file number -> the path of a deleted data file which is still read
+    deleted: HashMap<u64, PathBuf>,
+}
+
+impl DataDirs {
+    /// create all directories and find the data files which already exist in them
+    pub(crate) fn open(
+        mut dirs: Vec<PathBuf>,
+        cold_dir: Option<PathBuf>,
+        policy: PlacementPolicy,
+    ) -> Result<Self> {
+        let hot_dirs = dirs.len();
+        dirs.extend(cold_dir);
+        let mut locations = HashMap::new();
+        for (dir_index, dir) in dirs.iter().enumerate() {
+            create_dir_all(dir)?;
+            for number in file_numbers(dir)? {
+                if let Some(other) = locations.insert(number, dir_index) {
+                    return Err(KVStoreError::CommonStringError(format!(
+                        "data file {} exists in both {:?} and {:?}",
+                        number, dirs[other], dir
+                    )));
+                }
+            }
+        }
+        Ok(DataDirs {
+            dirs,
+            hot_dirs,
+            policy,
+            locations: RwLock::new(locations),
+            pins: Mutex::new(Pins::default()),
+        })
+    }
+
+    /// the numbers of all known data files in ascending order
+    pub(crate) fn file_numbers(&self) -> Vec<u64> {
+        let mut numbers: Vec<u64> = self.locations.read().unwrap().keys().copied().collect();
+        numbers.sort_unstable();
+        numbers
+    }
+
+    /// the path of an existing data file, or where it would be placed if it does not exist yet
+    pub(crate) fn file_path(&self, file_number: u64) -> PathBuf {
+        let dir_index = self
+            .locations
+            .read()
+            .unwrap()
+            .get(&file_number)
+            .copied()
+            .unwrap_or_else(|| self.choose_dir(file_number));
+        data_file_path(&self.dirs[dir_index], file_number)
+    }
+
+    /// pick a directory for a new data file and remember it
+    pub(crate) fn allocate(&self, file_number: u64) -> PathBuf {
+        let mut locations = self.locations.write().unwrap();
+        let dir_index = *locations
+            .entry(file_number)
+            .or_insert_with(|| self.choose_dir(file_number));
+        data_file_path(&self.dirs[dir_index], file_number)
+    }
+
+    /// place a new data file in the cold directory, if one is configured
+    pub(crate) fn allocate_cold(&self, file_number: u64) -> Option<PathBuf> {
+        if !self.has_cold_tier() {
+            return None;
+        }
+        let dir_index = self.hot_dirs;
+        self.locations
+            .write()
+            .unwrap()
+            .insert(file_number, dir_index);
+        Some(data_file_path(&self.dirs[dir_index], file_number))
+    }
+
+    /// the directory the store was opened at, which also holds its metadata
+    pub(crate) fn primary(&self) -> &Path {
+        &self.dirs[0]
+    }
+
+    pub(crate) fn has_cold_tier(&self) -> bool {
+        self.dirs.len() > self.hot_dirs
+    }
+
+    /// the tier of an existing data file
+    pub(crate) fn tier(&self, file_number: u64) -> Tier {
+        match self.locations.read().unwrap().get(&file_number) {
+            Some(dir_index) if *dir_index >= self.hot_dirs => Tier::Cold,
+            _ => Tier::Hot,
+        }
+    }
+
+    /// make the data files created in all directories durable, returning the number of
+    /// directories synced
+    pub(crate) fn sync(&self) -> Result<u64> {
+        for dir in &self.dirs {
+            sync_dir(dir)?;
+        }
+        Ok(self.dirs.len() as u64)
+    }
+
+    /// forget a deleted data file
+    pub(crate) fn release(&self, file_number: u64) {
+        self.locations.write().unwrap().remove(&file_number);
+    }
+
+    /// Delete a data file which is no longer live and forget it. A file pinned by a snapshot
+    /// is only deleted once the last one unpins it.
+    pub(crate) fn delete(&self, file_number: u64) {
+        let file_path = self.file_path(file_number);
+        self.release(file_number);
+        let mut pins = self.pins.lock().unwrap();
+        if pins.readers.contains_key(&file_number) {
+            pins.deleted.insert(file_number, file_path);
+        } else {
+            remove_file_or_warn(&file_path);
+        }
+    }
+
+    /// keep the data files `file_numbers` until they are unpinned again
+    pub(crate) fn pin(&self, file_numbers: &[u64]) {
+        let mut pins = self.pins.lock().unwrap();
+        for &file_number in file_numbers {
+            *pins.readers.entry(file_number).or_insert(0) += 1;
+        }
+    }
+
+    /// release the pins of `pin`, deleting the files deleted in the meantime
+    pub(crate) fn unpin(&self, file_numbers: &[u64]) {
+        let mut pins = self.pins.lock().unwrap();
+        for file_number in file_numbers {
+            if let Some(count) = pins.readers.get_mut(file_number) {
+                *count -= 1;
+                if *count > 0 {
+                    continue;
+                }
+            }
+            pins.readers.remove(file_number);
+            if let Some(file_path) = pins.deleted.remove(file_number) {
+                remove_file_or_warn(&file_path);
+            }
+        }
+    }
+
+    fn choose_dir(&self, file_number: u64) -> usize {
+        match self.policy {
+            PlacementPolicy::RoundRobin => (file_number % self.hot_dirs as u64) as usize,
+            PlacementPolicy::MostFreeSpace => self.dirs[..self.hot_dirs]
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, dir)| available_space(dir).unwrap_or(0))
+                .map(|(dir_index, _)| dir_index)
+                .unwrap_or(0),
+        }
+    }
+}
+
+fn remove_file_or_warn(file_path: &Path) {
+    if let Err(err) = remove_file(file_path) {
+        warn!("can not delete file {:?} because {}", file_path, err);
+    }
+}
+
+fn data_file_path(dir: &Path, file_number: u64) -> PathBuf {
+    dir.join(format!("data_{}.txt", file_number))
+}
+
+/// list the numbers of all data files in the directory
+fn file_numbers(dir_path: &Path) -> Result<Vec<u64>> {
+    Ok(read_dir(dir_path)?
+        .flat_map(|res| res.map(|e| e.path()))
+        .filter(|path| path.is_file() && path.extension() == Some("txt".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(|filename| filename.to_str())
+                .map(|filename| {
+                    filename
+                        .trim_start_matches("data_")
+                        .trim_end_matches(".txt")
+                })
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect())
+}
+
+#[cfg(unix)]
+fn available_space(dir: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn available_space(_: &Path) -> Option<u64> {
+    None
+}
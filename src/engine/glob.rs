@@ -0,0 +1,123 @@
+/// a glob pattern like `user:*:profile` which keys are matched against
+///
+/// `*` matches any sequence, `?` matches one character, `[abc]`/`[a-z]` match one character
+/// of a class (`[^...]` or `[!...]` negates it) and `\` escapes the next character.
+pub(crate) struct GlobPattern {
+    tokens: Vec<Token>,
+}
+
+enum Token {
+    Literal(char),
+    AnyChar,
+    AnySequence,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+impl GlobPattern {
+    pub(crate) fn new(pattern: &str) -> Self {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            let token = match chars[i] {
+                '*' => Token::AnySequence,
+                '?' => Token::AnyChar,
+                '\\' if i + 1 < chars.len() => {
+                    i += 1;
+                    Token::Literal(chars[i])
+                }
+                '[' => match parse_class(&chars[i + 1..]) {
+                    Some((token, consumed)) => {
+                        i += consumed;
+                        token
+                    }
+                    // an unclosed class is matched literally
+                    None => Token::Literal('['),
+                },
+                c => Token::Literal(c),
+            };
+            tokens.push(token);
+            i += 1;
+        }
+        GlobPattern { tokens }
+    }
+
+    /// the literal prefix which every matching key starts with
+    pub(crate) fn prefix(&self) -> String {
+        self.tokens
+            .iter()
+            .map_while(|token| match token {
+                Token::Literal(c) => Some(*c),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub(crate) fn matches(&self, key: &str) -> bool {
+        let key: Vec<char> = key.chars().collect();
+        let (mut t, mut k) = (0, 0);
+        // where to resume after the last `*` if the rest fails to match
+        let mut backtrack: Option<(usize, usize)> = None;
+        while k < key.len() {
+            let matched = match self.tokens.get(t) {
+                Some(Token::AnySequence) => {
+                    backtrack = Some((t, k));
+                    t += 1;
+                    continue;
+                }
+                Some(Token::AnyChar) => true,
+                Some(Token::Literal(c)) => *c == key[k],
+                Some(Token::Class { negated, ranges }) => {
+                    ranges
+                        .iter()
+                        .any(|(start, end)| *start <= key[k] && key[k] <= *end)
+                        != *negated
+                }
+                None => false,
+            };
+            if matched {
+                t += 1;
+                k += 1;
+            } else if let Some((star, start)) = backtrack {
+                t = star + 1;
+                k = start + 1;
+                backtrack = Some((star, start + 1));
+            } else {
+                return false;
+            }
+        }
+        self.tokens[t..]
+            .iter()
+            .all(|token| matches!(token, Token::AnySequence))
+    }
+}
+
+/// parse the class following a '[', returning it and the number of characters consumed
+fn parse_class(chars: &[char]) -> Option<(Token, usize)> {
+    let negated = matches!(chars.first(), Some('^') | Some('!'));
+    let mut i = usize::from(negated);
+    let mut ranges = Vec::new();
+    loop {
+        let start = match *chars.get(i)? {
+            // a ']' right after the opening bracket is a member of the class
+            ']' if !ranges.is_empty() => return Some((Token::Class { negated, ranges }, i + 1)),
+            '\\' => {
+                i += 1;
+                *chars.get(i)?
+            }
+            c => c,
+        };
+        let end = match (chars.get(i + 1), chars.get(i + 2)) {
+            (Some('-'), Some(end)) if *end != ']' => {
+                i += 2;
+                *end
+            }
+            _ => start,
+        };
+        ranges.push((start, end));
+        i += 1;
+    }
+}
@@ -0,0 +1,90 @@
+/// the number of buckets of a `SizeHistogram`, the last one holding all sizes from 2^62 bytes on
+const BUCKETS: usize = 64;
+
+/** The distribution of the sizes of keys or values in bytes, in buckets of powers of two, see
+`KvStoreStats::key_sizes` and `KvStoreStats::value_sizes`.
+# Example
+```
+use kvs::SizeHistogram;
+
+let mut sizes = SizeHistogram::default();
+for size in [3, 5, 6, 7, 100] {
+    sizes.record(size);
+}
+assert_eq!(sizes.count, 5);
+assert_eq!(sizes.max, 100);
+assert_eq!(sizes.buckets[3], 3);
+// a percentile is the upper bound of the bucket holding it
+assert_eq!(sizes.percentile(50.0), 8);
+assert_eq!(sizes.percentile(100.0), 128);
+```
+ */
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SizeHistogram {
+    /// number of sizes recorded
+    pub count: u64,
+    /// sum of the sizes recorded
+    pub sum: u64,
+    /// the largest size recorded
+    pub max: u64,
+    /// `buckets[0]` counts the sizes of 0 bytes, `buckets[i]` the ones from 2^(i-1) bytes up
+    /// to 2^i bytes, exclusive
+    pub buckets: Vec<u64>,
+}
+
+impl Default for SizeHistogram {
+    fn default() -> Self {
+        SizeHistogram {
+            count: 0,
+            sum: 0,
+            max: 0,
+            buckets: vec![0; BUCKETS],
+        }
+    }
+}
+
+impl SizeHistogram {
+    /// count a size of `size` bytes
+    pub fn record(&mut self, size: u64) {
+        let bucket = (u64::BITS - size.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.sum += size;
+        self.max = self.max.max(size);
+    }
+
+    /// add the sizes counted by `other`
+    pub fn merge(&mut self, other: &SizeHistogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+        self.max = self.max.max(other.max);
+    }
+
+    /// the mean size, 0 without sizes
+    pub fn mean(&self) -> f64 {
+        match self.count {
+            0 => 0.0,
+            count => self.sum as f64 / count as f64,
+        }
+    }
+
+    /// The size `percentile` percent of the sizes are at most, rounded up to the upper bound
+    /// of its bucket. 0 without sizes.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let rank = (self.count as f64 * percentile / 100.0).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return match bucket {
+                    0 => 0,
+                    bucket => 1 << bucket,
+                };
+            }
+        }
+        0
+    }
+}
@@ -1,26 +1,50 @@
-use crate::{Command, KVStoreError, KvsEngine, Result};
+use super::archive::{ArchiveHook, ArchiveStats, Archiver};
+use super::backup::{BackupFile, BackupManifest, BackupTarget};
+use super::direct::{readahead, SegmentFile};
+use super::dirs::{DataDirs, PlacementPolicy, Tier};
+use super::glob::GlobPattern;
+use super::histogram::SizeHistogram;
+use super::manifest::{new_instance_id, sync_dir, Manifest, ManifestEntry, FORMAT_VERSION};
+use super::namespace::Namespace;
+use super::segment::{read_header, write_header};
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use super::uring;
+use super::verify::{self, VerifyReport};
+use crate::thread_pool::CancellationToken;
+use crate::trace;
+use crate::{
+    json, Command, ErrorContext, KVStoreError, KvsReader, KvsWriter, Metric, Result, ResultExt,
+};
 use dashmap::DashMap;
-use log::{info, warn};
+use log::{debug, info, warn};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use serde_json::Deserializer;
 use std::cell::RefCell;
+use std::cmp;
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use std::fs::{create_dir_all, read_dir, remove_file, File, OpenOptions};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, remove_file, File};
 use std::io;
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Take, Write};
-use std::path::PathBuf;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 const MAX_USELESS_SIZE: u64 = 1024;
+/// the most patch records read on top of a JSON document before it is written whole again
+const MAX_PATCHES: u32 = 16;
 
 /** A KvStore stores key/value pairs using BitCask.
 # Example
 ```
 use std::env;
 use kvs::{KvStore, Result};
-use crate::kvs::KvsEngine;
+use crate::kvs::{KvsReader, KvsWriter};
  fn try_main() -> Result<()> {
     let mut store = KvStore::open(env::current_dir()?)?;
     store.set("1".to_owned(),"1".to_owned())?;
@@ -31,387 +55,3673 @@ use crate::kvs::KvsEngine;
 }
 ```
  */
- #[derive(Clone)]
- pub struct KvStore {
-     index: Arc<DashMap<String, CommandPosition>>,
-     writer: Arc<Mutex<Writer>>,
-     readers: Reader,
- }
- 
- impl KvStore {
-     /// Open the KvStore at a given path. Return the KvStore.
-     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-         let dir_path = Arc::new(path.into());
-         create_dir_all(dir_path.as_path())?;
- 
-         let mut index = Arc::new(DashMap::new());
-         let mut readers = HashMap::new();
- 
-         let (current_file_number, useless_size) =
-             Self::recover(&dir_path, &mut readers, &mut index)?;
- 
-         let current_file_path = dir_path.join(format!("data_{}.txt", current_file_number));
- 
-         let current_writer = BufWriterWithPosition::new(
-             OpenOptions::new()
-                 .create(true)
-                 .append(true)
-                 .open(&current_file_path)?,
-         )?;
- 
-         if current_file_number == 0 {
-             readers.insert(
-                 current_file_number,
-                 BufReader::new(File::open(&current_file_path)?),
-             );
-         }
- 
-         let readers = Reader {
-             dir_path: Arc::clone(&dir_path),
-             compaction_number: Arc::new(AtomicU64::new(0)),
-             readers: RefCell::new(readers),
-         };
- 
-         let writer = Arc::new(Mutex::new(Writer {
-             current_writer,
-             current_file_number,
-             useless_size,
-             dir_path,
-             index: Arc::clone(&index),
-             reader: readers.clone(),
-         }));
- 
-         Ok(KvStore {
-             readers,
-             writer,
-             index,
-         })
-     }
- 
-     fn recover(
-         dir_path: &Arc<PathBuf>,
-         current_readers: &mut HashMap<u64, BufReader<File>>,
-         index: &mut Arc<DashMap<String, CommandPosition>>,
-     ) -> Result<(u64, u64)> {
-         let mut versions: Vec<u64> = read_dir(dir_path.as_path())?
-             .flat_map(|res| res.map(|e| e.path()))
-             .filter(|path| path.is_file() && path.extension() == Some("txt".as_ref()))
-             .flat_map(|path| {
-                 path.file_name()
-                     .and_then(|filename| filename.to_str())
-                     .map(|filename| {
-                         filename
-                             .trim_start_matches("data_")
-                             .trim_end_matches(".txt")
-                     })
-                     .map(str::parse::<u64>)
-             })
-             .flatten()
-             .collect();
-         versions.sort();
- 
-         let mut useless_size = 0;
-         for version in &versions {
-             let file_path = dir_path.join(format!("data_{}.txt", version));
-             let reader = BufReader::new(File::open(&file_path)?);
-             let mut iter = Deserializer::from_reader(reader).into_iter::<Command>();
-             let mut before_offset = iter.byte_offset() as u64;
-             while let Some(command) = iter.next() {
-                 let after_offset = iter.byte_offset() as u64;
-                 match command? {
-                     Command::SET(key, _) => {
-                         useless_size += index
-                             .insert(
-                                 key,
-                                 CommandPosition {
-                                     offset: before_offset,
-                                     length: after_offset - before_offset,
-                                     file_number: *version,
-                                 },
-                             )
-                             .map(|cp| cp.length)
-                             .unwrap_or(0);
-                     }
-                     Command::RM(key) => {
-                         useless_size += index.remove(&key).map(|(_, cp)| cp.length).unwrap_or(0);
-                         useless_size += after_offset - before_offset;
-                     }
-                 };
-                 before_offset = after_offset;
-             }
-             current_readers.insert(*version, BufReader::new(File::open(&file_path)?));
-         }
- 
-         Ok((*versions.last().unwrap_or(&0), useless_size))
-     }
- }
- 
- impl KvsEngine for KvStore {
-     /// Set the value of a string key to a string. Return an error if the value is not written successfully.
-     fn set(&self, key: String, value: String) -> Result<()> {
-         self.writer.lock().unwrap().set(key, value)
-     }
- 
-     /// Get the string value of a string key. If the key does not exist, return None. Return an error if the value is not read successfully.
-     fn get(&self, key: String) -> Result<Option<String>> {
-         if let Some(entry) = self.index.get(&key) {
-             self.readers.read_command(entry.value())
-         } else {
-             Ok(None)
-         }
-     }
- 
-     /// Remove a given key. Return an error if the key does not exist or is not removed successfully.
-     fn remove(&self, key: String) -> Result<()> {
-         self.writer.lock().unwrap().remove(key)
-     }
- }
- 
- struct Reader {
-     dir_path: Arc<PathBuf>,
-     compaction_number: Arc<AtomicU64>,
-     readers: RefCell<HashMap<u64, BufReader<File>>>,
- }
- 
- impl Clone for Reader {
-     fn clone(&self) -> Self {
-         Reader {
-             dir_path: Arc::clone(&self.dir_path),
-             compaction_number: Arc::clone(&self.compaction_number),
-             readers: RefCell::new(HashMap::new()),
-         }
-     }
- }
- 
- impl Reader {
-     fn try_to_remove_stale_readers(&self) {
-         let compaction_number = self.compaction_number.load(Ordering::SeqCst);
-         let mut readers = self.readers.borrow_mut();
-         while !readers.is_empty() {
-             let reader_number = *readers.keys().next().unwrap();
-             if compaction_number <= reader_number {
-                 break;
-             }
-             readers.remove(&reader_number);
-         }
-     }
- 
-     fn read_add<F, R>(&self, position: &CommandPosition, f: F) -> Result<R>
-     where
-         F: FnOnce(Take<&mut BufReader<File>>) -> Result<R>,
-     {
-         self.try_to_remove_stale_readers();
- 
-         let mut readers = self.readers.borrow_mut();
- 
-         if let Entry::Vacant(entry) = readers.entry(position.file_number) {
-             let new_reader = BufReader::new(File::open(
-                 &self
-                     .dir_path
-                     .join(format!("data_{}.txt", position.file_number)),
-             )?);
-             entry.insert(new_reader);
-         }
- 
-         let source_reader = readers
-             .get_mut(&position.file_number)
-             .expect("Can not find key in files but it is in memory");
-         source_reader.seek(SeekFrom::Start(position.offset))?;
-         let data_reader = source_reader.take(position.length as u64);
-         f(data_reader)
-     }
- 
-     fn read_command(&self, position: &CommandPosition) -> Result<Option<String>> {
-         self.read_add(position, |data_reader| {
-             if let Command::SET(_, value) = serde_json::from_reader(data_reader)? {
-                 Ok(Some(value))
-             } else {
-                 Err(KVStoreError::UnknownCommandType)
-             }
-         })
-     }
- 
-     fn copy_data_to_writer(
-         &self,
-         position: &CommandPosition,
-         writer: &mut BufWriterWithPosition<File>,
-     ) -> Result<()> {
-         self.read_add(position, |mut data_reader| {
-             io::copy(&mut data_reader, writer)?;
-             Ok(())
-         })
-     }
- 
-     fn remove_useless_reader(&mut self, file_number: u64) -> Result<()> {
-         let mut readers = self.readers.borrow_mut();
-         let delete_file_numbers: Vec<u64> = readers
-             .iter()
-             .map(|(key, _)| *key)
-             .filter(|key| *key < file_number)
-             .collect();
- 
-         for number in delete_file_numbers {
-             readers.remove(&number);
-             let file_path = self.dir_path.join(format!("data_{}.txt", number));
-             if let Err(err) = remove_file(&file_path) {
-                 warn!("can not delete file {:?} because {}", file_path, err);
-             }
-         }
- 
-         Ok(())
-     }
- }
- 
- struct Writer {
-     dir_path: Arc<PathBuf>,
-     reader: Reader,
-     current_writer: BufWriterWithPosition<File>,
-     current_file_number: u64,
-     useless_size: u64,
-     index: Arc<DashMap<String, CommandPosition>>,
- }
- 
- impl Writer {
-     fn set(&mut self, key: String, value: String) -> Result<()> {
-         let command = Command::SET(key, value);
-         let data = serde_json::to_vec(&command)?;
- 
-         let offset = self.current_writer.get_position();
-         self.current_writer.write_all(&data)?;
-         self.current_writer.flush()?;
-         let length = self.current_writer.get_position() - offset;
-         let file_number = self.current_file_number;
- 
-         if let Command::SET(key, _) = command {
-             self.useless_size += self
-                 .index
-                 .insert(
-                     key,
-                     CommandPosition {
-                         offset,
-                         length,
-                         file_number,
-                     },
-                 )
-                 .map(|cp| cp.length)
-                 .unwrap_or(0);
-         }
- 
-         if self.useless_size > MAX_USELESS_SIZE {
-             let now = SystemTime::now();
-             info!("Compaction starts");
-             self.compact()?;
-             info!("Compaction finished, cost {:?}", now.elapsed());
-         }
- 
-         Ok(())
-     }
- 
-     fn remove(&mut self, key: String) -> Result<()> {
-         if self.index.get(&key).is_some() {
-             self.useless_size += self
-                 .index
-                 .remove(&key)
-                 .map(|(_, cp)| cp.length)
-                 .unwrap_or(0);
- 
-             let command = serde_json::to_vec(&Command::RM(key))?;
-             let offset = self.current_writer.get_position();
-             self.current_writer.write_all(&command)?;
-             self.current_writer.flush()?;
- 
-             self.useless_size += self.current_writer.get_position() - offset;
- 
-             if self.useless_size > MAX_USELESS_SIZE {
-                 self.compact()?;
-             }
- 
-             Ok(())
-         } else {
-             Err(KVStoreError::KeyNotFound)
-         }
-     }
- 
-     fn compact(&mut self) -> Result<()> {
-         self.create_new_file()?;
- 
-         let mut before_offset = 0;
-         for mut entry in self.index.iter_mut() {
-             let position = entry.value_mut();
-             self.reader
-                 .copy_data_to_writer(position, &mut self.current_writer)?;
-             let after_offset = self.current_writer.position;
-             *position = CommandPosition {
-                 offset: before_offset,
-                 length: after_offset - before_offset,
-                 file_number: self.current_file_number,
-             };
-             before_offset = after_offset;
-         }
-         self.current_writer.flush()?;
- 
-         self.reader
-             .compaction_number
-             .store(self.current_file_number, Ordering::SeqCst);
- 
-         self.reader
-             .remove_useless_reader(self.current_file_number)?;
- 
-         self.useless_size = 0;
- 
-         self.create_new_file()?;
- 
-         Ok(())
-     }
- 
-     fn create_new_file(&mut self) -> Result<()> {
-         self.current_file_number += 1;
-         self.current_writer = BufWriterWithPosition::new(
-             OpenOptions::new().create(true).append(true).open(
-                 &self
-                     .dir_path
-                     .join(format!("data_{}.txt", self.current_file_number)),
-             )?,
-         )?;
-         Ok(())
-     }
- }
- 
- /// a struct which records writer's current position
- struct BufWriterWithPosition<T: Write + Seek> {
-     position: u64,
-     writer: BufWriter<T>,
- }
- 
- impl<T: Write + Seek> BufWriterWithPosition<T> {
-     fn new(mut inner: T) -> Result<Self> {
-         let position = inner.seek(SeekFrom::End(0))?;
-         Ok(BufWriterWithPosition {
-             position,
-             writer: BufWriter::new(inner),
-         })
-     }
- 
-     fn get_position(&self) -> u64 {
-         self.position
-     }
- }
- 
- impl<T: Write + Seek> Write for BufWriterWithPosition<T> {
-     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-         let len = self.writer.write(buf)?;
-         self.position += len as u64;
-         Ok(len)
-     }
- 
-     fn flush(&mut self) -> io::Result<()> {
-         self.writer.flush()
-     }
- }
- 
- /// a struct which records command's metadata
- struct CommandPosition {
-     offset: u64,
-     length: u64,
-     file_number: u64,
- }
\ No newline at end of file
+#[derive(Clone)]
+pub struct KvStore {
+    index: Arc<DashMap<String, CommandPosition>>,
+    writer: Arc<Mutex<Writer>>,
+    readers: Reader,
+    access_clock: Arc<AccessClock>,
+    quarantine: Arc<DashMap<String, String>>,
+    /// stream key -> the sequences of the first live and the last event of the stream
+    streams: Arc<DashMap<String, StreamRange>>,
+}
+
+/** A builder which configures how a KvStore is opened.
+# Example
+```
+use std::env;
+use kvs::{KvStore, Result};
+
+fn try_main() -> Result<()> {
+    let store = KvStore::builder()
+        .max_disk_size(64 * 1024 * 1024)
+        .open(env::current_dir()?)?;
+    assert_eq!(store.stats().max_disk_size, Some(64 * 1024 * 1024));
+    Ok(())
+}
+```
+ */
+#[derive(Clone, Debug, Default)]
+pub struct KvStoreBuilder {
+    max_disk_size: Option<u64>,
+    quota_policy: QuotaPolicy,
+    data_dirs: Vec<PathBuf>,
+    placement_policy: PlacementPolicy,
+    cold_tier: Option<(PathBuf, Duration)>,
+    trash_retention: Option<Duration>,
+    compaction_policy: CompactionPolicy,
+    compaction_threads: usize,
+    write_stall: Option<(u64, Duration)>,
+    paranoid_checks: bool,
+    direct_io: bool,
+    preallocate: Option<u64>,
+    usage_prefix: Option<KeyPrefix>,
+    on_progress: Option<ProgressCallback>,
+    compaction_filter: Option<FilterHook>,
+    scrub_interval: Option<Duration>,
+    archive: Option<ArchiveHook>,
+    retention: Vec<RetentionRule>,
+}
+
+/// reports the progress of recovery as data files done, total data files and keys loaded
+#[derive(Clone)]
+struct ProgressCallback(Arc<dyn Fn(usize, usize, u64) + Send + Sync>);
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// the compaction filter of a store
+#[derive(Clone)]
+struct FilterHook(Arc<dyn CompactionFilter>);
+
+impl fmt::Debug for FilterHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CompactionFilter")
+    }
+}
+
+/// What compaction does with a live record, as decided by a `CompactionFilter`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompactionDecision {
+    /// copy the record as it is
+    Keep,
+    /// drop the key as if it was removed, without going through the trash or the watchers
+    Remove,
+    /// keep the key with the given value instead
+    ChangeValue(String),
+}
+
+/// Decides for each live record a compaction copies whether to keep, drop or rewrite it,
+/// e.g. to expire keys with a timestamp in their value without a separate scan.
+/// Registered with `KvStoreBuilder::compaction_filter`; closures taking the key and the value
+/// are filters as well.
+pub trait CompactionFilter: Send + Sync + 'static {
+    /// the decision for the current `value` of `key`
+    fn filter(&self, key: &str, value: &str) -> CompactionDecision;
+}
+
+impl<F> CompactionFilter for F
+where
+    F: Fn(&str, &str) -> CompactionDecision + Send + Sync + 'static,
+{
+    fn filter(&self, key: &str, value: &str) -> CompactionDecision {
+        self(key, value)
+    }
+}
+
+/// how often recovery logs its progress
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// the number of keys a scan visits or reads between two checks for its cancellation
+const SCAN_CHUNK: usize = 1024;
+
+/// the access stamp of the keys recovered from the cold tier: older than any read, so they
+/// stay cold across reopens until they are read again
+const UNREAD_COLD: u64 = 0;
+
+/// What a KvStore does when a write would exceed its disk quota
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QuotaPolicy {
+    /// fail the write with `KVStoreError::QuotaExceeded`
+    #[default]
+    Reject,
+    /// evict the least recently used keys until the write fits, like a cache
+    EvictLru,
+}
+
+/// When a KvStore compacts its data files to reclaim the space of stale commands
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompactionPolicy {
+    /// after a write once the stale commands exceed the given number of bytes
+    UselessSize(u64),
+    /// after a write once the stale commands exceed the given percentage of the live data and 1 KiB
+    GarbageRatio(u32),
+    /// in the background at the given interval if there are stale commands; it must not be zero
+    Interval(Duration),
+    /// in the background once no write happened for the given duration and there are stale
+    /// commands; it must not be zero
+    Idle(Duration),
+}
+
+/// How `KvStoreStats::prefixes` groups keys, e.g. by tenant
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyPrefix {
+    /// the key up to and including the first occurrence of the character, like `tenant1/` of
+    /// `tenant1/users/42`; keys without it are grouped under the empty prefix
+    Delimiter(char),
+    /// the first given number of characters of the key, or the whole key if it is shorter
+    Length(usize),
+}
+
+impl KeyPrefix {
+    /// Return the prefix `key` is grouped under.
+    pub fn of<'a>(&self, key: &'a str) -> &'a str {
+        match *self {
+            KeyPrefix::Delimiter(delimiter) => match key.find(delimiter) {
+                Some(index) => &key[..index + delimiter.len_utf8()],
+                None => "",
+            },
+            KeyPrefix::Length(length) => match key.char_indices().nth(length) {
+                Some((index, _)) => &key[..index],
+                None => key,
+            },
+        }
+    }
+}
+
+/// how long the records of the keys of a database starting with a prefix are kept
+#[derive(Clone, Debug)]
+struct RetentionRule {
+    namespace: Namespace,
+    prefix: String,
+    max_age: Duration,
+}
+
+/// whether the record of `key` written at `written_at` is older than the retention of its
+/// prefix at `now`, both in milliseconds since the unix epoch
+fn is_retention_expired(rules: &[RetentionRule], key: &str, written_at: u64, now: u64) -> bool {
+    rules.iter().any(|rule| {
+        rule.namespace
+            .decode(key)
+            .is_some_and(|key| key.starts_with(rule.prefix.as_str()))
+            && written_at + rule.max_age.as_millis() as u64 <= now
+    })
+}
+
+impl Default for CompactionPolicy {
+    fn default() -> Self {
+        CompactionPolicy::UselessSize(MAX_USELESS_SIZE)
+    }
+}
+
+impl KvStoreBuilder {
+    /// Limit the total size of the data files to `bytes`.
+    /// Once the limit is reached, `set` returns `KVStoreError::QuotaExceeded`.
+    pub fn max_disk_size(mut self, bytes: u64) -> Self {
+        self.max_disk_size = Some(bytes);
+        self
+    }
+
+    /// Choose what happens when a write would exceed `max_disk_size`.
+    /// With `QuotaPolicy::EvictLru` the store behaves as a persistent cache.
+    pub fn quota_policy(mut self, policy: QuotaPolicy) -> Self {
+        self.quota_policy = policy;
+        self
+    }
+
+    /// Add another directory which new data files can be placed in, e.g. on a different disk.
+    /// Recovery scans the opened path and all added directories.
+    pub fn data_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.data_dirs.push(path.into());
+        self
+    }
+
+    /// Choose how new data files are spread over the directories.
+    pub fn placement_policy(mut self, policy: PlacementPolicy) -> Self {
+        self.placement_policy = policy;
+        self
+    }
+
+    /// Move keys which have not been read for `cold_after` to the slower directory `path` during compaction.
+    /// Keys which are read again move back to the hot directories on the next compaction; keys in
+    /// the cold directory which were not read since the store was opened stay there.
+    /// Like the directories added by `data_dir`, the cold directory must be configured on every open.
+    pub fn cold_tier(mut self, path: impl Into<PathBuf>, cold_after: Duration) -> Self {
+        self.cold_tier = Some((path.into(), cold_after));
+        self
+    }
+
+    /// Keep removed keys in a trash for `retention`, so that `KvStore::restore_key` can bring them back.
+    /// Expired keys are purged by compaction.
+    pub fn trash_retention(mut self, retention: Duration) -> Self {
+        self.trash_retention = Some(retention);
+        self
+    }
+
+    /// Choose when compaction runs. A disk quota still compacts whenever it is reached.
+    /// `open` fails with `KVStoreError::InvalidConfig` for a background policy waiting zero.
+    pub fn compaction_policy(mut self, policy: CompactionPolicy) -> Self {
+        self.compaction_policy = policy;
+        self
+    }
+
+    /// Let compaction copy different data files on up to `threads` threads in parallel.
+    pub fn compaction_threads(mut self, threads: usize) -> Self {
+        self.compaction_threads = threads;
+        self
+    }
+
+    /// Delay each `set` once the stale commands exceed `threshold` bytes, so that background compaction
+    /// can catch up. The delay grows linearly up to `max_delay` at twice the threshold.
+    pub fn write_stall(mut self, threshold: u64, max_delay: Duration) -> Self {
+        self.write_stall = Some((threshold, max_delay));
+        self
+    }
+
+    /// Validate every record read against its index entry and re-read the output of compaction,
+    /// returning `KVStoreError::CorruptedRecord` on a mismatch. This trades speed for integrity
+    /// while debugging: data files carry no checksums, so each record is checked to decode to
+    /// the expected key and to span exactly the indexed length. Reads failing these checks
+    /// are not repaired like other reads of records which do not set the expected key.
+    pub fn paranoid_checks(mut self, enabled: bool) -> Self {
+        self.paranoid_checks = enabled;
+        self
+    }
+
+    /// Write data files with O_DIRECT on Linux, so heavy writes do not evict the data reads
+    /// depend on from the page cache. Each flush then rewrites the last partial block of the file.
+    /// Other platforms and file systems without direct I/O fall back to buffered writes.
+    pub fn direct_io(mut self, enabled: bool) -> Self {
+        self.direct_io = enabled;
+        self
+    }
+
+    /// Preallocate each log file to `bytes` when it becomes active and continue the log in a new
+    /// file once it is full, so appends do not extend the file's allocation one block at a time.
+    /// The space a log file did not use is released when it is sealed. Linux only.
+    pub fn preallocate(mut self, bytes: u64) -> Self {
+        self.preallocate = Some(bytes);
+        self
+    }
+
+    /// Account the live keys and bytes of each prefix of the keys in `KvStoreStats::prefixes`,
+    /// so the storage of a multi-tenant store can be attributed to its tenants.
+    pub fn usage_prefix(mut self, prefix: KeyPrefix) -> Self {
+        self.usage_prefix = Some(prefix);
+        self
+    }
+
+    /// Call `callback` with the number of data files recovered, the total number of data files
+    /// and the number of keys loaded so far after each data file `open` reads, so that slow
+    /// recovery of a large store can be told apart from a hang.
+    pub fn on_progress<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(usize, usize, u64) + Send + Sync + 'static,
+    {
+        self.on_progress = Some(ProgressCallback(Arc::new(callback)));
+        self
+    }
+
+    /// Pass every live record compactions copy through `filter`, which can keep, drop or
+    /// rewrite it. Records in the trash are kept until their retention expires regardless.
+    pub fn compaction_filter(mut self, filter: impl CompactionFilter) -> Self {
+        self.compaction_filter = Some(FilterHook(Arc::new(filter)));
+        self
+    }
+
+    /// Upload each log file to `target` in a background thread as soon as it is sealed, once
+    /// compaction starts or its preallocated space is used up, for continuous backups and
+    /// point-in-time recovery outside of the store: the writes up to any sealed log file are
+    /// the ones of a backup followed by the archived log files after it. The log files are
+    /// named `wal/data_<file number>.txt`, the number padded to 20 digits, and compaction only
+    /// deletes them once they are archived. Log files left unarchived when the store was last
+    /// closed are archived when it is opened again, unless a compaction already replaced them.
+    pub fn archive_to(mut self, target: impl BackupTarget + Send + Sync + 'static) -> Self {
+        self.archive = Some(ArchiveHook(Arc::new(target)));
+        self
+    }
+
+    /// Drop the keys of `database` starting with `prefix` at the first compaction after they
+    /// were last written more than `max_age` ago, whether they were removed or not, for
+    /// keyspaces of metrics or events which are only appended to; `CompactionPolicy::Interval`
+    /// compacts such stores regularly. Call it once per prefix;
+    /// a key covered by several prefixes is kept for the shortest of their ages. The write
+    /// times only survive a restart per data file, so after a restart a key may be kept
+    /// longer than `max_age`, but never dropped earlier.
+    pub fn retention(
+        mut self,
+        database: u32,
+        prefix: impl Into<String>,
+        max_age: Duration,
+    ) -> Self {
+        self.retention.push(RetentionRule {
+            namespace: Namespace::new(database),
+            prefix: prefix.into(),
+            max_age,
+        });
+        self
+    }
+
+    /// Verify one sealed data file every `interval` in a background thread: re-read its records,
+    /// check that each one decodes to the key its index entry expects, and repair the entries
+    /// which do not or quarantine them if they can not be repaired, so that corruption is found
+    /// before reads run into it. Reads of quarantined keys fail until the keys are written again.
+    pub fn scrub_interval(mut self, interval: Duration) -> Self {
+        self.scrub_interval = Some(interval);
+        self
+    }
+
+    /// Open the KvStore at a given path with the configured options. Return the KvStore.
+    pub fn open(self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path.into(), self)
+    }
+
+    /// Open the KvStore at a given path read-only as a `SnapshotEngine`, which leaves its files
+    /// as they are. Of the options, only its data directories and `paranoid_checks` apply.
+    pub fn open_snapshot(self, path: impl Into<PathBuf>) -> Result<SnapshotEngine> {
+        SnapshotEngine::open_with(path.into(), self)
+    }
+
+    /// Rewrite the data files of the KvStore at a given path in the newest on-disk format,
+    /// so it can still be opened once the older formats are no longer supported.
+    /// Stores in the newest format are left as they are.
+    pub fn upgrade(self, path: impl Into<PathBuf>) -> Result<()> {
+        let store = self.open(path)?;
+        let mut writer = store.writer.lock().unwrap();
+        let format_version = writer.manifest.format_version;
+        if format_version < FORMAT_VERSION {
+            info!(
+                "Upgrade the store from format {} to {}",
+                format_version, FORMAT_VERSION
+            );
+            writer.timed_compact()?;
+        }
+        Ok(())
+    }
+
+    /// Compact the KvStore at a given path into as few data files as possible, e.g. to reclaim
+    /// the space of an archived store, and return its disk usage before and after.
+    /// The store must not be open elsewhere.
+    pub fn trim(mut self, path: impl Into<PathBuf>) -> Result<TrimReport> {
+        // a single compaction job writes a single data file per tier
+        self.compaction_threads = 1;
+        self.compaction_policy = CompactionPolicy::UselessSize(u64::MAX);
+        self.scrub_interval = None;
+        let store = self.open(path)?;
+        let usage = |stats: KvStoreStats| {
+            let tiers = [Some(stats.hot_tier), stats.cold_tier];
+            tiers
+                .into_iter()
+                .flatten()
+                .fold((0, 0), |(files, bytes), tier| {
+                    (files + tier.data_files, bytes + tier.disk_usage)
+                })
+        };
+        let (data_files_before, disk_usage_before) = usage(store.stats());
+        store.writer.lock().unwrap().timed_compact()?;
+        let (data_files_after, disk_usage_after) = usage(store.stats());
+        store.close()?;
+        Ok(TrimReport {
+            data_files_before,
+            disk_usage_before,
+            data_files_after,
+            disk_usage_after,
+        })
+    }
+
+    /// Validate the KvStore at a given path offline without opening or changing it: the
+    /// manifest against the data files, the records of every live data file and the index
+    /// rebuilt from them. Data files carry no checksums, so each record is checked to decode.
+    /// Problems are returned in the report, errors only if the files can not be read at all.
+    pub fn verify(self, path: impl Into<PathBuf>) -> Result<VerifyReport> {
+        let path = path.into();
+        if !path.is_dir() {
+            return Err(KVStoreError::Io(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no store at {:?}", path),
+            )));
+        }
+        let mut dir_paths = vec![path];
+        dir_paths.extend(self.data_dirs);
+        let cold_dir = self.cold_tier.map(|(cold_dir, _)| cold_dir);
+        let dirs = DataDirs::open(dir_paths, cold_dir, self.placement_policy)?;
+        verify::verify(&dirs)
+    }
+}
+
+/// A snapshot of the storage usage of a KvStore
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KvStoreStats {
+    /// number of live keys
+    pub live_keys: u64,
+    /// total size of all data files in bytes
+    pub disk_usage: u64,
+    /// size of the stale commands which compaction can reclaim
+    pub useless_size: u64,
+    /// the configured quota, if any
+    pub max_disk_size: Option<u64>,
+    /// number of keys evicted by `QuotaPolicy::EvictLru` since the store was opened
+    pub evicted_keys: u64,
+    /// number of keys dropped by `KvStoreBuilder::retention` since the store was opened
+    pub expired_keys: u64,
+    /// the sequence number of the last write, counting every set and remove
+    pub sequence: u64,
+    /// number of removed keys in the trash, including expired ones until the next compaction
+    pub trashed_keys: u64,
+    /// whether writes are currently delayed because compaction is behind
+    pub write_stalled: bool,
+    /// number of writes delayed by the write stall since the store was opened
+    pub stalled_writes: u64,
+    /// number of reads since the store was opened which found a record of another key than
+    /// the index entry, after which the entry was looked up again in the data files
+    pub corrupted_reads: u64,
+    /// number of data files the scrubber verified since the store was opened
+    pub scrubbed_files: u64,
+    /// number of records the scrubber verified since the store was opened
+    pub scrubbed_records: u64,
+    /// number of bad records the scrubber found since the store was opened
+    pub scrub_errors: u64,
+    /// number of keys whose reads fail because the scrubber found their records corrupted
+    pub quarantined_keys: u64,
+    /// bytes of records read from the data files since the store was opened
+    pub bytes_read: u64,
+    /// bytes of records appended to the log by writes since the store was opened
+    pub bytes_written: u64,
+    /// number of records appended to the log by writes since the store was opened
+    pub records_appended: u64,
+    /// bytes of live records compactions copied to new data files since the store was opened
+    pub compaction_bytes_copied: u64,
+    /// number of fsyncs of data files, manifests and directories since the store was opened
+    pub fsyncs: u64,
+    /// the sizes of the keys written since the last compaction and of the live keys it
+    /// copied, or of those in the data files when the store was opened
+    pub key_sizes: SizeHistogram,
+    /// the sizes of the values counted like `key_sizes`; compaction tells the sizes of the
+    /// values it copies from their records, so values holding characters JSON escapes count
+    /// a little larger, and the patches of JSON documents are only counted once folded
+    pub value_sizes: SizeHistogram,
+    /// usage of the hot directories
+    pub hot_tier: TierStats,
+    /// usage of the cold directory, if tiering is enabled
+    pub cold_tier: Option<TierStats>,
+    /// usage of each key prefix, if `KvStoreBuilder::usage_prefix` is set
+    pub prefixes: BTreeMap<String, PrefixStats>,
+    /// the progress of archiving the log files, if `KvStoreBuilder::archive_to` is set
+    pub archive: Option<ArchiveStats>,
+}
+
+/// The storage usage of one tier of a KvStore
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TierStats {
+    /// number of data files in the tier
+    pub data_files: u64,
+    /// number of live keys stored in the tier
+    pub live_keys: u64,
+    /// total size of the data files in the tier in bytes
+    pub disk_usage: u64,
+}
+
+/// The storage usage of the keys sharing a prefix
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PrefixStats {
+    /// number of live keys with the prefix
+    pub live_keys: u64,
+    /// size of the commands setting the current values of the keys in bytes
+    pub live_bytes: u64,
+}
+
+/// The estimated size of a range of keys, see `KvStore::approximate_size`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RangeSize {
+    /// number of live keys in the range
+    pub live_keys: u64,
+    /// size of the commands setting the current values of the keys in bytes
+    pub live_bytes: u64,
+}
+
+/// The disk usage of a KvStore before and after `KvStoreBuilder::trim`
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TrimReport {
+    /// number of data files before the compaction
+    pub data_files_before: u64,
+    /// total size of the data files before the compaction in bytes
+    pub disk_usage_before: u64,
+    /// number of data files after the compaction
+    pub data_files_after: u64,
+    /// total size of the data files after the compaction in bytes
+    pub disk_usage_after: u64,
+}
+
+impl KvStoreStats {
+    /// The stats as metrics: the sizes and key counts as gauges, the totals as counters.
+    pub fn metrics(&self) -> Vec<(String, Metric)> {
+        let gauge = |name: &str, value: u64| (name.to_owned(), Metric::Gauge(value as f64));
+        let counter = |name: &str, value: u64| (name.to_owned(), Metric::Counter(value));
+        let mut metrics = vec![
+            gauge("live_keys", self.live_keys),
+            gauge("disk_usage", self.disk_usage),
+            gauge("useless_size", self.useless_size),
+            gauge("trashed_keys", self.trashed_keys),
+            gauge("write_stalled", self.write_stalled as u64),
+            counter("writes", self.sequence),
+            counter("evicted_keys", self.evicted_keys),
+            counter("expired_keys", self.expired_keys),
+            counter("stalled_writes", self.stalled_writes),
+            counter("corrupted_reads", self.corrupted_reads),
+            counter("scrubbed_files", self.scrubbed_files),
+            counter("scrubbed_records", self.scrubbed_records),
+            counter("scrub_errors", self.scrub_errors),
+            gauge("quarantined_keys", self.quarantined_keys),
+            counter("bytes_read", self.bytes_read),
+            counter("bytes_written", self.bytes_written),
+            counter("records_appended", self.records_appended),
+            counter("compaction_bytes_copied", self.compaction_bytes_copied),
+            counter("fsyncs", self.fsyncs),
+            gauge("hot_tier.data_files", self.hot_tier.data_files),
+            gauge("hot_tier.disk_usage", self.hot_tier.disk_usage),
+        ];
+        for (name, sizes) in [
+            ("key_sizes", &self.key_sizes),
+            ("value_sizes", &self.value_sizes),
+        ] {
+            metrics.push((format!("{}.mean", name), Metric::Gauge(sizes.mean())));
+            metrics.push(gauge(&format!("{}.p50", name), sizes.percentile(50.0)));
+            metrics.push(gauge(&format!("{}.p99", name), sizes.percentile(99.0)));
+            metrics.push(gauge(&format!("{}.max", name), sizes.max));
+        }
+        if let Some(cold_tier) = &self.cold_tier {
+            metrics.push(gauge("cold_tier.data_files", cold_tier.data_files));
+            metrics.push(gauge("cold_tier.disk_usage", cold_tier.disk_usage));
+        }
+        if let Some(archive) = &self.archive {
+            metrics.push(counter("archive.archived_files", archive.archived_files));
+            metrics.push(counter("archive.archived_bytes", archive.archived_bytes));
+            metrics.push(gauge("archive.pending_files", archive.pending_files));
+            metrics.push(gauge("archive.lag_ms", archive.lag.as_millis() as u64));
+        }
+        metrics
+    }
+}
+
+impl KvStore {
+    /// Open the KvStore at a given path with default options. Return the KvStore.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        Self::builder().open(path)
+    }
+
+    /// Close the store cleanly: make the writes durable and record that the store was closed,
+    /// so the next `open` trusts the log files instead of checking them for records torn by a
+    /// crash. Writes through clones of the store after it was closed void the record, and the
+    /// store is released once the clones are dropped as well.
+    pub fn close(self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.current_writer.sync()?;
+        writer.reader.io.synced(1);
+        let clean = CleanShutdown {
+            file_number: writer.current_file_number,
+            length: writer.current_writer.get_position(),
+        };
+        clean.store(writer.dirs.primary())?;
+        writer.reader.io.synced(2);
+        info!("Closed the store cleanly");
+        Ok(())
+    }
+
+    /// Rewrite the data files of the KvStore at a given path with default options
+    /// in the newest on-disk format.
+    pub fn upgrade(path: impl Into<PathBuf>) -> Result<()> {
+        Self::builder().upgrade(path)
+    }
+
+    /// Compact the KvStore at a given path with default options into as few data files as
+    /// possible, see `KvStoreBuilder::trim`.
+    pub fn trim(path: impl Into<PathBuf>) -> Result<TrimReport> {
+        Self::builder().trim(path)
+    }
+
+    /// Validate the KvStore at a given path with default options offline, see
+    /// `KvStoreBuilder::verify`.
+    pub fn verify(path: impl Into<PathBuf>) -> Result<VerifyReport> {
+        Self::builder().verify(path)
+    }
+
+    /// Create a builder to configure the KvStore before opening it.
+    pub fn builder() -> KvStoreBuilder {
+        KvStoreBuilder::default()
+    }
+
+    /// Return the current storage usage of the store.
+    pub fn stats(&self) -> KvStoreStats {
+        let writer = self.writer.lock().unwrap();
+        let dirs = &writer.dirs;
+        let io = &self.readers.io;
+
+        let mut hot_tier = TierStats::default();
+        let mut cold_tier = TierStats::default();
+        let mut cold_files = HashSet::new();
+        for file_number in dirs.file_numbers() {
+            let tier_stats = match dirs.tier(file_number) {
+                Tier::Hot => &mut hot_tier,
+                Tier::Cold => {
+                    cold_files.insert(file_number);
+                    &mut cold_tier
+                }
+            };
+            tier_stats.data_files += 1;
+            tier_stats.disk_usage += fs::metadata(dirs.file_path(file_number))
+                .map(|metadata| metadata.len())
+                .unwrap_or(0);
+        }
+        cold_tier.live_keys = self
+            .index
+            .iter()
+            .filter(|entry| cold_files.contains(&entry.value().file_number))
+            .count() as u64;
+        hot_tier.live_keys = self.index.len() as u64 - cold_tier.live_keys;
+
+        let mut prefixes = BTreeMap::new();
+        if let Some(usage_prefix) = writer.usage_prefix {
+            for entry in self.index.iter() {
+                let prefix = usage_prefix.of(entry.key());
+                if !prefixes.contains_key(prefix) {
+                    prefixes.insert(prefix.to_owned(), PrefixStats::default());
+                }
+                let prefix_stats = prefixes.get_mut(prefix).unwrap();
+                prefix_stats.live_keys += 1;
+                prefix_stats.live_bytes += entry.value().length;
+            }
+        }
+
+        KvStoreStats {
+            live_keys: self.index.len() as u64,
+            disk_usage: writer.disk_usage,
+            useless_size: writer.useless_size,
+            max_disk_size: writer.max_disk_size,
+            evicted_keys: writer.evicted_keys,
+            expired_keys: writer.expired_keys,
+            sequence: writer.sequence,
+            trashed_keys: writer.trash.len() as u64,
+            write_stalled: !writer.stall_delay().is_zero(),
+            stalled_writes: writer.stalled_writes,
+            corrupted_reads: writer.corrupted_reads,
+            scrubbed_files: writer.scrubbed_files,
+            scrubbed_records: writer.scrubbed_records,
+            scrub_errors: writer.scrub_errors,
+            quarantined_keys: self.quarantine.len() as u64,
+            bytes_read: io.bytes_read.load(Ordering::Relaxed),
+            bytes_written: io.bytes_written.load(Ordering::Relaxed),
+            records_appended: io.records_appended.load(Ordering::Relaxed),
+            compaction_bytes_copied: io.compaction_bytes_copied.load(Ordering::Relaxed),
+            fsyncs: io.fsyncs.load(Ordering::Relaxed),
+            key_sizes: writer.sizes.keys.clone(),
+            value_sizes: writer.sizes.values.clone(),
+            hot_tier,
+            cold_tier: dirs.has_cold_tier().then_some(cold_tier),
+            prefixes,
+            archive: writer.archiver.as_ref().map(|archiver| archiver.stats()),
+        }
+    }
+
+    /// Copy all data files to `target` as a new full backup and return its manifest.
+    /// Writes are only held back while the data files and their lengths are noted; the
+    /// backup copies them up to those lengths, pinned so that compaction does not delete them
+    /// in the meantime, so it is consistent as of that point.
+    pub fn backup(&self, target: &dyn BackupTarget) -> Result<BackupManifest> {
+        self.backup_with_parent(target, None, &CancellationToken::new())
+    }
+
+    /// Like `backup`, but stop with `KVStoreError::Cancelled` before the next data file once
+    /// `cancel` is cancelled. The files copied so far are left in `target` without a manifest,
+    /// so they are not listed as a backup.
+    pub fn backup_cancellable(
+        &self,
+        target: &dyn BackupTarget,
+        cancel: &CancellationToken,
+    ) -> Result<BackupManifest> {
+        self.backup_with_parent(target, None, cancel)
+    }
+
+    /// Copy the data written since the latest backup in `target` as a new backup and return its manifest.
+    /// The latest backup must have been taken from this store; without any, a full backup is taken.
+    pub fn backup_incremental(&self, target: &dyn BackupTarget) -> Result<BackupManifest> {
+        self.backup_incremental_cancellable(target, &CancellationToken::new())
+    }
+
+    /// Like `backup_incremental`, but stop once `cancel` is cancelled like `backup_cancellable`.
+    pub fn backup_incremental_cancellable(
+        &self,
+        target: &dyn BackupTarget,
+        cancel: &CancellationToken,
+    ) -> Result<BackupManifest> {
+        let parent = BackupManifest::list(target)?.pop();
+        self.backup_with_parent(target, parent, cancel)
+    }
+
+    fn backup_with_parent(
+        &self,
+        target: &dyn BackupTarget,
+        parent: Option<BackupManifest>,
+        cancel: &CancellationToken,
+    ) -> Result<BackupManifest> {
+        let backed_up = match &parent {
+            Some(parent) => parent.backed_up_lengths(target)?,
+            None => HashMap::new(),
+        };
+        let created_at = now_millis();
+        // ids sort by creation time, and a second backup within the same millisecond gets the next one
+        let mut id_millis = created_at;
+        while !target.list(&format!("{:013}/", id_millis))?.is_empty() {
+            id_millis += 1;
+        }
+        let id = format!("{:013}", id_millis);
+
+        let (dirs, lengths, manifest) = {
+            let mut writer = self.writer.lock().unwrap();
+            writer.current_writer.flush()?;
+            let file_numbers = writer.dirs.file_numbers();
+            let mut lengths = Vec::with_capacity(file_numbers.len());
+            for &file_number in &file_numbers {
+                let length = if file_number == writer.current_file_number {
+                    writer.current_writer.get_position()
+                } else {
+                    fs::metadata(writer.dirs.file_path(file_number))?.len()
+                };
+                lengths.push((file_number, length));
+            }
+            writer.dirs.pin(&file_numbers);
+            let manifest = BackupManifest {
+                id,
+                created_at,
+                parent: parent.as_ref().map(|parent| parent.id.clone()),
+                sequence: writer.sequence,
+                compacted_sequence: writer.manifest.compacted_sequence,
+                first_log_file: writer.manifest.first_log_file(),
+                file_numbers,
+                files: Vec::new(),
+            };
+            (Arc::clone(&writer.dirs), lengths, manifest)
+        };
+        let copied = copy_backup_files(&dirs, target, &manifest, &lengths, &backed_up, cancel);
+        dirs.unpin(&manifest.file_numbers);
+        let manifest = BackupManifest {
+            files: copied?,
+            ..manifest
+        };
+        manifest.put(target)?;
+        info!(
+            "Backup {} finished with {} copied data files",
+            manifest.id,
+            manifest.files.len()
+        );
+        Ok(manifest)
+    }
+
+    /// Restore the backup `backup_id` from `target` into the empty directory `path` and open it.
+    /// An incremental backup is restored by replaying its chain from the last full backup.
+    pub fn restore(
+        target: &dyn BackupTarget,
+        backup_id: &str,
+        path: impl Into<PathBuf>,
+    ) -> Result<KvStore> {
+        let manifest = BackupManifest::get(target, backup_id)?;
+        Self::restore_manifest(target, &manifest, manifest.sequence, path.into())
+    }
+
+    /// Restore the state as of the write with the given sequence number from the backups in `target`
+    /// into the empty directory `path` and open it, e.g. to undo an accidental bulk delete.
+    /// It needs a backup taken after that write without a compaction in between.
+    pub fn restore_to(
+        target: &dyn BackupTarget,
+        sequence: u64,
+        path: impl Into<PathBuf>,
+    ) -> Result<KvStore> {
+        // later backups can only have compacted more of the history
+        let manifest = BackupManifest::list(target)?
+            .into_iter()
+            .find(|manifest| manifest.sequence >= sequence)
+            .filter(|manifest| manifest.compacted_sequence <= sequence)
+            .ok_or(KVStoreError::SequenceNotRetained(sequence))?;
+        Self::restore_manifest(target, &manifest, sequence, path.into())
+    }
+
+    fn restore_manifest(
+        target: &dyn BackupTarget,
+        manifest: &BackupManifest,
+        sequence: u64,
+        path: PathBuf,
+    ) -> Result<KvStore> {
+        let dirs = DataDirs::open(vec![path.clone()], None, PlacementPolicy::default())?;
+        if !dirs.file_numbers().is_empty() {
+            return Err(KVStoreError::CommonStringError(format!(
+                "can not restore into {:?} because it already contains data files",
+                path
+            )));
+        }
+        // the data files only appear once they are complete, so a failed restore can be retried
+        let mut tmp_files = HashMap::new();
+        for backup in manifest.chain(target)? {
+            for file in &backup.files {
+                // data files which were deleted by a later compaction are skipped
+                if !manifest.file_numbers.contains(&file.file_number) {
+                    continue;
+                }
+                let tmp_file = match tmp_files.entry(file.file_number) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let tmp_path = dirs.allocate(file.file_number).with_extension("partial");
+                        entry.insert(File::create(tmp_path)?)
+                    }
+                };
+                if tmp_file.seek(SeekFrom::End(0))? != file.offset {
+                    return Err(KVStoreError::CommonStringError(format!(
+                        "object {} does not continue data file {} of the earlier backups",
+                        file.object, file.file_number
+                    )));
+                }
+                let copied = io::copy(&mut target.get(&file.object)?, tmp_file)?;
+                if copied != file.length {
+                    return Err(KVStoreError::CommonStringError(format!(
+                        "object {} has {} bytes instead of {}",
+                        file.object, copied, file.length
+                    )));
+                }
+            }
+        }
+        let mut file_numbers: Vec<u64> = tmp_files.keys().copied().collect();
+        file_numbers.sort_unstable();
+        // writes after `sequence` are cut off the end of the log
+        let mut writes = manifest.compacted_sequence;
+        for &file_number in &file_numbers {
+            let tmp_file = tmp_files.remove(&file_number).unwrap();
+            let tmp_path = dirs.file_path(file_number).with_extension("partial");
+            if file_number >= manifest.first_log_file {
+                let (count, end) = count_commands(&tmp_path, sequence - writes)?;
+                writes += count;
+                tmp_file.set_len(end)?;
+            }
+            tmp_file.sync_all()?;
+            fs::rename(&tmp_path, dirs.file_path(file_number))?;
+        }
+        Manifest::untracked(
+            &file_numbers,
+            manifest.first_log_file,
+            manifest.compacted_sequence,
+        )
+        .store(&path)?;
+        info!(
+            "Restored backup {} as of sequence {} into {:?}",
+            manifest.id, sequence, path
+        );
+        KvStore::open(path)
+    }
+
+    /// Get the values of many keys at once, in the order of `keys` and None for missing keys.
+    /// With the `io-uring` feature on Linux the reads are submitted together, otherwise they are
+    /// sorted by their position in the data files.
+    pub fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        let mut found = Vec::with_capacity(keys.len());
+        let mut positions = Vec::with_capacity(keys.len());
+        for (index, key) in keys.iter().enumerate() {
+            if let Some(entry) = self.index.get(key) {
+                entry.value().touch(&self.access_clock);
+                found.push(index);
+                positions.push(entry.value().location());
+            }
+        }
+
+        let records = match self.readers.read_records(&positions) {
+            Ok(records) => records,
+            // a concurrent compaction may have deleted the data files, so read again one by one
+            Err(err) => {
+                debug!("Read the keys one by one after a failed batch: {}", err);
+                return keys.into_iter().map(|key| self.get(key)).collect();
+            }
+        };
+        let mut values = vec![None; keys.len()];
+        for ((index, position), data) in found.into_iter().zip(&positions).zip(records) {
+            values[index] = match self.readers.decode_record(&keys[index], position, &data) {
+                Ok(value) => Some(value),
+                Err(err) if !self.readers.paranoid_checks && err.is_corruption() => {
+                    self.repair(&keys[index], position, err)?
+                }
+                Err(err) => return Err(err),
+            };
+        }
+        Ok(values)
+    }
+
+    /// Append `payload` to the event stream at `stream_key` and return its sequence, starting
+    /// at 1. Each event is an ordinary key of its own, `<stream_key>\u{0}<sequence>` with the
+    /// sequence padded to 20 digits, written to the log like any other value, so appending
+    /// never rewrites the earlier events. A sequence is only handed out again if the store is
+    /// opened again after every later event of the stream was removed.
+    pub fn append_event(&self, stream_key: String, payload: String) -> Result<u64> {
+        check_stream_key(&stream_key)?;
+        self.stall_writes();
+        let mut writer = self.writer.lock().unwrap();
+        let sequence = self.streams.get(&stream_key).map_or(0, |range| range.last) + 1;
+        writer.set(event_key(&stream_key, sequence), payload)?;
+        Ok(sequence)
+    }
+
+    /// Read up to `limit` events of the stream at `stream_key` from the sequence `from_seq` on,
+    /// in the order they were appended, as pairs of their sequence and payload.
+    /// The events are looked up by their keys from `from_seq`, or the first live event of the
+    /// stream if that is later, up to the last sequence of the stream, so only removed events
+    /// between live ones are skipped one by one.
+    pub fn read_events(
+        &self,
+        stream_key: &str,
+        from_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<(u64, String)>> {
+        check_stream_key(stream_key)?;
+        let range = match self.streams.get(stream_key) {
+            Some(range) => *range,
+            None => return Ok(Vec::new()),
+        };
+        let mut sequences = Vec::new();
+        let mut keys = Vec::new();
+        for sequence in cmp::max(from_seq, range.first)..=range.last {
+            if keys.len() == limit {
+                break;
+            }
+            let key = event_key(stream_key, sequence);
+            if self.index.contains_key(&key) {
+                sequences.push(sequence);
+                keys.push(key);
+            }
+        }
+        // events may be removed concurrently
+        let payloads = self.get_many(keys)?;
+        Ok(sequences
+            .into_iter()
+            .zip(payloads)
+            .filter_map(|(sequence, payload)| Some((sequence, payload?)))
+            .collect())
+    }
+
+    /// Iterate a consistent snapshot of all key/value pairs, ordered by key.
+    /// Writes are only held back while the snapshot is taken; the data files it reads are
+    /// pinned so that compaction does not delete them until the iterator is dropped.
+    pub fn iter(&self) -> Result<KvStoreIter> {
+        self.scan(..)
+    }
+
+    /// Like `iter`, but only over the keys in `range`, e.g. `"log:2024".."log:2025"`.
+    /// The iterator is double-ended, so `scan(range)?.rev()` starts at the last key.
+    pub fn scan<'a, R: RangeBounds<&'a str>>(&self, range: R) -> Result<KvStoreIter> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.current_writer.flush()?;
+        let mut entries: Vec<(String, CommandPosition)> = self
+            .index
+            .iter()
+            .filter(|entry| range.contains(&entry.key().as_str()))
+            .map(|entry| (entry.key().clone(), entry.value().location()))
+            .collect();
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+        // the records patch records apply to may be in any data file
+        let mut file_numbers: Vec<u64> = if entries.iter().any(|(_, position)| position.patches > 0)
+        {
+            writer.dirs.file_numbers()
+        } else {
+            entries
+                .iter()
+                .map(|(_, position)| position.file_number)
+                .collect()
+        };
+        file_numbers.sort_unstable();
+        file_numbers.dedup();
+        let mut files = HashMap::with_capacity(file_numbers.len());
+        for &file_number in &file_numbers {
+            let file = File::open(writer.dirs.file_path(file_number))?;
+            files.insert(file_number, BufReader::new(file));
+        }
+        writer.dirs.pin(&file_numbers);
+        Ok(KvStoreIter {
+            dirs: Arc::clone(&writer.dirs),
+            file_numbers,
+            files,
+            entries: entries.into_iter(),
+            io: Arc::clone(&self.readers.io),
+        })
+    }
+
+    /// Look up the record of `key` again in the data files after reading it from `stale` failed
+    /// with `err`, repair its index entry and read the value it points at now.
+    /// Return `err` if the data files do not tell where the value of the key is instead.
+    fn repair(
+        &self,
+        key: &str,
+        stale: &CommandPosition,
+        err: KVStoreError,
+    ) -> Result<Option<String>> {
+        let mut writer = self.writer.lock().unwrap();
+        let current = match self.index.get(key) {
+            Some(entry) => entry.value().location(),
+            None => return Ok(None),
+        };
+        // the key was written since it was read
+        if !current.is_at(stale) {
+            drop(writer);
+            return self.readers.read_command(key, &current);
+        }
+        writer.corrupted_reads += 1;
+        drop(writer);
+        repair_key(&self.writer, key, stale, err)?;
+        match self.index.get(key) {
+            Some(entry) => self.readers.read_command(key, &entry.value().location()),
+            None => Ok(None),
+        }
+    }
+
+    /// Verify all sealed data files once, like the background scrubber of
+    /// `KvStoreBuilder::scrub_interval` does one after another.
+    pub fn scrub(&self) -> Result<()> {
+        let sealed = self.writer.lock().unwrap().sealed_files();
+        for file_number in sealed {
+            scrub_file(&self.writer, file_number)?;
+        }
+        Ok(())
+    }
+
+    /// The UUID the store was given when it was first opened, which stays with its data files
+    /// for good, so replication can tell the store apart from others. A store restored from a
+    /// backup gets a new one.
+    pub fn instance_id(&self) -> String {
+        let writer = self.writer.lock().unwrap();
+        writer.manifest.instance_id.clone().unwrap_or_default()
+    }
+
+    /// The instance id of the store whose writes are replicated to this one, pinned by the
+    /// first replication handshake, see `accept_replication_source`.
+    pub fn replication_source(&self) -> Option<String> {
+        let writer = self.writer.lock().unwrap();
+        writer.manifest.replication_source.clone()
+    }
+
+    /// Accept the writes replicated from the store `source` from now on. The first store
+    /// accepted is recorded in the manifest, so it is still the only one accepted after a
+    /// restart; the handshakes of other stores fail with `KVStoreError::UnrelatedStore`.
+    pub fn accept_replication_source(&self, source: &str) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        match &writer.manifest.replication_source {
+            Some(expected) if expected != source => Err(KVStoreError::UnrelatedStore(
+                expected.clone(),
+                source.to_owned(),
+            )),
+            Some(_) => Ok(()),
+            None => {
+                writer.manifest.replication_source = Some(source.to_owned());
+                writer.manifest.last_sequence = writer.sequence;
+                writer.store_manifest()
+            }
+        }
+    }
+
+    /// The logical databases recorded by `create_namespace`, in the order they were created.
+    pub fn namespaces(&self) -> Vec<u32> {
+        self.writer.lock().unwrap().manifest.namespaces()
+    }
+
+    /// Estimate the live keys and bytes within `range` from the index alone, without reading
+    /// any value, e.g. to plan where to split the keys. Concurrent writes may or may not be counted.
+    pub fn approximate_size<'a, R: RangeBounds<&'a str>>(&self, range: R) -> RangeSize {
+        let mut size = RangeSize::default();
+        for entry in self.index.iter() {
+            if range.contains(&entry.key().as_str()) {
+                size.live_keys += 1;
+                size.live_bytes += entry.value().length;
+            }
+        }
+        size
+    }
+
+    /// The pair of the smallest key, or None if the store is empty.
+    /// The index is not ordered, so this visits every key.
+    pub fn first_key_value(&self) -> Result<Option<(String, String)>> {
+        self.edge_key_value(cmp::Ordering::Less)
+    }
+
+    /// The pair of the largest key, e.g. the latest entry of timestamp-prefixed keys,
+    /// or None if the store is empty. The index is not ordered, so this visits every key.
+    pub fn last_key_value(&self) -> Result<Option<(String, String)>> {
+        self.edge_key_value(cmp::Ordering::Greater)
+    }
+
+    /// the pair of the key which compares as `edge` to all others
+    fn edge_key_value(&self, edge: cmp::Ordering) -> Result<Option<(String, String)>> {
+        loop {
+            let mut found: Option<String> = None;
+            for entry in self.index.iter() {
+                if found
+                    .as_ref()
+                    .is_none_or(|found| entry.key().cmp(found) == edge)
+                {
+                    found = Some(entry.key().clone());
+                }
+            }
+            let Some(key) = found else {
+                return Ok(None);
+            };
+            // the key may be removed concurrently, then look again
+            if let Some(value) = self.get(key.clone())? {
+                return Ok(Some((key, value)));
+            }
+        }
+    }
+
+    /// Bring a removed key back from the trash with the value it had when it was removed.
+    /// Return `KVStoreError::KeyNotFound` if the key is not in the trash or its retention expired.
+    pub fn restore_key(&self, key: String) -> Result<()> {
+        self.writer.lock().unwrap().restore_key(key)
+    }
+
+    fn open_with(path: PathBuf, options: KvStoreBuilder) -> Result<KvStore> {
+        // the background compaction would check for stale commands without pause
+        if let CompactionPolicy::Interval(wait) | CompactionPolicy::Idle(wait) =
+            options.compaction_policy
+        {
+            if wait.is_zero() {
+                return Err(KVStoreError::InvalidConfig(format!(
+                    "compaction policy {:?} waits zero",
+                    options.compaction_policy
+                )));
+            }
+        }
+        let mut dir_paths = vec![path];
+        dir_paths.extend(options.data_dirs);
+        let (cold_dir, cold_after) = options.cold_tier.unzip();
+        let dirs = Arc::new(DataDirs::open(
+            dir_paths,
+            cold_dir,
+            options.placement_policy,
+        )?);
+
+        let mut index = Arc::new(DashMap::new());
+        let mut readers = HashMap::new();
+        let mut trash = HashMap::new();
+        let access_clock = Arc::new(AccessClock::new());
+        let quarantine = Arc::new(DashMap::new());
+        let streams = Arc::new(DashMap::new());
+
+        let (mut manifest, tracked) = match Manifest::load(dirs.primary())? {
+            Some(manifest) => (manifest, true),
+            None => {
+                // all data files of a store without a manifest are live
+                let point = CompactionPoint::load(dirs.primary())?;
+                let manifest =
+                    Manifest::untracked(&dirs.file_numbers(), point.first_log_file, point.sequence);
+                (manifest, false)
+            }
+        };
+        // data files missing from the manifest are leftovers of an interrupted compaction
+        for file_number in dirs.file_numbers() {
+            if !manifest.is_live(file_number) {
+                let file_path = dirs.file_path(file_number);
+                info!("Delete the leftover data file {:?}", file_path);
+                remove_file(&file_path)?;
+                dirs.release(file_number);
+            }
+        }
+        // the records last appended to the log files may be torn unless the store was closed
+        let clean = CleanShutdown::take(dirs.primary())?.is_some_and(|clean| clean.matches(&dirs));
+        if clean {
+            info!("Open the store closed cleanly");
+        } else {
+            info!("Open the store, which was not closed cleanly");
+            // only the log file written last can end in a torn record, the earlier ones
+            // were complete when the log went on in the next one
+            let file_numbers = dirs.file_numbers();
+            let current = manifest
+                .log_files
+                .iter()
+                .filter(|file_number| file_numbers.contains(file_number))
+                .max();
+            if let Some(file_number) = current {
+                truncate_torn_tail(&dirs.file_path(*file_number))?;
+            }
+        }
+
+        let (mut current_file_number, useless_size, disk_usage, log_writes, format_version, sizes) =
+            Self::recover(
+                &dirs,
+                &mut readers,
+                &mut index,
+                &mut trash,
+                &access_clock,
+                &manifest.log_files,
+                options.on_progress.as_ref(),
+            )?;
+
+        // new writes go to a log file the manifest knows about
+        if manifest.compacted_files.contains(&current_file_number) {
+            current_file_number += 1;
+        }
+        let sequence = manifest.compacted_sequence + log_writes;
+        if !tracked
+            || !manifest.log_files.contains(&current_file_number)
+            || manifest.format_version != format_version
+            || manifest.instance_id.is_none()
+        {
+            if !manifest.log_files.contains(&current_file_number) {
+                manifest.log_files.push(current_file_number);
+            }
+            manifest.instance_id.get_or_insert_with(new_instance_id);
+            manifest.format_version = format_version;
+            manifest.last_sequence = sequence;
+            manifest.store(dirs.primary())?;
+            CompactionPoint::remove(dirs.primary())?;
+        }
+
+        let created = !dirs.file_numbers().contains(&current_file_number);
+        let current_file_path = dirs.allocate(current_file_number);
+        let compaction_pool = match options.compaction_threads {
+            0 | 1 => None,
+            threads => Some(
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(threads)
+                    .thread_name(|index| format!("kvs-compaction-{}", index))
+                    .build()?,
+            ),
+        };
+
+        let sealed_log_files: Vec<u64> = manifest
+            .log_files
+            .iter()
+            .copied()
+            .filter(|file_number| *file_number != current_file_number)
+            .collect();
+        let archiver = options
+            .archive
+            .as_ref()
+            .map(|hook| Archiver::start(hook, &dirs, &sealed_log_files))
+            .transpose()?;
+
+        let current_writer =
+            BufWriterWithPosition::new(SegmentFile::open(&current_file_path, options.direct_io)?)?;
+        if let Some(size) = options.preallocate {
+            current_writer.writer.get_ref().preallocate(size);
+        }
+        // the header of a new log file
+        let disk_usage = if created {
+            disk_usage + current_writer.get_position()
+        } else {
+            disk_usage
+        };
+
+        if current_file_number == 0 {
+            readers.insert(
+                current_file_number,
+                BufReader::new(File::open(&current_file_path)?),
+            );
+        }
+        for entry in index.iter() {
+            if let Some((stream_key, sequence)) = stream_event(entry.key()) {
+                note_event(&streams, stream_key.to_owned(), sequence);
+            }
+        }
+
+        let readers = Reader {
+            dirs: Arc::clone(&dirs),
+            compaction_number: Arc::new(AtomicU64::new(0)),
+            readers: RefCell::new(readers),
+            paranoid_checks: options.paranoid_checks,
+            io: Arc::new(IoCounters::default()),
+        };
+
+        let writer = Arc::new(Mutex::new(Writer {
+            current_writer,
+            current_file_number,
+            useless_size,
+            disk_usage,
+            max_disk_size: options.max_disk_size,
+            quota_policy: options.quota_policy,
+            evicted_keys: 0,
+            expired_keys: 0,
+            sequence,
+            manifest,
+            trash,
+            trash_retention: options.trash_retention,
+            compaction_policy: options.compaction_policy,
+            compaction_pool,
+            last_write: Instant::now(),
+            write_stall: options.write_stall,
+            stalled_writes: 0,
+            corrupted_reads: 0,
+            scrub_cursor: 0,
+            scrubbed_files: 0,
+            scrubbed_records: 0,
+            scrub_errors: 0,
+            quarantine: Arc::clone(&quarantine),
+            direct_io: options.direct_io,
+            preallocate: options.preallocate,
+            cold_after,
+            usage_prefix: options.usage_prefix,
+            compaction_filter: options.compaction_filter,
+            retention: options.retention,
+            streams: Arc::clone(&streams),
+            sizes,
+            archiver,
+            dirs,
+            index: Arc::clone(&index),
+            reader: readers.clone(),
+            access_clock: Arc::clone(&access_clock),
+        }));
+
+        match options.compaction_policy {
+            CompactionPolicy::Interval(_) | CompactionPolicy::Idle(_) => {
+                let writer = Arc::downgrade(&writer);
+                thread::spawn(move || compaction_scheduler(writer, options.compaction_policy));
+            }
+            CompactionPolicy::UselessSize(_) | CompactionPolicy::GarbageRatio(_) => {}
+        }
+        if let Some(interval) = options.scrub_interval {
+            let writer = Arc::downgrade(&writer);
+            thread::spawn(move || scrubber(writer, interval));
+        }
+
+        Ok(KvStore {
+            readers,
+            writer,
+            index,
+            access_clock,
+            quarantine,
+            streams,
+        })
+    }
+
+    fn recover(
+        dirs: &DataDirs,
+        current_readers: &mut HashMap<u64, BufReader<File>>,
+        index: &mut Arc<DashMap<String, CommandPosition>>,
+        trash: &mut HashMap<String, TrashedKey>,
+        access_clock: &AccessClock,
+        log_files: &[u64],
+        on_progress: Option<&ProgressCallback>,
+    ) -> Result<(u64, u64, u64, u64, u32, Sizes)> {
+        let versions = dirs.file_numbers();
+        let started = Instant::now();
+        let mut last_log = started;
+        info!("Recover {} data files", versions.len());
+
+        let mut useless_size = 0;
+        let mut disk_usage = 0;
+        let mut log_writes = 0;
+        let mut sizes = Sizes::default();
+        let mut format_version = FORMAT_VERSION;
+        for (done, version) in (1..).zip(&versions) {
+            let file_path = dirs.file_path(*version);
+            let mut file = File::open(&file_path)
+                .with_context(|| ErrorContext::new("open").file(&file_path))?;
+            let metadata = file.metadata()?;
+            disk_usage += metadata.len();
+            // the records only know the data file they were written to, which was last written
+            // to when it was last modified
+            let written_at = metadata
+                .modified()
+                .map_or_else(|_| now_millis(), millis_since_epoch);
+            let (file_format, start) = read_header(&mut file)?;
+            format_version = format_version.min(file_format);
+            let cold = dirs.tier(*version) == Tier::Cold;
+            let access_stamp = || {
+                if cold {
+                    UNREAD_COLD
+                } else {
+                    access_clock.tick()
+                }
+            };
+            let reader = BufReader::new(file);
+            let mut iter = Deserializer::from_reader(reader).into_iter::<Command>();
+            let mut before_offset = start;
+            while let Some(command) = iter.next() {
+                let after_offset = start + iter.byte_offset() as u64;
+                let command = command.with_context(|| {
+                    ErrorContext::new("recover")
+                        .file(&file_path)
+                        .offset(before_offset)
+                })?;
+                match command {
+                    Command::SET(key, value) => {
+                        sizes.record(&key, value.len() as u64);
+                        useless_size += trash
+                            .remove(&key)
+                            .map(|trashed| trashed.position.length)
+                            .unwrap_or(0);
+                        useless_size += index
+                            .insert(
+                                key,
+                                CommandPosition::new(
+                                    *version,
+                                    before_offset,
+                                    after_offset - before_offset,
+                                    access_stamp(),
+                                    written_at,
+                                ),
+                            )
+                            .map(|cp| cp.length)
+                            .unwrap_or(0);
+                    }
+                    Command::PATCH(key, ..) => {
+                        let patches = index.get(&key).map_or(0, |cp| cp.patches) + 1;
+                        useless_size += index
+                            .insert(
+                                key,
+                                CommandPosition::new(
+                                    *version,
+                                    before_offset,
+                                    after_offset - before_offset,
+                                    access_stamp(),
+                                    written_at,
+                                )
+                                .with_patches(patches),
+                            )
+                            .map(|cp| cp.length)
+                            .unwrap_or(0);
+                    }
+                    Command::RM(key) => {
+                        useless_size += index.remove(&key).map(|(_, cp)| cp.length).unwrap_or(0);
+                        useless_size += after_offset - before_offset;
+                    }
+                    Command::TRASH(key, removed_at) => {
+                        if let Some((key, position)) = index.remove(&key) {
+                            let trashed = TrashedKey {
+                                position,
+                                removed_at,
+                            };
+                            useless_size += trash
+                                .insert(key, trashed)
+                                .map(|trashed| trashed.position.length)
+                                .unwrap_or(0);
+                        }
+                        // compaction writes the records of the trash again
+                        if log_files.contains(version) {
+                            useless_size += after_offset - before_offset;
+                        }
+                    }
+                };
+                before_offset = after_offset;
+                if log_files.contains(version) {
+                    log_writes += 1;
+                }
+            }
+            current_readers.insert(*version, BufReader::new(File::open(&file_path)?));
+
+            if let Some(ProgressCallback(callback)) = on_progress {
+                callback(done, versions.len(), index.len() as u64);
+            }
+            if last_log.elapsed() >= PROGRESS_LOG_INTERVAL {
+                info!(
+                    "Recovered {}/{} data files, {} keys loaded",
+                    done,
+                    versions.len(),
+                    index.len()
+                );
+                last_log = Instant::now();
+            }
+        }
+        info!(
+            "Recovered {} keys from {} data files in {:?}",
+            index.len(),
+            versions.len(),
+            started.elapsed()
+        );
+
+        Ok((
+            *versions.last().unwrap_or(&0),
+            useless_size,
+            disk_usage,
+            log_writes,
+            format_version,
+            sizes,
+        ))
+    }
+}
+
+impl KvStore {
+    /// Wait out the write stall before a write, if compaction is behind. Return the delay.
+    fn stall_writes(&self) -> Option<Duration> {
+        // the writer is not locked while waiting so that compaction can run
+        let delay = self.writer.lock().unwrap().stall_delay();
+        if delay.is_zero() {
+            return None;
+        }
+        self.writer.lock().unwrap().stalled_writes += 1;
+        thread::sleep(delay);
+        Some(delay)
+    }
+}
+
+impl KvsWriter for KvStore {
+    /// Set the value of a string key to a string. Return an error if the value is not written successfully.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let mut span = trace::span("kvs.engine.set");
+        if let Some(delay) = self.stall_writes() {
+            span.attribute("kvs.stall", format!("{:?}", delay));
+        }
+        self.writer.lock().unwrap().set(key, value)
+    }
+
+    /// Close the store cleanly, see `KvStore::close`.
+    fn close(self) -> Result<()> {
+        KvStore::close(self)
+    }
+
+    /// Set the value of a key to a JSON document, appending only the change of the field at
+    /// `pointer` to the log; compaction folds the changes into the whole document again.
+    fn set_json_field(
+        &self,
+        key: String,
+        document: String,
+        pointer: &str,
+        field: &str,
+    ) -> Result<()> {
+        let _span = trace::span("kvs.engine.set_json_field");
+        self.stall_writes();
+        self.writer
+            .lock()
+            .unwrap()
+            .set_json_field(key, document, pointer, field)
+    }
+
+    /// Remove a given key. Return an error if the key does not exist or is not removed successfully.
+    fn remove(&self, key: String) -> Result<()> {
+        let _span = trace::span("kvs.engine.remove");
+        self.writer.lock().unwrap().remove(key)
+    }
+
+    /// Record the creation of a logical database in the manifest, once.
+    fn create_namespace(&self, database: u32) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        if writer.manifest.namespaces().contains(&database) {
+            return Ok(());
+        }
+        let sequence = writer.sequence;
+        writer
+            .manifest
+            .entries
+            .push(ManifestEntry::CreateNamespace { database, sequence });
+        writer.manifest.last_sequence = sequence;
+        writer.store_manifest()
+    }
+
+    fn accept_replication_source(&self, source: &str) -> Result<()> {
+        KvStore::accept_replication_source(self, source)
+    }
+}
+
+impl KvsReader for KvStore {
+    /// Get the string value of a string key. If the key does not exist, return None. Return an error if the value is not read successfully.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let _span = trace::span("kvs.engine.get");
+        let position = match self.index.get(&key) {
+            Some(entry) => {
+                entry.value().touch(&self.access_clock);
+                entry.value().location()
+            }
+            None => return Ok(None),
+        };
+        if let Some(reason) = self.quarantine.get(&key) {
+            return Err(KVStoreError::CorruptedRecord(
+                position.file_number,
+                position.offset,
+                reason.clone(),
+            ));
+        }
+        match self.readers.read_command(&key, &position) {
+            Err(err) if !self.readers.paranoid_checks && err.is_corruption() => {
+                self.repair(&key, &position, err)
+            }
+            result => result,
+        }
+    }
+
+    /// Return up to `limit` keys ordered after `after`, picked from the index without
+    /// reading any value.
+    fn scan_keys(&self, after: Option<&String>, limit: usize) -> Result<Vec<String>> {
+        // the `limit` least keys after `after` seen so far, the greatest on top
+        let mut keys: BinaryHeap<String> = BinaryHeap::with_capacity(limit);
+        for entry in self.index.iter() {
+            let key = entry.key();
+            if after.is_some_and(|after| key <= after) {
+                continue;
+            }
+            if keys.len() < limit {
+                keys.push(key.clone());
+            } else if keys.peek().is_some_and(|greatest| key < greatest) {
+                keys.pop();
+                keys.push(key.clone());
+            }
+        }
+        Ok(keys.into_sorted_vec())
+    }
+
+    /// Return the gauges and counters of `stats`.
+    fn metrics(&self) -> Vec<(String, Metric)> {
+        self.stats().metrics()
+    }
+
+    fn instance_id(&self) -> Option<String> {
+        Some(KvStore::instance_id(self))
+    }
+
+    fn replication_source(&self) -> Option<String> {
+        KvStore::replication_source(self)
+    }
+
+    /// Return all key/value pairs whose key matches a glob pattern, ordered by key.
+    fn scan_match(&self, pattern: String) -> Result<Vec<(String, String)>> {
+        self.scan_match_cancellable(pattern, &CancellationToken::new())
+    }
+
+    /// Return all key/value pairs whose key matches a glob pattern, ordered by key,
+    /// checking `cancel` every `SCAN_CHUNK` keys.
+    fn scan_match_cancellable(
+        &self,
+        pattern: String,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<(String, String)>> {
+        let _span = trace::span("kvs.engine.scan");
+        let pattern = GlobPattern::new(&pattern);
+        let prefix = pattern.prefix();
+        let mut keys = Vec::new();
+        for (visited, entry) in self.index.iter().enumerate() {
+            if visited % SCAN_CHUNK == 0 {
+                cancel.check()?;
+            }
+            let key = entry.key();
+            if key.starts_with(&prefix) && pattern.matches(key) {
+                keys.push(key.clone());
+            }
+        }
+        keys.sort_unstable();
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for chunk in keys.chunks(SCAN_CHUNK) {
+            cancel.check()?;
+            let values = self.get_many(chunk.to_vec())?;
+            // keys may be removed concurrently
+            pairs.extend(
+                chunk
+                    .iter()
+                    .cloned()
+                    .zip(values)
+                    .filter_map(|(key, value)| Some((key, value?))),
+            );
+        }
+        Ok(pairs)
+    }
+}
+
+/** A KvStore frozen as it was closed, e.g. a copy of its directory or a restored backup, opened
+read-only to serve analytics or to check a restore without touching the store serving writes.
+It reads like a KvStore but has no writer: it neither creates a log file nor compacts, deletes
+or repairs data files, and it has no writes at all, so it is served by `KvServer::read_only`,
+which answers every write with `KVStoreError::ReadOnlySnapshot`.
+# Example
+```
+use kvs::{KvStore, KvsReader, KvsWriter, Result, SnapshotEngine};
+# fn try_main() -> Result<()> {
+# let dir = tempfile::TempDir::new()?;
+let store = KvStore::open(dir.path())?;
+store.set("key".to_owned(), "value".to_owned())?;
+store.close()?;
+
+let snapshot = SnapshotEngine::open(dir.path())?;
+assert_eq!(snapshot.get("key".to_owned())?, Some("value".to_owned()));
+# Ok(())
+# }
+# try_main().unwrap();
+```
+ */
+#[derive(Clone)]
+pub struct SnapshotEngine {
+    readers: Reader,
+    /// the live keys, ordered so scans only visit the keys starting with their prefix
+    index: Arc<BTreeMap<String, CommandPosition>>,
+    instance_id: Option<String>,
+}
+
+impl SnapshotEngine {
+    /// Open the KvStore at a given path read-only with default options.
+    pub fn open(path: impl Into<PathBuf>) -> Result<SnapshotEngine> {
+        KvStore::builder().open_snapshot(path)
+    }
+
+    fn open_with(path: PathBuf, options: KvStoreBuilder) -> Result<SnapshotEngine> {
+        let mut dir_paths = vec![path];
+        dir_paths.extend(options.data_dirs);
+        let (cold_dir, _) = options.cold_tier.unzip();
+        // a missing directory is no store, rather than an empty one to create
+        for dir in dir_paths.iter().chain(&cold_dir) {
+            if !dir.is_dir() {
+                return Err(KVStoreError::CommonStringError(format!(
+                    "{:?} holds no store",
+                    dir
+                )));
+            }
+        }
+        let dirs = Arc::new(DataDirs::open(
+            dir_paths,
+            cold_dir,
+            options.placement_policy,
+        )?);
+        let manifest = match Manifest::load(dirs.primary())? {
+            Some(manifest) => manifest,
+            None => {
+                let point = CompactionPoint::load(dirs.primary())?;
+                Manifest::untracked(&dirs.file_numbers(), point.first_log_file, point.sequence)
+            }
+        };
+        // the leftovers of an interrupted compaction are skipped rather than deleted
+        for file_number in dirs.file_numbers() {
+            if !manifest.is_live(file_number) {
+                dirs.release(file_number);
+            }
+        }
+
+        let mut index = Arc::new(DashMap::new());
+        let mut readers = HashMap::new();
+        KvStore::recover(
+            &dirs,
+            &mut readers,
+            &mut index,
+            &mut HashMap::new(),
+            &AccessClock::new(),
+            &manifest.log_files,
+            options.on_progress.as_ref(),
+        )?;
+        let index = index
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().location()))
+            .collect();
+        Ok(SnapshotEngine {
+            readers: Reader {
+                dirs,
+                compaction_number: Arc::new(AtomicU64::new(0)),
+                readers: RefCell::new(readers),
+                paranoid_checks: options.paranoid_checks,
+                io: Arc::new(IoCounters::default()),
+            },
+            index: Arc::new(index),
+            instance_id: manifest.instance_id,
+        })
+    }
+}
+
+impl KvsReader for SnapshotEngine {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let _span = trace::span("kvs.engine.get");
+        match self.index.get(&key) {
+            Some(position) => self.readers.read_command(&key, position),
+            None => Ok(None),
+        }
+    }
+
+    fn scan_match(&self, pattern: String) -> Result<Vec<(String, String)>> {
+        self.scan_match_cancellable(pattern, &CancellationToken::new())
+    }
+
+    /// Return all key/value pairs whose key matches a glob pattern, ordered by key,
+    /// checking `cancel` every `SCAN_CHUNK` keys.
+    fn scan_match_cancellable(
+        &self,
+        pattern: String,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<(String, String)>> {
+        let _span = trace::span("kvs.engine.scan");
+        let pattern = GlobPattern::new(&pattern);
+        let prefix = pattern.prefix();
+        let matches: Vec<(&String, &CommandPosition)> = self
+            .index
+            .range(prefix.clone()..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter(|(key, _)| pattern.matches(key))
+            .collect();
+
+        let mut pairs = Vec::with_capacity(matches.len());
+        for chunk in matches.chunks(SCAN_CHUNK) {
+            cancel.check()?;
+            let positions: Vec<CommandPosition> = chunk
+                .iter()
+                .map(|(_, position)| position.location())
+                .collect();
+            let records = self.readers.read_records(&positions)?;
+            for (((key, _), position), data) in chunk.iter().zip(&positions).zip(records) {
+                let value = self.readers.decode_record(key, position, &data)?;
+                pairs.push(((*key).clone(), value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn scan_keys(&self, after: Option<&String>, limit: usize) -> Result<Vec<String>> {
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        Ok(self
+            .index
+            .range::<String, _>((start, Bound::Unbounded))
+            .take(limit)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+
+    fn metrics(&self) -> Vec<(String, Metric)> {
+        vec![
+            (
+                "live_keys".to_owned(),
+                Metric::Gauge(self.index.len() as f64),
+            ),
+            (
+                "bytes_read".to_owned(),
+                Metric::Counter(self.readers.io.bytes_read.load(Ordering::Relaxed)),
+            ),
+        ]
+    }
+
+    fn instance_id(&self) -> Option<String> {
+        self.instance_id.clone()
+    }
+}
+
+/// An iterator over a snapshot of the key/value pairs of a KvStore, see `KvStore::iter`
+pub struct KvStoreIter {
+    dirs: Arc<DataDirs>,
+    /// the data files pinned for the snapshot
+    file_numbers: Vec<u64>,
+    files: HashMap<u64, BufReader<File>>,
+    entries: std::vec::IntoIter<(String, CommandPosition)>,
+    io: Arc<IoCounters>,
+}
+
+impl KvStoreIter {
+    fn read(&mut self, key: &str, position: &CommandPosition) -> Result<String> {
+        let files = &mut self.files;
+        let io = &self.io;
+        let mut read = |position: &CommandPosition| -> Result<Vec<u8>> {
+            let reader = files
+                .get_mut(&position.file_number)
+                .expect("the data files of the snapshot are open");
+            reader.seek(SeekFrom::Start(position.offset))?;
+            let mut data = Vec::with_capacity(position.length as usize);
+            reader.take(position.length).read_to_end(&mut data)?;
+            io.read(position.length);
+            Ok(data)
+        };
+        let data = read(position)?;
+        let record = check_record(key, position, &data)?;
+        resolve(key, record, read)
+    }
+}
+
+impl Iterator for KvStoreIter {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, position) = self.entries.next()?;
+        Some(self.read(&key, &position).map(|value| (key, value)))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+}
+
+impl DoubleEndedIterator for KvStoreIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let (key, position) = self.entries.next_back()?;
+        Some(self.read(&key, &position).map(|value| (key, value)))
+    }
+}
+
+impl Drop for KvStoreIter {
+    fn drop(&mut self) {
+        self.dirs.unpin(&self.file_numbers);
+    }
+}
+
+struct Reader {
+    dirs: Arc<DataDirs>,
+    compaction_number: Arc<AtomicU64>,
+    readers: RefCell<HashMap<u64, BufReader<File>>>,
+    paranoid_checks: bool,
+    /// the I/O of the store, shared by the readers of all threads and the writer
+    io: Arc<IoCounters>,
+}
+
+/// the I/O counters of `KvStoreStats`
+#[derive(Default)]
+struct IoCounters {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    records_appended: AtomicU64,
+    compaction_bytes_copied: AtomicU64,
+    fsyncs: AtomicU64,
+}
+
+impl IoCounters {
+    /// count a record of `length` bytes appended to the log
+    fn appended(&self, length: u64) {
+        self.bytes_written.fetch_add(length, Ordering::Relaxed);
+        self.records_appended.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn read(&self, length: u64) {
+        self.bytes_read.fetch_add(length, Ordering::Relaxed);
+    }
+
+    fn synced(&self, fsyncs: u64) {
+        self.fsyncs.fetch_add(fsyncs, Ordering::Relaxed);
+    }
+}
+
+impl Clone for Reader {
+    fn clone(&self) -> Self {
+        Reader {
+            dirs: Arc::clone(&self.dirs),
+            compaction_number: Arc::clone(&self.compaction_number),
+            readers: RefCell::new(HashMap::new()),
+            paranoid_checks: self.paranoid_checks,
+            io: Arc::clone(&self.io),
+        }
+    }
+}
+
+impl Reader {
+    fn try_to_remove_stale_readers(&self) {
+        let compaction_number = self.compaction_number.load(Ordering::SeqCst);
+        let mut readers = self.readers.borrow_mut();
+        while !readers.is_empty() {
+            let reader_number = *readers.keys().next().unwrap();
+            if compaction_number <= reader_number {
+                break;
+            }
+            readers.remove(&reader_number);
+        }
+    }
+
+    fn read_add<F, R>(&self, position: &CommandPosition, f: F) -> Result<R>
+    where
+        F: FnOnce(Take<&mut BufReader<File>>) -> Result<R>,
+    {
+        self.try_to_remove_stale_readers();
+
+        let mut readers = self.readers.borrow_mut();
+
+        if let Entry::Vacant(entry) = readers.entry(position.file_number) {
+            let new_reader = BufReader::new(File::open(self.dirs.file_path(position.file_number))?);
+            entry.insert(new_reader);
+        }
+
+        let source_reader = readers
+            .get_mut(&position.file_number)
+            .expect("Can not find key in files but it is in memory");
+        source_reader.seek(SeekFrom::Start(position.offset))?;
+        let data_reader = source_reader.take(position.length);
+        self.io.read(position.length);
+        f(data_reader)
+    }
+
+    fn read_command(&self, key: &str, position: &CommandPosition) -> Result<Option<String>> {
+        self.read_value(key, position).map(Some)
+    }
+
+    /// the value of `key` which the record at `position` sets or patches
+    fn read_value(&self, key: &str, position: &CommandPosition) -> Result<String> {
+        let record = if self.paranoid_checks {
+            let data = self.read_record(position)?;
+            check_record(key, position, &data)?
+        } else {
+            self.read_add(position, |data_reader| {
+                record_of(key, position, serde_json::from_reader(data_reader)?)
+            })
+            .with_context(|| self.read_context(position).key(key))?
+        };
+        self.resolve(key, record)
+    }
+
+    /// the value of `key` after applying a patch record to the records it patches
+    fn resolve(&self, key: &str, record: Record) -> Result<String> {
+        resolve(key, record, |base| self.read_record(base))
+    }
+
+    /// the context of an error reading the record at `position`
+    fn read_context(&self, position: &CommandPosition) -> ErrorContext {
+        ErrorContext::new("read")
+            .file(self.dirs.file_path(position.file_number))
+            .offset(position.offset)
+    }
+
+    /// read the raw bytes of the records at `positions`, batching the reads with io_uring if enabled
+    fn read_records(&self, positions: &[CommandPosition]) -> Result<Vec<Vec<u8>>> {
+        self.try_to_remove_stale_readers();
+        {
+            let mut readers = self.readers.borrow_mut();
+            // file number -> the span of the file the batch reads and how many bytes of it
+            let mut spans: BTreeMap<u64, (u64, u64, u64)> = BTreeMap::new();
+            for position in positions {
+                if let Entry::Vacant(entry) = readers.entry(position.file_number) {
+                    let file = File::open(self.dirs.file_path(position.file_number))?;
+                    entry.insert(BufReader::new(file));
+                }
+                let end = position.offset + position.length;
+                let span = spans
+                    .entry(position.file_number)
+                    .or_insert((position.offset, end, 0));
+                span.0 = span.0.min(position.offset);
+                span.1 = span.1.max(end);
+                span.2 += position.length;
+            }
+            // read ahead the spans the batch mostly reads, but not whole files for a few records
+            for (file_number, (start, end, read)) in spans {
+                if read * 4 >= end - start {
+                    readahead(readers[&file_number].get_ref(), start, end - start);
+                }
+            }
+
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            let reads: Vec<(&File, u64, u64)> = positions
+                .iter()
+                .map(|position| {
+                    let reader = &readers[&position.file_number];
+                    (reader.get_ref(), position.offset, position.length)
+                })
+                .collect();
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            match uring::read_batch(&reads) {
+                Ok(records) => {
+                    for record in &records {
+                        self.io.read(record.len() as u64);
+                    }
+                    return Ok(records);
+                }
+                Err(err) => debug!("Can not read with io_uring, read one by one: {}", err),
+            }
+        }
+
+        // read each data file from front to back
+        let mut order: Vec<usize> = (0..positions.len()).collect();
+        order
+            .sort_unstable_by_key(|&index| (positions[index].file_number, positions[index].offset));
+        let mut records = vec![Vec::new(); positions.len()];
+        for index in order {
+            records[index] = self.read_record(&positions[index])?;
+        }
+        Ok(records)
+    }
+
+    /// the value of a record read from `position` for `key`
+    fn decode_record(&self, key: &str, position: &CommandPosition, data: &[u8]) -> Result<String> {
+        let record = if self.paranoid_checks {
+            check_record(key, position, data)?
+        } else {
+            record_of(key, position, serde_json::from_slice(data)?)?
+        };
+        self.resolve(key, record)
+    }
+
+    /// read the raw bytes of the record at `position`
+    fn read_record(&self, position: &CommandPosition) -> Result<Vec<u8>> {
+        self.read_add(position, |mut data_reader| {
+            let mut data = Vec::with_capacity(position.length as usize);
+            data_reader.read_to_end(&mut data)?;
+            Ok(data)
+        })
+        .with_context(|| self.read_context(position))
+    }
+
+    fn copy_data_to_writer(
+        &self,
+        position: &CommandPosition,
+        writer: &mut BufWriterWithPosition<SegmentFile>,
+    ) -> Result<()> {
+        self.read_add(position, |mut data_reader| {
+            io::copy(&mut data_reader, writer)?;
+            Ok(())
+        })
+    }
+
+    fn remove_useless_reader(&mut self, file_number: u64) -> Result<()> {
+        let mut readers = self.readers.borrow_mut();
+        readers.retain(|number, _| *number >= file_number);
+
+        let delete_file_numbers: Vec<u64> = self
+            .dirs
+            .file_numbers()
+            .into_iter()
+            .filter(|number| *number < file_number)
+            .collect();
+
+        for number in delete_file_numbers {
+            self.dirs.delete(number);
+        }
+
+        Ok(())
+    }
+}
+
+/// the error of reading a record at `position` which sets `found` instead of `key`
+fn wrong_key(position: &CommandPosition, found: &str, key: &str) -> KVStoreError {
+    KVStoreError::CorruptedRecord(
+        position.file_number,
+        position.offset,
+        format!("the record sets {} instead of {}", found, key),
+    )
+}
+
+/// what a record setting or patching the value of a key holds
+enum Record {
+    /// the whole value
+    Value(String),
+    /// a JSON pointer, the JSON text of the field at it and where the patched document is
+    Patch(String, String, CommandPosition),
+}
+
+/// the record `command` read from `position` for `key`
+fn record_of(key: &str, position: &CommandPosition, command: Command) -> Result<Record> {
+    match command {
+        Command::SET(found, value) if found == key => Ok(Record::Value(value)),
+        Command::PATCH(found, pointer, field, (file_number, offset, length)) if found == key => {
+            let base = CommandPosition::new(file_number, offset, length, 0, 0);
+            Ok(Record::Patch(pointer, field, base))
+        }
+        Command::SET(found, _) | Command::PATCH(found, ..) => Err(wrong_key(position, &found, key)),
+        _ => Err(KVStoreError::UnknownCommandType),
+    }
+}
+
+/// The value of `key` which `record` stands for, reading the records under a patch record
+/// with `read` down to the whole document and applying the patches on top of it.
+fn resolve<F>(key: &str, mut record: Record, mut read: F) -> Result<String>
+where
+    F: FnMut(&CommandPosition) -> Result<Vec<u8>>,
+{
+    let mut patches = Vec::new();
+    let mut document = loop {
+        match record {
+            Record::Value(value) => break value,
+            Record::Patch(pointer, field, base) => {
+                let data = read(&base)?;
+                record = check_record(key, &base, &data)?;
+                patches.push((pointer, field));
+            }
+        }
+    };
+    for (pointer, field) in patches.into_iter().rev() {
+        document = json::set_field(&document, &pointer, &field)?;
+    }
+    Ok(document)
+}
+
+/// check that `data` read from `position` is exactly one record setting or patching `key`
+fn check_record(key: &str, position: &CommandPosition, data: &[u8]) -> Result<Record> {
+    let corrupted = |reason: String| {
+        KVStoreError::CorruptedRecord(position.file_number, position.offset, reason)
+    };
+    if data.len() as u64 != position.length {
+        return Err(corrupted(format!(
+            "read {} bytes instead of {}",
+            data.len(),
+            position.length
+        )));
+    }
+    match serde_json::from_slice(data) {
+        Ok(Command::RM(_)) | Ok(Command::TRASH(..)) => {
+            Err(corrupted(format!("the record does not set {}", key)))
+        }
+        Ok(command) => record_of(key, position, command),
+        Err(err) => Err(corrupted(err.to_string())),
+    }
+}
+
+struct Writer {
+    dirs: Arc<DataDirs>,
+    reader: Reader,
+    current_writer: BufWriterWithPosition<SegmentFile>,
+    current_file_number: u64,
+    useless_size: u64,
+    disk_usage: u64,
+    max_disk_size: Option<u64>,
+    quota_policy: QuotaPolicy,
+    evicted_keys: u64,
+    expired_keys: u64,
+    /// the number of writes the store has applied
+    sequence: u64,
+    manifest: Manifest,
+    trash: HashMap<String, TrashedKey>,
+    trash_retention: Option<Duration>,
+    compaction_policy: CompactionPolicy,
+    compaction_pool: Option<rayon::ThreadPool>,
+    last_write: Instant,
+    write_stall: Option<(u64, Duration)>,
+    stalled_writes: u64,
+    corrupted_reads: u64,
+    /// the data file the background scrubber verified last
+    scrub_cursor: u64,
+    scrubbed_files: u64,
+    scrubbed_records: u64,
+    scrub_errors: u64,
+    /// key -> why its record is corrupted, for the keys the scrubber could not repair
+    quarantine: Arc<DashMap<String, String>>,
+    direct_io: bool,
+    preallocate: Option<u64>,
+    cold_after: Option<Duration>,
+    usage_prefix: Option<KeyPrefix>,
+    compaction_filter: Option<FilterHook>,
+    retention: Vec<RetentionRule>,
+    streams: Arc<DashMap<String, StreamRange>>,
+    sizes: Sizes,
+    archiver: Option<Arc<Archiver>>,
+    index: Arc<DashMap<String, CommandPosition>>,
+    access_clock: Arc<AccessClock>,
+}
+
+impl Writer {
+    fn set(&mut self, key: String, value: String) -> Result<()> {
+        let value_size = value.len() as u64;
+        let data = serde_json::to_vec(&Command::SET(key.clone(), value))?;
+        let event =
+            stream_event(&key).map(|(stream_key, sequence)| (stream_key.to_owned(), sequence));
+        self.append_value(key, &data, value_size, 0)?;
+        if let Some((stream_key, sequence)) = event {
+            note_event(&self.streams, stream_key, sequence);
+        }
+        Ok(())
+    }
+
+    /// Set the value of `key` to the JSON `document` by appending a patch record setting the
+    /// field at `pointer` of its current document to `field`, or the whole document once the
+    /// value is read through `MAX_PATCHES` patch records or the key is missing.
+    fn set_json_field(
+        &mut self,
+        key: String,
+        document: String,
+        pointer: &str,
+        field: &str,
+    ) -> Result<()> {
+        let base = self
+            .index
+            .get(&key)
+            .map(|entry| entry.value().location())
+            .filter(|base| base.patches < MAX_PATCHES && !self.quarantine.contains_key(&key));
+        let base = match base {
+            Some(base) => base,
+            None => return self.set(key, document),
+        };
+        let command = Command::PATCH(
+            key.clone(),
+            pointer.to_owned(),
+            field.to_owned(),
+            (base.file_number, base.offset, base.length),
+        );
+        let value_size = document.len() as u64;
+        self.append_value(
+            key,
+            &serde_json::to_vec(&command)?,
+            value_size,
+            base.patches + 1,
+        )
+    }
+
+    /// append the record `data` setting or patching the value of `key` to a value of
+    /// `value_size` bytes, `patches` patch records deep
+    fn append_value(
+        &mut self,
+        key: String,
+        data: &[u8],
+        value_size: u64,
+        patches: u32,
+    ) -> Result<()> {
+        self.ensure_quota(data.len() as u64)?;
+
+        let offset = self.current_writer.get_position();
+        self.current_writer.write_all(data)?;
+        self.current_writer.flush()?;
+        let length = self.current_writer.get_position() - offset;
+        let file_number = self.current_file_number;
+        self.reader.io.appended(length);
+        self.sizes.record(&key, value_size);
+        self.disk_usage += length;
+        self.sequence += 1;
+
+        self.quarantine.remove(&key);
+        self.useless_size += self
+            .trash
+            .remove(&key)
+            .map(|trashed| trashed.position.length)
+            .unwrap_or(0);
+        // the record under a patch record is only needed until compaction folds the patch
+        self.useless_size += self
+            .index
+            .insert(
+                key,
+                CommandPosition::new(
+                    file_number,
+                    offset,
+                    length,
+                    self.access_clock.tick(),
+                    now_millis(),
+                )
+                .with_patches(patches),
+            )
+            .map(|cp| cp.length)
+            .unwrap_or(0);
+
+        self.after_write()
+    }
+
+    fn remove(&mut self, key: String) -> Result<()> {
+        self.quarantine.remove(&key);
+        if let Some((key, position)) = self.index.remove(&key) {
+            forget_event(&self.streams, &self.index, &key);
+            let command = match self.trash_retention {
+                Some(_) => {
+                    let removed_at = now_millis();
+                    let command = serde_json::to_vec(&Command::TRASH(key.clone(), removed_at))?;
+                    let trashed = TrashedKey {
+                        position,
+                        removed_at,
+                    };
+                    self.useless_size += self
+                        .trash
+                        .insert(key, trashed)
+                        .map(|trashed| trashed.position.length)
+                        .unwrap_or(0);
+                    command
+                }
+                None => {
+                    self.useless_size += position.length;
+                    serde_json::to_vec(&Command::RM(key))?
+                }
+            };
+            let offset = self.current_writer.get_position();
+            self.current_writer.write_all(&command)?;
+            self.current_writer.flush()?;
+
+            let length = self.current_writer.get_position() - offset;
+            self.reader.io.appended(length);
+            self.useless_size += length;
+            self.disk_usage += length;
+            self.sequence += 1;
+
+            self.after_write()
+        } else {
+            Err(KVStoreError::KeyNotFound)
+        }
+    }
+
+    /// roll the log file and compact if the compaction policy asks for it after a write
+    fn after_write(&mut self) -> Result<()> {
+        self.last_write = Instant::now();
+        self.roll_log_file()?;
+        let due = match self.compaction_policy {
+            CompactionPolicy::UselessSize(bytes) => self.useless_size > bytes,
+            CompactionPolicy::GarbageRatio(percent) => {
+                let live_size = self.disk_usage.saturating_sub(self.useless_size);
+                self.useless_size > MAX_USELESS_SIZE
+                    && self.useless_size * 100 > live_size * percent as u64
+            }
+            CompactionPolicy::Interval(_) | CompactionPolicy::Idle(_) => false,
+        };
+        if due {
+            self.timed_compact()?;
+        }
+        Ok(())
+    }
+
+    /// how long the next write has to wait for compaction to catch up
+    fn stall_delay(&self) -> Duration {
+        match self.write_stall {
+            Some((threshold, max_delay)) if self.useless_size > threshold => {
+                let excess = (self.useless_size - threshold) as f64 / cmp::max(threshold, 1) as f64;
+                max_delay.mul_f64(excess.min(1.0))
+            }
+            _ => Duration::ZERO,
+        }
+    }
+
+    fn timed_compact(&mut self) -> Result<()> {
+        let now = SystemTime::now();
+        let mut span = trace::span("kvs.compaction");
+        info!("Compaction starts");
+        if let Err(err) = self.compact() {
+            span.error(&err);
+            return Err(err);
+        }
+        info!("Compaction finished, cost {:?}", now.elapsed());
+        Ok(())
+    }
+
+    /// whether the index entry of `key` still points at the record at `position`
+    fn is_indexed_at(&self, key: &str, position: &CommandPosition) -> bool {
+        self.index
+            .get(key)
+            .is_some_and(|entry| entry.value().is_at(position))
+    }
+
+    /// the data files no write goes to anymore, oldest first
+    fn sealed_files(&self) -> Vec<u64> {
+        let mut file_numbers = self.dirs.file_numbers();
+        file_numbers.retain(|file_number| *file_number != self.current_file_number);
+        file_numbers
+    }
+
+    /// the sealed data file the background scrubber verifies next, after the last one in turn
+    fn next_scrub_file(&mut self) -> Option<u64> {
+        let sealed = self.sealed_files();
+        let next = sealed
+            .iter()
+            .find(|file_number| **file_number > self.scrub_cursor)
+            .or_else(|| sealed.first())
+            .copied()?;
+        self.scrub_cursor = next;
+        Some(next)
+    }
+
+    fn restore_key(&mut self, key: String) -> Result<()> {
+        let position = match self.trash.get(&key) {
+            Some(trashed) if !self.is_expired(trashed) => &trashed.position,
+            _ => return Err(KVStoreError::KeyNotFound),
+        };
+        let value = self
+            .reader
+            .read_command(&key, position)?
+            .ok_or(KVStoreError::KeyNotFound)?;
+        self.set(key, value)
+    }
+
+    fn is_expired(&self, trashed: &TrashedKey) -> bool {
+        match self.trash_retention {
+            Some(retention) => trashed.removed_at + retention.as_millis() as u64 <= now_millis(),
+            None => true,
+        }
+    }
+
+    /// Make room for `incoming` bytes under the disk quota, compacting first if stale commands can be reclaimed.
+    /// Only `set` is checked so that a full store can always be shrunk by removing keys.
+    fn ensure_quota(&mut self, incoming: u64) -> Result<()> {
+        let max_disk_size = match self.max_disk_size {
+            Some(max_disk_size) => max_disk_size,
+            None => return Ok(()),
+        };
+        if self.disk_usage + incoming <= max_disk_size {
+            return Ok(());
+        }
+        if self.useless_size > 0 {
+            info!("Disk quota reached, compaction starts");
+            self.compact()?;
+        }
+        if self.disk_usage + incoming > max_disk_size {
+            if self.quota_policy == QuotaPolicy::Reject || incoming > max_disk_size {
+                return Err(KVStoreError::QuotaExceeded);
+            }
+            self.evict_lru(max_disk_size - incoming)?;
+        }
+        Ok(())
+    }
+
+    /// Evict the least recently used keys until the live data fits in `budget` bytes.
+    /// No tombstones are written: the following compaction drops the evicted commands from disk.
+    fn evict_lru(&mut self, budget: u64) -> Result<()> {
+        let mut candidates: Vec<(u64, String, u64)> = self
+            .index
+            .iter()
+            .map(|entry| {
+                let position = entry.value();
+                (
+                    position.last_access.load(Ordering::Relaxed),
+                    entry.key().clone(),
+                    position.length,
+                )
+            })
+            .collect();
+        candidates.sort_unstable();
+
+        // there is no stale command left at this point, so all data on disk is live
+        let mut live_size = self.disk_usage;
+        for (_, key, length) in candidates {
+            if live_size <= budget {
+                break;
+            }
+            self.index.remove(&key);
+            forget_event(&self.streams, &self.index, &key);
+            live_size -= length;
+            self.evicted_keys += 1;
+            debug!("Evict key {}", key);
+        }
+
+        self.compact()
+    }
+
+    fn compact(&mut self) -> Result<()> {
+        // the compaction outputs are numbered after the log file sealed by the compaction
+        let sealed_log = self.current_file_number;
+        let compaction_number = sealed_log + 1;
+
+        // keys which have not been read recently are copied to data files in the cold tier
+        let cold_before = self
+            .cold_after
+            .map(|cold_after| {
+                self.access_clock
+                    .now()
+                    .saturating_sub(cold_after.as_micros() as u64)
+            })
+            .filter(|_| self.dirs.has_cold_tier());
+
+        // the trash keeps the last values of removed keys until their retention expires
+        let expired: Vec<String> = self
+            .trash
+            .iter()
+            .filter(|(_, trashed)| self.is_expired(trashed))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.trash.remove(&key);
+            debug!("Purge key {} from the trash", key);
+        }
+
+        let mut sources: BTreeMap<u64, Vec<CompactionItem>> = BTreeMap::new();
+        for entry in self.index.iter() {
+            let position = entry.value();
+            let cold = cold_before
+                .map(|cold_before| {
+                    let last_access = position.last_access.load(Ordering::Relaxed);
+                    last_access == UNREAD_COLD || last_access < cold_before
+                })
+                .unwrap_or(false);
+            sources
+                .entry(position.file_number)
+                .or_default()
+                .push(CompactionItem {
+                    key: entry.key().clone(),
+                    source: position.location(),
+                    cold,
+                    removed_at: None,
+                });
+        }
+        for (key, trashed) in &self.trash {
+            sources
+                .entry(trashed.position.file_number)
+                .or_default()
+                .push(CompactionItem {
+                    key: key.clone(),
+                    source: trashed.position.location(),
+                    cold: false,
+                    removed_at: Some(trashed.removed_at),
+                });
+        }
+
+        // each worker copies whole source files, the largest ones are spread first
+        let workers = self
+            .compaction_pool
+            .as_ref()
+            .map(|pool| pool.current_num_threads())
+            .unwrap_or(1)
+            .clamp(1, cmp::max(sources.len(), 1));
+        let mut jobs = Vec::with_capacity(workers);
+        for _ in 0..workers {
+            self.current_file_number += 1;
+            let hot = (
+                self.current_file_number,
+                self.dirs.allocate(self.current_file_number),
+            );
+            let cold = match cold_before {
+                Some(_) => {
+                    self.current_file_number += 1;
+                    self.dirs
+                        .allocate_cold(self.current_file_number)
+                        .map(|path| (self.current_file_number, path))
+                }
+                None => None,
+            };
+            jobs.push(CompactionJob {
+                reader: self.reader.clone(),
+                filter: self.compaction_filter.clone(),
+                retention: self.retention.clone(),
+                now: now_millis(),
+                direct_io: self.direct_io,
+                hot,
+                cold,
+                items: Vec::new(),
+                size: 0,
+            });
+        }
+        let mut sources: Vec<(u64, Vec<CompactionItem>)> = sources
+            .into_values()
+            .map(|items| (items.iter().map(|item| item.source.length).sum(), items))
+            .collect();
+        sources.sort_by_key(|(size, _)| cmp::Reverse(*size));
+        for (size, items) in sources {
+            let job = jobs.iter_mut().min_by_key(|job| job.size).unwrap();
+            job.size += size;
+            job.items.extend(items);
+        }
+
+        let outputs: Vec<CompactionOutput> = match &self.compaction_pool {
+            Some(pool) if jobs.len() > 1 => pool.install(|| {
+                jobs.into_par_iter()
+                    .map(CompactionJob::run)
+                    .collect::<Result<_>>()
+            })?,
+            _ => jobs
+                .into_iter()
+                .map(CompactionJob::run)
+                .collect::<Result<_>>()?,
+        };
+
+        // the index only moves to the new data files once all of them are written
+        self.disk_usage = 0;
+        self.sizes = Sizes::default();
+        for output in outputs {
+            self.disk_usage += output.size;
+            self.sizes.merge(&output.sizes);
+            for key in output.dropped {
+                self.index.remove(&key);
+                forget_event(&self.streams, &self.index, &key);
+            }
+            self.expired_keys += output.expired.len() as u64;
+            for key in output.expired {
+                self.index.remove(&key);
+                forget_event(&self.streams, &self.index, &key);
+            }
+            for (key, trashed, location) in output.locations {
+                if trashed {
+                    if let Some(trashed) = self.trash.get_mut(&key) {
+                        trashed.position.move_to(&location);
+                    }
+                } else if let Some(mut entry) = self.index.get_mut(&key) {
+                    entry.value_mut().move_to(&location);
+                }
+            }
+        }
+
+        // the old data files are only deleted once the manifest durably points at the new ones,
+        // which hold the state as of the current sequence
+        let synced = self.dirs.sync()?;
+        self.reader.io.synced(synced);
+        let compacted_files = (compaction_number..=self.current_file_number).collect();
+        self.create_new_file(sealed_log)?;
+        self.manifest = Manifest {
+            format_version: FORMAT_VERSION,
+            options: self.manifest.options.clone(),
+            compacted_files,
+            log_files: vec![self.current_file_number],
+            compacted_sequence: self.sequence,
+            last_sequence: self.sequence,
+            entries: self.manifest.entries.clone(),
+            instance_id: self.manifest.instance_id.clone(),
+            replication_source: self.manifest.replication_source.clone(),
+        };
+        self.store_manifest()?;
+
+        self.reader
+            .compaction_number
+            .store(compaction_number, Ordering::SeqCst);
+
+        self.reader.remove_useless_reader(compaction_number)?;
+
+        self.useless_size = 0;
+
+        Ok(())
+    }
+
+    /// seal the current log file `sealed_log` and continue the log in a new file
+    fn create_new_file(&mut self, sealed_log: u64) -> Result<()> {
+        self.current_writer.seal()?;
+        if let Some(archiver) = &self.archiver {
+            archiver.seal(sealed_log);
+        }
+        self.current_file_number += 1;
+        self.current_writer = BufWriterWithPosition::new(SegmentFile::open(
+            &self.dirs.allocate(self.current_file_number),
+            self.direct_io,
+        )?)?;
+        if let Some(size) = self.preallocate {
+            self.current_writer.writer.get_ref().preallocate(size);
+        }
+        self.disk_usage += self.current_writer.get_position();
+        Ok(())
+    }
+
+    /// continue the log in a new file once the preallocated space of the current one is used up
+    fn roll_log_file(&mut self) -> Result<()> {
+        match self.preallocate {
+            Some(size) if self.current_writer.get_position() >= size => {}
+            _ => return Ok(()),
+        }
+        self.create_new_file(self.current_file_number)?;
+        self.manifest.log_files.push(self.current_file_number);
+        self.manifest.last_sequence = self.sequence;
+        self.store_manifest()
+    }
+
+    /// durably write the manifest, see `Manifest::store`
+    fn store_manifest(&self) -> Result<()> {
+        self.manifest.store(self.dirs.primary())?;
+        // the manifest file and its directory
+        self.reader.io.synced(2);
+        Ok(())
+    }
+}
+
+/// the bytes of a record setting a value besides the key and the value, `{"SET":["",""]}`
+const SET_RECORD_OVERHEAD: u64 = 15;
+
+/// the sizes of the keys and values written since the last compaction and live at it
+#[derive(Default)]
+struct Sizes {
+    keys: SizeHistogram,
+    values: SizeHistogram,
+}
+
+impl Sizes {
+    fn record(&mut self, key: &str, value_size: u64) {
+        self.keys.record(key.len() as u64);
+        self.values.record(value_size);
+    }
+
+    fn merge(&mut self, other: &Sizes) {
+        self.keys.merge(&other.keys);
+        self.values.merge(&other.values);
+    }
+}
+
+/// a live command which compaction copies to a new data file
+struct CompactionItem {
+    key: String,
+    source: CommandPosition,
+    cold: bool,
+    /// when the key was removed, if this is the last value of a key in the trash
+    removed_at: Option<u64>,
+}
+
+/// the share of a compaction one worker copies into its own data files
+struct CompactionJob {
+    reader: Reader,
+    filter: Option<FilterHook>,
+    retention: Vec<RetentionRule>,
+    /// in milliseconds since the unix epoch
+    now: u64,
+    direct_io: bool,
+    hot: (u64, PathBuf),
+    cold: Option<(u64, PathBuf)>,
+    items: Vec<CompactionItem>,
+    size: u64,
+}
+
+struct CompactionOutput {
+    size: u64,
+    /// key, whether it is in the trash, and where its command was copied to
+    locations: Vec<(String, bool, CommandPosition)>,
+    /// the keys the compaction filter dropped
+    dropped: Vec<String>,
+    /// the keys dropped because they are older than their retention
+    expired: Vec<String>,
+    /// the sizes of the live keys and values copied
+    sizes: Sizes,
+}
+
+impl CompactionJob {
+    fn run(mut self) -> Result<CompactionOutput> {
+        let direct_io = self.direct_io;
+        let open = |path: &PathBuf| -> Result<BufWriterWithPosition<SegmentFile>> {
+            BufWriterWithPosition::new(SegmentFile::open(path, direct_io)?)
+        };
+        let mut hot_writer = open(&self.hot.1)?;
+        let mut cold_writer = match &self.cold {
+            Some((file_number, path)) => Some((*file_number, open(path)?)),
+            None => None,
+        };
+
+        // read each source file from front to back
+        self.items
+            .sort_unstable_by_key(|item| (item.source.file_number, item.source.offset));
+        let mut locations = Vec::with_capacity(self.items.len());
+        let mut dropped = Vec::new();
+        let mut expired = Vec::new();
+        let mut copies = Vec::new();
+        let mut copied = 0;
+        let mut sizes = Sizes::default();
+        // the newest write copied to each output file, which becomes its modification time
+        let (mut hot_written, mut cold_written) = (0, 0);
+        for item in self.items {
+            if item.removed_at.is_none()
+                && is_retention_expired(
+                    &self.retention,
+                    &item.key,
+                    item.source.written_at,
+                    self.now,
+                )
+            {
+                expired.push(item.key);
+                continue;
+            }
+            let decision = match (&self.filter, item.removed_at) {
+                (Some(FilterHook(filter)), None) => {
+                    let data = self.reader.read_record(&item.source)?;
+                    let record = check_record(&item.key, &item.source, &data)?;
+                    filter.filter(&item.key, &self.reader.resolve(&item.key, record)?)
+                }
+                _ => CompactionDecision::Keep,
+            };
+            // the patches of a JSON document are folded into a record of the whole document
+            let decision = match decision {
+                CompactionDecision::Keep if item.source.patches > 0 => {
+                    CompactionDecision::ChangeValue(
+                        self.reader.read_value(&item.key, &item.source)?,
+                    )
+                }
+                decision => decision,
+            };
+            let (file_number, writer) = match cold_writer.as_mut() {
+                Some((file_number, writer)) if item.cold => (*file_number, writer),
+                _ => (self.hot.0, &mut hot_writer),
+            };
+            let offset = writer.position;
+            // the source of a copy which must be identical to it
+            let source = match decision {
+                CompactionDecision::Keep => {
+                    self.reader.copy_data_to_writer(&item.source, writer)?;
+                    if item.removed_at.is_none() {
+                        let overhead = item.key.len() as u64 + SET_RECORD_OVERHEAD;
+                        sizes.record(&item.key, item.source.length.saturating_sub(overhead));
+                    }
+                    Some(item.source.location())
+                }
+                CompactionDecision::Remove => {
+                    dropped.push(item.key);
+                    continue;
+                }
+                CompactionDecision::ChangeValue(value) => {
+                    if item.removed_at.is_none() {
+                        sizes.record(&item.key, value.len() as u64);
+                    }
+                    let command = Command::SET(item.key.clone(), value);
+                    writer.write_all(&serde_json::to_vec(&command)?)?;
+                    None
+                }
+            };
+            let length = writer.position - offset;
+            copied += length;
+            if self.reader.paranoid_checks {
+                let copy = CommandPosition::new(file_number, offset, length, 0, 0);
+                copies.push((item.key.clone(), copy, source));
+            }
+            if let Some(removed_at) = item.removed_at {
+                let command = Command::TRASH(item.key.clone(), removed_at);
+                writer.write_all(&serde_json::to_vec(&command)?)?;
+            }
+            locations.push((
+                item.key,
+                item.removed_at.is_some(),
+                CommandPosition::new(file_number, offset, length, 0, item.source.written_at),
+            ));
+            if file_number == self.hot.0 {
+                hot_written = hot_written.max(item.source.written_at);
+            } else {
+                cold_written = cold_written.max(item.source.written_at);
+            }
+        }
+
+        hot_writer.sync()?;
+        self.reader.io.synced(1);
+        let mut size = hot_writer.position;
+        if let Some((_, mut writer)) = cold_writer {
+            writer.sync()?;
+            self.reader.io.synced(1);
+            size += writer.position;
+        }
+        self.reader
+            .io
+            .compaction_bytes_copied
+            .fetch_add(copied, Ordering::Relaxed);
+        // so the retention of the copied keys does not start over when the store is opened again
+        if !self.retention.is_empty() {
+            set_written_at(&self.hot.1, hot_written)?;
+            if let Some((_, path)) = &self.cold {
+                set_written_at(path, cold_written)?;
+            }
+        }
+
+        // read every copy back before the old data files can be deleted
+        for (key, copy, source) in copies {
+            let data = self.reader.read_record(&copy)?;
+            check_record(&key, &copy, &data)?;
+            let source = match source {
+                Some(source) => source,
+                None => continue,
+            };
+            if data != self.reader.read_record(&source)? {
+                return Err(KVStoreError::CorruptedRecord(
+                    copy.file_number,
+                    copy.offset,
+                    format!(
+                        "the copy differs from offset {} of data file {}",
+                        source.offset, source.file_number
+                    ),
+                ));
+            }
+        }
+        if !dropped.is_empty() {
+            debug!("The compaction filter dropped {} keys", dropped.len());
+        }
+        if !expired.is_empty() {
+            debug!("The retention dropped {} keys", expired.len());
+        }
+        Ok(CompactionOutput {
+            size,
+            locations,
+            dropped,
+            expired,
+            sizes,
+        })
+    }
+}
+
+/// run the background compactions of the `Interval` and `Idle` policies until the store is dropped
+fn compaction_scheduler(writer: Weak<Mutex<Writer>>, policy: CompactionPolicy) {
+    let mut wait = match policy {
+        CompactionPolicy::Interval(wait) | CompactionPolicy::Idle(wait) => wait,
+        _ => return,
+    };
+    loop {
+        thread::sleep(wait);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let mut writer = writer.lock().unwrap();
+        let due = match policy {
+            CompactionPolicy::Interval(_) => true,
+            CompactionPolicy::Idle(idle) => {
+                // sleep until the store has been idle long enough since the last write
+                wait = idle.saturating_sub(writer.last_write.elapsed());
+                if wait.is_zero() {
+                    wait = idle;
+                    true
+                } else {
+                    false
+                }
+            }
+            _ => return,
+        };
+        if due && writer.useless_size > 0 {
+            if let Err(err) = writer.timed_compact() {
+                warn!("Background compaction failed: {}", err);
+            }
+        }
+    }
+}
+
+/// verify the sealed data files one after another every `interval` until the store is dropped
+fn scrubber(writer: Weak<Mutex<Writer>>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => return,
+        };
+        let next = writer.lock().unwrap().next_scrub_file();
+        if let Some(file_number) = next {
+            if let Err(err) = scrub_file(&writer, file_number) {
+                warn!("Scrubbing data file {} failed: {}", file_number, err);
+            }
+        }
+    }
+}
+
+/// Copy the data files of `lengths` up to their lengths to `target` as objects of the backup
+/// of `manifest`, only the tails after the bytes `backed_up` by the earlier backups of its chain.
+fn copy_backup_files(
+    dirs: &DataDirs,
+    target: &dyn BackupTarget,
+    manifest: &BackupManifest,
+    lengths: &[(u64, u64)],
+    backed_up: &HashMap<u64, u64>,
+    cancel: &CancellationToken,
+) -> Result<Vec<BackupFile>> {
+    let mut files = Vec::new();
+    for &(file_number, length) in lengths {
+        cancel.check()?;
+        // data files are append-only, so only their new tail needs to be copied
+        let offset = backed_up.get(&file_number).copied().unwrap_or(0);
+        if offset > length {
+            return Err(KVStoreError::CommonStringError(format!(
+                "data file {} is shorter than in backup {}, which is not a backup of this store",
+                file_number,
+                manifest.parent.clone().unwrap_or_default()
+            )));
+        }
+        if offset == length {
+            continue;
+        }
+        let mut file = File::open(dirs.file_path(file_number))?;
+        file.seek(SeekFrom::Start(offset))?;
+        let object = format!("{}/data_{}.txt", manifest.id, file_number);
+        target.put(&object, &mut file.take(length - offset))?;
+        files.push(BackupFile {
+            file_number,
+            offset,
+            length: length - offset,
+            object,
+        });
+    }
+    Ok(files)
+}
+
+/// Verify the records of the sealed data file `file_number` and the index entries pointing
+/// into it, repairing or quarantining the entries of bad records. Writes are only held back
+/// while the entries are collected and repaired; the file is pinned against compaction.
+fn scrub_file(writer: &Mutex<Writer>, file_number: u64) -> Result<()> {
+    let (dirs, mut file, entries) = {
+        let writer = writer.lock().unwrap();
+        // compaction may have deleted it since
+        if !writer.dirs.file_numbers().contains(&file_number) {
+            return Ok(());
+        }
+        let file = File::open(writer.dirs.file_path(file_number))?;
+        let entries: Vec<(String, CommandPosition)> = writer
+            .index
+            .iter()
+            .filter(|entry| entry.value().file_number == file_number)
+            .map(|entry| (entry.key().clone(), entry.value().location()))
+            .collect();
+        writer.dirs.pin(&[file_number]);
+        (Arc::clone(&writer.dirs), file, entries)
+    };
+    let verified = verify_file(&mut file, file_number, &entries);
+    dirs.unpin(&[file_number]);
+    let (records, mut bad_offsets, bad_entries) = verified?;
+
+    for (key, stale, err) in bad_entries {
+        bad_offsets.insert(stale.offset);
+        if let Err(err) = repair_key(writer, &key, &stale, err) {
+            let writer = writer.lock().unwrap();
+            // the key was written or compacted since
+            if writer.is_indexed_at(&key, &stale) {
+                warn!("Quarantine {} after: {}", key, err);
+                writer.quarantine.insert(key, err.to_string());
+            }
+        }
+    }
+    let mut writer = writer.lock().unwrap();
+    writer.scrubbed_files += 1;
+    writer.scrubbed_records += records;
+    writer.scrub_errors += bad_offsets.len() as u64;
+    Ok(())
+}
+
+/// Look up the record of `key` again in the data files after reading it from `stale` failed
+/// with `err`, and repair its index entry unless the key was written since. Only the data files
+/// up to the one of `stale` are read, as any later record of the key would be indexed instead;
+/// writes are not held back meanwhile, the files are pinned against compaction.
+/// Return `err` if the data files do not tell where the value of the key is instead.
+fn repair_key(
+    writer: &Mutex<Writer>,
+    key: &str,
+    stale: &CommandPosition,
+    err: KVStoreError,
+) -> Result<()> {
+    let (dirs, file_numbers) = {
+        let mut writer = writer.lock().unwrap();
+        if !writer.is_indexed_at(key, stale) {
+            return Ok(());
+        }
+        writer.current_writer.flush()?;
+        let mut file_numbers = writer.dirs.file_numbers();
+        file_numbers.retain(|file_number| *file_number <= stale.file_number);
+        writer.dirs.pin(&file_numbers);
+        (Arc::clone(&writer.dirs), file_numbers)
+    };
+    let rescanned = rescan_key(&dirs, key, stale, &file_numbers);
+    dirs.unpin(&file_numbers);
+    let found = match rescanned {
+        Ok(found) => found,
+        Err(rescan_err) => {
+            warn!(
+                "Can not look up {} again in the data files: {}",
+                key, rescan_err
+            );
+            return Err(err);
+        }
+    };
+
+    let writer = writer.lock().unwrap();
+    if !writer.is_indexed_at(key, stale) {
+        return Ok(());
+    }
+    match found {
+        None => Err(err),
+        Some(Some(position)) => {
+            warn!(
+                "Repair the index entry of {} from offset {} of data file {} to offset {} of data file {} after: {}",
+                key, stale.offset, stale.file_number, position.offset, position.file_number, err
+            );
+            position
+                .last_access
+                .store(writer.access_clock.tick(), Ordering::Relaxed);
+            writer.index.insert(key.to_owned(), position);
+            Ok(())
+        }
+        Some(None) => {
+            warn!(
+                "Remove the index entry of the removed key {} after: {}",
+                key, err
+            );
+            writer.index.remove(key);
+            forget_event(&writer.streams, &writer.index, key);
+            Ok(())
+        }
+    }
+}
+
+/// Find the last record of `key` before the record at `stale` by reading the data files
+/// `file_numbers` newest first, e.g. because the index entry of the key points at a record of
+/// another key. Older files are only read as far as needed to count the patches on top of the
+/// value. Return Some(None) if the key was removed last and None if no record of the key is found.
+fn rescan_key(
+    dirs: &DataDirs,
+    key: &str,
+    stale: &CommandPosition,
+    file_numbers: &[u64],
+) -> Result<Option<Option<CommandPosition>>> {
+    let mut found: Option<Option<CommandPosition>> = None;
+    for &file_number in file_numbers.iter().rev() {
+        let end = (file_number == stale.file_number).then_some(stale.offset);
+        let records = key_records(dirs, file_number, key, end)?;
+        // the record was written before the stale one and before its data file was last
+        // modified, as `open` assumes
+        let written_at = fs::metadata(dirs.file_path(file_number))
+            .and_then(|metadata| metadata.modified())
+            .map_or(stale.written_at, millis_since_epoch)
+            .min(stale.written_at);
+        for (command, offset, length) in records.into_iter().rev() {
+            let position = || CommandPosition::new(file_number, offset, length, 0, written_at);
+            match (&mut found, command) {
+                (Some(Some(patched)), Command::PATCH(..)) => patched.patches += 1,
+                (Some(_), _) => return Ok(found),
+                (None, Command::SET(..)) => return Ok(Some(Some(position()))),
+                (None, Command::PATCH(..)) => found = Some(Some(position().with_patches(1))),
+                (None, _) => return Ok(Some(None)),
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// the records of `key` in the data file `file_number` before the offset `end`, with their
+/// offsets and lengths in the order they were written. Records which can not be decoded are
+/// skipped by reading on from the next byte which may start a record.
+fn key_records(
+    dirs: &DataDirs,
+    file_number: u64,
+    key: &str,
+    end: Option<u64>,
+) -> Result<Vec<(Command, u64, u64)>> {
+    let file_path = dirs.file_path(file_number);
+    let mut file =
+        File::open(&file_path).with_context(|| ErrorContext::new("rescan").file(&file_path))?;
+    let (_, start) = read_header(&mut file)?;
+    let end = match end {
+        Some(end) => end,
+        None => file.metadata()?.len(),
+    };
+    let mut records = Vec::new();
+    let mut offset = start;
+    while offset < end {
+        file.seek(SeekFrom::Start(offset))?;
+        let reader = BufReader::new((&mut file).take(end - offset));
+        let mut iter = Deserializer::from_reader(reader).into_iter::<Command>();
+        let mut before_offset = offset;
+        let mut bad_offset = None;
+        while let Some(command) = iter.next() {
+            let after_offset = offset + iter.byte_offset() as u64;
+            match command {
+                Ok(command) if command.key() == key => {
+                    records.push((command, before_offset, after_offset - before_offset));
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    warn!(
+                        "Skip the bad record at offset {} of data file {} while looking up {}: {}",
+                        before_offset, file_number, key, err
+                    );
+                    bad_offset = Some(before_offset);
+                    break;
+                }
+            }
+            before_offset = after_offset;
+        }
+        offset = match bad_offset {
+            Some(bad_offset) => match next_record_start(&mut file, bad_offset + 1, end)? {
+                Some(next) => next,
+                None => break,
+            },
+            None => break,
+        };
+    }
+    Ok(records)
+}
+
+/// the offset of the first `{"` at or after `offset` and before `end`, which is where a record
+/// may start as it can not occur within the escaped strings of a record
+fn next_record_start(file: &mut File, offset: u64, end: u64) -> Result<Option<u64>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut previous = 0;
+    for (index, byte) in BufReader::new(file.take(end - offset)).bytes().enumerate() {
+        let byte = byte?;
+        if previous == b'{' && byte == b'"' {
+            return Ok(Some(offset + index as u64 - 1));
+        }
+        previous = byte;
+    }
+    Ok(None)
+}
+
+/// the number of records read from a data file, the offsets of those which could not be read
+/// and the index entries pointing at bad records
+type VerifiedFile = (
+    u64,
+    HashSet<u64>,
+    Vec<(String, CommandPosition, KVStoreError)>,
+);
+
+/// read all records of the data file `file_number` and check those `entries` point at
+fn verify_file(
+    file: &mut File,
+    file_number: u64,
+    entries: &[(String, CommandPosition)],
+) -> Result<VerifiedFile> {
+    let (_, start) = read_header(file)?;
+    let mut records = 0;
+    let mut bad_offsets = HashSet::new();
+    let mut iter = Deserializer::from_reader(BufReader::new(&mut *file)).into_iter::<Command>();
+    let mut before_offset = start;
+    while let Some(command) = iter.next() {
+        if let Err(err) = command {
+            // the records after it can not be told apart anymore
+            warn!(
+                "Bad record at offset {} of data file {}: {}",
+                before_offset, file_number, err
+            );
+            bad_offsets.insert(before_offset);
+            break;
+        }
+        records += 1;
+        before_offset = start + iter.byte_offset() as u64;
+    }
+
+    let mut bad_entries = Vec::new();
+    let mut reader = BufReader::new(file);
+    for (key, position) in entries {
+        reader.seek(SeekFrom::Start(position.offset))?;
+        let mut data = Vec::with_capacity(position.length as usize);
+        (&mut reader).take(position.length).read_to_end(&mut data)?;
+        if let Err(err) = check_record(key, position, &data) {
+            bad_entries.push((key.clone(), position.location(), err));
+        }
+    }
+    Ok((records, bad_offsets, bad_entries))
+}
+
+/// the last value of a removed key which is kept in the trash
+struct TrashedKey {
+    position: CommandPosition,
+    /// in milliseconds since the unix epoch
+    removed_at: u64,
+}
+
+/// separates the stream key from the sequence in the keys of the events of a stream
+const EVENT_SEPARATOR: char = '\u{0}';
+
+fn check_stream_key(stream_key: &str) -> Result<()> {
+    if stream_key.contains(EVENT_SEPARATOR) {
+        return Err(KVStoreError::CommonStringError(
+            "a stream key must not contain \\u{0}".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// the key of an event of a stream, padded so the keys sort like the sequences
+fn event_key(stream_key: &str, sequence: u64) -> String {
+    format!("{}{}{:020}", stream_key, EVENT_SEPARATOR, sequence)
+}
+
+/// the stream key and sequence of `key` if it is the key of an event
+fn stream_event(key: &str) -> Option<(&str, u64)> {
+    let (stream_key, sequence) = key.split_once(EVENT_SEPARATOR)?;
+    if sequence.len() != 20 || !sequence.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    Some((stream_key, sequence.parse().ok()?))
+}
+
+/// the sequences of the events of a stream
+#[derive(Clone, Copy)]
+struct StreamRange {
+    /// the sequence of the first live event, or one past the last if none is live
+    first: u64,
+    /// the sequence of the last event appended
+    last: u64,
+}
+
+/// widen the sequences of the stream at `stream_key` to the live event `sequence`
+fn note_event(streams: &DashMap<String, StreamRange>, stream_key: String, sequence: u64) {
+    let mut range = streams.entry(stream_key).or_insert(StreamRange {
+        first: sequence,
+        last: sequence,
+    });
+    range.first = cmp::min(range.first, sequence);
+    range.last = cmp::max(range.last, sequence);
+}
+
+/// move the first live event of a stream past `key` once it is removed from `index`, if it
+/// is the key of that event
+fn forget_event(
+    streams: &DashMap<String, StreamRange>,
+    index: &DashMap<String, CommandPosition>,
+    key: &str,
+) {
+    let (stream_key, sequence) = match stream_event(key) {
+        Some(event) => event,
+        None => return,
+    };
+    if let Some(mut range) = streams.get_mut(stream_key) {
+        if range.first == sequence {
+            range.first = (sequence + 1..=range.last)
+                .find(|sequence| index.contains_key(&event_key(stream_key, *sequence)))
+                .unwrap_or(range.last + 1);
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    millis_since_epoch(SystemTime::now())
+}
+
+/// set the modification time of a data file to the newest write it holds, if it holds any
+fn set_written_at(path: &Path, written_at: u64) -> Result<()> {
+    if written_at > 0 {
+        File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(UNIX_EPOCH + Duration::from_millis(written_at))?;
+    }
+    Ok(())
+}
+
+fn millis_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// where the last compaction left the log, as stored by stores before the manifest:
+/// data files from `first_log_file` on hold the writes after `sequence` one by one
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+struct CompactionPoint {
+    sequence: u64,
+    first_log_file: u64,
+}
+
+impl CompactionPoint {
+    /// read the newest compaction point, or the start of the log if the store was never compacted
+    fn load(dir: &Path) -> Result<Self> {
+        match Self::stored_files(dir)?.last() {
+            Some(path) => Ok(serde_json::from_slice(&fs::read(path)?)?),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// delete the compaction points once the manifest replaced them
+    fn remove(dir: &Path) -> Result<()> {
+        for path in Self::stored_files(dir)? {
+            remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// the files holding compaction points, oldest first
+    fn stored_files(dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut files: Vec<(u64, PathBuf)> = fs::read_dir(dir)?
+            .flat_map(|res| res.map(|entry| entry.path()))
+            .filter_map(|path| {
+                let first_log_file = path
+                    .file_name()?
+                    .to_str()?
+                    .strip_prefix("compaction_")?
+                    .strip_suffix(".json")?
+                    .parse()
+                    .ok()?;
+                Some((first_log_file, path))
+            })
+            .collect();
+        files.sort_unstable();
+        Ok(files.into_iter().map(|(_, path)| path).collect())
+    }
+}
+
+/// what `KvStore::close` records in `CLEAN_SHUTDOWN_FILE`: the newest data file and its length,
+/// which no write changed since if the store is still as it was closed
+#[derive(Serialize, Deserialize, Debug)]
+struct CleanShutdown {
+    file_number: u64,
+    length: u64,
+}
+
+/// the file in the primary data directory recording the last clean close of the store
+const CLEAN_SHUTDOWN_FILE: &str = "clean_shutdown.json";
+
+impl CleanShutdown {
+    /// durably record the clean close
+    fn store(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(CLEAN_SHUTDOWN_FILE);
+        let mut file = File::create(&path)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        sync_dir(dir)
+    }
+
+    /// read and delete the record of the last clean close, if there is one, so a crash of the
+    /// store opened now is not taken for a clean close
+    fn take(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(CLEAN_SHUTDOWN_FILE);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        remove_file(&path)?;
+        sync_dir(dir)?;
+        // a record torn by a crash while closing is no clean close
+        Ok(serde_json::from_slice(&data).ok())
+    }
+
+    /// whether the data files are as they were closed
+    fn matches(&self, dirs: &DataDirs) -> bool {
+        dirs.file_numbers().last() == Some(&self.file_number)
+            && fs::metadata(dirs.file_path(self.file_number))
+                .is_ok_and(|metadata| metadata.len() == self.length)
+    }
+}
+
+/// Cut the record a crash tore off the end of the log file at `path`, one which ends early or
+/// which the file system filled with zeros. Other bad records are left to recovery to report.
+fn truncate_torn_tail(path: &Path) -> Result<()> {
+    let mut file = File::open(path)?;
+    let (_, start) = read_header(&mut file)?;
+    let mut iter = Deserializer::from_reader(BufReader::new(&mut file)).into_iter::<Command>();
+    let mut end = start;
+    let torn = loop {
+        match iter.next() {
+            Some(Ok(_)) => end = start + iter.byte_offset() as u64,
+            Some(Err(err)) => break err.is_eof(),
+            None => return Ok(()),
+        }
+    };
+    drop(iter);
+    let mut tail = Vec::new();
+    file.seek(SeekFrom::Start(end))?;
+    file.read_to_end(&mut tail)?;
+    if torn || tail.iter().all(|byte| *byte == 0) {
+        warn!(
+            "Cut the torn record at offset {} of {:?}, {} bytes",
+            end,
+            path,
+            tail.len()
+        );
+        let file = File::options().write(true).open(path)?;
+        file.set_len(end)?;
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// count up to `limit` commands at the start of a data file, returning the count and the offset after them
+fn count_commands(path: &Path, limit: u64) -> Result<(u64, u64)> {
+    let mut file = File::open(path)?;
+    let (_, start) = read_header(&mut file)?;
+    let mut iter = Deserializer::from_reader(BufReader::new(file)).into_iter::<Command>();
+    let mut count = 0;
+    while count < limit {
+        match iter.next() {
+            Some(command) => {
+                command?;
+                count += 1;
+            }
+            None => break,
+        }
+    }
+    Ok((count, start + iter.byte_offset() as u64))
+}
+
+/// a struct which records writer's current position
+struct BufWriterWithPosition<T: Write + Seek> {
+    position: u64,
+    writer: BufWriter<T>,
+}
+
+impl<T: Write + Seek> BufWriterWithPosition<T> {
+    /// start writing at the end of `inner`, with a header if it is a new data file
+    fn new(mut inner: T) -> Result<Self> {
+        let position = inner.seek(SeekFrom::End(0))?;
+        let mut writer = BufWriterWithPosition {
+            position,
+            writer: BufWriter::new(inner),
+        };
+        if position == 0 {
+            write_header(&mut writer)?;
+        }
+        Ok(writer)
+    }
+
+    fn get_position(&self) -> u64 {
+        self.position
+    }
+}
+
+impl BufWriterWithPosition<SegmentFile> {
+    /// flush the buffer and release the space reserved beyond the written data
+    fn seal(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_mut().seal()
+    }
+
+    /// flush the buffer and make the written data durable
+    fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_mut().sync_all()
+    }
+}
+
+impl<T: Write + Seek> Write for BufWriterWithPosition<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.position += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// a struct which records command's metadata
+struct CommandPosition {
+    offset: u64,
+    length: u64,
+    file_number: u64,
+    last_access: AtomicU64,
+    /// when the command was written in milliseconds since the unix epoch, or at the latest
+    written_at: u64,
+    /// the number of patch records read on top of the record of the whole value
+    patches: u32,
+}
+
+impl CommandPosition {
+    fn new(file_number: u64, offset: u64, length: u64, last_access: u64, written_at: u64) -> Self {
+        CommandPosition {
+            offset,
+            length,
+            file_number,
+            last_access: AtomicU64::new(last_access),
+            written_at,
+            patches: 0,
+        }
+    }
+
+    /// the position of a record which is `patches` patch records deep
+    fn with_patches(mut self, patches: u32) -> Self {
+        self.patches = patches;
+        self
+    }
+
+    /// a copy of where the command is, for reading it without the index
+    fn location(&self) -> CommandPosition {
+        CommandPosition::new(
+            self.file_number,
+            self.offset,
+            self.length,
+            0,
+            self.written_at,
+        )
+        .with_patches(self.patches)
+    }
+
+    /// point at the copy of the command made by compaction
+    fn move_to(&mut self, location: &CommandPosition) {
+        self.file_number = location.file_number;
+        self.offset = location.offset;
+        self.length = location.length;
+        self.patches = location.patches;
+    }
+
+    /// whether both point at the same record
+    fn is_at(&self, other: &CommandPosition) -> bool {
+        (self.file_number, self.offset) == (other.file_number, other.offset)
+    }
+
+    /// record a read for the LRU eviction
+    fn touch(&self, access_clock: &AccessClock) {
+        self.last_access
+            .store(access_clock.tick(), Ordering::Relaxed);
+    }
+}
+
+/// a clock which hands out strictly increasing access stamps,
+/// measured in microseconds since the store was opened
+struct AccessClock {
+    opened_at: Instant,
+    last: AtomicU64,
+}
+
+impl AccessClock {
+    fn new() -> Self {
+        AccessClock {
+            opened_at: Instant::now(),
+            last: AtomicU64::new(0),
+        }
+    }
+
+    fn now(&self) -> u64 {
+        self.opened_at.elapsed().as_micros() as u64
+    }
+
+    fn tick(&self) -> u64 {
+        let now = self.now();
+        let last = self
+            .last
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+                Some(cmp::max(last + 1, now))
+            })
+            .unwrap();
+        cmp::max(last + 1, now)
+    }
+}
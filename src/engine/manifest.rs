@@ -0,0 +1,294 @@
+use crate::random::random_u64;
+use crate::{KVStoreError, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const MANIFEST_PREFIX: &str = "MANIFEST-";
+
+/// the newest on-disk format this version of kvs reads and writes:
+/// 1. data files hold JSON records only
+/// 2. data files start with a header stamping their format version
+/// 3. data files may hold JSON patch records
+pub(crate) const FORMAT_VERSION: u32 = 3;
+/// the encoding of the records in the data files
+const CODEC: &str = "json";
+/// the compression of the records in the data files
+const COMPRESSION: &str = "none";
+
+/// the live data files of a KvStore
+///
+/// Compaction makes its new data files durable and writes a new manifest before it deletes
+/// the data files they replace, so recovery can tell the leftovers of an interrupted compaction
+/// from live data. Every manifest goes to a new `MANIFEST-<n>` file, and the previous one is
+/// only deleted once the new one is synced.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    /// the oldest on-disk format of the live data files
+    #[serde(default = "first_format_version")]
+    pub(crate) format_version: u32,
+    /// how the records in the data files are encoded
+    #[serde(default)]
+    pub(crate) options: StoreOptions,
+    /// the data files written by the last compaction
+    pub(crate) compacted_files: Vec<u64>,
+    /// the data files holding the writes after the last compaction one by one
+    pub(crate) log_files: Vec<u64>,
+    /// the sequence number of the last write before the last compaction
+    pub(crate) compacted_sequence: u64,
+    /// the sequence number of the last write when the manifest was written
+    #[serde(default)]
+    pub(crate) last_sequence: u64,
+    /// the changes of the options and namespaces of the store, oldest first
+    #[serde(default)]
+    pub(crate) entries: Vec<ManifestEntry>,
+    /// the UUID the store was given when it was first opened, see `KvStore::instance_id`
+    #[serde(default)]
+    pub(crate) instance_id: Option<String>,
+    /// the instance id of the store whose replicated writes the store accepts, pinned by the
+    /// first replication handshake, see `KvStore::accept_replication_source`
+    #[serde(default)]
+    pub(crate) replication_source: Option<String>,
+}
+
+/// a change of how a store is laid out, recorded so that the store describes itself fully
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ManifestEntry {
+    /// the records of the data files from `first_file` on use `value` for the option
+    SetOption {
+        option: String,
+        value: String,
+        first_file: u64,
+    },
+    /// the logical database was first selected after the write `sequence`
+    CreateNamespace { database: u32, sequence: u64 },
+}
+
+/// the options a store was created with, which every version opening it must support
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct StoreOptions {
+    pub(crate) codec: String,
+    pub(crate) compression: String,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions {
+            codec: CODEC.to_owned(),
+            compression: COMPRESSION.to_owned(),
+        }
+    }
+}
+
+/// the part of a manifest every format version starts with
+#[derive(Deserialize)]
+struct ManifestHeader {
+    #[serde(default = "first_format_version")]
+    format_version: u32,
+}
+
+fn first_format_version() -> u32 {
+    1
+}
+
+impl Manifest {
+    /// a manifest for data files which were not tracked by one, e.g. of a store written before
+    /// manifests existed: files from `first_log_file` on are the log.
+    /// It claims the oldest format until the data files are read.
+    pub(crate) fn untracked(
+        file_numbers: &[u64],
+        first_log_file: u64,
+        compacted_sequence: u64,
+    ) -> Self {
+        let (log_files, compacted_files) = file_numbers
+            .iter()
+            .partition(|file_number| **file_number >= first_log_file);
+        let options = StoreOptions::default();
+        Manifest {
+            format_version: first_format_version(),
+            entries: options.entries(0),
+            options,
+            compacted_files,
+            log_files,
+            compacted_sequence,
+            last_sequence: compacted_sequence,
+            instance_id: None,
+            replication_source: None,
+        }
+    }
+
+    /// the options the records of the data file `file_number` were written with
+    pub(crate) fn options_of(&self, file_number: u64) -> StoreOptions {
+        let mut options = StoreOptions::default();
+        for entry in &self.entries {
+            match entry {
+                ManifestEntry::SetOption {
+                    option,
+                    value,
+                    first_file,
+                } if *first_file <= file_number => match option.as_str() {
+                    "codec" => options.codec = value.clone(),
+                    "compression" => options.compression = value.clone(),
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+        options
+    }
+
+    /// the logical databases created in the store, see `ManifestEntry::CreateNamespace`
+    pub(crate) fn namespaces(&self) -> Vec<u32> {
+        self.entries
+            .iter()
+            .filter_map(|entry| match entry {
+                ManifestEntry::CreateNamespace { database, .. } => Some(*database),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub(crate) fn is_live(&self, file_number: u64) -> bool {
+        self.compacted_files.contains(&file_number) || self.log_files.contains(&file_number)
+    }
+
+    pub(crate) fn first_log_file(&self) -> u64 {
+        match (self.log_files.first(), self.compacted_files.last()) {
+            (Some(log_file), _) => *log_file,
+            (None, Some(compacted_file)) => compacted_file + 1,
+            (None, None) => 0,
+        }
+    }
+
+    /// read the newest complete manifest in `dir`, if any
+    ///
+    /// Return `KVStoreError::IncompatibleFormat` if the store was written by a newer format
+    /// and `KVStoreError::UnsupportedStoreOption` if it uses an option this version lacks.
+    pub(crate) fn load(dir: &Path) -> Result<Option<Self>> {
+        for (_, path) in stored_files(dir)?.iter().rev() {
+            let data = fs::read(path)?;
+            // a crash while writing a manifest leaves the previous one in place
+            let header: ManifestHeader = match serde_json::from_slice(&data) {
+                Ok(header) => header,
+                Err(err) => {
+                    warn!("Skip the incomplete manifest {:?}: {}", path, err);
+                    continue;
+                }
+            };
+            if header.format_version > FORMAT_VERSION {
+                return Err(KVStoreError::IncompatibleFormat(
+                    header.format_version,
+                    FORMAT_VERSION,
+                ));
+            }
+            let mut manifest: Manifest = serde_json::from_slice(&data)?;
+            // manifests written before the entries describe all data files by their options
+            if manifest.entries.is_empty() {
+                manifest.entries = manifest.options.entries(0);
+            }
+            manifest.options.validate()?;
+            for file_number in manifest.compacted_files.iter().chain(&manifest.log_files) {
+                manifest.options_of(*file_number).validate()?;
+            }
+            return Ok(Some(manifest));
+        }
+        Ok(None)
+    }
+
+    /// durably write the manifest to `dir` and delete the older ones
+    pub(crate) fn store(&self, dir: &Path) -> Result<()> {
+        let stored = stored_files(dir)?;
+        let number = stored.last().map(|(number, _)| number + 1).unwrap_or(0);
+        let mut file = File::create(dir.join(format!("{}{}", MANIFEST_PREFIX, number)))?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        sync_dir(dir)?;
+        for (_, path) in stored {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
+
+impl StoreOptions {
+    /// the entries setting the options for the data files from `first_file` on
+    fn entries(&self, first_file: u64) -> Vec<ManifestEntry> {
+        [("codec", &self.codec), ("compression", &self.compression)]
+            .into_iter()
+            .map(|(option, value)| ManifestEntry::SetOption {
+                option: option.to_owned(),
+                value: value.clone(),
+                first_file,
+            })
+            .collect()
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.codec != CODEC {
+            return Err(KVStoreError::UnsupportedStoreOption(
+                "codec".to_owned(),
+                self.codec.clone(),
+            ));
+        }
+        if self.compression != COMPRESSION {
+            return Err(KVStoreError::UnsupportedStoreOption(
+                "compression".to_owned(),
+                self.compression.clone(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// a random version 4 UUID identifying a store
+pub(crate) fn new_instance_id() -> String {
+    let bits = u128::from(random_u64()) << 64 | u128::from(random_u64());
+    // the version and the variant
+    let bits = bits & !(0xf << 76) & !(0x3 << 62) | 0x4 << 76 | 0x2 << 62;
+    let hex = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// make the creation and deletion of files in `dir` durable
+pub(crate) fn sync_dir(dir: &Path) -> Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// the paths of the manifest files in `dir`, oldest first
+pub(crate) fn manifest_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    Ok(stored_files(dir)?
+        .into_iter()
+        .map(|(_, path)| path)
+        .collect())
+}
+
+/// the manifest files in `dir`, oldest first
+fn stored_files(dir: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let mut files: Vec<(u64, PathBuf)> = fs::read_dir(dir)?
+        .flat_map(|res| res.map(|entry| entry.path()))
+        .filter_map(|path| {
+            let number = path
+                .file_name()?
+                .to_str()?
+                .strip_prefix(MANIFEST_PREFIX)?
+                .parse()
+                .ok()?;
+            Some((number, path))
+        })
+        .collect();
+    files.sort_unstable();
+    Ok(files)
+}
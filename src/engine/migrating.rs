@@ -0,0 +1,231 @@
+use super::{KvsReader, KvsWriter};
+use crate::thread_pool::CancellationToken;
+use crate::{KVStoreError, Metric, Result};
+use log::info;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+
+/// the most keys `MigratingEngine::backfill` copies with the writes held back
+const BACKFILL_BATCH: usize = 256;
+/// the most keys `MigratingEngine::backfill` lists of each engine at a time
+const BACKFILL_KEYS: usize = 4096;
+
+/// An engine migrating from an old engine to a new one online, e.g. from `KvStore` to
+/// `SledKvsEngine`. Every write goes to both engines. Reads go to the old engine until
+/// `cutover`, which first copies the keys written before the migration began, and to the
+/// new one after it. The engine read from is written first, so it stays the source of truth.
+/// The cutover is not persisted: once it is done, open the new engine on its own.
+#[derive(Clone)]
+pub struct MigratingEngine<O, N> {
+    old: O,
+    new: N,
+    state: Arc<MigrationState>,
+}
+
+struct MigrationState {
+    cut_over: AtomicBool,
+    /// held for reading by writes and for writing by the backfill, so a key it copies can
+    /// not be written in between
+    writes: RwLock<()>,
+}
+
+impl<O: KvsReader + KvsWriter, N: KvsReader + KvsWriter> MigratingEngine<O, N> {
+    /// start migrating from `old` to `new`, reading from `old`
+    pub fn new(old: O, new: N) -> Self {
+        MigratingEngine {
+            old,
+            new,
+            state: Arc::new(MigrationState {
+                cut_over: AtomicBool::new(false),
+                writes: RwLock::new(()),
+            }),
+        }
+    }
+
+    /// whether reads go to the new engine
+    pub fn is_cut_over(&self) -> bool {
+        self.state.cut_over.load(Ordering::SeqCst)
+    }
+
+    /// Make the keys of the new engine the ones of the old engine, copying the keys written
+    /// before the migration began and removing the ones the old engine does not have.
+    /// The keys of both engines are listed in key order `BACKFILL_KEYS` at a time, so neither
+    /// engine is scanned whole at once, and writes are held back while each batch of keys is
+    /// copied. Return the number of keys copied.
+    pub fn backfill(&self) -> Result<u64> {
+        let mut copied = 0;
+        let mut after: Option<String> = None;
+        loop {
+            let old_keys = self.old.scan_keys(after.as_ref(), BACKFILL_KEYS)?;
+            let new_keys = self.new.scan_keys(after.as_ref(), BACKFILL_KEYS)?;
+            // a full list may end before keys of the other engine, so only the keys up to
+            // the end of both lists are compared
+            let until = [&old_keys, &new_keys]
+                .into_iter()
+                .filter(|keys| keys.len() == BACKFILL_KEYS)
+                .filter_map(|keys| keys.last())
+                .min()
+                .cloned();
+            let listed = |key: &String| until.as_ref().is_none_or(|until| key <= until);
+            let old_keys: Vec<String> = old_keys.into_iter().filter(|key| listed(key)).collect();
+            let stale: Vec<String> = {
+                let old_keys: HashSet<&String> = old_keys.iter().collect();
+                new_keys
+                    .into_iter()
+                    .filter(|key| listed(key) && !old_keys.contains(key))
+                    .collect()
+            };
+
+            for batch in old_keys.chunks(BACKFILL_BATCH) {
+                let _writes = self.state.writes.write().unwrap();
+                for key in batch {
+                    match self.old.get(key.clone())? {
+                        Some(value) => {
+                            self.new.set(key.clone(), value)?;
+                            copied += 1;
+                        }
+                        None => ignore_missing(self.new.remove(key.clone()))?,
+                    }
+                }
+            }
+            for batch in stale.chunks(BACKFILL_BATCH) {
+                let _writes = self.state.writes.write().unwrap();
+                for key in batch {
+                    // written since the keys were listed
+                    if self.old.get(key.clone())?.is_none() {
+                        ignore_missing(self.new.remove(key.clone()))?;
+                    }
+                }
+            }
+
+            match until {
+                Some(until) => after = Some(until),
+                None => break,
+            }
+        }
+        info!("Backfilled {} keys into the new engine", copied);
+        Ok(copied)
+    }
+}
+
+impl<O: KvsReader + KvsWriter, N: KvsReader + KvsWriter> KvsReader for MigratingEngine<O, N> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if self.is_cut_over() {
+            self.new.get(key)
+        } else {
+            self.old.get(key)
+        }
+    }
+
+    fn scan_match(&self, pattern: String) -> Result<Vec<(String, String)>> {
+        if self.is_cut_over() {
+            self.new.scan_match(pattern)
+        } else {
+            self.old.scan_match(pattern)
+        }
+    }
+
+    fn scan_match_cancellable(
+        &self,
+        pattern: String,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<(String, String)>> {
+        if self.is_cut_over() {
+            self.new.scan_match_cancellable(pattern, cancel)
+        } else {
+            self.old.scan_match_cancellable(pattern, cancel)
+        }
+    }
+
+    fn scan_keys(&self, after: Option<&String>, limit: usize) -> Result<Vec<String>> {
+        if self.is_cut_over() {
+            self.new.scan_keys(after, limit)
+        } else {
+            self.old.scan_keys(after, limit)
+        }
+    }
+
+    fn metrics(&self) -> Vec<(String, Metric)> {
+        let mut metrics = if self.is_cut_over() {
+            self.new.metrics()
+        } else {
+            self.old.metrics()
+        };
+        metrics.push((
+            "migration.cut_over".to_owned(),
+            Metric::Gauge(self.is_cut_over() as u64 as f64),
+        ));
+        metrics
+    }
+
+    /// the id of the store the keys migrate to, which keeps serving them afterwards
+    fn instance_id(&self) -> Option<String> {
+        self.new.instance_id()
+    }
+
+    /// the source the store the keys migrate to accepts, as it carries the instance id
+    fn replication_source(&self) -> Option<String> {
+        self.new.replication_source()
+    }
+}
+
+impl<O: KvsReader + KvsWriter, N: KvsReader + KvsWriter> KvsWriter for MigratingEngine<O, N> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let _writes = self.state.writes.read().unwrap();
+        if self.is_cut_over() {
+            self.new.set(key.clone(), value.clone())?;
+            self.old.set(key, value)
+        } else {
+            self.old.set(key.clone(), value.clone())?;
+            self.new.set(key, value)
+        }
+    }
+
+    /// remove a key of the engine read from, and of the other one if it has it
+    fn remove(&self, key: String) -> Result<()> {
+        let _writes = self.state.writes.read().unwrap();
+        if self.is_cut_over() {
+            self.new.remove(key.clone())?;
+            ignore_missing(self.old.remove(key))
+        } else {
+            self.old.remove(key.clone())?;
+            // the key may have been written before the migration and not be backfilled yet
+            ignore_missing(self.new.remove(key))
+        }
+    }
+
+    fn create_namespace(&self, database: u32) -> Result<()> {
+        self.old.create_namespace(database)?;
+        self.new.create_namespace(database)
+    }
+
+    fn accept_replication_source(&self, source: &str) -> Result<()> {
+        self.new.accept_replication_source(source)
+    }
+
+    /// backfill the new engine and read from it from now on; writes still go to both
+    fn cutover(&self) -> Result<()> {
+        if self.is_cut_over() {
+            return Ok(());
+        }
+        self.backfill()?;
+        let _writes = self.state.writes.write().unwrap();
+        self.state.cut_over.store(true, Ordering::SeqCst);
+        info!("Cut over to the new engine");
+        Ok(())
+    }
+
+    fn close(self) -> Result<()> {
+        self.old.close()?;
+        self.new.close()
+    }
+}
+
+/// a removal which succeeded or found no key to remove
+fn ignore_missing(result: Result<()>) -> Result<()> {
+    match result {
+        Err(KVStoreError::KeyNotFound) => Ok(()),
+        result => result,
+    }
+}
@@ -1,23 +1,188 @@
-use crate::Result;
+use crate::thread_pool::CancellationToken;
+use crate::{KVStoreError, Metric, Result};
 use serde::{Deserialize, Serialize};
 
+mod archive;
+mod backup;
+mod direct;
+mod dirs;
+mod glob;
+mod histogram;
 mod kv;
+mod manifest;
+mod migrating;
+mod namespace;
+mod s3;
+mod segment;
 mod sled;
+mod typed;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+mod verify;
 
-pub use self::kv::KvStore;
+pub use self::archive::ArchiveStats;
+pub use self::backup::{BackupFile, BackupManifest, BackupTarget, DirBackupTarget};
+pub use self::dirs::PlacementPolicy;
+pub use self::histogram::SizeHistogram;
+pub use self::kv::{
+    CompactionDecision, CompactionFilter, CompactionPolicy, KeyPrefix, KvStore, KvStoreBuilder,
+    KvStoreIter, KvStoreStats, PrefixStats, QuotaPolicy, RangeSize, SnapshotEngine, TierStats,
+    TrimReport,
+};
+pub use self::migrating::MigratingEngine;
+pub(crate) use self::namespace::Namespace;
+pub use self::s3::S3BackupTarget;
 pub use self::sled::SledKvsEngine;
+pub use self::typed::TypedEngine;
+pub use self::verify::{VerifyProblem, VerifyReport};
 
-/// A trait which supports pluggable storage engines
-pub trait KvsEngine: Clone + Send + 'static {
-    /// Set the value of a string key to a string.
-    /// Return an error if the value is not written successfully.
-    fn set(&self, key: String, value: String) -> Result<()>;
-    /// Get the string value of a string key. If the key does not exist, return None.
+/// The reads of a pluggable storage engine, of string keys and values unless it stores
+/// other types like `TypedEngine`
+pub trait KvsReader<K = String, V = String>: Clone + Send + 'static {
+    /// Get the value of a key. If the key does not exist, return None.
     /// Return an error if the value is not read successfully.
-    fn get(&self, key: String) -> Result<Option<String>>;
-    /// Remove a given string key.
+    fn get(&self, key: K) -> Result<Option<V>>;
+    /// Return all key/value pairs whose key matches a glob pattern like `user:*:profile`, ordered by key.
+    /// `*` matches any sequence, `?` matches one character and `[a-z]` matches a character class.
+    fn scan_match(&self, pattern: String) -> Result<Vec<(K, V)>>;
+    /// Like `scan_match`, but stop with the error of `CancellationToken::check` once `cancel`
+    /// is cancelled or expired.
+    /// Engines which can not stop a scan midway check the token before and after it.
+    fn scan_match_cancellable(
+        &self,
+        pattern: String,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<(K, V)>> {
+        cancel.check()?;
+        let pairs = self.scan_match(pattern)?;
+        cancel.check()?;
+        Ok(pairs)
+    }
+    /// Return up to `limit` keys ordered after `after`, or from the first key if it is None,
+    /// so all keys can be visited in bounded batches.
+    /// Engines which can not list their keys alone scan all key/value pairs for each batch.
+    fn scan_keys(&self, after: Option<&K>, limit: usize) -> Result<Vec<K>>
+    where
+        K: Ord,
+    {
+        Ok(self
+            .scan_match("*".to_owned())?
+            .into_iter()
+            .map(|(key, _)| key)
+            .filter(|key| after.is_none_or(|after| key > after))
+            .take(limit)
+            .collect())
+    }
+    /// Return the metrics of the engine, e.g. for `StatsdSink`.
+    fn metrics(&self) -> Vec<(String, Metric)> {
+        Vec::new()
+    }
+    /// Return the persistent id of the store, see `KvStore::instance_id`, or None for engines
+    /// which do not identify their stores.
+    fn instance_id(&self) -> Option<String> {
+        None
+    }
+    /// Return the instance id of the store whose replicated writes the store accepts, see
+    /// `KvStore::accept_replication_source`, or None if it accepted none yet or the engine
+    /// does not record it.
+    fn replication_source(&self) -> Option<String> {
+        None
+    }
+}
+
+/// The writes of a pluggable storage engine, of string keys and values unless it stores
+/// other types like `TypedEngine`
+pub trait KvsWriter<K = String, V = String>: Clone + Send + 'static {
+    /// Set the value of a key.
+    /// Return an error if the value is not written successfully.
+    fn set(&self, key: K, value: V) -> Result<()>;
+    /// Remove a given key.
     /// Return an error if the key does not exit or value is not read successfully.
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove(&self, key: K) -> Result<()>;
+    /// Set the value of a key to the JSON `document`, which is its JSON document before with
+    /// the field at the JSON pointer `pointer` set to the JSON text `field`. Engines which
+    /// can store just the change do, others set the whole document.
+    fn set_json_field(&self, key: K, document: V, pointer: &str, field: &str) -> Result<()> {
+        let _ = (pointer, field);
+        self.set(key, document)
+    }
+    /// Record that the logical database `database` is in use, for engines which describe
+    /// their databases in their own metadata. Others accept it as it is.
+    fn create_namespace(&self, database: u32) -> Result<()> {
+        let _ = database;
+        Ok(())
+    }
+    /// Accept the writes replicated from the store with the instance id `source` from now on,
+    /// failing with `KVStoreError::UnrelatedStore` if the store accepts another one, see
+    /// `KvStore::accept_replication_source`. Engines which do not record it accept any store.
+    fn accept_replication_source(&self, source: &str) -> Result<()> {
+        let _ = source;
+        Ok(())
+    }
+    /// Switch the reads of an engine migrating to another one over to the new engine, see
+    /// `MigratingEngine`. Other engines fail with `KVStoreError::NotMigrating`.
+    fn cutover(&self) -> Result<()> {
+        Err(KVStoreError::NotMigrating)
+    }
+    /// Close the engine once it is written no more, e.g. when the server stops, see
+    /// `KvStore::close`. Engines which need no clean close accept it as it is.
+    fn close(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A pluggable storage engine which supports both reads and writes
+pub trait KvsEngine<K = String, V = String>: KvsReader<K, V> + KvsWriter<K, V> {}
+
+impl<K, V, T: KvsReader<K, V> + KvsWriter<K, V>> KvsEngine<K, V> for T {}
+
+/// An engine which only exposes the reads of another one, e.g. to hand to code which
+/// must not write
+#[derive(Clone)]
+pub struct ReadOnlyEngine<E>(E);
+
+impl<E> ReadOnlyEngine<E> {
+    /// wrap an engine, hiding its writes
+    pub fn new(engine: E) -> Self {
+        ReadOnlyEngine(engine)
+    }
+}
+
+impl<K, V, E: KvsReader<K, V>> KvsReader<K, V> for ReadOnlyEngine<E> {
+    fn get(&self, key: K) -> Result<Option<V>> {
+        self.0.get(key)
+    }
+
+    fn scan_match(&self, pattern: String) -> Result<Vec<(K, V)>> {
+        self.0.scan_match(pattern)
+    }
+
+    fn scan_match_cancellable(
+        &self,
+        pattern: String,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<(K, V)>> {
+        self.0.scan_match_cancellable(pattern, cancel)
+    }
+
+    fn scan_keys(&self, after: Option<&K>, limit: usize) -> Result<Vec<K>>
+    where
+        K: Ord,
+    {
+        self.0.scan_keys(after, limit)
+    }
+
+    fn metrics(&self) -> Vec<(String, Metric)> {
+        self.0.metrics()
+    }
+
+    fn instance_id(&self) -> Option<String> {
+        self.0.instance_id()
+    }
+
+    fn replication_source(&self) -> Option<String> {
+        self.0.replication_source()
+    }
 }
 
 /// a struct which supports serialization and deserialization
@@ -27,4 +192,22 @@ pub enum Command {
     SET(String, String),
     /// for rm command
     RM(String),
-}
\ No newline at end of file
+    /// for rm command with a trash: the key was removed at the given time in milliseconds
+    /// since the unix epoch, but its last value is kept until the trash retention expires
+    TRASH(String, u64),
+    /// for json_set command on a JSON document: the field at a JSON pointer of the document
+    /// written at (file number, offset, length) is set to the given JSON text
+    PATCH(String, String, String, (u64, u64, u64)),
+}
+
+impl Command {
+    /// the key the command writes
+    pub(crate) fn key(&self) -> &str {
+        match self {
+            Command::SET(key, _)
+            | Command::RM(key)
+            | Command::TRASH(key, _)
+            | Command::PATCH(key, ..) => key,
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use crate::{KVStoreError, Result};
+
+/// the character the stored keys of databases other than 0 start with
+const NAMESPACE_MARKER: char = '\u{0}';
+
+/// A logical database of a store. Database 0 stores its keys as they are, so stores written
+/// before databases existed keep their keys there. The keys of database `n` are stored as
+/// `\u{0}n:key`, which no key of database 0 may start with.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Namespace {
+    database: u32,
+    prefix: String,
+}
+
+impl Namespace {
+    pub(crate) fn new(database: u32) -> Self {
+        let prefix = match database {
+            0 => String::new(),
+            database => format!("{}{}:", NAMESPACE_MARKER, database),
+        };
+        Namespace { database, prefix }
+    }
+
+    pub(crate) fn database(&self) -> u32 {
+        self.database
+    }
+
+    /// the prefix of the stored keys of the database, empty for database 0
+    pub(crate) fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// the stored key of `key`
+    pub(crate) fn encode(&self, key: &str) -> Result<String> {
+        if key.starts_with(NAMESPACE_MARKER) {
+            return Err(KVStoreError::ReservedKey);
+        }
+        Ok(format!("{}{}", self.prefix, key))
+    }
+
+    /// the glob pattern matching the stored keys of the keys `pattern` matches
+    pub(crate) fn encode_pattern(&self, pattern: &str) -> String {
+        format!("{}{}", self.prefix, pattern)
+    }
+
+    /// the key of a stored key of any database
+    pub(crate) fn key_of(stored: &str) -> &str {
+        match stored.strip_prefix(NAMESPACE_MARKER) {
+            Some(rest) => rest.split_once(':').map_or(rest, |(_, key)| key),
+            None => stored,
+        }
+    }
+
+    /// the key of a stored key, or None if it belongs to another database
+    pub(crate) fn decode<'a>(&self, stored: &'a str) -> Option<&'a str> {
+        if self.database == 0 {
+            return (!stored.starts_with(NAMESPACE_MARKER)).then_some(stored);
+        }
+        stored.strip_prefix(self.prefix.as_str())
+    }
+}
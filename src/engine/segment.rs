@@ -0,0 +1,58 @@
+use super::manifest::FORMAT_VERSION;
+use crate::{KVStoreError, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// the start of every data file written in format version 2 or later
+const HEADER_PREFIX: &[u8] = b"{\"format_version\":";
+/// the most bytes a header takes
+const MAX_HEADER_SIZE: usize = 64;
+
+/// the header which stamps a data file with the format of its records
+///
+/// Data files of format version 1 have no header and start with their first record.
+#[derive(Serialize, Deserialize)]
+struct SegmentHeader {
+    format_version: u32,
+}
+
+/// write the header of the current format to an empty data file
+pub(crate) fn write_header<W: Write>(writer: &mut W) -> Result<()> {
+    serde_json::to_writer(
+        writer,
+        &SegmentHeader {
+            format_version: FORMAT_VERSION,
+        },
+    )?;
+    Ok(())
+}
+
+/// read the format version of a data file and leave `reader` at the offset of its first record,
+/// which is returned as well
+pub(crate) fn read_header<R: Read + Seek>(reader: &mut R) -> Result<(u32, u64)> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut buf = Vec::with_capacity(MAX_HEADER_SIZE);
+    reader
+        .by_ref()
+        .take(MAX_HEADER_SIZE as u64)
+        .read_to_end(&mut buf)?;
+
+    let (format_version, start) = if buf.starts_with(HEADER_PREFIX) {
+        let mut iter = Deserializer::from_slice(&buf).into_iter::<SegmentHeader>();
+        let header = iter.next().ok_or_else(|| {
+            KVStoreError::CommonStringError("truncated data file header".to_owned())
+        })??;
+        (header.format_version, iter.byte_offset() as u64)
+    } else {
+        (1, 0)
+    };
+    if format_version > FORMAT_VERSION {
+        return Err(KVStoreError::IncompatibleFormat(
+            format_version,
+            FORMAT_VERSION,
+        ));
+    }
+    reader.seek(SeekFrom::Start(start))?;
+    Ok((format_version, start))
+}
@@ -1,5 +1,8 @@
-use crate::{KVStoreError, KvsEngine, Result};
+use super::glob::GlobPattern;
+use crate::thread_pool::CancellationToken;
+use crate::{KVStoreError, KvsReader, KvsWriter, Result};
 use sled::Db;
+use std::ops::Bound;
 use std::path::PathBuf;
 
 /** A KvStore stores key/value pairs using sled.
@@ -7,7 +10,7 @@ use std::path::PathBuf;
 ```
 use std::env;
 use kvs::{SledKvsEngine, Result};
-use crate::kvs::KvsEngine;
+use crate::kvs::{KvsReader, KvsWriter};
 
 fn try_main() -> Result<()> {
     let mut store = SledKvsEngine::open(env::current_dir()?)?;
@@ -33,7 +36,7 @@ impl SledKvsEngine {
     }
 }
 
-impl KvsEngine for SledKvsEngine {
+impl KvsWriter for SledKvsEngine {
     /// Set the value of a string key to a string. Return an error if the value is not written successfully.
     fn set(&self, key: String, value: String) -> Result<()> {
         self.inner.insert(key, value.into_bytes())?;
@@ -41,6 +44,21 @@ impl KvsEngine for SledKvsEngine {
         Ok(())
     }
 
+    /// Remove a given key. Return an error if the key does not exist or is not removed successfully.
+    fn remove(&self, key: String) -> Result<()> {
+        self.inner.remove(key)?.ok_or(KVStoreError::KeyNotFound)?;
+        self.inner.flush()?;
+        Ok(())
+    }
+
+    /// Flush the writes sled has not made durable yet.
+    fn close(self) -> Result<()> {
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+impl KvsReader for SledKvsEngine {
     /// Get the string value of a string key. If the key does not exist, return None. Return an error if the value is not read successfully.
     fn get(&self, key: String) -> Result<Option<String>> {
         Ok(self
@@ -51,10 +69,43 @@ impl KvsEngine for SledKvsEngine {
             .transpose()?)
     }
 
-    /// Remove a given key. Return an error if the key does not exist or is not removed successfully.
-    fn remove(&self, key: String) -> Result<()> {
-        self.inner.remove(key)?.ok_or(KVStoreError::KeyNotFound)?;
-        self.inner.flush()?;
-        Ok(())
+    /// Return all key/value pairs whose key matches a glob pattern, ordered by key.
+    fn scan_match(&self, pattern: String) -> Result<Vec<(String, String)>> {
+        self.scan_match_cancellable(pattern, &CancellationToken::new())
     }
-}
\ No newline at end of file
+
+    /// Return all key/value pairs whose key matches a glob pattern, ordered by key,
+    /// checking `cancel` before each key.
+    fn scan_match_cancellable(
+        &self,
+        pattern: String,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<(String, String)>> {
+        let pattern = GlobPattern::new(&pattern);
+        let mut pairs = Vec::new();
+        for item in self.inner.scan_prefix(pattern.prefix()) {
+            cancel.check()?;
+            let (key, value) = item?;
+            let key = String::from_utf8(key.to_vec())?;
+            if pattern.matches(&key) {
+                pairs.push((key, String::from_utf8(value.to_vec())?));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// Return up to `limit` keys ordered after `after` without reading their values.
+    fn scan_keys(&self, after: Option<&String>, limit: usize) -> Result<Vec<String>> {
+        let iter = match after {
+            Some(after) => self
+                .inner
+                .range::<&[u8], _>((Bound::Excluded(after.as_bytes()), Bound::Unbounded)),
+            None => self.inner.iter(),
+        };
+        let mut keys = Vec::new();
+        for key in iter.keys().take(limit) {
+            keys.push(String::from_utf8(key?.to_vec())?);
+        }
+        Ok(keys)
+    }
+}
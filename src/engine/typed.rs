@@ -0,0 +1,144 @@
+use super::{KvsReader, KvsWriter};
+use crate::thread_pool::CancellationToken;
+use crate::{Metric, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::any::{Any, TypeId};
+use std::marker::PhantomData;
+
+/// An engine which stores keys of type `K` and values of type `V` in an engine of strings,
+/// as JSON unless they are strings themselves, so existing string keys and values stay
+/// readable. Patterns of `scan_match` match the stored form of the keys, e.g. `"user:*`
+/// for keys serialized as JSON strings.
+pub struct TypedEngine<E, K, V> {
+    engine: E,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<E, K, V> TypedEngine<E, K, V> {
+    /// store typed keys and values in an engine of strings
+    pub fn new(engine: E) -> Self {
+        TypedEngine {
+            engine,
+            types: PhantomData,
+        }
+    }
+
+    /// the engine the keys and values are stored in
+    pub fn inner(&self) -> &E {
+        &self.engine
+    }
+}
+
+impl<E: Clone, K, V> Clone for TypedEngine<E, K, V> {
+    fn clone(&self) -> Self {
+        TypedEngine::new(self.engine.clone())
+    }
+}
+
+impl<E, K, V> KvsReader<K, V> for TypedEngine<E, K, V>
+where
+    E: KvsReader,
+    K: Serialize + DeserializeOwned + 'static,
+    V: Serialize + DeserializeOwned + 'static,
+{
+    fn get(&self, key: K) -> Result<Option<V>> {
+        self.engine.get(encode(&key)?)?.map(decode).transpose()
+    }
+
+    fn scan_match(&self, pattern: String) -> Result<Vec<(K, V)>> {
+        decode_pairs(self.engine.scan_match(pattern)?)
+    }
+
+    fn scan_match_cancellable(
+        &self,
+        pattern: String,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<(K, V)>> {
+        decode_pairs(self.engine.scan_match_cancellable(pattern, cancel)?)
+    }
+
+    /// Return up to `limit` keys ordered by their stored form after the one of `after`.
+    fn scan_keys(&self, after: Option<&K>, limit: usize) -> Result<Vec<K>>
+    where
+        K: Ord,
+    {
+        let after = after.map(encode).transpose()?;
+        self.engine
+            .scan_keys(after.as_ref(), limit)?
+            .into_iter()
+            .map(decode)
+            .collect()
+    }
+
+    fn metrics(&self) -> Vec<(String, Metric)> {
+        self.engine.metrics()
+    }
+
+    fn instance_id(&self) -> Option<String> {
+        self.engine.instance_id()
+    }
+
+    fn replication_source(&self) -> Option<String> {
+        self.engine.replication_source()
+    }
+}
+
+impl<E, K, V> KvsWriter<K, V> for TypedEngine<E, K, V>
+where
+    E: KvsWriter,
+    K: Serialize + DeserializeOwned + 'static,
+    V: Serialize + DeserializeOwned + 'static,
+{
+    fn set(&self, key: K, value: V) -> Result<()> {
+        self.engine.set(encode(&key)?, encode(&value)?)
+    }
+
+    fn remove(&self, key: K) -> Result<()> {
+        self.engine.remove(encode(&key)?)
+    }
+
+    fn create_namespace(&self, database: u32) -> Result<()> {
+        self.engine.create_namespace(database)
+    }
+
+    fn accept_replication_source(&self, source: &str) -> Result<()> {
+        self.engine.accept_replication_source(source)
+    }
+
+    fn cutover(&self) -> Result<()> {
+        self.engine.cutover()
+    }
+
+    fn close(self) -> Result<()> {
+        self.engine.close()
+    }
+}
+
+/// the stored form of a key or value, the string itself for strings
+fn encode<T: Serialize + 'static>(value: &T) -> Result<String> {
+    match (value as &dyn Any).downcast_ref::<String>() {
+        Some(text) => Ok(text.clone()),
+        None => Ok(serde_json::to_string(value)?),
+    }
+}
+
+/// the key or value of a stored form written by `encode`
+fn decode<T: DeserializeOwned + 'static>(text: String) -> Result<T> {
+    if TypeId::of::<T>() != TypeId::of::<String>() {
+        return Ok(serde_json::from_str(&text)?);
+    }
+    let text: Box<dyn Any> = Box::new(text);
+    Ok(*text.downcast::<T>().expect("T is String"))
+}
+
+fn decode_pairs<K, V>(pairs: Vec<(String, String)>) -> Result<Vec<(K, V)>>
+where
+    K: DeserializeOwned + 'static,
+    V: DeserializeOwned + 'static,
+{
+    pairs
+        .into_iter()
+        .map(|(key, value)| Ok((decode(key)?, decode(value)?)))
+        .collect()
+}
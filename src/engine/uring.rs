@@ -0,0 +1,57 @@
+use io_uring::{opcode, types, IoUring};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+/// the most reads submitted to the ring at once
+const RING_SIZE: u32 = 256;
+
+/// Read `length` bytes at `offset` of each file with io_uring, submitting the reads in batches
+/// so that the device can serve them in parallel. Return an error if io_uring is not available.
+pub(crate) fn read_batch(reads: &[(&File, u64, u64)]) -> io::Result<Vec<Vec<u8>>> {
+    let mut ring = IoUring::new(RING_SIZE)?;
+    let mut buffers: Vec<Vec<u8>> = reads
+        .iter()
+        .map(|(_, _, length)| vec![0; *length as usize])
+        .collect();
+
+    for start in (0..reads.len()).step_by(RING_SIZE as usize) {
+        let end = reads.len().min(start + RING_SIZE as usize);
+        for (index, (file, offset, _)) in reads.iter().enumerate().take(end).skip(start) {
+            let buffer = &mut buffers[index];
+            let entry = opcode::Read::new(
+                types::Fd(file.as_raw_fd()),
+                buffer.as_mut_ptr(),
+                buffer.len() as u32,
+            )
+            .offset(*offset)
+            .build()
+            .user_data(index as u64);
+            // SAFETY: the buffer and the file outlive the read, which completes before returning
+            unsafe {
+                ring.submission()
+                    .push(&entry)
+                    .expect("the ring has room for a batch");
+            }
+        }
+        ring.submit_and_wait(end - start)?;
+
+        let completions: Vec<(usize, i32)> = ring
+            .completion()
+            .map(|entry| (entry.user_data() as usize, entry.result()))
+            .collect();
+        for (index, result) in completions {
+            if result < 0 {
+                return Err(io::Error::from_raw_os_error(-result));
+            }
+            // finish short reads with a plain read
+            let read = result as usize;
+            let (file, offset, _) = reads[index];
+            if read < buffers[index].len() {
+                file.read_exact_at(&mut buffers[index][read..], offset + read as u64)?;
+            }
+        }
+    }
+    Ok(buffers)
+}
@@ -0,0 +1,242 @@
+use super::dirs::DataDirs;
+use super::manifest::{self, Manifest};
+use super::segment::read_header;
+use crate::{Command, Result};
+use serde::Serialize;
+use serde_json::Deserializer;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+/// The result of `KvStoreBuilder::verify`, serialized as JSON by `kvs verify`
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// number of live data files read
+    pub data_files: u64,
+    /// number of records decoded from the live data files
+    pub records: u64,
+    /// number of live keys in the index rebuilt from the records
+    pub live_keys: u64,
+    /// everything found wrong, empty if the store is sound
+    pub problems: Vec<VerifyProblem>,
+}
+
+impl VerifyReport {
+    /// whether no problem was found
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// A problem found by `KvStoreBuilder::verify`
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum VerifyProblem {
+    /// the newest complete manifest can not be loaded, e.g. it uses an unsupported option
+    BadManifest {
+        /// why it can not be loaded
+        reason: String,
+    },
+    /// a manifest file which is not the one the store is opened with, left by a crash
+    /// while the manifest was replaced
+    DanglingManifest {
+        /// the path of the manifest file
+        path: PathBuf,
+    },
+    /// a data file the manifest lists as live does not exist
+    MissingFile {
+        /// the number of the data file
+        file: u64,
+    },
+    /// a data file the manifest does not list, left by an interrupted compaction
+    DanglingFile {
+        /// the number of the data file
+        file: u64,
+    },
+    /// a data file written in an older format than the manifest claims for all live files
+    FormatMismatch {
+        /// the number of the data file
+        file: u64,
+        /// the format version of the data file
+        format_version: u32,
+        /// the format version of the manifest
+        manifest_version: u32,
+    },
+    /// a record which does not decode; the records after it in the same file are not read
+    BadRecord {
+        /// the number of the data file
+        file: u64,
+        /// the offset of the record in the data file
+        offset: u64,
+        /// why it does not decode
+        reason: String,
+    },
+    /// an entry of the rebuilt index which does not read back as the record of its key
+    BadIndexEntry {
+        /// the key of the entry
+        key: String,
+        /// the number of the data file the entry points at
+        file: u64,
+        /// the offset the entry points at
+        offset: u64,
+    },
+    /// the data files hold fewer writes than the manifest recorded when it was written
+    LostWrites {
+        /// the sequence number of the last write recorded by the manifest
+        expected: u64,
+        /// the sequence number of the last write in the data files
+        found: u64,
+    },
+}
+
+/// validate the store in `dirs` without opening it, see `KvStoreBuilder::verify`
+pub(crate) fn verify(dirs: &DataDirs) -> Result<VerifyReport> {
+    let mut report = VerifyReport::default();
+    let manifest = match Manifest::load(dirs.primary()) {
+        Ok(manifest) => manifest,
+        Err(err) => {
+            report.problems.push(VerifyProblem::BadManifest {
+                reason: err.to_string(),
+            });
+            return Ok(report);
+        }
+    };
+    let file_numbers = dirs.file_numbers();
+    let live_files = match &manifest {
+        Some(manifest) => {
+            let manifest_files = manifest::manifest_files(dirs.primary())?;
+            // the loaded manifest is the newest one which decodes
+            let current = manifest_files
+                .iter()
+                .rposition(|path| {
+                    fs::read(path)
+                        .ok()
+                        .and_then(|data| serde_json::from_slice::<Manifest>(&data).ok())
+                        .is_some()
+                })
+                .unwrap_or(0);
+            for (index, path) in manifest_files.into_iter().enumerate() {
+                if index != current {
+                    report
+                        .problems
+                        .push(VerifyProblem::DanglingManifest { path });
+                }
+            }
+            let mut live_files: Vec<u64> = manifest
+                .compacted_files
+                .iter()
+                .chain(&manifest.log_files)
+                .copied()
+                .collect();
+            live_files.sort_unstable();
+            for file in &live_files {
+                // the log file of a new store is only created once it is opened
+                if !file_numbers.contains(file) && Some(file) != manifest.log_files.last() {
+                    report
+                        .problems
+                        .push(VerifyProblem::MissingFile { file: *file });
+                }
+            }
+            for file in &file_numbers {
+                if !manifest.is_live(*file) {
+                    report
+                        .problems
+                        .push(VerifyProblem::DanglingFile { file: *file });
+                }
+            }
+            live_files.retain(|file| file_numbers.contains(file));
+            live_files
+        }
+        // all data files of a store without a manifest are live
+        None => file_numbers,
+    };
+
+    let mut index = HashMap::new();
+    let mut log_writes = 0;
+    for file in &live_files {
+        let mut reader = BufReader::new(File::open(dirs.file_path(*file))?);
+        report.data_files += 1;
+        let (format_version, start) = match read_header(&mut reader) {
+            Ok(header) => header,
+            Err(err) => {
+                report.problems.push(VerifyProblem::BadRecord {
+                    file: *file,
+                    offset: 0,
+                    reason: err.to_string(),
+                });
+                continue;
+            }
+        };
+        if let Some(manifest) = manifest.as_ref() {
+            if format_version < manifest.format_version {
+                report.problems.push(VerifyProblem::FormatMismatch {
+                    file: *file,
+                    format_version,
+                    manifest_version: manifest.format_version,
+                });
+            }
+        }
+        let is_log = manifest
+            .as_ref()
+            .is_some_and(|manifest| manifest.log_files.contains(file));
+        let mut iter = Deserializer::from_reader(reader).into_iter::<Command>();
+        let mut before_offset = start;
+        while let Some(command) = iter.next() {
+            let after_offset = start + iter.byte_offset() as u64;
+            match command {
+                Ok(Command::SET(key, _)) | Ok(Command::PATCH(key, ..)) => {
+                    index.insert(key, (*file, before_offset, after_offset - before_offset));
+                }
+                Ok(Command::RM(key)) | Ok(Command::TRASH(key, _)) => {
+                    index.remove(&key);
+                }
+                Err(err) => {
+                    report.problems.push(VerifyProblem::BadRecord {
+                        file: *file,
+                        offset: before_offset,
+                        reason: err.to_string(),
+                    });
+                    break;
+                }
+            }
+            report.records += 1;
+            if is_log {
+                log_writes += 1;
+            }
+            before_offset = after_offset;
+        }
+    }
+    report.live_keys = index.len() as u64;
+
+    let mut readers = HashMap::new();
+    let mut entries: Vec<_> = index.into_iter().collect();
+    entries.sort_unstable();
+    for (key, (file, offset, length)) in entries {
+        let reader = match readers.entry(file) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(BufReader::new(File::open(dirs.file_path(file))?)),
+        };
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::with_capacity(length as usize);
+        reader.take(length).read_to_end(&mut data)?;
+        match serde_json::from_slice(&data) {
+            Ok(Command::SET(found, _)) | Ok(Command::PATCH(found, ..)) if found == key => {}
+            _ => report
+                .problems
+                .push(VerifyProblem::BadIndexEntry { key, file, offset }),
+        }
+    }
+
+    if let Some(manifest) = manifest {
+        let found = manifest.compacted_sequence + log_writes;
+        if found < manifest.last_sequence {
+            report.problems.push(VerifyProblem::LostWrites {
+                expected: manifest.last_sequence,
+                found,
+            });
+        }
+    }
+    Ok(report)
+}
@@ -1,51 +1,554 @@
-use failure::Fail;
+use crate::RemoteError;
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
 use std::{io, string};
 
 /// well-defined Result
 pub type Result<T> = std::result::Result<T, KVStoreError>;
 
-#[derive(Fail, Debug)]
-/// well-defined Error
+/** well-defined Error
+
+Every variant has a stable numeric `code`, which never changes once released, so clients and
+logs can tell errors apart without parsing their messages. Errors may carry where they
+happened, attached with `ResultExt::context`.
+# Example
+```
+use kvs::{ErrorContext, KVStoreError, ResultExt};
+
+let err = Err::<(), _>(KVStoreError::KeyNotFound)
+    .context(ErrorContext::new("read").key("user:1"))
+    .unwrap_err();
+assert_eq!(err.code(), 2001);
+assert!(matches!(err.root(), KVStoreError::KeyNotFound));
+assert_eq!(err.to_string(), "read of key user:1: Key not found");
+```
+ */
+#[derive(Debug)]
 pub enum KVStoreError {
     /// Io error
-    #[fail(display = "{}", _0)]
-    Io(#[cause] io::Error),
+    Io(io::Error),
 
     /// Serde error
-    #[fail(display = "{}", _0)]
-    Serde(#[cause] serde_json::Error),
+    Serde(serde_json::Error),
 
     /// Sled error
-    #[fail(display = "{}", _0)]
-    Sled(#[cause] sled::Error),
+    Sled(sled::Error),
 
     /// FromUtf8 Error
-    #[fail(display = "Utf8 Error {}", _0)]
-    Utf8Error(#[cause] string::FromUtf8Error),
+    Utf8Error(string::FromUtf8Error),
 
     /// Build ThreadPool Error
-    #[fail(display = "Build ThreadPool Error {}", _0)]
-    ThreadPoolBuildError(#[cause] rayon::ThreadPoolBuildError),
+    ThreadPoolBuildError(rayon::ThreadPoolBuildError),
 
     /// Key not found error
-    #[fail(display = "Key not found")]
     KeyNotFound,
 
+    /// Key already exists error, for writes which only add keys
+    KeyExists,
+
+    /// Disk quota exceeded error
+    QuotaExceeded,
+
     /// Unknown command type error
-    #[fail(display = "Unknown command type")]
     UnknownCommandType,
 
     /// Unknown engine type error
-    #[fail(display = "Unknown engine type")]
     UnknownEngineType,
 
     /// Unknown engine type error
-    #[fail(display = "Change engine after initialization")]
     ChangeEngineError,
 
+    /// Watch from a revision the server has not reached yet, e.g. after the server restarted
+    FutureRevision(u64, u64),
+
+    /// Watch from a revision whose events the server no longer retains
+    CompactedRevision(u64, u64),
+
+    /// A transaction reads or writes a key written by another client since it began
+    TransactionConflict(String),
+
+    /// Other writers held a key longer than a write waits to lock it
+    LockTimeout(String),
+
+    /// Commit or roll back a transaction on a connection without one
+    NoTransaction,
+
+    /// Begin a transaction on a connection which already has one
+    TransactionInProgress,
+
+    /// A job stopped because its `CancellationToken` was cancelled
+    Cancelled,
+
+    /// A request or job did not finish before its deadline, see `Request::DEADLINE`
+    DeadlineExceeded,
+
+    /// A request shed because the server is overloaded
+    Overloaded,
+
+    /// A request with a key another shard of the cluster serves, carrying its address
+    Moved(String),
+
+    /// A request waited in vain for the server to apply the writes of its consistency token,
+    /// see `Client::read_your_writes`
+    NotCaughtUp(u64, u64),
+
+    /// A write sent to a read-only replica, carrying the address of its leader
+    ReadOnlyReplica(String),
+
+    /// A write sent to a leader which holds no lease from its follower, or to a promoted
+    /// follower waiting for the lease of the old leader to expire, see `KvServer::leader_lease`
+    LeaseExpired,
+
+    /// A request of a leader fenced off by the promotion of its follower, carrying the epoch of
+    /// the promotion
+    Fenced(u64),
+
+    /// Cut over an engine which is not migrating to another one, see `MigratingEngine`
+    NotMigrating,
+
+    /// Keys moved between shards which differ between them after the copy, see `Reshard`
+    ShardMismatch(u64),
+
+    /// Backup not found error
+    BackupNotFound(String),
+
+    /// No backup retains the writes up to a sequence number
+    SequenceNotRetained(u64),
+
+    /// The store was written by a newer, incompatible on-disk format
+    IncompatibleFormat(u32, u32),
+
+    /// The store uses an option this version of kvs does not support
+    UnsupportedStoreOption(String, String),
+
+    /// A record does not match its index entry, found by `KvStoreBuilder::paranoid_checks`
+    CorruptedRecord(u64, u64, String),
+
+    /// A value read by a client with `Client::integrity` does not match the checksum it was
+    /// written with, carrying its key
+    IntegrityMismatch(String),
+
+    /// A bitmap request found a value which is not a base64 bitmap, carrying its key
+    NotABitmap(String),
+
+    /// A HyperLogLog request found a value which is not a sketch, carrying its key
+    NotAHyperLogLog(String),
+
+    /// A JSON request found a value which is not a JSON document, carrying its key
+    NotAJsonDocument(String),
+
+    /// A client kill request names no connection of the server, carrying the id
+    ClientNotFound(u64),
+
+    /// The server was configured not to run a command, carrying its name
+    CommandDisabled(String),
+
+    /// A watcher fell behind reading its events, so the server stopped sending them
+    SlowClient,
+
+    /// A request sent to a server draining its connections, see `Drain`
+    Draining,
+
+    /// Replication between stores which are not the ones it started between, carrying the
+    /// instance id expected and the one found, see `KvStore::instance_id`
+    UnrelatedStore(String, String),
+
+    /// A write to a store opened read-only, see `SnapshotEngine`
+    ReadOnlySnapshot,
+
+    /// Replicated writes sent on a connection which did not complete the replication
+    /// handshake, see `Request::REPLICATE`
+    NoReplicationHandshake,
+
+    /// A key of database 0 starts with the character reserved for the keys of other databases
+    ReservedKey,
+
+    /// Select a logical database the server does not have
+    DatabaseOutOfRange(u32, u32),
+
+    /// The server configuration is invalid
+    InvalidConfig(String),
+
+    /// A peer sent a frame or handshake which is not of the kvs protocol
+    InvalidFrame(String),
+
+    /// Unexpected response error
+    UnexpectedResponse(String),
+
     /// common string error
-    #[fail(display = "{}", _0)]
     CommonStringError(String),
+
+    /// An error with where it happened, see `ResultExt::context`
+    Context(ErrorContext, Box<KVStoreError>),
+
+    /// An error the server answered a request with, with the code and classification the
+    /// error had on the server
+    Remote(RemoteError),
+}
+
+impl KVStoreError {
+    /// The stable code of the error, the one of `root` for an error with context:
+    /// 1xxx for I/O and encoding errors, 2xxx for keys and data files, 3xxx for engines,
+    /// backups and configuration, 4xxx for requests and 5xxx for the protocol.
+    pub fn code(&self) -> u32 {
+        match self {
+            KVStoreError::Io(_) => 1001,
+            KVStoreError::Serde(_) => 1002,
+            KVStoreError::Sled(_) => 1003,
+            KVStoreError::Utf8Error(_) => 1004,
+            KVStoreError::ThreadPoolBuildError(_) => 1005,
+            KVStoreError::KeyNotFound => 2001,
+            KVStoreError::KeyExists => 2002,
+            KVStoreError::QuotaExceeded => 2003,
+            KVStoreError::ReservedKey => 2004,
+            KVStoreError::UnknownCommandType => 2005,
+            KVStoreError::CorruptedRecord(..) => 2006,
+            KVStoreError::IncompatibleFormat(..) => 2007,
+            KVStoreError::UnsupportedStoreOption(..) => 2008,
+            KVStoreError::IntegrityMismatch(_) => 2009,
+            KVStoreError::NotABitmap(_) => 2010,
+            KVStoreError::NotAHyperLogLog(_) => 2011,
+            KVStoreError::NotAJsonDocument(_) => 2012,
+            KVStoreError::UnknownEngineType => 3001,
+            KVStoreError::ChangeEngineError => 3002,
+            KVStoreError::BackupNotFound(_) => 3003,
+            KVStoreError::SequenceNotRetained(_) => 3004,
+            KVStoreError::InvalidConfig(_) => 3005,
+            KVStoreError::NotMigrating => 3006,
+            KVStoreError::ShardMismatch(_) => 3007,
+            KVStoreError::FutureRevision(..) => 4001,
+            KVStoreError::CompactedRevision(..) => 4002,
+            KVStoreError::DatabaseOutOfRange(..) => 4003,
+            KVStoreError::TransactionConflict(_) => 4004,
+            KVStoreError::LockTimeout(_) => 4005,
+            KVStoreError::NoTransaction => 4006,
+            KVStoreError::TransactionInProgress => 4007,
+            KVStoreError::Cancelled => 4008,
+            KVStoreError::DeadlineExceeded => 4009,
+            KVStoreError::Overloaded => 4010,
+            KVStoreError::Moved(_) => 4011,
+            KVStoreError::NotCaughtUp(..) => 4012,
+            KVStoreError::ReadOnlyReplica(_) => 4013,
+            KVStoreError::LeaseExpired => 4014,
+            KVStoreError::Fenced(_) => 4015,
+            KVStoreError::ClientNotFound(_) => 4016,
+            KVStoreError::CommandDisabled(_) => 4017,
+            KVStoreError::SlowClient => 4018,
+            KVStoreError::Draining => 4019,
+            KVStoreError::UnrelatedStore(..) => 4020,
+            KVStoreError::ReadOnlySnapshot => 4021,
+            KVStoreError::NoReplicationHandshake => 4022,
+            KVStoreError::InvalidFrame(_) => 5001,
+            KVStoreError::UnexpectedResponse(_) => 5002,
+            KVStoreError::CommonStringError(_) => 5000,
+            KVStoreError::Context(_, err) => err.code(),
+            KVStoreError::Remote(err) => err.code,
+        }
+    }
+
+    /// Whether sending the same request again may succeed, e.g. after a lost connection or
+    /// while the server is overloaded, see `RetryingClient`.
+    pub fn is_retryable(&self) -> bool {
+        match self.root() {
+            KVStoreError::Io(err) => matches!(
+                err.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::UnexpectedEof
+            ),
+            KVStoreError::LockTimeout(_)
+            | KVStoreError::DeadlineExceeded
+            | KVStoreError::Overloaded
+            | KVStoreError::NotCaughtUp(..)
+            | KVStoreError::LeaseExpired
+            | KVStoreError::Draining => true,
+            KVStoreError::Remote(err) => err.retryable,
+            _ => false,
+        }
+    }
+
+    /// Whether the error comes from a passing condition, like contention or overload, rather
+    /// than from the request or the data. Unlike retryable errors, a transaction conflict is
+    /// resolved by running the transaction again from its start.
+    pub fn is_transient(&self) -> bool {
+        match self.root() {
+            KVStoreError::TransactionConflict(_) => true,
+            KVStoreError::Remote(err) => err.transient,
+            _ => self.is_retryable(),
+        }
+    }
+
+    /// Whether stored data is corrupted: a record not matching its index, a data file
+    /// which does not decode or a value not matching its checksum.
+    pub fn is_corruption(&self) -> bool {
+        match self.root() {
+            KVStoreError::CorruptedRecord(..)
+            | KVStoreError::IntegrityMismatch(_)
+            | KVStoreError::UnknownCommandType => true,
+            KVStoreError::Serde(_) | KVStoreError::Utf8Error(_) => {
+                self.contexts().any(|context| context.file.is_some())
+            }
+            KVStoreError::Remote(err) => err.corruption,
+            _ => false,
+        }
+    }
+
+    /// the error without the contexts attached to it
+    pub fn root(&self) -> &KVStoreError {
+        match self {
+            KVStoreError::Context(_, err) => err.root(),
+            err => err,
+        }
+    }
+
+    /// the contexts attached to the error, the last attached first
+    pub fn contexts(&self) -> impl Iterator<Item = &ErrorContext> {
+        std::iter::successors(Some(self), |err| match err {
+            KVStoreError::Context(_, err) => Some(err),
+            _ => None,
+        })
+        .filter_map(|err| match err {
+            KVStoreError::Context(context, _) => Some(context),
+            _ => None,
+        })
+    }
+}
+
+impl fmt::Display for KVStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KVStoreError::Io(err) => write!(f, "{}", err),
+            KVStoreError::Serde(err) => write!(f, "{}", err),
+            KVStoreError::Sled(err) => write!(f, "{}", err),
+            KVStoreError::Utf8Error(err) => write!(f, "Utf8 Error {}", err),
+            KVStoreError::ThreadPoolBuildError(err) => write!(f, "Build ThreadPool Error {}", err),
+            KVStoreError::KeyNotFound => write!(f, "Key not found"),
+            KVStoreError::KeyExists => write!(f, "Key already exists"),
+            KVStoreError::QuotaExceeded => write!(f, "Storage quota exceeded"),
+            KVStoreError::UnknownCommandType => write!(f, "Unknown command type"),
+            KVStoreError::UnknownEngineType => write!(f, "Unknown engine type"),
+            KVStoreError::ChangeEngineError => write!(f, "Change engine after initialization"),
+            KVStoreError::FutureRevision(revision, current) => write!(
+                f,
+                "Revision {} is newer than the current revision {}",
+                revision, current
+            ),
+            KVStoreError::CompactedRevision(revision, oldest) => write!(
+                f,
+                "Revision {} is compacted, the oldest retained revision is {}",
+                revision, oldest
+            ),
+            KVStoreError::TransactionConflict(key) => write!(
+                f,
+                "Transaction conflicts with a concurrent write of key {}",
+                key
+            ),
+            KVStoreError::LockTimeout(key) => {
+                write!(f, "Timed out waiting for the lock of key {}", key)
+            }
+            KVStoreError::NoTransaction => write!(f, "No transaction is in progress"),
+            KVStoreError::TransactionInProgress => write!(f, "A transaction is already in progress"),
+            KVStoreError::Cancelled => write!(f, "The job was cancelled"),
+            KVStoreError::DeadlineExceeded => write!(f, "The deadline of the request was exceeded"),
+            KVStoreError::Overloaded => write!(f, "The server is overloaded, retry later"),
+            KVStoreError::Moved(owner) => write!(f, "The key is served by the shard at {}", owner),
+            KVStoreError::NotCaughtUp(token, applied) => write!(
+                f,
+                "The server applied the writes up to {} but not up to the token {}",
+                applied, token
+            ),
+            KVStoreError::ReadOnlyReplica(leader) => {
+                write!(f, "Read-only replica, the leader is {}", leader)
+            }
+            KVStoreError::LeaseExpired => {
+                write!(f, "The server holds no leader lease, retry later")
+            }
+            KVStoreError::Fenced(epoch) => write!(
+                f,
+                "The leader was fenced off by the promotion of its follower at epoch {}",
+                epoch
+            ),
+            KVStoreError::NotMigrating => {
+                write!(f, "The engine is not migrating to another engine")
+            }
+            KVStoreError::ShardMismatch(keys) => write!(
+                f,
+                "{} moved keys differ between the shards, the keys were written during the move",
+                keys
+            ),
+            KVStoreError::BackupNotFound(id) => write!(f, "Backup {} not found", id),
+            KVStoreError::SequenceNotRetained(sequence) => write!(
+                f,
+                "No backup retains the state as of sequence {}",
+                sequence
+            ),
+            KVStoreError::IncompatibleFormat(version, supported) => write!(
+                f,
+                "Store format version {} is newer than the supported version {}, upgrade kvs to open it",
+                version, supported
+            ),
+            KVStoreError::UnsupportedStoreOption(option, value) => {
+                write!(f, "Unsupported store option {} = {}", option, value)
+            }
+            KVStoreError::CorruptedRecord(file_number, offset, reason) => write!(
+                f,
+                "Corrupted record in data file {} at offset {}: {}",
+                file_number, offset, reason
+            ),
+            KVStoreError::IntegrityMismatch(key) => {
+                write!(f, "The value of key {} does not match its checksum", key)
+            }
+            KVStoreError::NotABitmap(key) => {
+                write!(f, "The value of key {} is not a base64 bitmap", key)
+            }
+            KVStoreError::NotAHyperLogLog(key) => {
+                write!(f, "The value of key {} is not a HyperLogLog sketch", key)
+            }
+            KVStoreError::NotAJsonDocument(key) => {
+                write!(f, "The value of key {} is not a JSON document", key)
+            }
+            KVStoreError::ClientNotFound(id) => write!(f, "No client connection with id {}", id),
+            KVStoreError::CommandDisabled(name) => {
+                write!(f, "The {} command is disabled on this server", name)
+            }
+            KVStoreError::SlowClient => write!(
+                f,
+                "The watch fell behind reading its events, watch again from the last revision read"
+            ),
+            KVStoreError::Draining => {
+                write!(f, "The server is draining, connect to another server")
+            }
+            KVStoreError::UnrelatedStore(expected, found) => write!(
+                f,
+                "Replication between unrelated stores: expected instance {} but found {}",
+                expected, found
+            ),
+            KVStoreError::ReadOnlySnapshot => write!(f, "The store is a read-only snapshot"),
+            KVStoreError::NoReplicationHandshake => write!(
+                f,
+                "Replicated writes must follow a replication handshake on the same connection"
+            ),
+            KVStoreError::ReservedKey => write!(
+                f,
+                "Keys starting with \\u{{0}} are reserved for logical databases"
+            ),
+            KVStoreError::DatabaseOutOfRange(database, databases) => write!(
+                f,
+                "Database {} is out of range, the server has {}",
+                database, databases
+            ),
+            KVStoreError::InvalidConfig(message) => write!(f, "Invalid configuration: {}", message),
+            KVStoreError::InvalidFrame(message) => write!(f, "Invalid frame: {}", message),
+            KVStoreError::UnexpectedResponse(response) => {
+                write!(f, "Unexpected response {}", response)
+            }
+            KVStoreError::CommonStringError(message) => write!(f, "{}", message),
+            KVStoreError::Context(context, err) => write!(f, "{}: {}", context, err),
+            KVStoreError::Remote(err) => write!(f, "{}", err.message),
+        }
+    }
+}
+
+impl Error for KVStoreError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KVStoreError::Io(err) => Some(err),
+            KVStoreError::Serde(err) => Some(err),
+            KVStoreError::Sled(err) => Some(err),
+            KVStoreError::Utf8Error(err) => Some(err),
+            KVStoreError::ThreadPoolBuildError(err) => Some(err),
+            KVStoreError::Context(_, err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Where an error happened: the operation and, if known, the key, file and offset involved
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+    /// what was being done, like `read` or `recover`
+    pub operation: String,
+    /// the key read or written
+    pub key: Option<String>,
+    /// the file read or written
+    pub file: Option<PathBuf>,
+    /// the offset in the file
+    pub offset: Option<u64>,
+}
+
+impl ErrorContext {
+    /// the context of `operation`
+    pub fn new(operation: &str) -> Self {
+        ErrorContext {
+            operation: operation.to_owned(),
+            ..ErrorContext::default()
+        }
+    }
+
+    /// the key the operation read or wrote
+    pub fn key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    /// the file the operation read or wrote
+    pub fn file(mut self, file: impl Into<PathBuf>) -> Self {
+        self.file = Some(file.into());
+        self
+    }
+
+    /// the offset in the file the operation read or wrote at
+    pub fn offset(mut self, offset: u64) -> Self {
+        self.offset = Some(offset);
+        self
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.operation)?;
+        if let Some(key) = &self.key {
+            write!(f, " of key {}", key)?;
+        }
+        if let Some(file) = &self.file {
+            write!(f, " in {}", file.display())?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at offset {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Attaches an `ErrorContext` to the error of a result, converting it to a `KVStoreError`
+pub trait ResultExt<T> {
+    /// attach `context` to the error
+    fn context(self, context: ErrorContext) -> Result<T>;
+
+    /// attach the context `context` returns to the error, building it only on errors
+    fn with_context<F>(self, context: F) -> Result<T>
+    where
+        F: FnOnce() -> ErrorContext;
+}
+
+impl<T, E: Into<KVStoreError>> ResultExt<T> for std::result::Result<T, E> {
+    fn context(self, context: ErrorContext) -> Result<T> {
+        self.with_context(|| context)
+    }
+
+    fn with_context<F>(self, context: F) -> Result<T>
+    where
+        F: FnOnce() -> ErrorContext,
+    {
+        self.map_err(|err| KVStoreError::Context(context(), Box::new(err.into())))
+    }
 }
 
 impl From<io::Error> for KVStoreError {
@@ -76,4 +579,4 @@ impl From<rayon::ThreadPoolBuildError> for KVStoreError {
     fn from(err: rayon::ThreadPoolBuildError) -> Self {
         KVStoreError::ThreadPoolBuildError(err)
     }
-}
\ No newline at end of file
+}
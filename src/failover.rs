@@ -0,0 +1,225 @@
+use crate::{Client, KVStoreError, Request, Result};
+use log::{info, warn};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The role of a server among the servers replicating the same keys: a leader taking the
+/// writes of clients, or a read-only follower of one, which a failover promotes to leader.
+///
+/// No two servers take writes at the same time. A leader with a lease only takes writes while
+/// the lease its follower renews holds, see `KvServer::leader_lease`, and a follower promoted
+/// by `PROMOTE` only takes writes once the last lease it granted expired. The promotion
+/// increments the epoch, so the follower refuses the leases and the replicated writes of the
+/// old leader from then on: the old leader is fenced off and becomes a follower itself.
+pub(crate) struct Role {
+    state: Mutex<RoleState>,
+}
+
+struct RoleState {
+    /// the leader of a follower, None for a leader
+    leader: Option<String>,
+    /// the number of promotions the server knows of
+    epoch: u64,
+    /// until when a leader holding a lease from its follower may take writes
+    lease: Option<Instant>,
+    /// until when a follower granted the lease to its leader
+    granted: Option<Instant>,
+    /// when a follower last granted the lease, or became a follower
+    heard: Instant,
+}
+
+impl Role {
+    /// a leader taking writes without a lease
+    pub(crate) fn leader() -> Role {
+        Role::new(None)
+    }
+
+    /// a read-only follower of the server at `leader`
+    pub(crate) fn follower(leader: &str) -> Role {
+        Role::new(Some(leader.to_owned()))
+    }
+
+    fn new(leader: Option<String>) -> Role {
+        Role {
+            state: Mutex::new(RoleState {
+                leader,
+                epoch: 0,
+                lease: None,
+                granted: None,
+                heard: Instant::now(),
+            }),
+        }
+    }
+
+    /// fail a write of a client unless the server is a leader holding its lease, if it needs one
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        match (&state.leader, state.lease) {
+            (Some(leader), _) => Err(KVStoreError::ReadOnlyReplica(leader.clone())),
+            (None, Some(lease)) if Instant::now() >= lease => Err(KVStoreError::LeaseExpired),
+            _ => Ok(()),
+        }
+    }
+
+    /// fail the writes replicated from a leader fenced off by the promotion of the server
+    pub(crate) fn check_replicated(&self) -> Result<()> {
+        let state = self.state.lock().unwrap();
+        match state.leader {
+            None if state.epoch > 0 => Err(KVStoreError::Fenced(state.epoch)),
+            _ => Ok(()),
+        }
+    }
+
+    /// grant the lease to the leader of a follower for `duration`, unless it was fenced off
+    pub(crate) fn grant(&self, epoch: u64, duration: Duration) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.leader.is_none() || epoch < state.epoch {
+            return Err(KVStoreError::Fenced(state.epoch));
+        }
+        let now = Instant::now();
+        state.epoch = epoch;
+        state.heard = now;
+        state.granted = Some(now + duration);
+        Ok(())
+    }
+
+    /// Promote a follower to leader, refusing the leases of the old leader at once and taking
+    /// writes once the last lease granted to it expired. Return the epoch of the promotion.
+    pub(crate) fn promote(&self) -> u64 {
+        let (epoch, granted) = {
+            let mut state = self.state.lock().unwrap();
+            if state.leader.is_none() {
+                return state.epoch;
+            }
+            state.epoch += 1;
+            (state.epoch, state.granted)
+        };
+        if let Some(granted) = granted {
+            thread::sleep(granted.saturating_duration_since(Instant::now()));
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(leader) = state.leader.take() {
+            info!(
+                "Promoted to leader at epoch {}, fencing off {}",
+                epoch, leader
+            );
+        }
+        state.granted = None;
+        state.epoch
+    }
+
+    /// the leader of a follower and how long ago it last renewed its lease
+    fn silence(&self) -> Option<(String, Duration)> {
+        let state = self.state.lock().unwrap();
+        let leader = state.leader.clone()?;
+        Some((leader, state.heard.elapsed()))
+    }
+
+    /// take writes only while a lease holds from now on, none until the first renewal
+    fn require_lease(&self) {
+        self.state.lock().unwrap().lease = Some(Instant::now());
+    }
+
+    fn renew(&self, until: Instant) {
+        let mut state = self.state.lock().unwrap();
+        if state.leader.is_none() {
+            state.lease = state.lease.max(Some(until));
+        }
+    }
+
+    fn epoch(&self) -> u64 {
+        self.state.lock().unwrap().epoch
+    }
+
+    /// become a follower of the server which fenced the leader off
+    fn fence(&self, leader: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.leader = Some(leader.to_owned());
+        state.lease = None;
+        state.heard = Instant::now();
+    }
+}
+
+/// A part of a failover running in the background until it is dropped: renewing the lease of
+/// a leader, see `KvServer::leader_lease`, or watching the leader of a follower, see
+/// `KvServer::failover_after`
+pub struct FailoverTask {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl FailoverTask {
+    /// run `step` every `interval` until it returns false or the task is dropped
+    fn spawn<F: FnMut() -> bool + Send + 'static>(interval: Duration, mut step: F) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            while step() {
+                if !matches!(
+                    stopped.recv_timeout(interval),
+                    Err(RecvTimeoutError::Timeout)
+                ) {
+                    break;
+                }
+            }
+        });
+        FailoverTask {
+            stop: Some(stop),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for FailoverTask {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Renew the lease of a leader at its follower three times per `duration`. The lease counts
+/// from the time the renewal was sent, so it expires before the follower lets it go.
+pub(crate) fn keep_lease(role: Arc<Role>, follower: &str, duration: Duration) -> FailoverTask {
+    role.require_lease();
+    let follower = follower.to_owned();
+    FailoverTask::spawn(duration / 3, move || {
+        let sent = Instant::now();
+        let lease = Request::LEASE(role.epoch(), duration.as_millis() as u64);
+        let renewal =
+            Client::new(&follower).and_then(|client| client.timeout(duration).request(&lease));
+        match renewal {
+            Ok(_) => {
+                role.renew(sent + duration);
+                true
+            }
+            Err(err) if err.code() == KVStoreError::Fenced(0).code() => {
+                warn!("Fenced off by {}, following it: {}", follower, err);
+                role.fence(&follower);
+                false
+            }
+            Err(err) => {
+                warn!("Can not renew the leader lease at {}: {}", follower, err);
+                true
+            }
+        }
+    })
+}
+
+/// Promote a follower once its leader did not renew its lease for `timeout`.
+pub(crate) fn watch_leader(role: Arc<Role>, timeout: Duration) -> FailoverTask {
+    FailoverTask::spawn(timeout / 4, move || match role.silence() {
+        Some((leader, silence)) if silence >= timeout => {
+            warn!(
+                "No lease renewal from {} for {:?}, promoting to leader",
+                leader, silence
+            );
+            role.promote();
+            false
+        }
+        Some(_) => true,
+        None => false,
+    })
+}
@@ -0,0 +1,308 @@
+use crate::{KVStoreError, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{self, BufReader, IoSlice, Read, Write};
+
+/// the first bytes each side of a connection sends, followed by a byte of `FLAG_*`s,
+/// offered by the client and accepted by the server
+const MAGIC: &[u8; 4] = b"KVS\x01";
+/// LZ4 compression of large frames
+pub(crate) const FLAG_LZ4: u8 = 1;
+/// the bit of a frame header marking a compressed payload; the other bits are its length
+const COMPRESSED: u32 = 1 << 31;
+/// the largest payload accepted, compressed or not; payloads are read as their bytes arrive,
+/// so a header claiming a large one costs no memory until the peer sends it
+const MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+/// the most an LZ4 block can decompress to per compressed byte, so the claimed size of a
+/// compressed payload is only allocated for a payload which can decompress to it
+const MAX_LZ4_RATIO: usize = 255;
+/// payloads smaller than this are sent as they are, compressing them saves too little
+const COMPRESSION_THRESHOLD: usize = 512;
+/// the bytes of frames a `FrameWriter` queues before writing them without waiting for a flush
+const MAX_QUEUED: usize = 64 * 1024;
+/// the largest buffer a `FrameReader` keeps for the next frame, so one large frame does not
+/// hold its memory for the rest of the connection
+const MAX_REUSED_BUFFER: usize = 64 * 1024;
+
+/// offer `flags` to the server and return the ones it accepted
+pub(crate) fn client_handshake(stream: &mut (impl Read + Write), flags: u8) -> Result<u8> {
+    stream.write_all(MAGIC)?;
+    stream.write_all(&[flags])?;
+    stream.flush()?;
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply)?;
+    if &reply[..4] != MAGIC {
+        return Err(invalid("the server does not speak the kvs protocol"));
+    }
+    Ok(reply[4] & flags)
+}
+
+/// Accept the flags of the client which are in `supported`, and return them.
+/// Return None if the client closed the connection without a handshake.
+pub(crate) fn server_handshake(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    supported: u8,
+) -> Result<Option<u8>> {
+    let mut hello = [0; 5];
+    if !read_start(reader, &mut hello)? {
+        return Ok(None);
+    }
+    if &hello[..4] != MAGIC {
+        return Err(invalid("the client does not speak the kvs protocol"));
+    }
+    let flags = hello[4] & supported;
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[flags])?;
+    writer.flush()?;
+    Ok(Some(flags))
+}
+
+/// Read the next frame, or None if the connection was closed between frames.
+/// A frame is a big-endian u32 header, the length of the payload with `COMPRESSED` set if
+/// it is LZ4, followed by the payload, a JSON value.
+pub(crate) fn read_frame<T: DeserializeOwned>(reader: &mut impl Read) -> Result<Option<T>> {
+    let (len, compressed) = match read_header(reader)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    let mut payload = Vec::new();
+    read_exact_growing(reader, len, &mut payload)?;
+    if compressed {
+        payload = decompress(&payload)?;
+    }
+    Ok(Some(serde_json::from_slice(&payload)?))
+}
+
+/// Reads frames into buffers reused from one frame to the next, so a payload can be decoded
+/// without allocating, borrowing from the buffer, see `RequestRef`.
+pub(crate) struct FrameReader<R: Read> {
+    reader: R,
+    payload: Vec<u8>,
+    decompressed: Vec<u8>,
+}
+
+impl<R: Read> FrameReader<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        FrameReader {
+            reader,
+            payload: Vec::new(),
+            decompressed: Vec::new(),
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// The payload of the next frame, decompressed, or None if the connection was closed
+    /// between frames, see `read_frame`.
+    pub(crate) fn read_payload(&mut self) -> Result<Option<&[u8]>> {
+        let (len, compressed) = match read_header(&mut self.reader)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        if self.payload.capacity() > MAX_REUSED_BUFFER {
+            self.payload = Vec::new();
+        }
+        read_exact_growing(&mut self.reader, len, &mut self.payload)?;
+        if !compressed {
+            return Ok(Some(&self.payload));
+        }
+        let size = decompressed_size(&self.payload)?;
+        reuse(&mut self.decompressed, size);
+        let size = lz4_flex::decompress_into(&self.payload[4..], &mut self.decompressed)
+            .map_err(|err| invalid(err.to_string()))?;
+        Ok(Some(&self.decompressed[..size]))
+    }
+}
+
+impl<R: Read> FrameReader<BufReader<R>> {
+    /// whether the whole next frame was received already, so it is read without waiting
+    pub(crate) fn frame_buffered(&self) -> bool {
+        let buffer = self.reader.buffer();
+        match buffer.get(..4) {
+            Some(header) => {
+                let header = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+                4 + (header & !COMPRESSED) as usize <= buffer.len()
+            }
+            None => false,
+        }
+    }
+}
+
+/// resize a buffer for the next frame, dropping it first if it grew too large to keep
+fn reuse(buffer: &mut Vec<u8>, len: usize) {
+    if buffer.capacity() > MAX_REUSED_BUFFER {
+        *buffer = Vec::new();
+    }
+    buffer.clear();
+    buffer.resize(len, 0);
+}
+
+/// Read the `len` bytes of a payload into `buffer`, replacing its content. The buffer grows
+/// as the bytes arrive rather than by the length claimed in the header of the frame.
+fn read_exact_growing(reader: &mut impl Read, len: usize, buffer: &mut Vec<u8>) -> Result<()> {
+    buffer.clear();
+    let read = reader.take(len as u64).read_to_end(buffer)?;
+    if read < len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+/// the length of the payload of the next frame and whether it is compressed, or None if the
+/// connection was closed between frames
+fn read_header(reader: &mut impl Read) -> Result<Option<(usize, bool)>> {
+    let mut header = [0; 4];
+    if !read_start(reader, &mut header)? {
+        return Ok(None);
+    }
+    let header = u32::from_be_bytes(header);
+    let len = (header & !COMPRESSED) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(invalid(format!("frame of {} bytes is too large", len)));
+    }
+    Ok(Some((len, header & COMPRESSED != 0)))
+}
+
+/// Writes frames, compressing large ones if the handshake accepted `FLAG_LZ4`. The frames
+/// are queued until a flush, or until they take `MAX_QUEUED` bytes, and then written with a
+/// single vectored write, so a batch of responses takes one system call.
+pub(crate) struct FrameWriter<W: Write> {
+    writer: W,
+    compress: bool,
+    /// the headers and payloads of the frames not written yet
+    queued: Vec<([u8; 4], Vec<u8>)>,
+    queued_bytes: usize,
+}
+
+impl<W: Write> FrameWriter<W> {
+    pub(crate) fn new(writer: W, flags: u8) -> Self {
+        FrameWriter {
+            writer,
+            compress: flags & FLAG_LZ4 != 0,
+            queued: Vec::new(),
+            queued_bytes: 0,
+        }
+    }
+
+    /// write a frame without flushing it
+    pub(crate) fn write<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        let mut compressed = false;
+        let mut payload = serde_json::to_vec(value)?;
+        if self.compress && payload.len() >= COMPRESSION_THRESHOLD {
+            let smaller = lz4_flex::compress_prepend_size(&payload);
+            if smaller.len() < payload.len() {
+                payload = smaller;
+                compressed = true;
+            }
+        }
+        if payload.len() > MAX_FRAME_SIZE {
+            return Err(invalid(format!(
+                "frame of {} bytes is too large",
+                payload.len()
+            )));
+        }
+        let mut header = payload.len() as u32;
+        if compressed {
+            header |= COMPRESSED;
+        }
+        self.queued_bytes += 4 + payload.len();
+        self.queued.push((header.to_be_bytes(), payload));
+        if self.queued_bytes >= MAX_QUEUED {
+            self.write_queued()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        self.write_queued()?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// write the queued frames to the writer
+    fn write_queued(&mut self) -> Result<()> {
+        let mut slices: Vec<IoSlice> = self
+            .queued
+            .iter()
+            .flat_map(|(header, payload)| [IoSlice::new(header), IoSlice::new(payload)])
+            .collect();
+        write_all_vectored(&mut self.writer, &mut slices)?;
+        self.queued.clear();
+        self.queued_bytes = 0;
+        Ok(())
+    }
+
+    /// write a frame and flush it
+    pub(crate) fn send<T: Serialize>(&mut self, value: &T) -> Result<()> {
+        self.write(value)?;
+        self.flush()
+    }
+}
+
+impl<W: Write> Drop for FrameWriter<W> {
+    fn drop(&mut self) {
+        // as a `BufWriter` does, the frames queued are written but errors ignored
+        let _ = self.write_queued();
+    }
+}
+
+/// write all of `slices`, in as few writes as the writer takes them in
+fn write_all_vectored(writer: &mut impl Write, mut slices: &mut [IoSlice]) -> io::Result<()> {
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => IoSlice::advance_slices(&mut slices, written),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// fill `buf`, or return false if the connection is closed before its first byte
+fn read_start(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool> {
+    loop {
+        match reader.read(&mut buf[..1]) {
+            Ok(0) => return Ok(false),
+            Ok(_) => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    reader.read_exact(&mut buf[1..])?;
+    Ok(true)
+}
+
+/// decompress an LZ4 block prepended with its little-endian decompressed size,
+/// checking the size before allocating it
+fn decompress(payload: &[u8]) -> Result<Vec<u8>> {
+    let size = decompressed_size(payload)?;
+    lz4_flex::decompress(&payload[4..], size).map_err(|err| invalid(err.to_string()))
+}
+
+/// the size an LZ4 payload prepended with it decompresses to, at most `MAX_FRAME_SIZE` and
+/// what its compressed bytes can decompress to
+fn decompressed_size(payload: &[u8]) -> Result<usize> {
+    if payload.len() < 4 {
+        return Err(invalid("compressed frame without its size"));
+    }
+    let size = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+    if size > MAX_FRAME_SIZE {
+        return Err(invalid(format!("frame of {} bytes is too large", size)));
+    }
+    if size > (payload.len() - 4) * MAX_LZ4_RATIO {
+        return Err(invalid(format!(
+            "{} compressed bytes can not decompress to {} bytes",
+            payload.len() - 4,
+            size
+        )));
+    }
+    Ok(size)
+}
+
+fn invalid(message: impl Into<String>) -> KVStoreError {
+    KVStoreError::InvalidFrame(message.into())
+}
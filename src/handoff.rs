@@ -0,0 +1,202 @@
+use crate::{KVStoreError, Result};
+use log::{info, warn};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::TcpListener;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// the most listeners handed over at once
+const MAX_HANDOFF_LISTENERS: usize = 64;
+
+/// Hands the listeners of a running server over to a new server process, e.g. a newer binary,
+/// over the Unix socket at `path`, so an upgrade drops no client.
+///
+/// Once the new process has the listeners `is_stop` is set, which makes the server stop
+/// accepting and leaves the waiting connections to the new process. Drop the handoff after the
+/// server and its engine are dropped: that tells the new process it may open the engine.
+pub struct ListenerHandoff {
+    path: PathBuf,
+    /// the connection of the process which took the listeners over
+    taken_by: Arc<Mutex<Option<UnixStream>>>,
+}
+
+impl ListenerHandoff {
+    /// Wait for a new process at `path` in the background.
+    pub fn start(
+        path: impl Into<PathBuf>,
+        listeners: &[TcpListener],
+        is_stop: Arc<AtomicBool>,
+    ) -> Result<Self> {
+        let path = path.into();
+        if path.exists() {
+            if UnixStream::connect(&path).is_ok() {
+                return Err(KVStoreError::CommonStringError(format!(
+                    "another server hands off its listeners at {}",
+                    path.display()
+                )));
+            }
+            // left by a server which handed off or crashed
+            fs::remove_file(&path)?;
+        }
+        let socket = UnixListener::bind(&path)?;
+        let listeners = listeners
+            .iter()
+            .map(TcpListener::try_clone)
+            .collect::<io::Result<Vec<_>>>()?;
+        let taken_by = Arc::new(Mutex::new(None));
+        let taken = Arc::clone(&taken_by);
+        thread::spawn(move || {
+            for stream in socket.incoming() {
+                match stream.and_then(|stream| hand_off(stream, &listeners)) {
+                    Ok(stream) => {
+                        info!("Handed the listeners off, stop accepting");
+                        *taken.lock().unwrap() = Some(stream);
+                        is_stop.store(true, Ordering::SeqCst);
+                        return;
+                    }
+                    Err(err) => warn!("Can not hand the listeners off: {}", err),
+                }
+            }
+        });
+        Ok(ListenerHandoff { path, taken_by })
+    }
+
+    /// Whether a new process took the listeners over.
+    pub fn is_handed_off(&self) -> bool {
+        self.taken_by.lock().unwrap().is_some()
+    }
+}
+
+impl Drop for ListenerHandoff {
+    fn drop(&mut self) {
+        // the socket belongs to the new process once it took the listeners over,
+        // which waits for this connection to close
+        if self.taken_by.lock().unwrap().take().is_none() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// send the listeners and wait for the new process to confirm it has them
+fn hand_off(mut stream: UnixStream, listeners: &[TcpListener]) -> io::Result<UnixStream> {
+    let fds: Vec<RawFd> = listeners.iter().map(AsRawFd::as_raw_fd).collect();
+    send_fds(&stream, &fds)?;
+    let mut ack = [0; 1];
+    stream.read_exact(&mut ack)?;
+    Ok(stream)
+}
+
+/// Take the listeners over from the server handing them off at `path` by `ListenerHandoff`,
+/// and wait until that server has stopped and released its engine. Connections wait on the
+/// listeners meanwhile.
+pub fn take_over_listeners(path: impl AsRef<Path>) -> Result<Vec<TcpListener>> {
+    let mut stream = UnixStream::connect(path.as_ref())?;
+    let listeners: Vec<TcpListener> = recv_fds(&stream)?
+        .into_iter()
+        // SAFETY: the descriptors were just received and are owned by nothing else
+        .map(|fd| unsafe { TcpListener::from_raw_fd(fd) })
+        .collect();
+    stream.write_all(&[1])?;
+    for listener in &listeners {
+        info!("Took over the listener on {}", listener.local_addr()?);
+    }
+    info!("Wait for the previous server to stop");
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest)?;
+    Ok(listeners)
+}
+
+fn send_fds(stream: &UnixStream, fds: &[RawFd]) -> io::Result<()> {
+    // at least one byte has to go along with the descriptors
+    let count = (fds.len() as u32).to_le_bytes();
+    let mut iov = libc::iovec {
+        iov_base: count.as_ptr() as *mut libc::c_void,
+        iov_len: count.len(),
+    };
+    let fds_len = mem::size_of_val(fds) as u32;
+    // SAFETY: CMSG_SPACE only computes a size
+    let space = unsafe { libc::CMSG_SPACE(fds_len) } as usize;
+    // u64s align the control messages
+    let mut control = vec![0u64; space.div_ceil(8)];
+    // SAFETY: an all zero msghdr is valid
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    // SAFETY: the control buffer has room for one message holding `fds`
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+    }
+    // SAFETY: `msg` points to buffers which outlive the call
+    if unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn recv_fds(stream: &UnixStream) -> io::Result<Vec<RawFd>> {
+    let mut count = [0u8; 4];
+    let mut iov = libc::iovec {
+        iov_base: count.as_mut_ptr() as *mut libc::c_void,
+        iov_len: count.len(),
+    };
+    let fds_len = (MAX_HANDOFF_LISTENERS * mem::size_of::<RawFd>()) as u32;
+    // SAFETY: CMSG_SPACE only computes a size
+    let space = unsafe { libc::CMSG_SPACE(fds_len) } as usize;
+    let mut control = vec![0u64; space.div_ceil(8)];
+    // SAFETY: an all zero msghdr is valid
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+    // SAFETY: `msg` points to buffers which outlive the call
+    let read = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) };
+    if read < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut fds = Vec::new();
+    // SAFETY: the kernel filled in `msg.msg_controllen` bytes of control messages
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for index in 0..len / mem::size_of::<RawFd>() {
+                    let fd = ptr::read_unaligned(data.add(index));
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                    fds.push(fd);
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if read as usize != count.len()
+        || msg.msg_flags & libc::MSG_CTRUNC != 0
+        || u32::from_le_bytes(count) as usize != fds.len()
+    {
+        for fd in fds {
+            // SAFETY: the descriptors were just received and are owned by nothing else
+            unsafe { libc::close(fd) };
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "incomplete listener handoff",
+        ));
+    }
+    Ok(fds)
+}
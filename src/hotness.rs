@@ -0,0 +1,128 @@
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+/// the rows of a sketch, each with its own hash; more rows make overestimates rarer
+const SKETCH_DEPTH: usize = 4;
+/// the counters per row; more counters make overestimates smaller
+const SKETCH_WIDTH: usize = 4096;
+/// the most keys kept as candidates for the hottest, so the most `TOPKEYS` returns
+const MAX_TOP_KEYS: usize = 64;
+/// the accesses after which all counts are halved, so keys which cooled down drop out
+const DECAY_INTERVAL: u64 = 1 << 20;
+
+/// The hottest keys of a server by approximate access count, returned for `Request::TOPKEYS`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct TopKeys {
+    /// the most read keys with their counts, hottest first
+    pub reads: Vec<(String, u64)>,
+    /// the most written keys with their counts, hottest first
+    pub writes: Vec<(String, u64)>,
+}
+
+/// The access frequency of the keys of a server, counted by reads and by writes
+#[derive(Default)]
+pub(crate) struct KeyHotness {
+    reads: Mutex<HotKeys>,
+    writes: Mutex<HotKeys>,
+}
+
+impl KeyHotness {
+    pub(crate) fn read(&self, key: &str) {
+        self.reads.lock().unwrap().record(key);
+    }
+
+    pub(crate) fn write(&self, key: &str) {
+        self.writes.lock().unwrap().record(key);
+    }
+
+    /// the `n` hottest keys, at most `MAX_TOP_KEYS`
+    pub(crate) fn top(&self, n: usize) -> TopKeys {
+        TopKeys {
+            reads: self.reads.lock().unwrap().top(n),
+            writes: self.writes.lock().unwrap().top(n),
+        }
+    }
+}
+
+/// A count-min sketch estimating the count of every key in fixed memory,
+/// and the keys with the highest estimates
+struct HotKeys {
+    counters: Vec<u64>,
+    candidates: HashMap<String, u64>,
+    accesses: u64,
+}
+
+impl Default for HotKeys {
+    fn default() -> Self {
+        HotKeys {
+            counters: vec![0; SKETCH_DEPTH * SKETCH_WIDTH],
+            candidates: HashMap::new(),
+            accesses: 0,
+        }
+    }
+}
+
+impl HotKeys {
+    fn record(&mut self, key: &str) {
+        self.accesses += 1;
+        if self.accesses.is_multiple_of(DECAY_INTERVAL) {
+            self.decay();
+        }
+
+        // the estimate is the smallest counter of the key, which collisions only ever raise
+        let mut estimate = u64::MAX;
+        for row in 0..SKETCH_DEPTH {
+            let counter = &mut self.counters[row * SKETCH_WIDTH + slot(row, key)];
+            *counter += 1;
+            estimate = estimate.min(*counter);
+        }
+
+        if let Some(count) = self.candidates.get_mut(key) {
+            *count = estimate;
+        } else if self.candidates.len() < MAX_TOP_KEYS {
+            self.candidates.insert(key.to_owned(), estimate);
+        } else {
+            let (coldest, count) = self
+                .candidates
+                .iter()
+                .min_by_key(|(_, count)| **count)
+                .map(|(key, count)| (key.clone(), *count))
+                .expect("candidates are full");
+            if estimate > count {
+                self.candidates.remove(&coldest);
+                self.candidates.insert(key.to_owned(), estimate);
+            }
+        }
+    }
+
+    fn decay(&mut self) {
+        for counter in self.counters.iter_mut() {
+            *counter /= 2;
+        }
+        self.candidates.retain(|_, count| {
+            *count /= 2;
+            *count > 0
+        });
+    }
+
+    fn top(&self, n: usize) -> Vec<(String, u64)> {
+        let mut top: Vec<_> = self
+            .candidates
+            .iter()
+            .map(|(key, count)| (key.clone(), *count))
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+}
+
+fn slot(row: usize, key: &str) -> usize {
+    let mut hasher = DefaultHasher::new();
+    row.hash(&mut hasher);
+    key.hash(&mut hasher);
+    (hasher.finish() % SKETCH_WIDTH as u64) as usize
+}
@@ -0,0 +1,89 @@
+use crate::cluster::hash;
+use crate::{KVStoreError, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+
+/// the bits of the hash of an element which pick its register
+const PRECISION: u32 = 12;
+/// 4096 registers of a byte each, for a standard error of about 1.6%
+const REGISTERS: usize = 1 << PRECISION;
+/// the start of every stored sketch, so other values are not taken for one
+const MAGIC: &[u8] = b"HYLL";
+
+/// A HyperLogLog sketch estimating the number of distinct elements added to it, stored
+/// base64 encoded as the value of a key. Each register holds the longest run of trailing zero
+/// bits seen in the hashes of the elements it was picked for.
+pub(crate) struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    /// an empty sketch
+    pub(crate) fn new() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; REGISTERS],
+        }
+    }
+
+    /// the sketch stored as the value of `key`; a missing key is an empty sketch
+    pub(crate) fn decode(key: &str, value: Option<String>) -> Result<HyperLogLog> {
+        let value = match value {
+            Some(value) => value,
+            None => return Ok(HyperLogLog::new()),
+        };
+        match BASE64.decode(value) {
+            Ok(bytes) if bytes.len() == MAGIC.len() + REGISTERS && bytes.starts_with(MAGIC) => {
+                Ok(HyperLogLog {
+                    registers: bytes[MAGIC.len()..].to_vec(),
+                })
+            }
+            _ => Err(KVStoreError::NotAHyperLogLog(key.to_owned())),
+        }
+    }
+
+    pub(crate) fn encode(&self) -> String {
+        BASE64.encode([MAGIC, &self.registers].concat())
+    }
+
+    /// add an element, returning whether the estimate may have changed
+    pub(crate) fn add(&mut self, element: &str) -> bool {
+        let hash = hash(element.as_bytes());
+        let register = (hash as usize) & (REGISTERS - 1);
+        let rest = hash >> PRECISION;
+        let rank = (rest.trailing_zeros().min(64 - PRECISION) + 1) as u8;
+        if rank > self.registers[register] {
+            self.registers[register] = rank;
+            return true;
+        }
+        false
+    }
+
+    /// make the sketch count the elements of `other` too
+    pub(crate) fn merge(&mut self, other: &HyperLogLog) {
+        for (register, other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*other);
+        }
+    }
+
+    /// the estimated number of distinct elements added
+    pub(crate) fn count(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|register| 2f64.powi(-(*register as i32)))
+            .sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self
+            .registers
+            .iter()
+            .filter(|register| **register == 0)
+            .count();
+        // linear counting is more accurate while few registers are set
+        if estimate <= 2.5 * m && zeros > 0 {
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+}
@@ -0,0 +1,71 @@
+use crate::{KVStoreError, Request, Result};
+
+/// the start of a value sealed with its checksum, followed by the checksum in hex and a `:`
+const SEAL_PREFIX: &str = "\u{1}crc32:";
+
+/// the length of a sealed value before the value itself
+const SEAL_LEN: usize = SEAL_PREFIX.len() + 9;
+
+/// the checksum of a value written to a key, so a value read back from another key fails too
+fn checksum(key: &str, value: &str) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&(key.len() as u64).to_le_bytes());
+    hasher.update(key.as_bytes());
+    hasher.update(value.as_bytes());
+    hasher.finalize()
+}
+
+/// the value with its checksum, as stored by the server
+pub(crate) fn seal(key: &str, value: &str) -> String {
+    format!("{}{:08x}:{}", SEAL_PREFIX, checksum(key, value), value)
+}
+
+/// Verify a value read from a key and strip its checksum. Values written without one,
+/// e.g. by a client without `Client::integrity`, are returned as they are.
+pub(crate) fn open(key: &str, value: String) -> Result<String> {
+    let Some(sealed) = value.strip_prefix(SEAL_PREFIX) else {
+        return Ok(value);
+    };
+    let expected = sealed
+        .get(..8)
+        .filter(|_| sealed.as_bytes().get(8) == Some(&b':'))
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok());
+    match expected {
+        Some(expected) if checksum(key, &value[SEAL_LEN..]) == expected => {
+            Ok(value[SEAL_LEN..].to_owned())
+        }
+        _ => Err(KVStoreError::IntegrityMismatch(key.to_owned())),
+    }
+}
+
+/// the request with the values it sets sealed
+pub(crate) fn seal_request(request: &Request) -> Request {
+    match request {
+        Request::SET(key, value) => Request::SET(key.clone(), seal(key, value)),
+        Request::TRACED(parent, request) => {
+            Request::TRACED(parent.clone(), Box::new(seal_request(request)))
+        }
+        Request::TAGGED(id, request) => Request::TAGGED(*id, Box::new(seal_request(request))),
+        Request::RETRYABLE(client, id, request) => {
+            Request::RETRYABLE(client.clone(), *id, Box::new(seal_request(request)))
+        }
+        Request::DEADLINE(millis, request) => {
+            Request::DEADLINE(*millis, Box::new(seal_request(request)))
+        }
+        Request::AFTER(token, request) => Request::AFTER(*token, Box::new(seal_request(request))),
+        request => request.clone(),
+    }
+}
+
+/// the key a request gets, if it is a get, to open the value it is answered with
+pub(crate) fn read_key(request: &Request) -> Option<&str> {
+    match request {
+        Request::GET(key) => Some(key),
+        Request::TRACED(_, request)
+        | Request::TAGGED(_, request)
+        | Request::RETRYABLE(_, _, request)
+        | Request::DEADLINE(_, request)
+        | Request::AFTER(_, request) => read_key(request),
+        _ => None,
+    }
+}
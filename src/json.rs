@@ -0,0 +1,66 @@
+use crate::{KVStoreError, Result};
+use serde_json::Value;
+
+/// The JSON document stored as the value of `key`; a missing key is an empty object.
+pub(crate) fn decode(key: &str, value: Option<String>) -> Result<Value> {
+    match value {
+        Some(value) => {
+            serde_json::from_str(&value).map_err(|_| KVStoreError::NotAJsonDocument(key.to_owned()))
+        }
+        None => Ok(Value::Object(Default::default())),
+    }
+}
+
+/// the field at the JSON pointer `pointer` of the JSON document stored as `value` as JSON
+/// text, None if the key is missing or the document lacks the field
+pub(crate) fn get_field(key: &str, value: Option<String>, pointer: &str) -> Result<Option<String>> {
+    match value {
+        Some(value) => Ok(decode(key, Some(value))?
+            .pointer(pointer)
+            .map(|field| field.to_string())),
+        None => Ok(None),
+    }
+}
+
+/// Set the field of `document` at the JSON pointer `pointer` to the JSON text `field` and
+/// return the new document as JSON text, see `set_pointer`.
+pub(crate) fn set_field(document: &str, pointer: &str, field: &str) -> Result<String> {
+    let mut document: Value = serde_json::from_str(document)?;
+    set_pointer(&mut document, pointer, serde_json::from_str(field)?)?;
+    Ok(document.to_string())
+}
+
+/// Set the field of `document` at the JSON pointer `pointer` to `field`. The empty pointer
+/// replaces the whole document. The parent of the field must exist: a field of an object is
+/// added or replaced, an element of an array replaced or appended at the index of its length
+/// or `-`.
+pub(crate) fn set_pointer(document: &mut Value, pointer: &str, field: Value) -> Result<()> {
+    if pointer.is_empty() {
+        *document = field;
+        return Ok(());
+    }
+    let missing =
+        || KVStoreError::CommonStringError(format!("the parent of {} is missing", pointer));
+    let (parent, last) = pointer.rsplit_once('/').ok_or_else(|| {
+        KVStoreError::CommonStringError(format!("{} is not a JSON pointer", pointer))
+    })?;
+    let last = last.replace("~1", "/").replace("~0", "~");
+    match document.pointer_mut(parent).ok_or_else(missing)? {
+        Value::Object(object) => {
+            object.insert(last, field);
+        }
+        Value::Array(array) => {
+            let index = match last.as_str() {
+                "-" => array.len(),
+                index => index.parse().map_err(|_| missing())?,
+            };
+            match index.cmp(&array.len()) {
+                std::cmp::Ordering::Less => array[index] = field,
+                std::cmp::Ordering::Equal => array.push(field),
+                std::cmp::Ordering::Greater => return Err(missing()),
+            }
+        }
+        _ => return Err(missing()),
+    }
+    Ok(())
+}
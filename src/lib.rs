@@ -2,17 +2,76 @@
 /*!
 The KvStore store key/value pairs.
  */
+mod access;
+mod admission;
+mod bitmap;
 mod client;
+mod clients;
+mod cluster;
+mod config;
+mod dedup;
+mod drain;
 mod engine;
 mod errors;
+mod failover;
+mod frame;
+#[cfg(unix)]
+mod handoff;
+mod hotness;
+mod hyperloglog;
+mod integrity;
+mod json;
+mod listeners;
+mod locks;
+mod log_file;
+mod memcached;
+mod metrics;
 mod proto;
+mod proxy;
+mod random;
+mod range;
+mod replication;
+mod resharding;
 mod server;
+mod transform;
+mod txn;
+mod watch;
 
 pub mod thread_pool;
+pub mod trace;
 
-pub use client::Client;
+pub use access::AccessList;
+pub use admission::DEFAULT_MAX_QUEUE_WAIT;
+pub use client::{Client, IdempotencyToken, MultiplexClient, Pipeline, RetryingClient, Watcher};
+pub use clients::ClientInfo;
+pub use cluster::{ClusterClient, DEFAULT_VIRTUAL_NODES};
+pub use config::{
+    AdmissionConfig, AuthConfig, ClusterConfig, DedupConfig, LimitsConfig, LogConfig, LogRotation,
+    MetricsConfig, ServerConfig, ServerRole, ThreadPoolConfig, ThreadPoolKind, TlsConfig,
+    TraceConfig,
+};
+pub use dedup::{DEFAULT_DEDUP_CACHE_SIZE, DEFAULT_DEDUP_CACHE_TTL};
+pub use drain::Drain;
 pub use engine::Command;
-pub use engine::{KvStore, KvsEngine, SledKvsEngine};
-pub use errors::{KVStoreError, Result};
-pub use proto::{Request, Response};
-pub use server::{EngineType, KvServer};
\ No newline at end of file
+pub use engine::{
+    ArchiveStats, BackupFile, BackupManifest, BackupTarget, CompactionDecision, CompactionFilter,
+    CompactionPolicy, DirBackupTarget, KeyPrefix, KvStore, KvStoreBuilder, KvStoreIter,
+    KvStoreStats, KvsEngine, KvsReader, KvsWriter, MigratingEngine, PlacementPolicy, PrefixStats,
+    QuotaPolicy, RangeSize, ReadOnlyEngine, S3BackupTarget, SizeHistogram, SledKvsEngine,
+    SnapshotEngine, TierStats, TrimReport, TypedEngine, VerifyProblem, VerifyReport,
+};
+pub use errors::{ErrorContext, KVStoreError, Result, ResultExt};
+pub use failover::FailoverTask;
+#[cfg(unix)]
+pub use handoff::{take_over_listeners, ListenerHandoff};
+pub use hotness::TopKeys;
+pub use listeners::systemd_listeners;
+pub use log_file::{RollingFile, Rotation};
+pub use metrics::{Metric, ServerMetrics, StatsdSink};
+pub use proto::{DatabaseStats, RemoteError, Request, Response, ServerInfo};
+pub use proxy::KvProxy;
+pub use replication::{ReplicationStats, Replicator};
+pub use resharding::{Reshard, ReshardProgress};
+pub use server::{EngineType, KvServer, ReadOnlyServing, DEFAULT_DATABASES};
+pub use transform::Transform;
+pub use watch::{WatchEvent, DEFAULT_WATCH_HISTORY};
@@ -0,0 +1,66 @@
+use crate::{KVStoreError, Result};
+use log::info;
+use std::env;
+use std::net::TcpListener;
+
+/// the first file descriptor systemd passes, after stdin, stdout and stderr
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Take the listening sockets systemd passed to this process by socket activation, following
+/// the `LISTEN_PID` and `LISTEN_FDS` protocol of `sd_listen_fds`. Return None if this process
+/// was not socket activated. The variables are removed so child processes do not take the
+/// sockets too, so call it once, before other threads start.
+pub fn systemd_listeners() -> Result<Option<Vec<TcpListener>>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    let (pid, fds) = match (pid, fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(None),
+    };
+    // the sockets were meant for another process
+    if pid.parse::<u32>().ok() != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let count: i32 = fds.parse().map_err(|_| {
+        KVStoreError::CommonStringError(format!("LISTEN_FDS={} is not a number", fds))
+    })?;
+    let listeners = take_listeners(count)?;
+    for listener in &listeners {
+        info!("Socket activated listener on {}", listener.local_addr()?);
+    }
+    Ok(Some(listeners))
+}
+
+#[cfg(unix)]
+fn take_listeners(count: i32) -> Result<Vec<TcpListener>> {
+    use std::io;
+    use std::os::unix::io::FromRawFd;
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // systemd does not set close-on-exec on the sockets it passes,
+            // and this fails if the descriptor is not open
+            // SAFETY: fcntl only changes the flags of the descriptor
+            if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            // SAFETY: systemd passes `count` open sockets from `LISTEN_FDS_START` on, owned by
+            // this process from now on, and the variables are removed so they are taken once
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            // fails if the descriptor is not a socket
+            listener.local_addr()?;
+            Ok(listener)
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn take_listeners(_count: i32) -> Result<Vec<TcpListener>> {
+    Err(KVStoreError::CommonStringError(
+        "socket activation is only supported on Unix".to_owned(),
+    ))
+}
@@ -0,0 +1,80 @@
+use crate::{KVStoreError, Result, ServerMetrics};
+use std::collections::HashSet;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Locks keys for writes which read and write several of them, like transactions.
+/// Keys are always locked in ascending order, so two writers locking overlapping keys
+/// can not each hold a key the other one waits for.
+pub(crate) struct KeyLockManager {
+    locked: Mutex<HashSet<String>>,
+    released: Condvar,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl KeyLockManager {
+    pub(crate) fn new(metrics: Arc<ServerMetrics>) -> Self {
+        KeyLockManager {
+            locked: Mutex::new(HashSet::new()),
+            released: Condvar::new(),
+            metrics,
+        }
+    }
+
+    /// Lock all `keys` until the returned guard is dropped, waiting at most `timeout` for
+    /// other writers to release them. On timeout no key is left locked.
+    pub(crate) fn try_lock_keys(&self, keys: &[String], timeout: Duration) -> Result<KeyLocks<'_>> {
+        let mut keys = keys.to_vec();
+        keys.sort_unstable();
+        keys.dedup();
+        let start = Instant::now();
+        let deadline = start + timeout;
+        let mut contended = false;
+        let mut locked = self.locked.lock().unwrap();
+        for (held, key) in keys.iter().enumerate() {
+            while locked.contains(key) {
+                contended = true;
+                let now = Instant::now();
+                if now >= deadline {
+                    for key in &keys[..held] {
+                        locked.remove(key);
+                    }
+                    drop(locked);
+                    self.released.notify_all();
+                    self.metrics.lock_wait(start.elapsed(), true);
+                    return Err(KVStoreError::LockTimeout(key.clone()));
+                }
+                locked = self
+                    .released
+                    .wait_timeout(locked, deadline - now)
+                    .unwrap()
+                    .0;
+            }
+            locked.insert(key.clone());
+        }
+        if contended {
+            self.metrics.lock_wait(start.elapsed(), false);
+        }
+        Ok(KeyLocks {
+            manager: self,
+            keys,
+        })
+    }
+}
+
+/// the keys locked by `KeyLockManager::try_lock_keys`, released when dropped
+pub(crate) struct KeyLocks<'a> {
+    manager: &'a KeyLockManager,
+    keys: Vec<String>,
+}
+
+impl Drop for KeyLocks<'_> {
+    fn drop(&mut self) {
+        let mut locked = self.manager.locked.lock().unwrap();
+        for key in &self.keys {
+            locked.remove(key);
+        }
+        drop(locked);
+        self.manager.released.notify_all();
+    }
+}
@@ -0,0 +1,131 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// When a `RollingFile` starts a new file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    /// never, the file grows without bound
+    Never,
+    /// before a write would make the file larger than this many bytes
+    Size(u64),
+    /// at the first write of every day, in UTC
+    Daily,
+}
+
+/** A log file which is rolled over by size or by day.
+Rolling renames `<path>` to `<path>.1`, shifting the older files to `<path>.2` and so on,
+and deletes the files beyond the `retain` newest.
+# Example
+```
+use kvs::{RollingFile, Rotation};
+use std::io::Write;
+
+fn try_main() -> std::io::Result<()> {
+    let mut log = RollingFile::open("kvs-server.log", Rotation::Size(64 * 1024 * 1024), 7)?;
+    writeln!(log, "started")?;
+    Ok(())
+}
+```
+ */
+pub struct RollingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    retain: usize,
+    file: File,
+    size: u64,
+    /// the day the current file was started, in days since the Unix epoch
+    day: u64,
+}
+
+impl RollingFile {
+    /// Append to the file at `path`, creating it if needed, and keep `retain` rolled files.
+    pub fn open(path: impl Into<PathBuf>, rotation: Rotation, retain: usize) -> io::Result<Self> {
+        let path = path.into();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        Ok(RollingFile {
+            path,
+            rotation,
+            retain,
+            file,
+            size: metadata.len(),
+            day: day_of(metadata.modified().unwrap_or_else(|_| SystemTime::now())),
+        })
+    }
+
+    /// The path of the current file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn should_roll(&self, len: usize) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        match self.rotation {
+            Rotation::Never => false,
+            Rotation::Size(max_size) => self.size + len as u64 > max_size,
+            Rotation::Daily => day_of(SystemTime::now()) != self.day,
+        }
+    }
+
+    fn roll(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let rolled = |index: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", index));
+            PathBuf::from(name)
+        };
+        remove_if_exists(&rolled(self.retain.max(1)))?;
+        for index in (1..self.retain).rev() {
+            let from = rolled(index);
+            if from.exists() {
+                fs::rename(&from, rolled(index + 1))?;
+            }
+        }
+        if self.retain > 0 {
+            fs::rename(&self.path, rolled(1))?;
+        } else {
+            remove_if_exists(&self.path)?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        self.day = day_of(SystemTime::now());
+        Ok(())
+    }
+}
+
+impl Write for RollingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.should_roll(buf.len()) {
+            self.roll()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+fn day_of(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() / SECONDS_PER_DAY)
+        .unwrap_or(0)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
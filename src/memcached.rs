@@ -0,0 +1,331 @@
+use crate::server::{wait_for_request, Shared};
+use crate::{KVStoreError, KvsEngine, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// the longest key memcached accepts
+const MAX_KEY_LENGTH: usize = 250;
+/// the largest data block memcached accepts by default
+const MAX_ITEM_SIZE: usize = 1024 * 1024;
+/// exptimes up to 30 days are relative to now, larger ones are unix times
+const MAX_RELATIVE_EXPTIME: i64 = 30 * 24 * 60 * 60;
+/// the marker of stored values carrying flags or an expiry, followed by an `Item` as JSON.
+/// Values stored with neither are kept as they are, so kvs clients read them too.
+const ITEM_MARKER: &str = "\u{0}memcached:";
+
+/// a value stored by the memcached protocol with its flags and expiry
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct Item {
+    flags: u32,
+    /// the unix time in seconds the item expires at
+    expires: Option<u64>,
+    data: String,
+}
+
+impl Item {
+    fn encode(self) -> Result<String> {
+        if self.flags == 0 && self.expires.is_none() {
+            return Ok(self.data);
+        }
+        Ok(format!("{}{}", ITEM_MARKER, serde_json::to_string(&self)?))
+    }
+
+    /// decode a stored value, which is plain data if it was not stored by memcached
+    fn decode(value: String) -> Result<Item> {
+        match value.strip_prefix(ITEM_MARKER) {
+            Some(item) => Ok(serde_json::from_str(item)?),
+            None => Ok(Item {
+                flags: 0,
+                expires: None,
+                data: value,
+            }),
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// how a storage command treats an existing item
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Store {
+    /// set it either way
+    Set,
+    /// only if there is none
+    Add,
+    /// only if there is one
+    Replace,
+}
+
+/// Serve the memcached text protocol on a connection until the client closes it:
+/// `get`, `set`, `add`, `replace` and `delete` with exptimes, `version` and `quit`.
+/// Data must be valid UTF-8, as the engine stores strings.
+/// While the server drains, the commands fail like those of the kvs protocol, see `Drain`.
+pub(crate) fn handle_memcached_connection<E: KvsEngine>(
+    shared: &Shared<E>,
+    stream: TcpStream,
+) -> Result<()> {
+    let connected = Instant::now();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    let mut line = String::new();
+    loop {
+        if reader.buffer().is_empty() && !wait_for_request(&stream, &shared.is_stop)? {
+            return Ok(());
+        }
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        debug!("Memcached request: {:?}", line.trim_end());
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let command = match words.first() {
+            Some(command) => *command,
+            None => {
+                write!(writer, "ERROR\r\n")?;
+                writer.flush()?;
+                continue;
+            }
+        };
+        let now = SystemTime::now();
+        let (name, reply) = match command {
+            "get" => (
+                "MEMCACHED_GET",
+                shared
+                    .drain
+                    .check(connected)
+                    .and_then(|()| get(shared, &words[1..])),
+            ),
+            "set" => (
+                "MEMCACHED_SET",
+                store(shared, &mut reader, &words, Store::Set, connected),
+            ),
+            "add" => (
+                "MEMCACHED_ADD",
+                store(shared, &mut reader, &words, Store::Add, connected),
+            ),
+            "replace" => (
+                "MEMCACHED_REPLACE",
+                store(shared, &mut reader, &words, Store::Replace, connected),
+            ),
+            "delete" => (
+                "MEMCACHED_DELETE",
+                shared
+                    .drain
+                    .check(connected)
+                    .and_then(|()| delete(shared, &words)),
+            ),
+            "version" => (
+                "MEMCACHED_VERSION",
+                Ok(Some(format!("VERSION {}\r\n", env!("CARGO_PKG_VERSION")))),
+            ),
+            "quit" => return Ok(()),
+            _ => ("MEMCACHED_UNKNOWN", Ok(Some("ERROR\r\n".to_owned()))),
+        };
+        let (reply, failed) = match reply {
+            Ok(reply) => (reply, false),
+            // the connection can not be resynchronized after a broken data block
+            Err(KVStoreError::Io(err)) => return Err(err.into()),
+            Err(err) => (Some(format!("SERVER_ERROR {}\r\n", err)), true),
+        };
+        let failed = failed
+            || reply.as_deref().is_some_and(|reply| {
+                reply.starts_with("ERROR") || reply.starts_with("CLIENT_ERROR")
+            });
+        shared
+            .metrics
+            .request(name, now.elapsed().unwrap_or_default(), failed);
+        // with noreply the client does not wait for a reply
+        if let Some(reply) = reply {
+            writer.write_all(reply.as_bytes())?;
+            writer.flush()?;
+        }
+    }
+}
+
+fn get<E: KvsEngine>(shared: &Shared<E>, keys: &[&str]) -> Result<Option<String>> {
+    if keys.is_empty() {
+        return Ok(Some("ERROR\r\n".to_owned()));
+    }
+    shared.check_enabled("GET")?;
+    let mut reply = String::new();
+    for key in keys {
+        shared.hotness.read(key);
+        if let Some(item) = read_item(shared, key)? {
+            reply.push_str(&format!(
+                "VALUE {} {} {}\r\n{}\r\n",
+                key,
+                item.flags,
+                item.data.len(),
+                item.data
+            ));
+        }
+    }
+    reply.push_str("END\r\n");
+    Ok(Some(reply))
+}
+
+/// `<command> <key> <flags> <exptime> <bytes> [noreply]` followed by the data block, of a
+/// connection opened at `connected`
+fn store<E: KvsEngine>(
+    shared: &Shared<E>,
+    reader: &mut impl BufRead,
+    words: &[&str],
+    mode: Store,
+    connected: Instant,
+) -> Result<Option<String>> {
+    let (key, flags, exptime, bytes) = match words {
+        [_, key, flags, exptime, bytes] | [_, key, flags, exptime, bytes, "noreply"] => (
+            *key,
+            flags.parse::<u32>(),
+            exptime.parse::<i64>(),
+            bytes.parse::<usize>(),
+        ),
+        _ => return Ok(Some("ERROR\r\n".to_owned())),
+    };
+    let noreply = words.get(5) == Some(&"noreply");
+    let (flags, exptime, bytes) = match (flags, exptime, bytes) {
+        (Ok(flags), Ok(exptime), Ok(bytes)) => (flags, exptime, bytes),
+        _ => return Ok(Some("CLIENT_ERROR bad command line format\r\n".to_owned())),
+    };
+
+    // the data block is read even for an invalid key, to stay in sync with the client
+    if bytes > MAX_ITEM_SIZE {
+        io::copy(&mut reader.take(bytes as u64 + 2), &mut io::sink())?;
+        return Ok(Some(
+            "SERVER_ERROR object too large for cache\r\n".to_owned(),
+        ));
+    }
+    let mut block = vec![0; bytes + 2];
+    reader.read_exact(&mut block)?;
+    if !block.ends_with(b"\r\n") {
+        // skip the rest of the block, which was longer than announced
+        if block.last() != Some(&b'\n') {
+            reader.read_line(&mut String::new())?;
+        }
+        return Ok(Some("CLIENT_ERROR bad data chunk\r\n".to_owned()));
+    }
+    block.truncate(bytes);
+    if let Some(error) = check_key(key) {
+        return Ok(Some(error));
+    }
+    let data = match String::from_utf8(block) {
+        Ok(data) => data,
+        Err(_) => return Ok(Some("CLIENT_ERROR data must be UTF-8\r\n".to_owned())),
+    };
+    shared.drain.check(connected)?;
+    shared.check_enabled("SET")?;
+    shared.check_writable()?;
+
+    shared.hotness.write(key);
+    let now = unix_now();
+    let expires = match exptime {
+        0 => None,
+        exptime if exptime < 0 => Some(0),
+        exptime if exptime <= MAX_RELATIVE_EXPTIME => Some(now + exptime as u64),
+        exptime => Some(exptime as u64),
+    };
+    let value = Item {
+        flags,
+        expires,
+        data,
+    }
+    .encode()?;
+    let engine = &shared.engine;
+    // the check runs with the write, so no other write of the server comes in between
+    let result = shared
+        .watch_hub
+        .apply(key.to_owned(), Some(value.clone()), || {
+            if mode == Store::Set {
+                return engine.set(key.to_owned(), value);
+            }
+            let exists = match engine.get(key.to_owned())? {
+                Some(stored) => !Item::decode(stored)?.is_expired(now),
+                None => false,
+            };
+            match (mode, exists) {
+                (Store::Add, true) => Err(KVStoreError::KeyExists),
+                (Store::Replace, false) => Err(KVStoreError::KeyNotFound),
+                _ => engine.set(key.to_owned(), value),
+            }
+        });
+    let reply = match result {
+        Ok(()) => "STORED\r\n",
+        Err(KVStoreError::KeyExists) | Err(KVStoreError::KeyNotFound) => "NOT_STORED\r\n",
+        Err(err) => return Err(err),
+    };
+    Ok((!noreply).then(|| reply.to_owned()))
+}
+
+/// `delete <key> [noreply]`
+fn delete<E: KvsEngine>(shared: &Shared<E>, words: &[&str]) -> Result<Option<String>> {
+    let (key, noreply) = match words {
+        [_, key] => (*key, false),
+        [_, key, "noreply"] => (*key, true),
+        _ => return Ok(Some("ERROR\r\n".to_owned())),
+    };
+    shared.check_enabled("RM")?;
+    shared.check_writable()?;
+    shared.hotness.write(key);
+    let reply = match read_item(shared, key)? {
+        Some(_) => {
+            let engine = &shared.engine;
+            match shared
+                .watch_hub
+                .apply(key.to_owned(), None, || engine.remove(key.to_owned()))
+            {
+                Ok(()) => "DELETED\r\n",
+                Err(KVStoreError::KeyNotFound) => "NOT_FOUND\r\n",
+                Err(err) => return Err(err),
+            }
+        }
+        None => "NOT_FOUND\r\n",
+    };
+    Ok((!noreply).then(|| reply.to_owned()))
+}
+
+/// the item of a key, removing it once it expired
+fn read_item<E: KvsEngine>(shared: &Shared<E>, key: &str) -> Result<Option<Item>> {
+    let item = match shared.engine.get(key.to_owned())? {
+        Some(value) => Item::decode(value)?,
+        None => return Ok(None),
+    };
+    let now = unix_now();
+    if !item.is_expired(now) {
+        return Ok(Some(item));
+    }
+    // unless it was set again in the meantime
+    let engine = &shared.engine;
+    let result = shared.watch_hub.apply(key.to_owned(), None, || {
+        let expired = match engine.get(key.to_owned())? {
+            Some(value) => Item::decode(value)?.is_expired(now),
+            None => false,
+        };
+        if !expired {
+            return Err(KVStoreError::KeyNotFound);
+        }
+        engine.remove(key.to_owned())
+    });
+    match result {
+        Ok(()) | Err(KVStoreError::KeyNotFound) => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn check_key(key: &str) -> Option<String> {
+    if key.len() > MAX_KEY_LENGTH || key.chars().any(char::is_control) {
+        return Some("CLIENT_ERROR bad key\r\n".to_owned());
+    }
+    None
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
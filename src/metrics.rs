@@ -0,0 +1,273 @@
+use crate::Result;
+use dashmap::DashMap;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// the most bytes of a StatsD datagram, which fits the MTU of common networks
+const MAX_DATAGRAM_SIZE: usize = 1432;
+
+/// A measurement reported to a metrics sink
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Metric {
+    /// a total which only grows, reported as the increase since the last flush
+    Counter(u64),
+    /// a value which goes up and down, reported as is
+    Gauge(f64),
+}
+
+/// The request counters of a `KvServer`
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    connections: AtomicU64,
+    errors: AtomicU64,
+    panics: AtomicU64,
+    request_micros: AtomicU64,
+    requests: DashMap<&'static str, u64>,
+    pool_panics: DashMap<String, u64>,
+    lock_waits: AtomicU64,
+    lock_timeouts: AtomicU64,
+    lock_wait_micros: AtomicU64,
+    shed: AtomicU64,
+    refused: AtomicU64,
+    slow_clients: AtomicU64,
+}
+
+impl ServerMetrics {
+    pub(crate) fn connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn request(&self, name: &'static str, elapsed: Duration, failed: bool) {
+        *self.requests.entry(name).or_insert(0) += 1;
+        self.request_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        if failed {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// a connection whose handler panicked, on a pool with the label `pool` if it has one
+    pub(crate) fn panic(&self, pool: Option<&str>) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+        if let Some(pool) = pool {
+            *self.pool_panics.entry(pool.to_owned()).or_insert(0) += 1;
+        }
+    }
+
+    /// a lock of keys which had to wait for other writers, and whether it gave up
+    pub(crate) fn lock_wait(&self, elapsed: Duration, timed_out: bool) {
+        self.lock_waits.fetch_add(1, Ordering::Relaxed);
+        self.lock_wait_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        if timed_out {
+            self.lock_timeouts.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// a request shed as the server was overloaded
+    pub(crate) fn shed(&self) {
+        self.shed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// a connection refused by the access list of the server
+    pub(crate) fn refused(&self) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// a client disconnected for falling behind reading its responses
+    pub(crate) fn slow_client(&self) {
+        self.slow_clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The counters as metrics: `connections`, `errors`, `panics`, `request_time_us`,
+    /// `requests.<command>`, `panics.<pool label>`, `lock_waits`, `lock_timeouts` and `lock_wait_time_us`
+    /// counting the locks of keys contended by transactions, `shed` counting the requests
+    /// rejected as the server was overloaded, `refused_connections` counting the connections
+    /// refused by the access list, and `slow_clients` counting the clients disconnected for
+    /// falling behind reading their responses.
+    pub fn metrics(&self) -> Vec<(String, Metric)> {
+        let mut metrics = vec![
+            (
+                "connections".to_owned(),
+                Metric::Counter(self.connections.load(Ordering::Relaxed)),
+            ),
+            (
+                "errors".to_owned(),
+                Metric::Counter(self.errors.load(Ordering::Relaxed)),
+            ),
+            (
+                "panics".to_owned(),
+                Metric::Counter(self.panics.load(Ordering::Relaxed)),
+            ),
+            (
+                "request_time_us".to_owned(),
+                Metric::Counter(self.request_micros.load(Ordering::Relaxed)),
+            ),
+            (
+                "lock_waits".to_owned(),
+                Metric::Counter(self.lock_waits.load(Ordering::Relaxed)),
+            ),
+            (
+                "lock_timeouts".to_owned(),
+                Metric::Counter(self.lock_timeouts.load(Ordering::Relaxed)),
+            ),
+            (
+                "lock_wait_time_us".to_owned(),
+                Metric::Counter(self.lock_wait_micros.load(Ordering::Relaxed)),
+            ),
+            (
+                "shed".to_owned(),
+                Metric::Counter(self.shed.load(Ordering::Relaxed)),
+            ),
+            (
+                "refused_connections".to_owned(),
+                Metric::Counter(self.refused.load(Ordering::Relaxed)),
+            ),
+            (
+                "slow_clients".to_owned(),
+                Metric::Counter(self.slow_clients.load(Ordering::Relaxed)),
+            ),
+        ];
+        for entry in self.requests.iter() {
+            metrics.push((
+                format!("requests.{}", entry.key().to_lowercase()),
+                Metric::Counter(*entry.value()),
+            ));
+        }
+        for entry in self.pool_panics.iter() {
+            metrics.push((
+                format!("panics.{}", entry.key()),
+                Metric::Counter(*entry.value()),
+            ));
+        }
+        metrics
+    }
+}
+
+/** Pushes metrics to a StatsD server over UDP every flush interval, for infrastructure which
+only takes pushed metrics. Counters are sent as `|c` with their increase since the last flush
+and gauges as `|g`. Dropping the sink flushes once more.
+# Example
+```no_run
+use kvs::{Metric, StatsdSink};
+use std::time::Duration;
+
+let sink = StatsdSink::start("127.0.0.1:8125", "kvs", Duration::from_secs(10), || {
+    vec![("live_keys".to_owned(), Metric::Gauge(42.0))]
+})
+.unwrap();
+```
+ */
+pub struct StatsdSink {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl StatsdSink {
+    /// Send the metrics `collect` returns to the StatsD server at `addr` every `interval`,
+    /// with their names prefixed by `prefix` and a dot unless it is empty.
+    pub fn start<F>(addr: &str, prefix: &str, interval: Duration, collect: F) -> Result<Self>
+    where
+        F: FnMut() -> Vec<(String, Metric)> + Send + 'static,
+    {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+        let prefix = if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("{}.", prefix)
+        };
+        let (stop, stopped) = mpsc::channel();
+        let handle = thread::spawn(move || {
+            let mut flusher = Flusher {
+                socket,
+                prefix,
+                collect,
+                counters: HashMap::new(),
+            };
+            loop {
+                let last = !matches!(
+                    stopped.recv_timeout(interval),
+                    Err(RecvTimeoutError::Timeout)
+                );
+                flusher.flush();
+                if last {
+                    return;
+                }
+            }
+        });
+        Ok(StatsdSink {
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for StatsdSink {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+struct Flusher<F> {
+    socket: UdpSocket,
+    prefix: String,
+    collect: F,
+    /// the totals of the counters at the last flush
+    counters: HashMap<String, u64>,
+}
+
+impl<F: FnMut() -> Vec<(String, Metric)>> Flusher<F> {
+    fn flush(&mut self) {
+        let mut lines = Vec::new();
+        for (name, metric) in (self.collect)() {
+            match metric {
+                Metric::Counter(total) => {
+                    let last = self.counters.insert(name.clone(), total).unwrap_or(0);
+                    // a counter which went back was reset, e.g. by a restart
+                    let increase = total.checked_sub(last).unwrap_or(total);
+                    if increase > 0 {
+                        lines.push(format!("{}{}:{}|c", self.prefix, name, increase));
+                    }
+                }
+                Metric::Gauge(value) => lines.push(format!("{}{}:{}|g", self.prefix, name, value)),
+            }
+        }
+
+        let mut datagram = String::new();
+        let mut sent = 0;
+        for line in lines {
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_SIZE {
+                sent += self.send(&datagram);
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            let _ = write!(datagram, "{}", line);
+        }
+        if !datagram.is_empty() {
+            sent += self.send(&datagram);
+        }
+        debug!("Sent {} StatsD datagrams", sent);
+    }
+
+    fn send(&self, datagram: &str) -> usize {
+        match self.socket.send(datagram.as_bytes()) {
+            Ok(_) => 1,
+            Err(err) => {
+                warn!("Can not send metrics to StatsD: {}", err);
+                0
+            }
+        }
+    }
+}
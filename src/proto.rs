@@ -1,7 +1,9 @@
+use crate::{ClientInfo, KVStoreError, TopKeys, Transform, WatchEvent};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 
 /// a request struct which supports serialization and deserialization
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
     /// for set command
     SET(String, String),
@@ -9,13 +11,316 @@ pub enum Request {
     RM(String),
     /// for get command
     GET(String),
+    /// for scan command, carrying a glob pattern
+    SCAN(String),
+    /// for watch command, carrying a key prefix and the last revision the client has seen
+    WATCH(String, u64),
+    /// for replicated writes, applied in order; removing a missing key is not an error.
+    /// Only accepted on a connection which completed the handshake of `REPLICATE`.
+    BATCH(Vec<WatchEvent>),
+    /// for select command, switching the connection to a logical database, 0 by default
+    SELECT(u32),
+    /// for flush command, removing all keys of the selected database
+    FLUSH,
+    /// for dbstats command, returning the `DatabaseStats` of the selected database
+    DBSTATS,
+    /// for topkeys command, carrying how many of the most read and most written keys to return
+    TOPKEYS(usize),
+    /// for client list command, answered with the kvs protocol connections of the server
+    CLIENTLIST,
+    /// for client kill command, closing the connection with the given id
+    CLIENTKILL(u64),
+    /// for begin command, starting a transaction of the connection: its reads see the keys
+    /// as of now and its writes are buffered until COMMIT
+    BEGIN,
+    /// for commit command, applying the writes of the transaction together
+    COMMIT,
+    /// for rollback command, discarding the writes of the transaction
+    ROLLBACK,
+    /// for checkpoint command, answered with the keys of all databases in batches of
+    /// `Response::Pairs`, followed by a `Response::Revision` with the revision they reflect
+    CHECKPOINT,
+    /// for cutover command, switching the reads of a server migrating to another engine over
+    /// to the new one, see `MigratingEngine`
+    CUTOVER,
+    /// for topology command, answered with the shards of the cluster the server belongs to,
+    /// see `KvServer::cluster`
+    TOPOLOGY,
+    /// for setbit command, setting the bit at an offset of the bitmap stored base64 encoded
+    /// as the value of a key, answered with the bit before as `0` or `1`
+    SETBIT(String, u64, bool),
+    /// for getbit command, answered with the bit at an offset of a bitmap as `0` or `1`
+    GETBIT(String, u64),
+    /// for bitcount command, answered with the number of set bits of a bitmap
+    BITCOUNT(String),
+    /// for pfadd command, adding elements to the HyperLogLog sketch stored as the value of a
+    /// key, answered with `1` if its estimate may have changed and `0` otherwise
+    PFADD(String, Vec<String>),
+    /// for pfcount command, answered with the estimated number of distinct elements added to
+    /// the sketches of the keys together
+    PFCOUNT(Vec<String>),
+    /// for getrange command, answered with the given number of bytes of the value of a key
+    /// from an offset on, fewer past the end of the value
+    GETRANGE(String, u64, u64),
+    /// for setrange command, overwriting the bytes of the value of a key from an offset on,
+    /// padding it with zero bytes up to the offset, answered with the new length of the value
+    SETRANGE(String, u64, String),
+    /// for jsonset command, setting the field at a JSON pointer of the JSON document stored
+    /// as the value of a key to the given JSON text; a missing key is an empty object
+    JSONSET(String, String, String),
+    /// for jsonget command, answered with the field at a JSON pointer of a JSON document as
+    /// JSON text
+    JSONGET(String, String),
+    /// for a leader renewing its lease at its follower for the given number of milliseconds,
+    /// carrying the epoch it knows of, see `KvServer::leader_lease`
+    LEASE(u64, u64),
+    /// for promote command, making a follower the leader once the lease of the old leader
+    /// expired, answered with the epoch of the promotion
+    PROMOTE,
+    /// for health command, answered with `serving`, or `draining` while the server drains its
+    /// connections, see `Drain`
+    HEALTH,
+    /// for drain command, draining the connections of the server, those open now being
+    /// served for the given number of milliseconds, see `Drain`
+    DRAIN(u64),
+    /// for info command, answered with the `ServerInfo` of the server
+    INFO,
+    /// for the handshake of a replicator pushing the writes of the store with the given
+    /// instance id, or of a store without one if it is empty, answered with the instance id
+    /// of the store of the server, see `KvServer::replicate_to`. The connection may send
+    /// `BATCH` once it passed.
+    REPLICATE(String),
+    /// for a `GET` or `SCAN` whose values the server transforms before answering, so a
+    /// client only receives the part of a big value it needs
+    TRANSFORM(Transform, Box<Request>),
+    /// for a request carrying the W3C `traceparent` of the client span, see `trace::TraceContext`
+    TRACED(String, Box<Request>),
+    /// for a request tagged with an id chosen by the client, answered by a `Response::Tagged`
+    /// with the same id, possibly before the responses of requests sent earlier
+    TAGGED(u64, Box<Request>),
+    /// for a request carrying the id of its client and an id the client numbers its requests
+    /// with, so the server answers a retry with the cached response instead of running it again
+    RETRYABLE(String, u64, Box<Request>),
+    /// for a request the client waits for at most the given number of milliseconds: the
+    /// server rejects it once they passed and stops a scan running out of them
+    DEADLINE(u64, Box<Request>),
+    /// for a request which must see the writes up to a consistency token, answered once the
+    /// server applied them with a `Response::Token`; attached inside a `DEADLINE`, which
+    /// bounds the wait
+    AFTER(u64, Box<Request>),
 }
 
 /// a response struct which supports serialization and deserialization
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum Response {
     /// for successful request
     Ok(Option<String>),
+    /// for successful scan request
+    Pairs(Vec<(String, String)>),
+    /// for successful dbstats request
+    DatabaseStats(DatabaseStats),
+    /// for successful topkeys request
+    TopKeys(TopKeys),
+    /// for successful client list request
+    Clients(Vec<ClientInfo>),
+    /// for each change streamed to a watch request
+    Event(WatchEvent),
     /// for failed request
     Err(String),
-}
\ No newline at end of file
+    /// for a request failed with a `KVStoreError`, carrying its code and classification
+    Failed(RemoteError),
+    /// for a `Request::TAGGED`, carrying its id and its response
+    Tagged(u64, Box<Response>),
+    /// for the end of a checkpoint, carrying the revision of the last write it reflects
+    Revision(u64),
+    /// for successful topology request, carrying the shards, empty if the server is not part
+    /// of a cluster
+    Topology(Vec<String>),
+    /// for successful info request
+    Info(ServerInfo),
+    /// for a `Request::AFTER`, carrying the consistency token of the writes the server applied
+    /// once it ran, and its response
+    Token(u64, Box<Response>),
+}
+
+/// A `KVStoreError` as sent by the server, see `KVStoreError::Remote`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct RemoteError {
+    /// the code of the error, see `KVStoreError::code`
+    pub code: u32,
+    /// the message of the error
+    pub message: String,
+    /// see `KVStoreError::is_retryable`
+    pub retryable: bool,
+    /// see `KVStoreError::is_transient`
+    pub transient: bool,
+    /// see `KVStoreError::is_corruption`
+    pub corruption: bool,
+}
+
+impl From<&KVStoreError> for RemoteError {
+    fn from(err: &KVStoreError) -> Self {
+        RemoteError {
+            code: err.code(),
+            message: err.to_string(),
+            retryable: err.is_retryable(),
+            transient: err.is_transient(),
+            corruption: err.is_corruption(),
+        }
+    }
+}
+
+/// the keys of a logical database, see `Request::SELECT`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DatabaseStats {
+    /// the number of the database
+    pub database: u32,
+    /// the number of keys in the database
+    pub keys: u64,
+    /// the total size of the keys and values in the database in bytes
+    pub bytes: u64,
+}
+
+/// what a server tells about itself, see `Request::INFO`
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ServerInfo {
+    /// the id of the store of the server, see `KvStore::instance_id`, or None if its engine
+    /// does not identify its stores
+    pub instance_id: Option<String>,
+    /// the id of the store whose writes are replicated to the server, once its replicator
+    /// identified itself since the server started
+    pub replicating_from: Option<String>,
+    /// the consistency token of the writes the server applied, see `Request::AFTER`
+    pub revision: u64,
+    /// what `Request::HEALTH` is answered with
+    pub health: String,
+}
+
+impl Response {
+    /// the response to a request failed with `err`
+    pub(crate) fn error(err: &KVStoreError) -> Response {
+        Response::Failed(err.into())
+    }
+
+    /// whether the request failed
+    pub(crate) fn is_err(&self) -> bool {
+        matches!(self, Response::Err(_) | Response::Failed(_))
+    }
+}
+
+/// the names of the commands a server can be configured not to run, see `Request::name`
+pub(crate) const COMMANDS: &[&str] = &[
+    "SET",
+    "RM",
+    "GET",
+    "SCAN",
+    "WATCH",
+    "BATCH",
+    "SELECT",
+    "FLUSH",
+    "DBSTATS",
+    "TOPKEYS",
+    "CLIENTLIST",
+    "CLIENTKILL",
+    "BEGIN",
+    "COMMIT",
+    "ROLLBACK",
+    "CHECKPOINT",
+    "CUTOVER",
+    "TOPOLOGY",
+    "SETBIT",
+    "GETBIT",
+    "BITCOUNT",
+    "PFADD",
+    "PFCOUNT",
+    "GETRANGE",
+    "SETRANGE",
+    "JSONSET",
+    "JSONGET",
+    "LEASE",
+    "PROMOTE",
+    "HEALTH",
+    "DRAIN",
+    "INFO",
+    "REPLICATE",
+];
+
+impl Request {
+    /// the name of the command
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::SET(..) => "SET",
+            Request::RM(..) => "RM",
+            Request::GET(..) => "GET",
+            Request::SCAN(..) => "SCAN",
+            Request::WATCH(..) => "WATCH",
+            Request::BATCH(..) => "BATCH",
+            Request::SELECT(..) => "SELECT",
+            Request::FLUSH => "FLUSH",
+            Request::DBSTATS => "DBSTATS",
+            Request::TOPKEYS(..) => "TOPKEYS",
+            Request::CLIENTLIST => "CLIENTLIST",
+            Request::CLIENTKILL(..) => "CLIENTKILL",
+            Request::BEGIN => "BEGIN",
+            Request::COMMIT => "COMMIT",
+            Request::ROLLBACK => "ROLLBACK",
+            Request::CHECKPOINT => "CHECKPOINT",
+            Request::CUTOVER => "CUTOVER",
+            Request::TOPOLOGY => "TOPOLOGY",
+            Request::SETBIT(..) => "SETBIT",
+            Request::GETBIT(..) => "GETBIT",
+            Request::BITCOUNT(..) => "BITCOUNT",
+            Request::PFADD(..) => "PFADD",
+            Request::PFCOUNT(..) => "PFCOUNT",
+            Request::GETRANGE(..) => "GETRANGE",
+            Request::SETRANGE(..) => "SETRANGE",
+            Request::JSONSET(..) => "JSONSET",
+            Request::JSONGET(..) => "JSONGET",
+            Request::LEASE(..) => "LEASE",
+            Request::PROMOTE => "PROMOTE",
+            Request::HEALTH => "HEALTH",
+            Request::DRAIN(..) => "DRAIN",
+            Request::INFO => "INFO",
+            Request::REPLICATE(..) => "REPLICATE",
+            Request::TRACED(..) => "TRACED",
+            Request::TAGGED(..) => "TAGGED",
+            Request::RETRYABLE(_, _, request)
+            | Request::DEADLINE(_, request)
+            | Request::AFTER(_, request)
+            | Request::TRANSFORM(_, request) => request.name(),
+        }
+    }
+}
+
+/// The most common requests, decoded borrowing their keys and values from the frame they were
+/// read from, unless those hold escapes. Encoded like the `Request` variants of the same name.
+#[derive(Deserialize, Debug)]
+pub(crate) enum RequestRef<'a> {
+    #[serde(rename = "SET")]
+    Set(#[serde(borrow)] Cow<'a, str>, #[serde(borrow)] Cow<'a, str>),
+    #[serde(rename = "RM")]
+    Rm(#[serde(borrow)] Cow<'a, str>),
+    #[serde(rename = "GET")]
+    Get(#[serde(borrow)] Cow<'a, str>),
+}
+
+impl<'a> RequestRef<'a> {
+    /// the request encoded as `payload`, or None if it is not one of these; other requests
+    /// are told apart by their leading tag, so they are only decoded once by the caller
+    pub(crate) fn parse(payload: &'a [u8]) -> Option<RequestRef<'a>> {
+        const TAGS: [&[u8]; 3] = [br#"{"SET":"#, br#"{"RM":"#, br#"{"GET":"#];
+        if !TAGS.iter().any(|tag| payload.starts_with(tag)) {
+            return None;
+        }
+        serde_json::from_slice(payload).ok()
+    }
+
+    /// the name of the command, see `Request::name`
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            RequestRef::Set(..) => "SET",
+            RequestRef::Rm(..) => "RM",
+            RequestRef::Get(..) => "GET",
+        }
+    }
+}
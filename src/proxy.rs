@@ -0,0 +1,250 @@
+use crate::cluster::{key_of, HashRing, DEFAULT_VIRTUAL_NODES};
+use crate::frame::{self, FrameWriter, FLAG_LZ4};
+use crate::server::wait_for_request;
+use crate::{Client, DatabaseStats, KVStoreError, Request, Response, Result};
+use log::{debug, error, info};
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// how often a proxy waiting for connections checks whether it is stopped
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A proxy speaking the kvs protocol which spreads the keys over shards, each a kvs-server,
+/// so clients need no sharding logic of their own.
+///
+/// A key is served by the shard after its hash on a consistent hash ring, so changing the
+/// shards with `set_shards` only moves the keys of the ring ranges which changed hands.
+/// The proxy does not move the keys themselves: copy them before changing the shards.
+/// Scans and flushes go to every shard and scans are merged in key order.
+/// Watches, transactions, batches, tagged requests and checkpoints are not supported.
+#[derive(Clone)]
+pub struct KvProxy {
+    ring: Arc<RwLock<HashRing>>,
+    /// incremented by each change of the shards
+    generation: Arc<AtomicU64>,
+    virtual_nodes: usize,
+    is_stop: Arc<AtomicBool>,
+}
+
+impl KvProxy {
+    /// init a proxy for the kvs-servers at `shards`, serving until `is_stop` is set
+    pub fn new(shards: Vec<String>, is_stop: Arc<AtomicBool>) -> Result<KvProxy> {
+        check_shards(&shards)?;
+        Ok(KvProxy {
+            ring: Arc::new(RwLock::new(HashRing::new(shards, DEFAULT_VIRTUAL_NODES))),
+            generation: Arc::new(AtomicU64::new(0)),
+            virtual_nodes: DEFAULT_VIRTUAL_NODES,
+            is_stop,
+        })
+    }
+
+    /// Give each shard `virtual_nodes` points of the ring, spreading the keys more evenly the
+    /// more it has. Every proxy of the same shards must use the same number.
+    pub fn virtual_nodes(mut self, virtual_nodes: usize) -> Self {
+        self.virtual_nodes = virtual_nodes.max(1);
+        let shards = self.shards();
+        *self.ring.write().unwrap() = HashRing::new(shards, self.virtual_nodes);
+        self
+    }
+
+    /// the addresses of the shards
+    pub fn shards(&self) -> Vec<String> {
+        self.ring.read().unwrap().shards.clone()
+    }
+
+    /// Route requests to the kvs-servers at `shards` from now on, also on the connections
+    /// already served. A request in flight finishes on the shard it was sent to.
+    pub fn set_shards(&self, shards: Vec<String>) -> Result<()> {
+        check_shards(&shards)?;
+        let ring = HashRing::new(shards, self.virtual_nodes);
+        info!("Route requests to the shards {:?}", ring.shards);
+        *self.ring.write().unwrap() = ring;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// the address of the shard serving `key`
+    pub fn shard_of(&self, key: &str) -> String {
+        let ring = self.ring.read().unwrap();
+        ring.shard_of(key)
+            .expect("a proxy has at least one shard")
+            .to_owned()
+    }
+
+    /// serve client connections at `addr` until `is_stop` is set
+    pub fn serve(&self, addr: &str) -> Result<()> {
+        self.serve_listener(TcpListener::bind(addr)?)
+    }
+
+    /// serve the connections of a listener which is already bound until `is_stop` is set,
+    /// each on a thread of its own
+    pub fn serve_listener(&self, listener: TcpListener) -> Result<()> {
+        listener.set_nonblocking(true)?;
+        while !self.is_stop.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    // the stream may inherit the flag of the listener on some platforms
+                    stream.set_nonblocking(false)?;
+                    let proxy = self.clone();
+                    thread::spawn(move || {
+                        if let Err(err) = proxy.handle_connection(stream) {
+                            error!("Unexpected error occurs when proxying requests: {:?}", err)
+                        }
+                    });
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL)
+                }
+                Err(err) => error!("Connection failed: {}", err),
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_connection(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream.try_clone()?);
+        if !wait_for_request(&stream, &self.is_stop)? {
+            return Ok(());
+        }
+        let flags = match frame::server_handshake(&mut reader, &mut writer, FLAG_LZ4)? {
+            Some(flags) => flags,
+            None => return Ok(()),
+        };
+        let mut writer = FrameWriter::new(writer, flags);
+        let mut session = ProxySession::default();
+        loop {
+            if reader.buffer().is_empty() && !wait_for_request(&stream, &self.is_stop)? {
+                return Ok(());
+            }
+            let request: Request = match frame::read_frame(&mut reader)? {
+                Some(request) => request,
+                None => return Ok(()),
+            };
+            debug!("Proxy request: {:?}", &request);
+            let generation = self.generation.load(Ordering::SeqCst);
+            if generation != session.generation {
+                // the connections to removed shards are not needed anymore
+                let shards = self.shards();
+                session.shards.retain(|shard, _| shards.contains(shard));
+                session.generation = generation;
+            }
+            let response = self.proxy_request(&mut session, request);
+            writer.send(&response)?;
+        }
+    }
+
+    fn proxy_request(&self, session: &mut ProxySession, request: Request) -> Response {
+        if let Some(key) = key_of(&request) {
+            let shard = self.shard_of(key);
+            return session.send(&shard, &request);
+        }
+        match unwrap(request) {
+            Request::SCAN(pattern) => {
+                let mut pairs = Vec::new();
+                for shard in self.shards() {
+                    match session.send(&shard, &Request::SCAN(pattern.clone())) {
+                        Response::Pairs(shard_pairs) => pairs.extend(shard_pairs),
+                        response => return response,
+                    }
+                }
+                pairs.sort_unstable();
+                Response::Pairs(pairs)
+            }
+            Request::DBSTATS => {
+                let mut stats = DatabaseStats {
+                    database: session.database,
+                    ..DatabaseStats::default()
+                };
+                for shard in self.shards() {
+                    match session.send(&shard, &Request::DBSTATS) {
+                        Response::DatabaseStats(shard_stats) => {
+                            stats.keys += shard_stats.keys;
+                            stats.bytes += shard_stats.bytes;
+                        }
+                        response => return response,
+                    }
+                }
+                Response::DatabaseStats(stats)
+            }
+            request @ (Request::SELECT(..) | Request::FLUSH) => {
+                for shard in self.shards() {
+                    let response = session.send(&shard, &request);
+                    if response.is_err() {
+                        return response;
+                    }
+                }
+                if let Request::SELECT(database) = request {
+                    session.database = database;
+                }
+                Response::Ok(None)
+            }
+            request => Response::Err(format!(
+                "a {} request is not supported by kvs-proxy",
+                request.name()
+            )),
+        }
+    }
+}
+
+/// the connections of a client connection to the shards, opened when first needed
+#[derive(Default)]
+struct ProxySession {
+    shards: HashMap<String, Client>,
+    /// the database selected by the client, selected on each connection to a shard
+    database: u32,
+    /// the generation of the shards the connections were checked against
+    generation: u64,
+}
+
+impl ProxySession {
+    /// send a request to a shard, dropping its connection if it fails so the next request
+    /// reconnects
+    fn send(&mut self, shard: &str, request: &Request) -> Response {
+        match self.try_send(shard, request) {
+            Ok(response) => response,
+            Err(err) => {
+                self.shards.remove(shard);
+                Response::error(&err)
+            }
+        }
+    }
+
+    fn try_send(&mut self, shard: &str, request: &Request) -> Result<Response> {
+        if !self.shards.contains_key(shard) {
+            let mut client = Client::new(shard)?;
+            if self.database != 0 {
+                client.select(self.database)?;
+            }
+            self.shards.insert(shard.to_owned(), client);
+        }
+        self.shards.get_mut(shard).unwrap().send(request)
+    }
+}
+
+/// the request without the trace, idempotency token, deadline or consistency token it is
+/// sent with, which are
+/// not passed on to every shard
+fn unwrap(request: Request) -> Request {
+    match request {
+        Request::TRACED(_, request)
+        | Request::RETRYABLE(_, _, request)
+        | Request::DEADLINE(_, request)
+        | Request::AFTER(_, request) => unwrap(*request),
+        request => request,
+    }
+}
+
+fn check_shards(shards: &[String]) -> Result<()> {
+    if shards.is_empty() {
+        return Err(KVStoreError::CommonStringError(
+            "a proxy needs at least one shard".to_owned(),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use crate::{KVStoreError, Result};
+
+/// the largest value `SETRANGE` grows a value to, 512 MiB
+pub(crate) const MAX_RANGE_END: u64 = 512 * 1024 * 1024;
+
+/// The `length` bytes of `value` from `offset` on, fewer past the end of the value and none
+/// for a missing key. The range must not start or end inside of a character.
+pub(crate) fn get_range(
+    key: &str,
+    value: Option<String>,
+    offset: u64,
+    length: u64,
+) -> Result<String> {
+    let value = value.unwrap_or_default();
+    let start = clamp(&value, offset);
+    let end = clamp(&value, offset.saturating_add(length));
+    check_boundary(key, &value, start)?;
+    check_boundary(key, &value, end)?;
+    Ok(value[start..end].to_owned())
+}
+
+/// Overwrite the bytes of `value` from `offset` on with `data`, padding the value with zero
+/// bytes up to `offset` if it is shorter, and return the new value. A missing key is an
+/// empty value. The range must not start or end inside of a character.
+pub(crate) fn set_range(
+    key: &str,
+    value: Option<String>,
+    offset: u64,
+    data: &str,
+) -> Result<String> {
+    let end = offset.saturating_add(data.len() as u64);
+    if end > MAX_RANGE_END {
+        return Err(KVStoreError::CommonStringError(format!(
+            "a value can not grow beyond {} bytes",
+            MAX_RANGE_END
+        )));
+    }
+    let mut value = value.unwrap_or_default();
+    let (start, end) = (offset as usize, end as usize);
+    if value.len() < start {
+        value.extend(std::iter::repeat_n('\0', start - value.len()));
+    }
+    let end = end.min(value.len());
+    check_boundary(key, &value, start)?;
+    check_boundary(key, &value, end)?;
+    value.replace_range(start..end, data);
+    Ok(value)
+}
+
+/// an offset into `value`, the end of it if the offset is past it
+fn clamp(value: &str, offset: u64) -> usize {
+    offset.min(value.len() as u64) as usize
+}
+
+fn check_boundary(key: &str, value: &str, index: usize) -> Result<()> {
+    if !value.is_char_boundary(index) {
+        return Err(KVStoreError::CommonStringError(format!(
+            "byte {} of the value of {} is inside of a character",
+            index, key
+        )));
+    }
+    Ok(())
+}
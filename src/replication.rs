@@ -0,0 +1,424 @@
+use crate::watch::{WatchHub, WatchReceiver};
+use crate::{Client, KVStoreError, KvsEngine, Request, Result, WatchEvent};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const BATCH_SIZE: usize = 128;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Pushes the writes of a server to a remote server in the background.
+///
+/// Writes are first appended to a spool file, so an unreachable remote only delays replication:
+/// the spool keeps growing until the remote is back and the acknowledged position survives restarts.
+/// Writes applied right before a crash may be missing from the spool.
+///
+/// A replicator started without an earlier spool first installs a snapshot of all keys on the
+/// remote, taken consistently with the writes which follow it.
+///
+/// Each push starts with a handshake exchanging the instance ids of the stores, see
+/// `KvStore::instance_id`. The replicator keeps pushing to the store it first reached and fails
+/// the pushes with `KVStoreError::UnrelatedStore` once the remote serves another one, e.g. after
+/// it was wiped, and the remote refuses the writes of other stores than the one it first
+/// accepted, which its store records, see `KvStore::accept_replication_source`.
+#[derive(Clone)]
+pub struct Replicator {
+    shared: Arc<Shared>,
+}
+
+/// The progress of a `Replicator`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplicationStats {
+    /// number of writes waiting to be acknowledged by the remote
+    pub pending_events: u64,
+    /// size of the spooled writes waiting to be acknowledged in bytes
+    pub pending_bytes: u64,
+    /// number of writes acknowledged by the remote since the replicator started
+    pub replicated_events: u64,
+    /// number of keys in the snapshot installed on the remote, or 0 if none was needed
+    pub snapshot_keys: u64,
+    /// how long the oldest pending write has been waiting
+    pub lag: Duration,
+    /// the error of the last failed push, cleared by the next successful one, or the error
+    /// which stopped spooling the writes, after which none is replicated any more
+    pub last_error: Option<String>,
+    /// the instance id of the store of the remote, once the first handshake with it succeeded
+    pub remote_instance_id: Option<String>,
+}
+
+struct Shared {
+    remote: String,
+    /// the instance id of the store whose writes are pushed, empty if its engine has none
+    instance_id: String,
+    spool_path: PathBuf,
+    position_path: PathBuf,
+    remote_id_path: PathBuf,
+    state: Mutex<SpoolState>,
+    appended: Condvar,
+}
+
+struct SpoolState {
+    writer: File,
+    len: u64,
+    acked: u64,
+    pending_events: u64,
+    replicated_events: u64,
+    snapshot_keys: u64,
+    last_error: Option<String>,
+    /// the error which stopped the spooling of writes, which stays
+    spool_error: Option<String>,
+    remote_id: Option<String>,
+}
+
+/// a write in the spool file
+#[derive(Serialize, Deserialize)]
+struct SpoolRecord {
+    at_millis: u64,
+    event: WatchEvent,
+}
+
+impl Replicator {
+    /// subscribe to all writes of the hub and start pushing them to `remote`
+    pub(crate) fn start<E: KvsEngine>(
+        engine: &E,
+        hub: &WatchHub,
+        remote: &str,
+        spool_dir: PathBuf,
+    ) -> Result<Replicator> {
+        fs::create_dir_all(&spool_dir)?;
+        let spool_path = spool_dir.join("replication.spool");
+        let position_path = spool_dir.join("replication.pos");
+        let remote_id_path = spool_dir.join("replication.remote");
+        let fresh = !spool_path.exists() && !position_path.exists();
+
+        let writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&spool_path)?;
+        let acked = match fs::read_to_string(&position_path) {
+            Ok(position) => position
+                .trim()
+                .parse::<u64>()
+                .unwrap_or(0)
+                .min(writer.metadata()?.len()),
+            Err(_) => 0,
+        };
+        let (mut pending_events, mut len) = count_records(&spool_path, acked)?;
+        let remote_id = fs::read_to_string(&remote_id_path)
+            .ok()
+            .map(|id| id.trim().to_owned())
+            .filter(|id| !id.is_empty());
+
+        // a new remote gets the current state instead of the writes from the beginning
+        let (revision, snapshot, receiver) = hub.subscribe(String::new(), || {
+            if fresh {
+                engine.scan_match("*".to_owned())
+            } else {
+                Ok(Vec::new())
+            }
+        })?;
+        let snapshot_keys = snapshot.len() as u64;
+        if fresh {
+            info!(
+                "Install a snapshot of {} keys at revision {} on {}",
+                snapshot_keys, revision, remote
+            );
+            let at_millis = now_millis();
+            let mut spool = BufWriter::new(&writer);
+            for (key, value) in snapshot {
+                let record = serde_json::to_vec(&SpoolRecord {
+                    at_millis,
+                    event: WatchEvent {
+                        revision,
+                        key,
+                        value: Some(value),
+                    },
+                })?;
+                spool.write_all(&record)?;
+                len += record.len() as u64;
+            }
+            spool.flush()?;
+            pending_events += snapshot_keys;
+        } else if pending_events > 0 {
+            info!(
+                "Resume replication to {} with {} pending writes",
+                remote, pending_events
+            );
+        }
+
+        let shared = Arc::new(Shared {
+            remote: remote.to_owned(),
+            instance_id: engine.instance_id().unwrap_or_default(),
+            spool_path,
+            position_path,
+            remote_id_path,
+            state: Mutex::new(SpoolState {
+                writer,
+                len,
+                acked,
+                pending_events,
+                replicated_events: 0,
+                snapshot_keys,
+                last_error: None,
+                spool_error: None,
+                remote_id,
+            }),
+            appended: Condvar::new(),
+        });
+
+        let tail = Arc::clone(&shared);
+        thread::spawn(move || {
+            if let Err(err) = tail.spool(receiver) {
+                warn!("Replication spool stopped: {}", err);
+                let err = format!("the replication spool stopped: {}", err);
+                tail.state.lock().unwrap().spool_error = Some(err);
+            }
+        });
+        let sender = Arc::clone(&shared);
+        thread::spawn(move || sender.push_loop());
+
+        Ok(Replicator { shared })
+    }
+
+    /// Return the current progress of the replication.
+    pub fn stats(&self) -> ReplicationStats {
+        let state = self.shared.state.lock().unwrap();
+        let lag = read_records(&self.shared.spool_path, state.acked, state.len, 1)
+            .ok()
+            .and_then(|(records, _)| records.into_iter().next())
+            .map(|record| Duration::from_millis(now_millis().saturating_sub(record.at_millis)))
+            .unwrap_or_default();
+        ReplicationStats {
+            pending_events: state.pending_events,
+            pending_bytes: state.len - state.acked,
+            replicated_events: state.replicated_events,
+            snapshot_keys: state.snapshot_keys,
+            lag,
+            last_error: state.spool_error.clone().or(state.last_error.clone()),
+            remote_instance_id: state.remote_id.clone(),
+        }
+    }
+}
+
+impl Shared {
+    fn spool(&self, receiver: WatchReceiver) -> Result<()> {
+        for event in receiver {
+            let record = serde_json::to_vec(&SpoolRecord {
+                at_millis: now_millis(),
+                event,
+            })?;
+            let mut state = self.state.lock().unwrap();
+            state.writer.write_all(&record)?;
+            state.len += record.len() as u64;
+            state.pending_events += 1;
+            self.appended.notify_one();
+        }
+        Ok(())
+    }
+
+    fn push_loop(&self) {
+        let mut backoff = Duration::from_millis(100);
+        let mut failures = 0;
+        loop {
+            let (events, end) = match self.next_batch() {
+                Ok(batch) => batch,
+                Err(err) => {
+                    warn!("Can not read the replication spool: {}", err);
+                    thread::sleep(MAX_BACKOFF);
+                    continue;
+                }
+            };
+            let count = events.len() as u64;
+            match self.push(events) {
+                Ok(()) => {
+                    if failures > 0 {
+                        info!(
+                            "Replication to {} resumed after {} failures",
+                            self.remote, failures
+                        );
+                    }
+                    failures = 0;
+                    backoff = Duration::from_millis(100);
+                    if let Err(err) = self.acknowledge(end, count) {
+                        warn!("Can not record the replication position: {}", err);
+                    }
+                }
+                Err(err) => {
+                    failures += 1;
+                    warn!("Replication to {} failed: {}", self.remote, err);
+                    self.state.lock().unwrap().last_error = Some(err.to_string());
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    /// wait for pending writes and return up to `BATCH_SIZE` of them with the offset after them
+    fn next_batch(&self) -> Result<(Vec<WatchEvent>, u64)> {
+        let mut state = self.state.lock().unwrap();
+        while state.acked == state.len {
+            state = self.appended.wait(state).unwrap();
+        }
+        let (records, end) = read_records(&self.spool_path, state.acked, state.len, BATCH_SIZE)?;
+        Ok((
+            records.into_iter().map(|record| record.event).collect(),
+            end,
+        ))
+    }
+
+    /// push `events` to the remote after the handshake
+    fn push(&self, events: Vec<WatchEvent>) -> Result<()> {
+        let mut client = Client::new(&self.remote)?;
+        let remote_id = client
+            .request(&Request::REPLICATE(self.instance_id.clone()))?
+            .unwrap_or_default();
+        self.check_remote(remote_id)?;
+        client.request(&Request::BATCH(events))?;
+        Ok(())
+    }
+
+    /// fail unless the remote serves the store it served at the first handshake, which is
+    /// recorded in the spool directory
+    fn check_remote(&self, remote_id: String) -> Result<()> {
+        // the stores of engines without an id can not be told apart
+        if remote_id.is_empty() {
+            return Ok(());
+        }
+        let mut state = self.state.lock().unwrap();
+        match &state.remote_id {
+            Some(expected) if *expected != remote_id => {
+                Err(KVStoreError::UnrelatedStore(expected.clone(), remote_id))
+            }
+            Some(_) => Ok(()),
+            None => {
+                info!("Replicate to the store {} at {}", remote_id, self.remote);
+                fs::write(&self.remote_id_path, &remote_id)?;
+                state.remote_id = Some(remote_id);
+                Ok(())
+            }
+        }
+    }
+
+    fn acknowledge(&self, end: u64, count: u64) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.acked = end;
+        state.pending_events -= count;
+        state.replicated_events += count;
+        state.last_error = None;
+        if state.acked == state.len {
+            // everything is replicated, so the spool can start over
+            state.writer.set_len(0)?;
+            state.len = 0;
+            state.acked = 0;
+        }
+        let tmp_path = self.position_path.with_extension("tmp");
+        fs::write(&tmp_path, state.acked.to_string())?;
+        fs::rename(&tmp_path, &self.position_path)?;
+        Ok(())
+    }
+}
+
+/// Replace the keys of `engine` with a checkpoint of the server at `remote`, returning the
+/// revision of the remote it reflects
+pub(crate) fn bootstrap<E: KvsEngine>(engine: &E, remote: &str) -> Result<u64> {
+    let mut stale: HashSet<String> = engine
+        .scan_match("*".to_owned())?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    let mut copied = 0;
+    let revision = Client::new(remote)?.checkpoint(|pairs| {
+        for (key, value) in pairs {
+            stale.remove(&key);
+            engine.set(key, value)?;
+            copied += 1;
+        }
+        Ok(())
+    })?;
+    for key in &stale {
+        engine.remove(key.clone())?;
+    }
+    info!(
+        "Bootstrapped {} keys at revision {} from {}, removed {} stale keys",
+        copied,
+        revision,
+        remote,
+        stale.len()
+    );
+    Ok(revision)
+}
+
+/// Count the records of the spool file at `path` from `start` on, one at a time, cutting a
+/// record a crash tore off its end. Return the count and the length of the file after it.
+fn count_records(path: &Path, start: u64) -> Result<(u64, u64)> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut iter = Deserializer::from_reader(BufReader::new(&mut file)).into_iter::<SpoolRecord>();
+    let mut count = 0;
+    let mut end = start;
+    loop {
+        match iter.next() {
+            Some(Ok(_)) => {
+                count += 1;
+                end = start + iter.byte_offset() as u64;
+            }
+            Some(Err(err)) if err.is_eof() => break,
+            Some(Err(err)) => return Err(err.into()),
+            None => return Ok((count, end)),
+        }
+    }
+    drop(iter);
+    warn!(
+        "Cut the torn record at offset {} of {:?}, {} bytes",
+        end,
+        path,
+        file.metadata()?.len() - end
+    );
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(end)?;
+    file.sync_all()?;
+    Ok((count, end))
+}
+
+/// read up to `limit` records between `start` and `end`, returning them with the offset after the last one
+fn read_records(
+    path: &PathBuf,
+    start: u64,
+    end: u64,
+    limit: usize,
+) -> Result<(Vec<SpoolRecord>, u64)> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let reader = BufReader::new(file.take(end - start));
+    let mut iter = Deserializer::from_reader(reader).into_iter::<SpoolRecord>();
+    let mut records = Vec::new();
+    while records.len() < limit {
+        match iter.next() {
+            Some(record) => records.push(record?),
+            None => break,
+        }
+    }
+    let offset = start + iter.byte_offset() as u64;
+    if records.is_empty() && offset < end {
+        return Err(KVStoreError::CommonStringError(format!(
+            "corrupted replication spool at offset {}",
+            offset
+        )));
+    }
+    Ok((records, offset))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
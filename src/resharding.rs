@@ -0,0 +1,150 @@
+use crate::engine::Namespace;
+use crate::{Client, KVStoreError, Request, Result, WatchEvent};
+use log::info;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// the most keys removed from the source shard by one request
+const REMOVE_BATCH: usize = 1024;
+
+/// called with the progress of a `Reshard`
+type ProgressCallback = Box<dyn FnMut(&ReshardProgress)>;
+
+/// The progress of a `Reshard`, reported after each batch of keys and returned once done
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReshardProgress {
+    /// the revision of the source shard the keys were copied at
+    pub revision: u64,
+    /// number of keys of the source shard read so far
+    pub scanned_keys: u64,
+    /// number of keys copied to the target shard so far
+    pub copied_keys: u64,
+    /// number of keys removed from the source shard so far, once the copy is checked
+    pub removed_keys: u64,
+}
+
+/// Moves keys from a kvs-server holding a shard to another one: the keys from a key on for a
+/// split, or all keys for a merge. The keys of every logical database are moved.
+///
+/// The keys are streamed from a checkpoint of the source and copied to the target in
+/// batches. The copy is then checked against the source, and only removed from the source
+/// if every moved key has the same value on both, failing with `ShardMismatch` otherwise.
+/// Writes to the moved keys must be held back while they move, e.g. by routing them to the
+/// target first: a move which fails the check can be run again.
+pub struct Reshard {
+    source: String,
+    target: String,
+    from_key: Option<String>,
+    on_progress: Option<ProgressCallback>,
+}
+
+impl Reshard {
+    /// move the keys from `from_key` on, in byte order, from `source` to `target`
+    pub fn split(source: &str, target: &str, from_key: &str) -> Reshard {
+        Reshard {
+            source: source.to_owned(),
+            target: target.to_owned(),
+            from_key: Some(from_key.to_owned()),
+            on_progress: None,
+        }
+    }
+
+    /// move all keys from `source` to `target`, which keeps its own keys
+    pub fn merge(source: &str, target: &str) -> Reshard {
+        Reshard {
+            source: source.to_owned(),
+            target: target.to_owned(),
+            from_key: None,
+            on_progress: None,
+        }
+    }
+
+    /// call `on_progress` after each batch of keys copied or removed
+    pub fn on_progress<F: FnMut(&ReshardProgress) + 'static>(mut self, on_progress: F) -> Self {
+        self.on_progress = Some(Box::new(on_progress));
+        self
+    }
+
+    /// move the keys and return the final progress
+    pub fn run(mut self) -> Result<ReshardProgress> {
+        let mut progress = ReshardProgress::default();
+        let mut source = Client::new(&self.source)?;
+        let mut target = Client::new(&self.target)?;
+        // the keys are written by BATCH, which follows the handshake of a store without an id
+        for client in [&mut source, &mut target] {
+            client.request(&Request::REPLICATE(String::new()))?;
+        }
+        let from_key = self.from_key.take();
+        let in_range = |key: &str| match &from_key {
+            Some(from_key) => Namespace::key_of(key) >= from_key.as_str(),
+            None => true,
+        };
+        let mut on_progress = self.on_progress.take();
+        let mut report = |progress: &ReshardProgress| {
+            if let Some(on_progress) = on_progress.as_mut() {
+                on_progress(progress);
+            }
+        };
+
+        progress.revision = source.checkpoint(|pairs| {
+            progress.scanned_keys += pairs.len() as u64;
+            let events: Vec<WatchEvent> = pairs
+                .into_iter()
+                .filter(|(key, _)| in_range(key))
+                .map(|(key, value)| event(key, Some(value)))
+                .collect();
+            if !events.is_empty() {
+                progress.copied_keys += events.len() as u64;
+                target.request(&Request::BATCH(events))?;
+            }
+            report(&progress);
+            Ok(())
+        })?;
+        info!(
+            "Copied {} keys from {} to {} at revision {}",
+            progress.copied_keys, self.source, self.target, progress.revision
+        );
+
+        // the keys as they are now on both shards, so a write during the copy is noticed
+        let mut copied = HashMap::new();
+        target.checkpoint(|pairs| {
+            copied.extend(pairs.into_iter().filter(|(key, _)| in_range(key)));
+            Ok(())
+        })?;
+        let mut moved = Vec::new();
+        let mut mismatched = 0;
+        source.checkpoint(|pairs| {
+            for (key, value) in pairs.into_iter().filter(|(key, _)| in_range(key)) {
+                if copied.get(&key) != Some(&value) {
+                    mismatched += 1;
+                }
+                moved.push(key);
+            }
+            Ok(())
+        })?;
+        if mismatched > 0 {
+            return Err(KVStoreError::ShardMismatch(mismatched));
+        }
+
+        for batch in moved.chunks(REMOVE_BATCH) {
+            let events = batch.iter().map(|key| event(key.clone(), None)).collect();
+            source.request(&Request::BATCH(events))?;
+            progress.removed_keys += batch.len() as u64;
+            report(&progress);
+        }
+        info!(
+            "Moved {} keys from {} to {}",
+            progress.removed_keys, self.source, self.target
+        );
+        Ok(progress)
+    }
+}
+
+/// a write of a stored key, whose revision the server applying it assigns
+fn event(key: String, value: Option<String>) -> WatchEvent {
+    WatchEvent {
+        revision: 0,
+        key,
+        value,
+    }
+}
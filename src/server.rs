@@ -1,93 +1,1931 @@
-use crate::thread_pool::ThreadPool;
-use crate::Result;
-use crate::{KvsEngine, Request, Response};
+use crate::access::AccessList;
+use crate::admission::AdmissionController;
+use crate::bitmap;
+use crate::clients::{ClientRegistry, CountedStream};
+use crate::cluster::ClusterTopology;
+use crate::dedup::{Lookup, ResponseCache};
+use crate::drain::Drain;
+use crate::engine::Namespace;
+use crate::failover::{self, FailoverTask, Role};
+use crate::frame::{self, FrameReader, FrameWriter, FLAG_LZ4};
+use crate::hotness::KeyHotness;
+use crate::hyperloglog::HyperLogLog;
+use crate::json;
+use crate::locks::KeyLockManager;
+use crate::memcached::handle_memcached_connection;
+use crate::proto::RequestRef;
+use crate::range;
+use crate::replication::{self, Replicator};
+use crate::thread_pool::{CancellationToken, PanicInfo, ThreadPool};
+use crate::trace::{self, TraceContext};
+use crate::txn::Transaction;
+use crate::watch::{SlowClientLimit, WatchHub, WatchReceiver, DEFAULT_WATCH_HISTORY};
+use crate::{
+    DatabaseStats, KvsEngine, KvsReader, KvsWriter, Metric, Request, Response, ServerInfo,
+    Transform, WatchEvent,
+};
+use crate::{KVStoreError, Result, ServerMetrics, StatsdSink};
 use log::{debug, error};
-use serde::Deserialize;
-use serde_json::Deserializer;
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::fmt;
-use std::io::BufReader;
+use std::io::{self, BufReader};
+use std::mem;
 use std::net::{TcpListener, TcpStream};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::path::PathBuf;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// the number of logical databases a server has by default
+pub const DEFAULT_DATABASES: u32 = 16;
+
+/// how often a server waiting for connections or requests checks whether it is stopped
+#[cfg(unix)]
+const STOP_POLL_INTERVAL_MS: i32 = 100;
+
+/// the threads a connection runs its tagged requests on, started with its first one
+const MULTIPLEX_WORKERS: usize = 4;
+
+/// how long a request without a deadline waits for the writes of its consistency token
+const CATCH_UP_TIMEOUT: Duration = Duration::from_secs(5);
+/// how often a request waiting for the writes of its consistency token checks for them
+const CATCH_UP_POLL_INTERVAL: Duration = Duration::from_millis(2);
+
+/// the most pairs of a checkpoint sent in one response
+const CHECKPOINT_BATCH: usize = 1024;
+
+thread_local! {
+    /// the writer of the kvs connection the thread serves, answered with an error if its
+    /// handler panics
+    static SERVED_CONNECTION: RefCell<Option<Arc<ResponseWriter>>> = const { RefCell::new(None) };
+}
 
 /// a generic KvServer which supports pluggable storage engines
 pub struct KvServer<E: KvsEngine, P: ThreadPool> {
     engine: E,
     pool: P,
     is_stop: Arc<AtomicBool>,
+    watch_hub: Arc<WatchHub>,
+    metrics: Arc<ServerMetrics>,
+    locks: Arc<KeyLockManager>,
+    hotness: Arc<KeyHotness>,
+    clients: Arc<ClientRegistry>,
+    databases: u32,
+    compression: bool,
+    /// the names of the commands answered with `KVStoreError::CommandDisabled`
+    disabled_commands: Arc<HashSet<String>>,
+    /// how far clients may fall behind reading their responses
+    slow_clients: Option<SlowClientLimit>,
+    dedup: Option<Arc<ResponseCache>>,
+    admission: Option<Arc<AdmissionController>>,
+    request_pools: Option<Arc<RequestPools>>,
+    cluster: Option<Arc<ClusterTopology>>,
+    /// the last revision of the writes replicated from another server, see `Request::AFTER`
+    replicated: Arc<AtomicU64>,
+    /// the id of the store whose writes are replicated to the server, see `Request::REPLICATE`
+    replication_source: Arc<Mutex<Option<String>>>,
+    /// whether the server takes writes, see `KvServer::follower`
+    role: Arc<Role>,
+    /// the clients whose connections are accepted
+    access: AccessList,
+    /// whether the server is draining its connections
+    drain: Drain,
+    /// the listeners of the other protocols
+    protocol_listeners: Vec<(TcpListener, Protocol)>,
+}
+
+impl<R: KvsReader, P: ThreadPool> KvServer<ReadOnlyServing<R>, P> {
+    /// create a server serving an engine which has no writes, e.g. a `SnapshotEngine`,
+    /// answering every write with `KVStoreError::ReadOnlySnapshot`
+    pub fn read_only(reader: R, pool: P, is_stop: Arc<AtomicBool>) -> Self {
+        KvServer::new(ReadOnlyServing::new(reader), pool, is_stop)
+    }
+}
+
+/// The engine of a server made by `KvServer::read_only`: the reads of an engine which has no
+/// writes, with every write refused by `KVStoreError::ReadOnlySnapshot` instead
+#[derive(Clone)]
+pub struct ReadOnlyServing<R>(R);
+
+impl<R: KvsReader> ReadOnlyServing<R> {
+    /// serve the reads of `reader` alone
+    pub fn new(reader: R) -> Self {
+        ReadOnlyServing(reader)
+    }
+}
+
+impl<R: KvsReader> KvsReader for ReadOnlyServing<R> {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.0.get(key)
+    }
+
+    fn scan_match(&self, pattern: String) -> Result<Vec<(String, String)>> {
+        self.0.scan_match(pattern)
+    }
+
+    fn scan_match_cancellable(
+        &self,
+        pattern: String,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<(String, String)>> {
+        self.0.scan_match_cancellable(pattern, cancel)
+    }
+
+    fn scan_keys(&self, after: Option<&String>, limit: usize) -> Result<Vec<String>> {
+        self.0.scan_keys(after, limit)
+    }
+
+    fn metrics(&self) -> Vec<(String, Metric)> {
+        self.0.metrics()
+    }
+
+    fn instance_id(&self) -> Option<String> {
+        self.0.instance_id()
+    }
+
+    fn replication_source(&self) -> Option<String> {
+        self.0.replication_source()
+    }
+}
+
+impl<R: KvsReader> KvsWriter for ReadOnlyServing<R> {
+    fn set(&self, _key: String, _value: String) -> Result<()> {
+        Err(KVStoreError::ReadOnlySnapshot)
+    }
+
+    fn remove(&self, _key: String) -> Result<()> {
+        Err(KVStoreError::ReadOnlySnapshot)
+    }
 }
 
 impl<E: KvsEngine, P: ThreadPool> KvServer<E, P> {
     /// create server with engine
     pub fn new(engine: E, pool: P, is_stop: Arc<AtomicBool>) -> Self {
+        let metrics = Arc::new(ServerMetrics::default());
+        let panics = Arc::clone(&metrics);
+        pool.set_panic_handler(Box::new(move |panic| {
+            panics.panic(panic.pool.as_deref());
+            answer_panic(panic);
+        }));
+        let replication_source = engine.replication_source();
         KvServer {
             engine,
             pool,
             is_stop,
+            watch_hub: Arc::new(WatchHub::new(DEFAULT_WATCH_HISTORY)),
+            locks: Arc::new(KeyLockManager::new(Arc::clone(&metrics))),
+            metrics,
+            hotness: Arc::new(KeyHotness::default()),
+            clients: Arc::new(ClientRegistry::default()),
+            databases: DEFAULT_DATABASES,
+            compression: true,
+            disabled_commands: Arc::new(HashSet::new()),
+            slow_clients: None,
+            dedup: None,
+            admission: None,
+            request_pools: None,
+            cluster: None,
+            replicated: Arc::new(AtomicU64::new(0)),
+            replication_source: Arc::new(Mutex::new(replication_source)),
+            role: Arc::new(Role::leader()),
+            access: AccessList::default(),
+            drain: Drain::default(),
+            protocol_listeners: Vec::new(),
+        }
+    }
+
+    /// the request counters of the server
+    pub fn metrics(&self) -> Arc<ServerMetrics> {
+        Arc::clone(&self.metrics)
+    }
+
+    /// Push the metrics of the server and, under `engine.`, of its engine to the StatsD server
+    /// at `addr` every `interval` in the background, until the sink is dropped.
+    pub fn push_metrics(&self, addr: &str, prefix: &str, interval: Duration) -> Result<StatsdSink> {
+        let metrics = Arc::clone(&self.metrics);
+        let engine = self.engine.clone();
+        StatsdSink::start(addr, prefix, interval, move || {
+            let mut all = metrics.metrics();
+            all.extend(
+                engine
+                    .metrics()
+                    .into_iter()
+                    .map(|(name, metric)| (format!("engine.{}", name), metric)),
+            );
+            all
+        })
+    }
+
+    /// keep the last `events` writes for watchers which reconnect, instead of `DEFAULT_WATCH_HISTORY`
+    pub fn watch_history(mut self, events: usize) -> Self {
+        self.watch_hub = Arc::new(WatchHub::new(events));
+        self
+    }
+
+    /// let clients `SELECT` one of `databases` logical databases, instead of `DEFAULT_DATABASES`
+    pub fn databases(mut self, databases: u32) -> Self {
+        self.databases = databases;
+        self
+    }
+
+    /// whether to accept clients offering to compress large frames with LZ4, true by default
+    pub fn compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    /// Answer the commands named, like `FLUSH` or `CLIENTKILL`, with
+    /// `KVStoreError::CommandDisabled` instead of running them, also when they are tagged,
+    /// traced or part of a transaction. The names are those of `Request::name`, in any case.
+    /// The commands of the memcached listeners are refused under the names of the requests
+    /// doing the same, e.g. memcached `set` under `SET`.
+    pub fn disable_commands<S: AsRef<str>>(mut self, names: &[S]) -> Self {
+        self.disabled_commands = Arc::new(
+            names
+                .iter()
+                .map(|name| name.as_ref().to_ascii_uppercase())
+                .collect(),
+        );
+        self
+    }
+
+    /// Disconnect the clients falling behind reading their responses for longer than
+    /// `timeout`, so a stalled client can not hold the memory of its responses: one taking
+    /// no bytes of a response, or a watcher whose events not sent yet take more than
+    /// `max_backlog` bytes. A watcher is told with `KVStoreError::SlowClient`, to watch again
+    /// from the last revision it read. Clients are never disconnected by default.
+    pub fn slow_clients(mut self, max_backlog: usize, timeout: Duration) -> Self {
+        self.slow_clients = Some(SlowClientLimit {
+            max_backlog,
+            timeout,
+        });
+        self
+    }
+
+    /// Cache the responses of the last `capacity` `Request::RETRYABLE`s for `ttl`, so a client
+    /// retrying a write whose response it did not get is answered without applying it twice,
+    /// e.g. `RetryingClient`. Without the cache retryable requests simply run again.
+    pub fn dedup_cache(mut self, capacity: usize, ttl: Duration) -> Self {
+        self.dedup = Some(Arc::new(ResponseCache::new(capacity, ttl)));
+        self
+    }
+
+    /// Shed requests with an `KVStoreError::Overloaded` error while the jobs of the thread pools
+    /// wait longer than `max_queue_wait` for a thread, to keep the latency of the others low:
+    /// scans and other admin requests first, reads and writes once the wait exceeds twice
+    /// `max_queue_wait`. Commits and rollbacks are never shed. The waits are those of new
+    /// connections, and of every request with `request_pools`.
+    pub fn admission_control(mut self, max_queue_wait: Duration) -> Self {
+        self.admission = Some(Arc::new(AdmissionController::new(max_queue_wait)));
+        self
+    }
+
+    /// Run reads, writes and admin requests like scans on pools of their own instead of the
+    /// thread of their connection, so a flood of expensive scans can not starve the reads.
+    /// The connection waits for each request to finish before it reads the next one, and
+    /// cancels the scans of a client which goes away in the meantime.
+    pub fn request_pools<Q>(mut self, reads: Q, writes: Q, admin: Q) -> Self
+    where
+        Q: ThreadPool + Send + Sync + 'static,
+    {
+        let pools = [reads, writes, admin];
+        for pool in &pools {
+            let panics = Arc::clone(&self.metrics);
+            pool.set_panic_handler(Box::new(move |panic| panics.panic(panic.pool.as_deref())));
         }
+        let [reads, writes, admin] = pools;
+        self.request_pools = Some(Arc::new(RequestPools {
+            reads: Box::new(move |job| reads.spawn_cancellable(job)),
+            writes: Box::new(move |job| writes.spawn_cancellable(job)),
+            admin: Box::new(move |job| admin.spawn_cancellable(job)),
+        }));
+        self
+    }
+
+    /// Accept only the connections of the clients `access` allows, on every protocol, closing
+    /// the others at once. Keep a clone of it to reload the rules while the server runs.
+    pub fn access_list(mut self, access: AccessList) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// Drain the connections of the server while `drain` is draining: `HEALTH` is answered
+    /// with `draining`, and the requests of the connections opened since the drain began, or
+    /// before it once their grace period passed, fail with `KVStoreError::Draining`, also on
+    /// the memcached listeners. Keep a clone of it to drain the server while
+    /// it runs, as `DRAIN` does.
+    pub fn drain(mut self, drain: Drain) -> Self {
+        self.drain = drain;
+        self
+    }
+
+    /// Serve the keys of the shard at `addr` of a cluster of servers holding the keys of
+    /// `shards`, placed on the consistent hash ring of `ClusterClient` and `KvProxy`. Requests
+    /// with a key another shard serves fail with `KVStoreError::Moved`, and `TOPOLOGY` is
+    /// answered with the shards so clients can find them, see `Client::connect_cluster`.
+    pub fn cluster(mut self, shards: Vec<String>, addr: &str) -> Self {
+        self.cluster = Some(Arc::new(ClusterTopology::new(shards, addr.to_owned())));
+        self
+    }
+
+    /// Serve as a read-only replica of the server at `leader`: the writes it replicates with
+    /// `BATCH` after the handshake of `REPLICATE` are applied, while the writes of clients,
+    /// including a `BATCH` without the handshake, fail with `KVStoreError::ReadOnlyReplica`
+    /// carrying the address of the leader, on every protocol.
+    pub fn follower(mut self, leader: &str) -> Self {
+        self.role = Arc::new(Role::follower(leader));
+        self
+    }
+
+    /// Take writes only while holding a lease for `duration`, renewed in the background at
+    /// the follower at `follower` until the task is dropped. Once the follower was promoted
+    /// with `PROMOTE`, it refuses the renewal: the server is fenced off and becomes a read-only
+    /// follower of it. Writes the server did not replicate before are not applied there.
+    pub fn leader_lease(&self, follower: &str, duration: Duration) -> FailoverTask {
+        failover::keep_lease(Arc::clone(&self.role), follower, duration)
+    }
+
+    /// Promote a follower to leader in the background once its leader did not renew its lease
+    /// for `timeout`, as `PROMOTE` does, until the task is dropped.
+    pub fn failover_after(&self, timeout: Duration) -> FailoverTask {
+        failover::watch_leader(Arc::clone(&self.role), timeout)
+    }
+
+    /// Also serve the memcached text protocol on `listeners`, so memcached clients can use the
+    /// store: `get`, `set`, `add`, `replace` and `delete` with exptimes. Items carrying flags
+    /// or an exptime are stored in an envelope, the others as plain values of database 0.
+    /// Expired items are removed when they are accessed.
+    pub fn memcached_listeners(mut self, listeners: Vec<TcpListener>) -> Self {
+        self.protocol_listeners.extend(
+            listeners
+                .into_iter()
+                .map(|listener| (listener, Protocol::Memcached)),
+        );
+        self
+    }
+
+    /// Push all writes applied from now on to the server at `remote` in the background.
+    /// The writes are spooled in `spool_dir` until the remote acknowledges them.
+    /// If `spool_dir` holds no earlier replication, a snapshot of all keys is pushed first.
+    pub fn replicate_to(&self, remote: &str, spool_dir: impl Into<PathBuf>) -> Result<Replicator> {
+        Replicator::start(&self.engine, &self.watch_hub, remote, spool_dir.into())
+    }
+
+    /// Replace the keys of the engine with a checkpoint of all keys of the running server at
+    /// `remote`, e.g. to warm up a standby before it serves. Writes the remote applies after
+    /// the checkpoint are not copied, see `replicate_to` to push them as well.
+    /// Return the revision of the remote the checkpoint reflects.
+    pub fn bootstrap_from(&self, remote: &str) -> Result<u64> {
+        replication::bootstrap(&self.engine, remote)
     }
 
     /// serve at addr to handle requests
     pub fn serve(&mut self, addr: &String) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
-        for stream in listener.incoming() {
+        self.serve_all(slice::from_ref(addr))
+    }
+
+    /// serve at all of `addrs` to handle requests
+    pub fn serve_all(&mut self, addrs: &[String]) -> Result<()> {
+        let listeners = addrs
+            .iter()
+            .map(TcpListener::bind)
+            .collect::<io::Result<Vec<_>>>()?;
+        self.serve_listeners(listeners)
+    }
+
+    /// serve the connections of listeners which are already bound, until `is_stop` is set
+    ///
+    /// A stopped server accepts no further connection, so the ones waiting on a listener shared
+    /// with another process, e.g. one taking over by `take_over_listeners`, are served by it.
+    #[cfg(unix)]
+    pub fn serve_listeners(&mut self, listeners: Vec<TcpListener>) -> Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        let listeners = self.with_protocol_listeners(listeners);
+        // another process sharing a listener may accept a connection first
+        for (listener, _) in &listeners {
+            listener.set_nonblocking(true)?;
+        }
+        let mut fds: Vec<libc::pollfd> = listeners
+            .iter()
+            .map(|(listener, _)| libc::pollfd {
+                fd: listener.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        while !self.is_stop.load(Ordering::SeqCst) {
+            // SAFETY: `fds` holds `fds.len()` pollfds for listeners which outlive the call
+            let ready = unsafe {
+                libc::poll(
+                    fds.as_mut_ptr(),
+                    fds.len() as libc::nfds_t,
+                    STOP_POLL_INTERVAL_MS,
+                )
+            };
+            if ready < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err.into());
+            }
             if self.is_stop.load(Ordering::SeqCst) {
                 break;
             }
-            let engine = self.engine.clone();
-            self.pool.spawn(move || match stream {
-                Ok(stream) => {
-                    if let Err(err) = handle_connection(engine, stream) {
-                        error!("Unexpected error occurs when serving request: {:?}", err)
+            for (fd, (listener, protocol)) in fds.iter().zip(&listeners) {
+                if fd.revents == 0 {
+                    continue;
+                }
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        // the stream may inherit the flag of the listener on some platforms
+                        stream.set_nonblocking(false)?;
+                        self.dispatch(*protocol, Ok(stream));
                     }
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(err) => self.dispatch(*protocol, Err(err)),
                 }
-                Err(err) => error!(
-                    "Unexpected error occurs when serving incoming request {:?}",
-                    err
-                ),
-            })
+            }
+        }
+        Ok(())
+    }
+
+    /// serve the connections of listeners which are already bound, until `is_stop` is set and
+    /// another connection wakes the server up
+    #[cfg(not(unix))]
+    pub fn serve_listeners(&mut self, listeners: Vec<TcpListener>) -> Result<()> {
+        let listeners = self.with_protocol_listeners(listeners);
+        if listeners.len() == 1 {
+            let (listener, protocol) = &listeners[0];
+            for stream in listener.incoming() {
+                if self.is_stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                self.dispatch(*protocol, stream);
+            }
+            return Ok(());
+        }
+
+        // each listener accepts on its own thread and hands the connections over
+        let (sender, receiver) = std::sync::mpsc::channel();
+        for (listener, protocol) in listeners {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if sender.send((protocol, stream)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+        for (protocol, stream) in receiver {
+            if self.is_stop.load(Ordering::SeqCst) {
+                break;
+            }
+            self.dispatch(protocol, stream);
+        }
+        Ok(())
+    }
+
+    /// the listeners of the kvs protocol followed by the ones of the other protocols
+    fn with_protocol_listeners(
+        &mut self,
+        listeners: Vec<TcpListener>,
+    ) -> Vec<(TcpListener, Protocol)> {
+        listeners
+            .into_iter()
+            .map(|listener| (listener, Protocol::Kvs))
+            .chain(self.protocol_listeners.drain(..))
+            .collect()
+    }
+
+    fn dispatch(&self, protocol: Protocol, stream: io::Result<TcpStream>) {
+        if let Ok(stream) = &stream {
+            if !self.admits_peer(stream) {
+                return;
+            }
+        }
+        let shared = Shared {
+            engine: self.engine.clone(),
+            watch_hub: Arc::clone(&self.watch_hub),
+            metrics: Arc::clone(&self.metrics),
+            locks: Arc::clone(&self.locks),
+            hotness: Arc::clone(&self.hotness),
+            clients: Arc::clone(&self.clients),
+            is_stop: Arc::clone(&self.is_stop),
+            databases: self.databases,
+            compression: self.compression,
+            disabled_commands: Arc::clone(&self.disabled_commands),
+            slow_clients: self.slow_clients,
+            dedup: self.dedup.clone(),
+            admission: self.admission.clone(),
+            request_pools: self.request_pools.clone(),
+            cluster: self.cluster.clone(),
+            replicated: Arc::clone(&self.replicated),
+            replication_source: Arc::clone(&self.replication_source),
+            role: Arc::clone(&self.role),
+            drain: self.drain.clone(),
+        };
+        let queued = Instant::now();
+        self.pool.spawn(move || match stream {
+            Ok(stream) => {
+                shared.metrics.connection();
+                shared.record_wait(queued);
+                let timeout = shared.slow_clients.map(|limit| limit.timeout);
+                if let Err(err) = stream.set_write_timeout(timeout) {
+                    error!("Can not set the write timeout of a connection: {}", err);
+                    return;
+                }
+                let result = match protocol {
+                    Protocol::Kvs => {
+                        let result = handle_connection(&shared, stream);
+                        SERVED_CONNECTION.with(|served| served.borrow_mut().take());
+                        result
+                    }
+                    Protocol::Memcached => handle_memcached_connection(&shared, stream),
+                };
+                match result {
+                    Err(err) if is_slow_client(&err) => {
+                        shared.metrics.slow_client();
+                        debug!("Disconnected a slow client: {}", err);
+                    }
+                    Err(err) => error!("Unexpected error occurs when serving request: {:?}", err),
+                    Ok(()) => {}
+                }
+            }
+            Err(err) => error!(
+                "Unexpected error occurs when serving incoming request {:?}",
+                err
+            ),
+        })
+    }
+}
+
+impl<E: KvsEngine, P: ThreadPool> KvServer<E, P> {
+    /// whether the access list lets the client of a connection in, counting it as refused if not
+    fn admits_peer(&self, stream: &TcpStream) -> bool {
+        let peer = match stream.peer_addr() {
+            Ok(peer) => peer,
+            Err(err) => {
+                debug!("Can not tell the address of a client: {}", err);
+                return false;
+            }
+        };
+        if self.access.is_allowed(peer.ip()) {
+            return true;
+        }
+        debug!("Refused the connection of {}", peer);
+        self.metrics.refused();
+        false
+    }
+}
+
+/// what the connections of a server share
+#[derive(Clone)]
+pub(crate) struct Shared<E: KvsEngine> {
+    pub(crate) engine: E,
+    pub(crate) watch_hub: Arc<WatchHub>,
+    pub(crate) metrics: Arc<ServerMetrics>,
+    pub(crate) locks: Arc<KeyLockManager>,
+    pub(crate) hotness: Arc<KeyHotness>,
+    clients: Arc<ClientRegistry>,
+    pub(crate) is_stop: Arc<AtomicBool>,
+    databases: u32,
+    compression: bool,
+    disabled_commands: Arc<HashSet<String>>,
+    pub(crate) slow_clients: Option<SlowClientLimit>,
+    dedup: Option<Arc<ResponseCache>>,
+    admission: Option<Arc<AdmissionController>>,
+    request_pools: Option<Arc<RequestPools>>,
+    cluster: Option<Arc<ClusterTopology>>,
+    replicated: Arc<AtomicU64>,
+    replication_source: Arc<Mutex<Option<String>>>,
+    role: Arc<Role>,
+    pub(crate) drain: Drain,
+}
+
+impl<E: KvsEngine> Shared<E> {
+    /// fail a write of a client if the server is a read-only replica or a leader without its
+    /// lease
+    pub(crate) fn check_writable(&self) -> Result<()> {
+        self.role.check_writable()
+    }
+
+    /// Whether the requests of `session` can be served by `serve_fast`: outside of
+    /// transactions, on a server which runs requests on the threads of their connections and
+    /// neither sheds them nor holds a shard of a cluster.
+    fn serves_fast(&self, session: &Session) -> bool {
+        session.transaction.is_none()
+            && self.request_pools.is_none()
+            && self.admission.is_none()
+            && self.cluster.is_none()
+    }
+
+    /// fail a command the server was configured not to run, see `KvServer::disable_commands`
+    pub(crate) fn check_enabled(&self, name: &str) -> Result<()> {
+        if self.disabled_commands.contains(name) {
+            return Err(KVStoreError::CommandDisabled(name.to_owned()));
         }
         Ok(())
     }
+
+    /// fail a request of a connection the server no longer serves as it drains, see `Drain`
+    fn check_draining(&self, name: &str, session: &Session) -> Result<()> {
+        match session.connected {
+            Some(connected) if name != "HEALTH" => self.drain.check(connected),
+            _ => Ok(()),
+        }
+    }
+
+    /// The handshake of a replicator pushing the writes of the store `source`: the first one
+    /// is trusted and recorded by the engine, see `KvsWriter::accept_replication_source`, so
+    /// those of other stores are refused with `KVStoreError::UnrelatedStore` after it, also
+    /// after a restart. Replicators of engines without an id pass.
+    fn identify_source(&self, source: String) -> Result<()> {
+        if source.is_empty() {
+            return Ok(());
+        }
+        let mut pinned = self.replication_source.lock().unwrap();
+        match pinned.as_ref() {
+            Some(expected) if *expected != source => {
+                Err(KVStoreError::UnrelatedStore(expected.clone(), source))
+            }
+            Some(_) => Ok(()),
+            None => {
+                self.engine.accept_replication_source(&source)?;
+                *pinned = Some(source);
+                Ok(())
+            }
+        }
+    }
+
+    /// what the server tells about itself, see `Request::INFO`
+    fn info(&self) -> ServerInfo {
+        ServerInfo {
+            instance_id: self.engine.instance_id(),
+            replicating_from: self.replication_source.lock().unwrap().clone(),
+            revision: self.applied_revision(),
+            health: self.drain.health().to_owned(),
+        }
+    }
+
+    /// The consistency token of the writes the server applied: the revision of the last
+    /// write replicated to it, or of its own last write if it is not a replica.
+    fn applied_revision(&self) -> u64 {
+        match self.replicated.load(Ordering::SeqCst) {
+            0 => self.watch_hub.revision(),
+            replicated => replicated,
+        }
+    }
+
+    /// wait until the server applied the writes of a consistency token, at most until the
+    /// deadline of the request or for `CATCH_UP_TIMEOUT`
+    fn catch_up(&self, token: u64, deadline: Option<Instant>) -> Result<()> {
+        let deadline = deadline.unwrap_or_else(|| Instant::now() + CATCH_UP_TIMEOUT);
+        loop {
+            let applied = self.applied_revision();
+            if applied >= token {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(KVStoreError::NotCaughtUp(token, applied));
+            }
+            thread::sleep(CATCH_UP_POLL_INTERVAL);
+        }
+    }
+
+    /// a job queued at `queued` started, see `KvServer::admission_control`
+    fn record_wait(&self, queued: Instant) {
+        if let Some(admission) = &self.admission {
+            admission.record_wait(queued.elapsed());
+        }
+    }
+
+    /// whether to run a request, counting it as shed if not
+    fn admits(&self, request: &Request) -> bool {
+        match &self.admission {
+            Some(admission) if !admission.admit(request) => {
+                self.metrics.shed();
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+/// a job run by a pool of `RequestPools`
+type PoolJob = Box<dyn FnOnce(CancellationToken) + Send + 'static>;
+
+/// spawns a job on a pool, returning the token to cancel it with
+type SpawnJob = dyn Fn(PoolJob) -> CancellationToken + Send + Sync;
+
+/// the pools requests run on by class, see `KvServer::request_pools`
+pub(crate) struct RequestPools {
+    reads: Box<SpawnJob>,
+    writes: Box<SpawnJob>,
+    admin: Box<SpawnJob>,
+}
+
+impl RequestPools {
+    /// the pool a request runs on, or None if it changes the session and runs on the
+    /// thread of its connection
+    fn pool_of(&self, request: &Request) -> Option<&SpawnJob> {
+        let pool = match request {
+            Request::GET(..)
+            | Request::GETBIT(..)
+            | Request::BITCOUNT(..)
+            | Request::PFCOUNT(..)
+            | Request::GETRANGE(..)
+            | Request::JSONGET(..) => &self.reads,
+            Request::SET(..)
+            | Request::RM(..)
+            | Request::SETBIT(..)
+            | Request::PFADD(..)
+            | Request::SETRANGE(..)
+            | Request::JSONSET(..)
+            | Request::BATCH(..) => &self.writes,
+            Request::SCAN(..)
+            | Request::FLUSH
+            | Request::DBSTATS
+            | Request::TOPKEYS(..)
+            | Request::CLIENTLIST
+            | Request::CLIENTKILL(..)
+            | Request::CUTOVER
+            | Request::PROMOTE => &self.admin,
+            Request::RETRYABLE(_, _, request)
+            | Request::DEADLINE(_, request)
+            | Request::AFTER(_, request)
+            | Request::TRANSFORM(_, request) => return self.pool_of(request),
+            Request::WATCH(..)
+            | Request::CHECKPOINT
+            | Request::SELECT(..)
+            | Request::BEGIN
+            | Request::COMMIT
+            | Request::ROLLBACK
+            | Request::TOPOLOGY
+            | Request::LEASE(..)
+            | Request::HEALTH
+            | Request::DRAIN(..)
+            | Request::INFO
+            | Request::REPLICATE(..)
+            | Request::TRACED(..)
+            | Request::TAGGED(..) => return None,
+        };
+        Some(pool.as_ref())
+    }
+}
+
+/// the protocol a listener speaks
+#[derive(Clone, Copy, Debug)]
+enum Protocol {
+    Kvs,
+    Memcached,
+}
+
+/// what a connection keeps between its requests
+#[derive(Default)]
+struct Session {
+    /// the database selected by the connection
+    namespace: Namespace,
+    /// the transaction begun by the connection
+    transaction: Option<Transaction>,
+    /// the connection, to notice its client going away while a request runs on a pool
+    client: Option<TcpStream>,
+    /// cancelled once the client of the running request went away
+    cancel: CancellationToken,
+    /// the deadline of the running request, see `Request::DEADLINE`
+    deadline: Option<Instant>,
+    /// when the connection was opened, to tell whether it is served while the server drains
+    connected: Option<Instant>,
+    /// the instance id of the store whose writes the connection replicates once it completed
+    /// the handshake of a replicator, which `BATCH` needs; empty for stores without an id
+    replicating: Option<String>,
+}
+
+/// a tagged request queued for the workers of its connection
+struct TaggedRequest {
+    id: u64,
+    /// the database selected when the request was received
+    namespace: Namespace,
+    connected: Option<Instant>,
+    parent: Option<TraceContext>,
+    request: Request,
+    received: SystemTime,
+}
+
+/// Serve the requests of a connection one after another until the client closes it.
+/// Tagged requests run concurrently instead, and their responses are written as they are
+/// ready, tagged with the same id.
+fn handle_connection<E: KvsEngine>(shared: &Shared<E>, stream: TcpStream) -> Result<()> {
+    let connected = Instant::now();
+    let connection = shared.clients.register(&stream)?;
+    let mut reader = BufReader::new(connection.counted(stream.try_clone()?));
+    let mut writer = connection.counted(stream.try_clone()?);
+    if !wait_for_request(&stream, &shared.is_stop)? {
+        return Ok(());
+    }
+    let supported = if shared.compression { FLAG_LZ4 } else { 0 };
+    let flags = match frame::server_handshake(&mut reader, &mut writer, supported)? {
+        Some(flags) => flags,
+        None => return Ok(()),
+    };
+    let writer = Arc::new(Mutex::new(FrameWriter::new(writer, flags)));
+    let mut reader = FrameReader::new(reader);
+    SERVED_CONNECTION.with(|served| *served.borrow_mut() = Some(Arc::clone(&writer)));
+    let mut session = Session {
+        client: Some(stream.try_clone()?),
+        connected: Some(connected),
+        ..Session::default()
+    };
+    // the queue of the workers running tagged requests, once there was one
+    let mut tagged: Option<Sender<TaggedRequest>> = None;
+    loop {
+        connection.select(session.namespace.database());
+        if reader.get_ref().buffer().is_empty() && !wait_for_request(&stream, &shared.is_stop)? {
+            return Ok(());
+        }
+        let payload = match reader.read_payload()? {
+            Some(payload) => payload,
+            None => return Ok(()),
+        };
+        let now = SystemTime::now();
+        // the most common requests are served without copying their keys out of the frame
+        let fast = if shared.serves_fast(&session) {
+            RequestRef::parse(payload)
+        } else {
+            None
+        };
+        if let Some(request) = fast {
+            debug!("Request: {:?}", &request);
+            connection.command(request.name());
+            let response = serve_fast(shared, &session, request, now);
+            respond(&writer, &response, reader.frame_buffered())?;
+            continue;
+        }
+        let request: Request = serde_json::from_slice(payload)?;
+        debug!("Request: {:?}", &request);
+        connection.command(request.name());
+
+        if let Request::TAGGED(id, request) = request {
+            let (parent, request) = untrace(*request);
+            let response = match request {
+                // a database selected applies to the requests sent after it, so it is not
+                // reordered with them
+                Request::SELECT(..) => serve_request(shared, &mut session, parent, request, now),
+                Request::WATCH(..) | Request::CHECKPOINT | Request::TAGGED(..) => {
+                    Response::Err(format!("a {} request can not be tagged", request.name()))
+                }
+                // the requests of a transaction run in order
+                _ if session.transaction.is_some() => {
+                    Response::Err("a tagged request can not run in a transaction".to_owned())
+                }
+                request => {
+                    let sender =
+                        tagged.get_or_insert_with(|| start_tagged_workers(shared, &writer));
+                    let request = TaggedRequest {
+                        id,
+                        namespace: session.namespace.clone(),
+                        connected: session.connected,
+                        parent,
+                        request,
+                        received: now,
+                    };
+                    if sender.send(request).is_err() {
+                        return Err(KVStoreError::CommonStringError(
+                            "the workers of the connection stopped".to_owned(),
+                        ));
+                    }
+                    continue;
+                }
+            };
+            write_response(&writer, &Response::Tagged(id, Box::new(response)))?;
+            continue;
+        }
+
+        let (parent, request) = untrace(request);
+        let name = request.name();
+        if let Request::WATCH(prefix, since_revision) = request {
+            let mut span = trace::server_span("kvs.request", parent);
+            span.attribute("kvs.request", name);
+            // the events would be mixed up with the responses still to come
+            if tagged.is_some() {
+                let err = "a connection with tagged requests can not watch";
+                span.error(err);
+                shared
+                    .metrics
+                    .request(name, now.elapsed().unwrap_or_default(), true);
+                write_response(&writer, &Response::Err(err.to_owned()))?;
+                return Ok(());
+            }
+            let check = shared
+                .check_enabled(name)
+                .and_then(|()| shared.check_draining(name, &session));
+            let namespace = session.namespace;
+            let prefix = format!("{}{}", namespace.prefix(), prefix);
+            match check.and_then(|()| {
+                shared
+                    .watch_hub
+                    .watch(prefix, since_revision, shared.slow_clients)
+            }) {
+                Ok((backlog, receiver)) => {
+                    shared
+                        .metrics
+                        .request(name, now.elapsed().unwrap_or_default(), false);
+                    // a watch streams until the client goes away,
+                    // so it gets its own thread instead of holding a worker of the pool
+                    // the connection is listed until the watch finishes
+                    let metrics = Arc::clone(&shared.metrics);
+                    thread::spawn(move || {
+                        let _connection = connection;
+                        if let Err(err) = stream_events(&writer, backlog, receiver, namespace) {
+                            if is_slow_client(&err) {
+                                metrics.slow_client();
+                            }
+                            debug!("Watch finished: {:?}", err);
+                        }
+                    });
+                    return Ok(());
+                }
+                // the client reads a watch until the connection is closed
+                Err(err) => {
+                    span.error(err.to_string());
+                    shared
+                        .metrics
+                        .request(name, now.elapsed().unwrap_or_default(), true);
+                    write_response(&writer, &Response::error(&err))?;
+                    return Ok(());
+                }
+            }
+        }
+
+        if let Request::CHECKPOINT = request {
+            let mut span = trace::server_span("kvs.request", parent);
+            span.attribute("kvs.request", name);
+            let result = match shared
+                .check_enabled(name)
+                .and_then(|()| shared.check_draining(name, &session))
+            {
+                Ok(()) => stream_checkpoint(shared, &writer)?,
+                Err(err) => {
+                    write_response(&writer, &Response::error(&err))?;
+                    Err(err)
+                }
+            };
+            if let Err(err) = &result {
+                span.error(err.to_string());
+            }
+            shared
+                .metrics
+                .request(name, now.elapsed().unwrap_or_default(), result.is_err());
+            continue;
+        }
+
+        let response = serve_request(shared, &mut session, parent, request, now);
+        respond(&writer, &response, reader.frame_buffered())?;
+    }
+}
+
+/// Answer a `Request::CHECKPOINT` with all keys as of the current revision, taken with
+/// writes held back. Return the error it was answered with, if any; the outer error is
+/// for the connection.
+fn stream_checkpoint<E: KvsEngine>(
+    shared: &Shared<E>,
+    writer: &ResponseWriter,
+) -> Result<Result<()>> {
+    let (revision, pairs) = {
+        let guard = shared.watch_hub.lock();
+        (guard.revision(), shared.engine.scan_match("*".to_owned()))
+    };
+    let pairs = match pairs {
+        Ok(pairs) => pairs,
+        Err(err) => {
+            write_response(writer, &Response::error(&err))?;
+            return Ok(Err(err));
+        }
+    };
+    let mut writer = writer.lock().unwrap();
+    for chunk in pairs.chunks(CHECKPOINT_BATCH) {
+        writer.write(&Response::Pairs(chunk.to_vec()))?;
+    }
+    writer.send(&Response::Revision(revision))?;
+    Ok(Ok(()))
+}
+
+/// the trace context a request carries, and the request itself
+fn untrace(request: Request) -> (Option<TraceContext>, Request) {
+    match request {
+        Request::TRACED(traceparent, request) => (TraceContext::parse(&traceparent), *request),
+        request => (None, request),
+    }
+}
+
+/// handle a request in a span, recording it in the metrics
+fn serve_request<E: KvsEngine>(
+    shared: &Shared<E>,
+    session: &mut Session,
+    parent: Option<TraceContext>,
+    request: Request,
+    received: SystemTime,
+) -> Response {
+    let name = request.name();
+    let mut span = trace::server_span("kvs.request", parent);
+    span.attribute("kvs.request", name);
+    let (deadline, request) = match request {
+        Request::DEADLINE(budget, request) => {
+            // the budget counts from the time the request was received
+            let waited = received.elapsed().unwrap_or_default();
+            let left = Duration::from_millis(budget).saturating_sub(waited);
+            (Some(Instant::now() + left), *request)
+        }
+        request => (None, request),
+    };
+    let (token, request) = match request {
+        Request::AFTER(token, request) => (Some(token), *request),
+        request => (None, request),
+    };
+    session.deadline = deadline;
+    let rejected = shared
+        .check_enabled(name)
+        .and_then(|()| shared.check_draining(name, session))
+        .err()
+        .or_else(|| match &shared.cluster {
+            Some(cluster) => cluster.check(&request),
+            None => None,
+        })
+        .or_else(|| shared.check_writable().err().filter(|_| is_write(&request)))
+        .or_else(|| token.and_then(|token| shared.catch_up(token, deadline).err()));
+    let response = match (&request, &shared.request_pools, rejected) {
+        (_, _, Some(err)) => Response::error(&err),
+        _ if !shared.admits(&request) => Response::error(&KVStoreError::Overloaded),
+        (Request::WATCH(..), _, _) => {
+            Response::Err("a WATCH request can not have a deadline".to_owned())
+        }
+        (_, Some(pools), _) => run_on_pool(shared, pools, session, request),
+        (_, None, _) => handle_request(shared, session, request),
+    };
+    session.deadline = None;
+    match &response {
+        Response::Err(err) => span.error(err),
+        Response::Failed(err) => span.error(&err.message),
+        _ => {}
+    }
+    shared.metrics.request(
+        name,
+        received.elapsed().unwrap_or_default(),
+        response.is_err(),
+    );
+    debug!("Response: {:?}, {:?}", &response, received.elapsed());
+    match token {
+        Some(_) => Response::Token(shared.applied_revision(), Box::new(response)),
+        None => response,
+    }
 }
 
-fn handle_connection<E: KvsEngine>(engine: E, mut stream: TcpStream) -> Result<()> {
-    let request =
-        Request::deserialize(&mut Deserializer::from_reader(BufReader::new(&mut stream)))?;
+/// Handle a `RequestRef` as `serve_request` does the `Request` of the same name, for a
+/// session and server `Shared::serves_fast` allows.
+fn serve_fast<E: KvsEngine>(
+    shared: &Shared<E>,
+    session: &Session,
+    request: RequestRef,
+    received: SystemTime,
+) -> Response {
+    let name = request.name();
+    let mut span = trace::server_span("kvs.request", None);
+    span.attribute("kvs.request", name);
+    let namespace = &session.namespace;
+    let response = match shared
+        .check_enabled(name)
+        .and_then(|()| shared.check_draining(name, session))
+        .and_then(|()| match &request {
+            RequestRef::Get(_) => Ok(()),
+            RequestRef::Set(..) | RequestRef::Rm(_) => shared.check_writable(),
+        }) {
+        Err(err) => Response::error(&err),
+        Ok(()) => match request {
+            RequestRef::Set(key, value) => set(shared, namespace, &key, value.into_owned()),
+            RequestRef::Rm(key) => remove(shared, namespace, &key),
+            RequestRef::Get(key) => get(shared, namespace, &key),
+        },
+    };
+    match &response {
+        Response::Err(err) => span.error(err),
+        Response::Failed(err) => span.error(&err.message),
+        _ => {}
+    }
+    shared.metrics.request(
+        name,
+        received.elapsed().unwrap_or_default(),
+        response.is_err(),
+    );
+    debug!("Response: {:?}, {:?}", &response, received.elapsed());
+    response
+}
+
+/// whether a request writes keys on behalf of a client, unlike the `BATCH` of a replication
+fn is_write(request: &Request) -> bool {
+    match request {
+        Request::SET(..)
+        | Request::RM(_)
+        | Request::SETBIT(..)
+        | Request::PFADD(..)
+        | Request::SETRANGE(..)
+        | Request::JSONSET(..)
+        | Request::FLUSH
+        | Request::CUTOVER
+        | Request::COMMIT => true,
+        Request::TRACED(_, request)
+        | Request::RETRYABLE(_, _, request)
+        | Request::DEADLINE(_, request)
+        | Request::AFTER(_, request)
+        | Request::TRANSFORM(_, request) => is_write(request),
+        _ => false,
+    }
+}
+
+/// handle a request on the pool of its class, waiting for its response
+fn run_on_pool<E: KvsEngine>(
+    shared: &Shared<E>,
+    pools: &RequestPools,
+    session: &mut Session,
+    request: Request,
+) -> Response {
+    let pool = match pools.pool_of(&request) {
+        Some(pool) => pool,
+        None => return handle_request(shared, session, request),
+    };
+    // the session moves to the pool with the request and comes back with the response
+    let (sender, receiver) = mpsc::channel();
+    let namespace = session.namespace.clone();
+    let client = session.client.take();
+    let mut moved = mem::take(session);
+    let shared = shared.clone();
+    let parent = trace::current();
+    let queued = Instant::now();
+    let cancel = pool(Box::new(move |cancel| {
+        shared.record_wait(queued);
+        let _span = trace::child_span("kvs.request.pool", parent);
+        moved.cancel = cancel;
+        let response = handle_request(&shared, &mut moved, request);
+        moved.cancel = CancellationToken::new();
+        let _ = sender.send((moved, response));
+    }));
+    let poll_interval = Duration::from_millis(STOP_POLL_INTERVAL_MS as u64);
+    let received = loop {
+        match receiver.recv_timeout(poll_interval) {
+            Ok(received) => break Some(received),
+            Err(RecvTimeoutError::Timeout) => {
+                if !cancel.is_cancelled() && client.as_ref().is_some_and(client_closed) {
+                    debug!("Cancel the request of a closed connection");
+                    cancel.cancel();
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break None,
+        }
+    };
+    let response = match received {
+        Some((moved, response)) => {
+            *session = moved;
+            response
+        }
+        // the job panicked, losing the transaction of the session
+        None => {
+            session.namespace = namespace;
+            Response::Err("Internal server error: the request did not finish".to_owned())
+        }
+    };
+    session.client = client;
+    response
+}
+
+/// the writer of the responses of a connection, shared by the workers of its tagged requests
+type ResponseWriter = Mutex<FrameWriter<CountedStream>>;
+
+fn write_response(writer: &ResponseWriter, response: &Response) -> Result<()> {
+    writer.lock().unwrap().send(response)
+}
+
+/// Write the response to a request, flushing it unless the next request of a pipeline was
+/// received already, so the responses to the pipeline are sent together.
+fn respond(writer: &ResponseWriter, response: &Response, pipelined: bool) -> Result<()> {
+    let mut writer = writer.lock().unwrap();
+    writer.write(response)?;
+    if !pipelined {
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+/// answer the client of a kvs connection whose handler panicked with an error
+fn answer_panic(panic: PanicInfo) {
+    let writer = match SERVED_CONNECTION.with(|served| served.borrow_mut().take()) {
+        Some(writer) => writer,
+        None => return,
+    };
+    let message = panic.message.as_deref().unwrap_or("unknown panic");
+    let response = Response::Err(format!("Internal server error: {}", message));
+    // the handler may have panicked while writing
+    let mut writer = writer
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Err(err) = writer.send(&response) {
+        debug!("Panic not answered: {:?}", err);
+    }
+}
 
-    let now = SystemTime::now();
-    debug!("Request: {:?}", &request);
+/// Start the workers of the tagged requests of a connection. They stop once the returned
+/// queue is dropped and drained.
+fn start_tagged_workers<E: KvsEngine>(
+    shared: &Shared<E>,
+    writer: &Arc<ResponseWriter>,
+) -> Sender<TaggedRequest> {
+    let (sender, receiver) = mpsc::channel::<TaggedRequest>();
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..MULTIPLEX_WORKERS {
+        let shared = shared.clone();
+        let writer = Arc::clone(writer);
+        let receiver = Arc::clone(&receiver);
+        thread::spawn(move || loop {
+            let next = receiver.lock().unwrap().recv();
+            let TaggedRequest {
+                id,
+                namespace,
+                connected,
+                parent,
+                request,
+                received,
+            } = match next {
+                Ok(tagged) => tagged,
+                Err(_) => return,
+            };
+            let mut session = Session {
+                namespace,
+                connected,
+                ..Session::default()
+            };
+            let response = serve_request(&shared, &mut session, parent, request, received);
+            let response = Response::Tagged(id, Box::new(response));
+            if let Err(err) = write_response(&writer, &response) {
+                debug!("Tagged response not written: {:?}", err);
+                return;
+            }
+        });
+    }
+    sender
+}
 
+fn handle_request<E: KvsEngine>(
+    shared: &Shared<E>,
+    session: &mut Session,
+    request: Request,
+) -> Response {
+    let Shared {
+        engine,
+        watch_hub,
+        hotness,
+        ..
+    } = shared;
+    let cancel = match session.deadline {
+        Some(deadline) => session.cancel.with_deadline(deadline),
+        None => session.cancel.clone(),
+    };
+    // the client gave up on the request while it waited
+    if let Err(err) = cancel.check() {
+        return Response::error(&err);
+    }
+    let request = match session.transaction.as_mut() {
+        Some(transaction) => {
+            match handle_in_transaction(shared, &session.namespace, transaction, request) {
+                Ok(response) => return response,
+                Err(request) => request,
+            }
+        }
+        None => request,
+    };
+    let namespace = &mut session.namespace;
     let response;
     match request {
-        Request::SET(key, value) => {
-            match engine.set(key, value) {
-                Ok(_) => response = Response::Ok(None),
-                Err(err) => response = Response::Err(format!("{}", err)),
+        Request::SET(key, value) => response = set(shared, namespace, &key, value),
+        Request::RM(key) => response = remove(shared, namespace, &key),
+        Request::GET(key) => response = get(shared, namespace, &key),
+        Request::SETBIT(key, offset, bit) => {
+            hotness.write(&key);
+            match namespace.encode(&key).and_then(|key| {
+                // the bitmap is read and written again while other writes are held back
+                let mut hub = watch_hub.lock();
+                let (value, old) = bitmap::set_bit(&key, engine.get(key.clone())?, offset, bit)?;
+                hub.apply(key.clone(), Some(value.clone()), || engine.set(key, value))?;
+                Ok(old)
+            }) {
+                Ok(old) => response = Response::Ok(Some((old as u8).to_string())),
+                Err(err) => response = Response::error(&err),
             };
         }
-        Request::RM(key) => {
-            match engine.remove(key) {
+        Request::GETBIT(key, offset) => {
+            hotness.read(&key);
+            match namespace
+                .encode(&key)
+                .and_then(|key| bitmap::get_bit(&key, engine.get(key.clone())?, offset))
+            {
+                Ok(bit) => response = Response::Ok(Some((bit as u8).to_string())),
+                Err(err) => response = Response::error(&err),
+            };
+        }
+        Request::BITCOUNT(key) => {
+            hotness.read(&key);
+            match namespace
+                .encode(&key)
+                .and_then(|key| bitmap::count(&key, engine.get(key.clone())?))
+            {
+                Ok(count) => response = Response::Ok(Some(count.to_string())),
+                Err(err) => response = Response::error(&err),
+            };
+        }
+        Request::PFADD(key, elements) => {
+            hotness.write(&key);
+            match namespace.encode(&key).and_then(|key| {
+                // the sketch is read and written again while other writes are held back
+                let mut hub = watch_hub.lock();
+                let mut sketch = HyperLogLog::decode(&key, engine.get(key.clone())?)?;
+                let mut changed = false;
+                for element in &elements {
+                    changed |= sketch.add(element);
+                }
+                if changed {
+                    let value = sketch.encode();
+                    hub.apply(key.clone(), Some(value.clone()), || engine.set(key, value))?;
+                }
+                Ok(changed)
+            }) {
+                Ok(changed) => response = Response::Ok(Some((changed as u8).to_string())),
+                Err(err) => response = Response::error(&err),
+            };
+        }
+        Request::PFCOUNT(keys) => {
+            // the sketches are merged into one counting the elements of all of them
+            let count = || -> Result<u64> {
+                let mut union = HyperLogLog::new();
+                for key in &keys {
+                    hotness.read(key);
+                    let key = namespace.encode(key)?;
+                    union.merge(&HyperLogLog::decode(&key, engine.get(key.clone())?)?);
+                }
+                Ok(union.count())
+            };
+            match count() {
+                Ok(count) => response = Response::Ok(Some(count.to_string())),
+                Err(err) => response = Response::error(&err),
+            };
+        }
+        Request::GETRANGE(key, offset, length) => {
+            hotness.read(&key);
+            match namespace
+                .encode(&key)
+                .and_then(|key| range::get_range(&key, engine.get(key.clone())?, offset, length))
+            {
+                Ok(value) => response = Response::Ok(Some(value)),
+                Err(err) => response = Response::error(&err),
+            };
+        }
+        Request::SETRANGE(key, offset, data) => {
+            hotness.write(&key);
+            match namespace.encode(&key).and_then(|key| {
+                // the value is read and written again while other writes are held back
+                let mut hub = watch_hub.lock();
+                let value = engine.get(key.clone())?;
+                if data.is_empty() {
+                    return Ok(value.map_or(0, |value| value.len()));
+                }
+                let value = range::set_range(&key, value, offset, &data)?;
+                let length = value.len();
+                hub.apply(key.clone(), Some(value.clone()), || engine.set(key, value))?;
+                Ok(length)
+            }) {
+                Ok(length) => response = Response::Ok(Some(length.to_string())),
+                Err(err) => response = Response::error(&err),
+            };
+        }
+        Request::JSONSET(key, pointer, field) => {
+            hotness.write(&key);
+            match namespace.encode(&key).and_then(|key| {
+                // the document is read and written again while other writes are held back
+                let mut hub = watch_hub.lock();
+                let mut document = json::decode(&key, engine.get(key.clone())?)?;
+                let field: serde_json::Value = serde_json::from_str(&field)?;
+                let field_text = field.to_string();
+                json::set_pointer(&mut document, &pointer, field)?;
+                let document = document.to_string();
+                hub.apply(key.clone(), Some(document.clone()), || {
+                    engine.set_json_field(key, document, &pointer, &field_text)
+                })
+            }) {
+                Ok(()) => response = Response::Ok(None),
+                Err(err) => response = Response::error(&err),
+            };
+        }
+        Request::JSONGET(key, pointer) => {
+            hotness.read(&key);
+            match namespace
+                .encode(&key)
+                .and_then(|key| json::get_field(&key, engine.get(key.clone())?, &pointer))
+            {
+                Ok(field) => response = Response::Ok(field),
+                Err(err) => response = Response::error(&err),
+            };
+        }
+        Request::SCAN(pattern) => {
+            match engine.scan_match_cancellable(namespace.encode_pattern(&pattern), &cancel) {
+                Ok(pairs) => {
+                    response = Response::Pairs(
+                        pairs
+                            .into_iter()
+                            .filter_map(|(key, value)| {
+                                namespace.decode(&key).map(|key| (key.to_owned(), value))
+                            })
+                            .collect(),
+                    )
+                }
+                Err(err) => response = Response::error(&err),
+            };
+        }
+        Request::BATCH(_) if session.replicating.is_none() => {
+            // a client wrapping its writes like replicated ones is refused like any other
+            // write of a client on a follower
+            let err = match shared.role.check_writable() {
+                Err(err @ KVStoreError::ReadOnlyReplica(_)) => err,
+                _ => KVStoreError::NoReplicationHandshake,
+            };
+            response = Response::error(&err)
+        }
+        Request::BATCH(events) => {
+            for event in &events {
+                hotness.write(&event.key);
+            }
+            // the revisions of the server the writes are replicated from
+            let revision = events.iter().map(|event| event.revision).max();
+            // writes pushed by a store, unlike the keys moved by a `Reshard`, are refused once
+            // a promotion fenced their leader off
+            let replicated = match (session.replicating.as_deref(), revision) {
+                (Some(""), None | Some(0)) => Ok(()),
+                _ => shared.role.check_replicated(),
+            };
+            match replicated.and_then(|()| apply_batch(engine, watch_hub, events)) {
+                Ok(_) => {
+                    shared
+                        .replicated
+                        .fetch_max(revision.unwrap_or(0), Ordering::SeqCst);
+                    response = Response::Ok(None)
+                }
+                Err(err) => response = Response::error(&err),
+            };
+        }
+        Request::SELECT(database) => {
+            if database < shared.databases {
+                match engine.create_namespace(database) {
+                    Ok(()) => {
+                        *namespace = Namespace::new(database);
+                        response = Response::Ok(None);
+                    }
+                    Err(err) => response = Response::error(&err),
+                }
+            } else {
+                let err = KVStoreError::DatabaseOutOfRange(database, shared.databases);
+                response = Response::error(&err);
+            }
+        }
+        Request::FLUSH => {
+            match flush(engine, watch_hub, namespace) {
                 Ok(_) => response = Response::Ok(None),
-                Err(err) => response = Response::Err(format!("{}", err)),
+                Err(err) => response = Response::error(&err),
             };
         }
-        Request::GET(key) => {
-            match engine.get(key) {
-                Ok(value) => response = Response::Ok(value),
-                Err(err) => response = Response::Err(format!("{}", err)),
+        Request::DBSTATS => {
+            match database_stats(engine, namespace, &cancel) {
+                Ok(stats) => response = Response::DatabaseStats(stats),
+                Err(err) => response = Response::error(&err),
+            };
+        }
+        Request::TOPKEYS(n) => response = Response::TopKeys(hotness.top(n)),
+        Request::CLIENTLIST => response = Response::Clients(shared.clients.list()),
+        Request::CLIENTKILL(id) => {
+            match shared.clients.kill(id) {
+                Ok(()) => response = Response::Ok(None),
+                Err(err) => response = Response::error(&err),
+            };
+        }
+        Request::TOPOLOGY => {
+            let shards = shared
+                .cluster
+                .as_ref()
+                .map(|cluster| cluster.shards().to_vec());
+            response = Response::Topology(shards.unwrap_or_default());
+        }
+        Request::LEASE(epoch, millis) => {
+            match shared.role.grant(epoch, Duration::from_millis(millis)) {
+                Ok(()) => response = Response::Ok(None),
+                Err(err) => response = Response::error(&err),
+            };
+        }
+        Request::PROMOTE => response = Response::Ok(Some(shared.role.promote().to_string())),
+        Request::HEALTH => response = Response::Ok(Some(shared.drain.health().to_owned())),
+        Request::INFO => response = Response::Info(shared.info()),
+        Request::REPLICATE(source) => {
+            match shared.identify_source(source.clone()) {
+                Ok(()) => {
+                    session.replicating = Some(source);
+                    response = Response::Ok(engine.instance_id())
+                }
+                Err(err) => response = Response::error(&err),
+            };
+        }
+        Request::DRAIN(millis) => {
+            shared.drain.start(Duration::from_millis(millis));
+            response = Response::Ok(None);
+        }
+        Request::CUTOVER => {
+            match engine.cutover() {
+                Ok(()) => response = Response::Ok(None),
+                Err(err) => response = Response::error(&err),
             };
         }
+        Request::BEGIN => {
+            session.transaction = Some(Transaction::begin(watch_hub));
+            response = Response::Ok(None);
+        }
+        Request::COMMIT => {
+            match session.transaction.take() {
+                Some(transaction) => match transaction.commit(engine, watch_hub, &shared.locks) {
+                    Ok(_) => response = Response::Ok(None),
+                    Err(err) => response = Response::error(&err),
+                },
+                None => response = Response::error(&KVStoreError::NoTransaction),
+            };
+        }
+        Request::ROLLBACK => {
+            match session.transaction.take() {
+                Some(_) => response = Response::Ok(None),
+                None => response = Response::error(&KVStoreError::NoTransaction),
+            };
+        }
+        Request::WATCH(..) => unreachable!("watches are streamed by handle_connection"),
+        Request::CHECKPOINT => {
+            response = Response::Err(
+                "a CHECKPOINT request can not have a deadline or be retried".to_owned(),
+            )
+        }
+        Request::TRACED(..) => {
+            response = Response::Err("a traced request can not be traced again".to_owned())
+        }
+        Request::TAGGED(..) => {
+            response = Response::Err("a tagged request can not be traced or tagged".to_owned())
+        }
+        Request::TRANSFORM(transform, request) => {
+            response = handle_transform(shared, session, &transform, *request)
+        }
+        Request::RETRYABLE(client_id, request_id, request) => {
+            response = handle_retryable(shared, session, &client_id, request_id, *request)
+        }
+        Request::DEADLINE(..) => {
+            response = Response::Err(
+                "a request can only have one deadline, attached outside of RETRYABLE".to_owned(),
+            )
+        }
+        Request::AFTER(..) => {
+            response = Response::Err(
+                "a request can only have one consistency token, attached inside of DEADLINE"
+                    .to_owned(),
+            )
+        }
+    }
+    response
+}
+
+fn set<E: KvsEngine>(
+    shared: &Shared<E>,
+    namespace: &Namespace,
+    key: &str,
+    value: String,
+) -> Response {
+    shared.hotness.write(key);
+    match namespace.encode(key).and_then(|key| {
+        shared
+            .watch_hub
+            .apply(key.clone(), Some(value.clone()), || {
+                shared.engine.set(key, value)
+            })
+    }) {
+        Ok(_) => Response::Ok(None),
+        Err(err) => Response::error(&err),
+    }
+}
+
+fn remove<E: KvsEngine>(shared: &Shared<E>, namespace: &Namespace, key: &str) -> Response {
+    shared.hotness.write(key);
+    match namespace.encode(key).and_then(|key| {
+        shared
+            .watch_hub
+            .apply(key.clone(), None, || shared.engine.remove(key))
+    }) {
+        Ok(_) => Response::Ok(None),
+        Err(err) => Response::error(&err),
+    }
+}
+
+fn get<E: KvsEngine>(shared: &Shared<E>, namespace: &Namespace, key: &str) -> Response {
+    shared.hotness.read(key);
+    match namespace.encode(key).and_then(|key| shared.engine.get(key)) {
+        Ok(value) => Response::Ok(value),
+        Err(err) => Response::error(&err),
+    }
+}
+
+/// Handle a request in the transaction of the connection, or return it if it runs as it
+/// does outside of transactions.
+fn handle_in_transaction<E: KvsEngine>(
+    shared: &Shared<E>,
+    namespace: &Namespace,
+    transaction: &mut Transaction,
+    request: Request,
+) -> std::result::Result<Response, Request> {
+    let Shared {
+        engine,
+        watch_hub,
+        hotness,
+        ..
+    } = shared;
+    let result = match request {
+        Request::SET(key, value) => {
+            hotness.write(&key);
+            namespace
+                .encode(&key)
+                .map(|key| transaction.set(key, value))
+                .map(|_| None)
+        }
+        Request::RM(key) => {
+            hotness.write(&key);
+            namespace
+                .encode(&key)
+                .and_then(|key| transaction.remove(engine, watch_hub, key))
+                .map(|_| None)
+        }
+        Request::GET(key) => {
+            hotness.read(&key);
+            namespace
+                .encode(&key)
+                .and_then(|key| transaction.get(engine, watch_hub, &key))
+        }
+        Request::BEGIN => Err(KVStoreError::TransactionInProgress),
+        Request::SCAN(..)
+        | Request::BATCH(..)
+        | Request::SELECT(..)
+        | Request::FLUSH
+        | Request::SETBIT(..)
+        | Request::GETBIT(..)
+        | Request::BITCOUNT(..)
+        | Request::PFADD(..)
+        | Request::PFCOUNT(..)
+        | Request::GETRANGE(..)
+        | Request::SETRANGE(..)
+        | Request::JSONSET(..)
+        | Request::JSONGET(..) => {
+            let err = format!("a {} request can not run in a transaction", request.name());
+            return Ok(Response::Err(err));
+        }
+        request => return Err(request),
+    };
+    Ok(match result {
+        Ok(value) => Response::Ok(value),
+        Err(err) => Response::error(&err),
+    })
+}
+
+/// run a get or a scan and transform the values it reads
+fn handle_transform<E: KvsEngine>(
+    shared: &Shared<E>,
+    session: &mut Session,
+    transform: &Transform,
+    request: Request,
+) -> Response {
+    let key = match &request {
+        Request::GET(key) => Some(key.clone()),
+        Request::SCAN(_) => None,
+        request => {
+            return Response::Err(format!(
+                "the values of a {} request can not be transformed",
+                request.name()
+            ))
+        }
+    };
+    let transformed = match (handle_request(shared, session, request), key) {
+        (Response::Ok(Some(value)), Some(key)) => transform.apply(&key, value).map(Response::Ok),
+        (Response::Pairs(pairs), _) => {
+            let mut transformed = Vec::with_capacity(pairs.len());
+            for (key, value) in pairs {
+                match transform.apply(&key, value) {
+                    Ok(Some(value)) => transformed.push((key, value)),
+                    Ok(None) => {}
+                    Err(err) => return Response::error(&err),
+                }
+            }
+            Ok(Response::Pairs(transformed))
+        }
+        (response, _) => Ok(response),
+    };
+    transformed.unwrap_or_else(|err| Response::error(&err))
+}
+
+/// run a request unless it already ran for an earlier attempt of its client
+fn handle_retryable<E: KvsEngine>(
+    shared: &Shared<E>,
+    session: &mut Session,
+    client_id: &str,
+    request_id: u64,
+    request: Request,
+) -> Response {
+    if let Request::WATCH(..) | Request::TRACED(..) | Request::TAGGED(..) | Request::RETRYABLE(..) =
+        request
+    {
+        return Response::Err(format!("a {} request can not be retried", request.name()));
+    }
+    let cache = match &shared.dedup {
+        Some(cache) => cache,
+        None => return handle_request(shared, session, request),
+    };
+    match cache.begin(client_id, request_id) {
+        Lookup::Done(response) => response,
+        Lookup::InFlight => Response::Err(format!(
+            "request {} of client {} is still running, retry later",
+            request_id, client_id
+        )),
+        Lookup::New => {
+            let response = handle_request(shared, session, request);
+            cache.finish(client_id, request_id, &response);
+            response
+        }
+    }
+}
+
+/// wait until the client sends a request, closes the connection or the server is stopped
+#[cfg(unix)]
+pub(crate) fn wait_for_request(stream: &TcpStream, is_stop: &AtomicBool) -> Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut fd = libc::pollfd {
+        fd: stream.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    while !is_stop.load(Ordering::SeqCst) {
+        // SAFETY: `fd` is a pollfd for a stream which outlives the call
+        let ready = unsafe { libc::poll(&mut fd, 1, STOP_POLL_INTERVAL_MS) };
+        if ready < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err.into());
+        }
+        if ready > 0 {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// wait until the client sends a request or closes the connection
+#[cfg(not(unix))]
+pub(crate) fn wait_for_request(_stream: &TcpStream, _is_stop: &AtomicBool) -> Result<bool> {
+    Ok(true)
+}
+
+/// whether the client closed the connection, without reading a request it sent
+#[cfg(unix)]
+fn client_closed(stream: &TcpStream) -> bool {
+    use std::os::unix::io::AsRawFd;
+
+    let mut fd = libc::pollfd {
+        fd: stream.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: `fd` is a pollfd for a stream which outlives the call
+    let ready = unsafe { libc::poll(&mut fd, 1, 0) };
+    if ready <= 0 {
+        return false;
+    }
+    if fd.revents & (libc::POLLHUP | libc::POLLERR) != 0 {
+        return true;
+    }
+    // a pipelined request is not a close
+    let mut byte = [0; 1];
+    matches!(stream.peek(&mut byte), Ok(0) | Err(_))
+}
+
+/// whether the client closed the connection
+#[cfg(not(unix))]
+fn client_closed(_stream: &TcpStream) -> bool {
+    false
+}
+
+/// remove all keys of the database
+fn flush<E: KvsEngine>(engine: &E, watch_hub: &WatchHub, namespace: &Namespace) -> Result<()> {
+    for (key, _) in engine.scan_match(namespace.encode_pattern("*"))? {
+        if namespace.decode(&key).is_none() {
+            continue;
+        }
+        // a key removed concurrently is gone as well
+        match watch_hub.apply(key.clone(), None, || engine.remove(key)) {
+            Ok(()) | Err(KVStoreError::KeyNotFound) => {}
+            Err(err) => return Err(err),
+        }
     }
+    Ok(())
+}
 
-    debug!("Response: {:?}, {:?}", &response, now.elapsed());
+fn database_stats<E: KvsEngine>(
+    engine: &E,
+    namespace: &Namespace,
+    cancel: &CancellationToken,
+) -> Result<DatabaseStats> {
+    let mut stats = DatabaseStats {
+        database: namespace.database(),
+        ..DatabaseStats::default()
+    };
+    for (key, value) in engine.scan_match_cancellable(namespace.encode_pattern("*"), cancel)? {
+        if let Some(key) = namespace.decode(&key) {
+            stats.keys += 1;
+            stats.bytes += (key.len() + value.len()) as u64;
+        }
+    }
+    Ok(stats)
+}
 
-    serde_json::to_writer(stream, &response)?;
+fn apply_batch<E: KvsEngine>(
+    engine: &E,
+    watch_hub: &WatchHub,
+    events: Vec<WatchEvent>,
+) -> Result<()> {
+    for event in events {
+        let key = event.key.clone();
+        let result = match event.value {
+            Some(value) => {
+                watch_hub.apply(key, Some(value.clone()), || engine.set(event.key, value))
+            }
+            None => watch_hub.apply(key, None, || engine.remove(event.key)),
+        };
+        match result {
+            Ok(()) | Err(KVStoreError::KeyNotFound) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
 
+fn stream_events(
+    writer: &ResponseWriter,
+    backlog: Vec<WatchEvent>,
+    mut receiver: WatchReceiver,
+    namespace: Namespace,
+) -> Result<()> {
+    // the keys of the database, without the events of the others
+    let decode = |mut event: WatchEvent| {
+        let key = namespace.decode(&event.key)?.to_owned();
+        event.key = key;
+        Some(Response::Event(event))
+    };
+    let mut writer = writer.lock().unwrap();
+    for response in backlog.into_iter().filter_map(decode) {
+        writer.write(&response)?;
+    }
+    writer.flush()?;
+    for response in receiver.by_ref().filter_map(decode) {
+        writer.send(&response)?;
+    }
+    if receiver.fell_behind() {
+        writer.send(&Response::error(&KVStoreError::SlowClient))?;
+        return Err(KVStoreError::SlowClient);
+    }
     Ok(())
 }
 
+/// whether a connection failed as its client fell behind reading its responses, so a write
+/// to it timed out
+fn is_slow_client(err: &KVStoreError) -> bool {
+    match err.root() {
+        KVStoreError::SlowClient => true,
+        KVStoreError::Io(err) => matches!(
+            err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ),
+        _ => false,
+    }
+}
+
 /// Indicates the type of engine
 #[derive(Debug)]
 pub enum EngineType {
@@ -104,4 +1942,4 @@ impl fmt::Display for EngineType {
             EngineType::SledKvsEngine => write!(f, "sled"),
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,91 @@
+use crate::{KVStoreError, Result};
+use log::warn;
+use std::io;
+use std::str::FromStr;
+
+/// Which cores the worker threads of a pool are pinned to, see `ThreadPoolBuilder::affinity`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CpuAffinity {
+    /// pin the workers to these cores, in turn
+    Cores(Vec<usize>),
+    /// pin the workers to cores as far apart as possible, e.g. on different NUMA nodes
+    Spread,
+    /// pin the workers to neighbouring cores, starting with the first one
+    Compact,
+}
+
+impl CpuAffinity {
+    /// the core of the worker `index` of a pool of `threads` workers on `cores` cores
+    pub(crate) fn core_of(&self, index: usize, threads: usize, cores: usize) -> usize {
+        let cores = cores.max(1);
+        match self {
+            CpuAffinity::Cores(ids) if !ids.is_empty() => ids[index % ids.len()],
+            CpuAffinity::Cores(_) | CpuAffinity::Compact => index % cores,
+            CpuAffinity::Spread => match threads {
+                threads if threads >= cores => index % cores,
+                threads => index * cores / threads,
+            },
+        }
+    }
+
+    /// pin the calling thread, the worker `index` of a pool of `threads` workers
+    pub(crate) fn pin_worker(&self, index: usize, threads: usize) {
+        let core = self.core_of(index, threads, num_cpus::get());
+        if let Err(err) = pin_current_thread(core) {
+            warn!("Can not pin worker {} to core {}: {}", index, core, err);
+        }
+    }
+}
+
+impl FromStr for CpuAffinity {
+    type Err = KVStoreError;
+
+    /// parse `spread`, `compact` or core IDs separated by commas like `0,2,4`
+    fn from_str(text: &str) -> Result<Self> {
+        match text.trim() {
+            "spread" => Ok(CpuAffinity::Spread),
+            "compact" => Ok(CpuAffinity::Compact),
+            cores => cores
+                .split(',')
+                .map(|core| core.trim().parse())
+                .collect::<std::result::Result<_, _>>()
+                .map(CpuAffinity::Cores)
+                .map_err(|_| {
+                    KVStoreError::InvalidConfig(format!(
+                        "affinity {} is neither spread, compact nor a list of core IDs",
+                        text
+                    ))
+                }),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(core: usize) -> io::Result<()> {
+    if core >= libc::CPU_SETSIZE as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the core ID is out of range",
+        ));
+    }
+    // SAFETY: the set is initialized before it is used and outlives the call
+    let result = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        libc::CPU_SET(core, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_core: usize) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "pinning threads is only supported on Linux",
+    ))
+}
@@ -0,0 +1,63 @@
+use crate::{KVStoreError, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Asks a job to stop early, see `ThreadPool::spawn_cancellable`. Cancellation is
+/// cooperative: a long-running job checks the token now and then and returns when it is
+/// cancelled. All clones of a token share the same state.
+/// A token may also expire at a deadline, after which it counts as cancelled.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    /// create a token which is not cancelled
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Return a token cancelled with this one which also expires at `deadline`, or at the
+    /// deadline of this one if it is earlier.
+    pub fn with_deadline(&self, deadline: Instant) -> Self {
+        CancellationToken {
+            cancelled: Arc::clone(&self.cancelled),
+            deadline: Some(self.deadline.map_or(deadline, |own| own.min(deadline))),
+        }
+    }
+
+    /// the time the token expires at, if any
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// the time left until the token expires, if it has a deadline
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    /// ask the jobs holding the token to stop
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// whether the token was cancelled or expired
+    pub fn is_cancelled(&self) -> bool {
+        self.check().is_err()
+    }
+
+    /// Return `KVStoreError::Cancelled` if the token was cancelled, or
+    /// `KVStoreError::DeadlineExceeded` if it expired, so a job can stop with `?`.
+    pub fn check(&self) -> Result<()> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return Err(KVStoreError::Cancelled);
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(KVStoreError::DeadlineExceeded),
+            _ => Ok(()),
+        }
+    }
+}
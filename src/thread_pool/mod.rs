@@ -3,15 +3,130 @@
 */
 
 use crate::Result;
+use log::error;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, RwLock};
+use std::thread;
 
+mod affinity;
+mod cancel;
 mod naive_thread_pool;
 mod rayon_thread_pool;
 mod shared_queue_thread_pool;
 
+pub use affinity::CpuAffinity;
+pub use cancel::CancellationToken;
 pub use naive_thread_pool::NaiveThreadPool;
 pub use rayon_thread_pool::RayonThreadPool;
 pub use shared_queue_thread_pool::SharedQueueThreadPool;
 
+/// A job of a thread pool which panicked, see `ThreadPool::set_panic_handler`
+#[derive(Clone, Debug)]
+pub struct PanicInfo {
+    /// the message the job panicked with, if it panicked with a string
+    pub message: Option<String>,
+    /// the name of the thread the job ran on, if it has one
+    pub thread: Option<String>,
+    /// the label of the pool, if it has one
+    pub pool: Option<String>,
+}
+
+/** How a thread pool is created, see `ThreadPool::build`.
+# Example
+```
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool, ThreadPoolBuilder};
+
+let pool: SharedQueueThreadPool = ThreadPoolBuilder::new(4).label("kv-read").build().unwrap();
+assert_eq!(pool.label(), Some("kv-read"));
+```
+ */
+#[derive(Clone, Debug)]
+pub struct ThreadPoolBuilder {
+    threads: usize,
+    label: Option<String>,
+    supervised: bool,
+    affinity: Option<CpuAffinity>,
+}
+
+impl ThreadPoolBuilder {
+    /// a pool of `threads` threads, without a label
+    pub fn new(threads: usize) -> Self {
+        ThreadPoolBuilder {
+            threads,
+            label: None,
+            supervised: false,
+            affinity: None,
+        }
+    }
+
+    /// Name the worker threads `<label>-<n>`, so they show up with a meaningful name in `ps`,
+    /// debuggers and profilers, and tag the logs and panics of the pool with `label`.
+    pub fn label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_owned());
+        self
+    }
+
+    /// run the pool in supervisor mode, see `ThreadPool::supervised`
+    pub fn supervised(mut self, supervised: bool) -> Self {
+        self.supervised = supervised;
+        self
+    }
+
+    /// Pin the worker threads to cores, which helps the tail latency of a server sharing
+    /// its machine with other services. Only supported on Linux, elsewhere it is ignored
+    /// with a warning.
+    pub fn affinity(mut self, affinity: CpuAffinity) -> Self {
+        self.affinity = Some(affinity);
+        self
+    }
+
+    /// create the pool
+    pub fn build<P: ThreadPool>(self) -> Result<P> {
+        P::build(&self)
+    }
+
+    /// the number of threads of the pool
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// the label of the pool
+    pub fn label_name(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// whether the pool runs in supervisor mode
+    pub fn is_supervised(&self) -> bool {
+        self.supervised
+    }
+
+    /// the cores the workers are pinned to
+    pub fn cpu_affinity(&self) -> Option<&CpuAffinity> {
+        self.affinity.as_ref()
+    }
+
+    /// pin the calling thread if it is the worker `index` of a pool with an affinity
+    pub(crate) fn pin_worker(&self, index: usize) {
+        if let Some(affinity) = &self.affinity {
+            affinity.pin_worker(index, self.threads);
+        }
+    }
+
+    /// the name of the thread `index` of the pool
+    pub(crate) fn thread_name(&self, index: usize) -> Option<String> {
+        self.label
+            .as_ref()
+            .map(|label| format!("{}-{}", label, index))
+    }
+}
+
+/// a handler of the jobs which panicked
+pub type PanicHandler = Box<dyn Fn(PanicInfo) + Send + Sync>;
+
+/// the panic handler of a pool, shared with its workers
+pub(crate) type PanicHandlerSlot = Arc<RwLock<Option<PanicHandler>>>;
+
 /**
  * 为了多线程需要抽象出线程池的概念，
  * ThreadPool trait 定义如下：
@@ -19,6 +134,7 @@ pub use shared_queue_thread_pool::SharedQueueThreadPool;
  * 1. 不仅需要满足 FnOnce() 的 bound 来满足近执行一次的语义，
  * 2. 还要实现 Send + ‘static 的 bound 来实现线程安全的发送接收和足够长的生命周期。
  */
+///
 /// a pool which use multi thread to execute tasks
 pub trait ThreadPool {
     /// Creates a new thread pool, immediately spawning the specified number of threads.
@@ -32,4 +148,88 @@ pub trait ThreadPool {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Spawn a job which is handed a token to check for cancellation, and return the token.
+    /// Cancelling it before the job starts skips the job; afterwards the job decides when to
+    /// stop, e.g. with `CancellationToken::check`.
+    fn spawn_cancellable<F>(&self, job: F) -> CancellationToken
+    where
+        F: FnOnce(CancellationToken) + Send + 'static,
+    {
+        let token = CancellationToken::new();
+        let cancel = token.clone();
+        self.spawn(move || {
+            if !cancel.is_cancelled() {
+                job(cancel)
+            }
+        });
+        token
+    }
+
+    /// Creates a new thread pool in supervisor mode, where a job which panics ends its worker
+    /// instead of leaving it running, and the panicked worker is detected and replaced by a new
+    /// thread, counted by `replaced_workers`. Pools without long-lived workers run as `new`.
+    fn supervised(threads: usize) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::new(threads)
+    }
+
+    /// The number of workers replaced after their job panicked, for monitoring.
+    fn replaced_workers(&self) -> u64 {
+        0
+    }
+
+    /// Creates a thread pool as configured by `builder`. Pools which do not support labels,
+    /// supervisor mode or pinning ignore them.
+    fn build(builder: &ThreadPoolBuilder) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        if builder.is_supervised() {
+            Self::supervised(builder.threads())
+        } else {
+            Self::new(builder.threads())
+        }
+    }
+
+    /// the label of the pool, see `ThreadPoolBuilder::label`
+    fn label(&self) -> Option<&str> {
+        None
+    }
+
+    /// Call `handler` when a job panics, on the thread of the job once the panic is caught,
+    /// instead of only logging the panic.
+    fn set_panic_handler(&self, handler: PanicHandler);
+}
+
+/// Run a job, catching its panic to log it and hand it to the panic handler.
+/// Return the panic to resume it with if the job panicked.
+pub(crate) fn run_job<F>(
+    job: F,
+    handler: &PanicHandlerSlot,
+    pool: Option<&str>,
+) -> Option<Box<dyn Any + Send>>
+where
+    F: FnOnce(),
+{
+    let payload = panic::catch_unwind(AssertUnwindSafe(job)).err()?;
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned());
+    let info = PanicInfo {
+        message,
+        thread: thread::current().name().map(str::to_owned),
+        pool: pool.map(str::to_owned),
+    };
+    match pool {
+        Some(pool) => error!("A job of pool {} panicked: {:?}", pool, info),
+        None => error!("A job panicked: {:?}", info),
+    }
+    if let Some(handler) = handler.read().unwrap().as_ref() {
+        handler(info);
+    }
+    Some(payload)
 }
@@ -1,18 +1,26 @@
-use crate::thread_pool::ThreadPool;
+use crate::thread_pool::{self, PanicHandler, PanicHandlerSlot, ThreadPool, ThreadPoolBuilder};
 use crate::Result;
+use log::error;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
 
 /// a naive thread pool
-pub struct NaiveThreadPool;
+pub struct NaiveThreadPool {
+    builder: ThreadPoolBuilder,
+    /// the number of threads spawned, whose turn decides the core a thread is pinned to
+    spawned: AtomicUsize,
+    panic_handler: PanicHandlerSlot,
+}
 
 // 对于最简单的 NaiveThreadPool，仅仅需要在 spawn 的时候创建一个线程让其执行即可。
 impl ThreadPool for NaiveThreadPool {
-    /// do nothing
-    fn new(_: usize) -> Result<Self>
+    /// a thread is spawned for each job, so the number of threads is ignored
+    fn new(threads: usize) -> Result<Self>
     where
         Self: Sized,
     {
-        Ok(NaiveThreadPool)
+        Self::build(&ThreadPoolBuilder::new(threads))
     }
 
     /// create a new thread for each spawned job.
@@ -20,6 +28,40 @@ impl ThreadPool for NaiveThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(job);
+        let panic_handler = self.panic_handler.clone();
+        let builder = Arc::new(self.builder.clone());
+        let index = self.spawned.fetch_add(1, Ordering::Relaxed);
+        let mut thread = thread::Builder::new();
+        if let Some(label) = builder.label_name() {
+            thread = thread.name(label.to_owned());
+        }
+        let spawned = thread.spawn(move || {
+            builder.pin_worker(index % builder.threads().max(1));
+            thread_pool::run_job(job, &panic_handler, builder.label_name());
+        });
+        if let Err(err) = spawned {
+            error!("Failed to spawn a thread: {}", err);
+        }
+    }
+
+    /// name every thread after the label, and pin them to the cores of the workers in turn
+    fn build(builder: &ThreadPoolBuilder) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Ok(NaiveThreadPool {
+            builder: builder.clone(),
+            spawned: AtomicUsize::new(0),
+            panic_handler: PanicHandlerSlot::default(),
+        })
+    }
+
+    fn label(&self) -> Option<&str> {
+        self.builder.label_name()
+    }
+
+    /// call the handler on the thread of a job which panicked
+    fn set_panic_handler(&self, handler: PanicHandler) {
+        *self.panic_handler.write().unwrap() = Some(handler);
     }
 }
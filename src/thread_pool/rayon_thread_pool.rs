@@ -1,8 +1,10 @@
-use crate::thread_pool::ThreadPool;
+use crate::thread_pool::{self, PanicHandler, PanicHandlerSlot, ThreadPool, ThreadPoolBuilder};
 
 /// a thread pool wrapping rayon's threadPool
 pub struct RayonThreadPool {
     pool: rayon::ThreadPool,
+    label: Option<String>,
+    panic_handler: PanicHandlerSlot,
 }
 
 // 对于 RayonThreadPool，直接参考官网的样例初始化对应的 pool 并直接 spawn 给其即可。
@@ -12,9 +14,7 @@ impl ThreadPool for RayonThreadPool {
     where
         Self: Sized,
     {
-        Ok(RayonThreadPool {
-            pool: rayon::ThreadPoolBuilder::new().num_threads(num).build()?,
-        })
+        Self::build(&ThreadPoolBuilder::new(num))
     }
 
     /// spawn job to rayon's threadPool
@@ -22,6 +22,39 @@ impl ThreadPool for RayonThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        self.pool.spawn(job);
+        // rayon aborts the process on a panic it has no handler for
+        let panic_handler = self.panic_handler.clone();
+        let label = self.label.clone();
+        self.pool.spawn(move || {
+            thread_pool::run_job(job, &panic_handler, label.as_deref());
+        });
+    }
+
+    /// init rayon's threadPool with named and pinned threads
+    fn build(builder: &ThreadPoolBuilder) -> crate::Result<Self>
+    where
+        Self: Sized,
+    {
+        let names = builder.clone();
+        let pins = builder.clone();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(builder.threads())
+            .thread_name(move |index| names.thread_name(index).unwrap_or_default())
+            .start_handler(move |index| pins.pin_worker(index))
+            .build()?;
+        Ok(RayonThreadPool {
+            pool,
+            label: builder.label_name().map(str::to_owned),
+            panic_handler: PanicHandlerSlot::default(),
+        })
+    }
+
+    fn label(&self) -> Option<&str> {
+        self.label.as_deref()
     }
-}
\ No newline at end of file
+
+    /// call the handler on the thread of a job which panicked
+    fn set_panic_handler(&self, handler: PanicHandler) {
+        *self.panic_handler.write().unwrap() = Some(handler);
+    }
+}
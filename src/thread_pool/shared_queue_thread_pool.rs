@@ -1,10 +1,10 @@
-use crate::thread_pool::ThreadPool;
+use crate::thread_pool::{self, PanicHandler, PanicHandlerSlot, ThreadPool, ThreadPoolBuilder};
 use crate::Result;
 use log::{debug, error};
-use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc::Receiver;
 use std::sync::{mpsc, Arc, Mutex};
-use std::{panic, thread};
+use std::{io, panic, thread};
 
 /**
  * 共享队列的 ThreadPool
@@ -14,10 +14,21 @@ use std::{panic, thread};
  */
 /// a shared queue thread pool
 pub struct SharedQueueThreadPool {
-    workers: Vec<Worker>,
+    workers: Arc<Workers>,
     sender: mpsc::Sender<Message>,
 }
 
+/// what the workers of a pool share
+struct Workers {
+    receiver: Mutex<Receiver<Message>>,
+    /// the thread of every worker by id, replaced when a worker panics in supervisor mode
+    threads: Mutex<Vec<Option<thread::JoinHandle<()>>>>,
+    /// the label and the mode of the pool
+    builder: ThreadPoolBuilder,
+    replaced: AtomicU64,
+    panic_handler: PanicHandlerSlot,
+}
+
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
 // 为了优雅停机，对于 Job 又包装了一层枚举和 Terminate 类型来支持子 thread 的优雅退出，
@@ -27,22 +38,59 @@ enum Message {
     Terminate,
 }
 
+impl Workers {
+    fn label(&self) -> &str {
+        self.builder.label_name().unwrap_or("pool")
+    }
+}
+
 impl ThreadPool for SharedQueueThreadPool {
     /// init num threads and related resources
     fn new(num: usize) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::build(&ThreadPoolBuilder::new(num))
+    }
+
+    /// init num threads which are replaced when a job panics
+    fn supervised(num: usize) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::build(&ThreadPoolBuilder::new(num).supervised(true))
+    }
+
+    /// init the threads, named after the label; the ones started are terminated if one fails
+    fn build(builder: &ThreadPoolBuilder) -> Result<Self>
     where
         Self: Sized,
     {
         let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let mut workers = Vec::with_capacity(num);
-        for id in 0..num {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+        let workers = Arc::new(Workers {
+            receiver: Mutex::new(receiver),
+            threads: Mutex::new(Vec::with_capacity(builder.threads())),
+            builder: builder.clone(),
+            replaced: AtomicU64::new(0),
+            panic_handler: PanicHandlerSlot::default(),
+        });
+        for id in 0..builder.threads() {
+            let thread = start_worker(id, Arc::clone(&workers))?;
+            workers.threads.lock().unwrap().push(Some(thread));
         }
 
         Ok(SharedQueueThreadPool { workers, sender })
     }
 
+    fn label(&self) -> Option<&str> {
+        self.workers.builder.label_name()
+    }
+
+    /// the workers replaced in supervisor mode
+    fn replaced_workers(&self) -> u64 {
+        self.workers.replaced.load(Ordering::Relaxed)
+    }
+
     /// spawn the job to pools
     fn spawn<F>(&self, job: F)
     where
@@ -51,56 +99,95 @@ impl ThreadPool for SharedQueueThreadPool {
         // 利用 Box 将闭包 F 放在堆上来支持线程安全的传递闭包。
         self.sender.send(Message::NewJob(Box::new(job))).unwrap()
     }
+
+    /// call the handler on the worker of a job which panicked
+    fn set_panic_handler(&self, handler: PanicHandler) {
+        *self.workers.panic_handler.write().unwrap() = Some(handler);
+    }
 }
 
 impl Drop for SharedQueueThreadPool {
     fn drop(&mut self) {
         debug!("Sending terminate message to all workers.");
 
-        for _ in &self.workers {
+        let workers = self.workers.threads.lock().unwrap().len();
+        for _ in 0..workers {
             self.sender.send(Message::Terminate).unwrap();
         }
 
-        debug!("Shutting down {} workers.", self.workers.len());
+        debug!("Shutting down {} workers.", workers);
 
-        for worker in &mut self.workers {
-            debug!("Shutting down worker {}", worker.id);
+        for id in 0..workers {
+            debug!("Shutting down worker {}", id);
 
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+            // a worker which panicked has put its replacement in its place before it ended
+            loop {
+                let thread = self.workers.threads.lock().unwrap()[id].take();
+                match thread.map(|thread| thread.join()) {
+                    Some(Err(_)) => continue,
+                    _ => break,
+                }
             }
         }
     }
 }
 
-struct Worker {
-    id: usize,
-    thread: Option<thread::JoinHandle<()>>,
-}
-
-impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<Receiver<Message>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
+fn start_worker(id: usize, workers: Arc<Workers>) -> io::Result<thread::JoinHandle<()>> {
+    let mut thread = thread::Builder::new();
+    if let Some(name) = workers.builder.thread_name(id) {
+        thread = thread.name(name);
+    }
+    thread.spawn(move || {
+        workers.builder.pin_worker(id);
+        let _supervisor = Supervisor {
+            id,
+            workers: Arc::clone(&workers),
+        };
+        loop {
+            let message = match workers.receiver.lock().unwrap().recv() {
+                Ok(message) => message,
+                Err(_) => break,
+            };
             match message {
                 Message::NewJob(job) => {
-                    debug!("{} receive a job", id);
+                    debug!("{} worker {} receive a job", workers.label(), id);
                     // 由于单元测试中传入的闭包可能会 panic 但不想看到线程池中的线程减少，
-                    // 一种方案是检测到线程 panic 退出之后新增新的线程，
+                    // 一种方案是检测到线程 panic 退出之后新增新的线程（见 supervised），
                     // 另一种方式则是panic::catch_unwind捕获可能得 panic。确保该线程不会由于执行闭包而 panic
-                    if let Err(err) = panic::catch_unwind(AssertUnwindSafe(job)) {
-                        error!("{} executes a job with error {:?}", id, err);
+                    let label = workers.builder.label_name();
+                    if let Some(payload) = thread_pool::run_job(job, &workers.panic_handler, label)
+                    {
+                        if workers.builder.is_supervised() {
+                            panic::resume_unwind(payload);
+                        }
                     }
                 }
                 Message::Terminate => {
-                    debug!("Worker {} terminated", id);
+                    debug!("{} worker {} terminated", workers.label(), id);
                     break;
                 }
             }
-        });
-        Worker {
-            id,
-            thread: Some(thread),
+        }
+    })
+}
+
+/// dropped when a worker ends, replacing it if it is unwinding from a panicked job
+struct Supervisor {
+    id: usize,
+    workers: Arc<Workers>,
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+        let label = self.workers.label();
+        error!("{} worker {} panicked, replacing it", label, self.id);
+        self.workers.replaced.fetch_add(1, Ordering::Relaxed);
+        match start_worker(self.id, Arc::clone(&self.workers)) {
+            Ok(thread) => self.workers.threads.lock().unwrap()[self.id] = Some(thread),
+            Err(err) => error!("{} worker {} not replaced: {}", label, self.id, err),
         }
     }
-}
\ No newline at end of file
+}
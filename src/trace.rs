@@ -0,0 +1,421 @@
+/*!
+  Records spans of requests, engine operations and compactions, and exports them to an
+  OpenTelemetry collector
+*/
+
+use crate::random::random_u64;
+use crate::{KVStoreError, Result};
+use log::{debug, warn};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{BufRead, BufReader, Write};
+use std::marker::PhantomData;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// the most spans sent to the collector in one export
+const EXPORT_BATCH_SIZE: usize = 512;
+/// how long finished spans wait for a batch to fill up before they are exported
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+/// the most finished spans waiting to be exported, further ones are dropped
+const MAX_QUEUED_SPANS: usize = 8192;
+/// how long connecting to the collector, sending an export and reading its answer may take each
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(5);
+/// the OTLP span kinds
+const SPAN_KIND_INTERNAL: u32 = 1;
+const SPAN_KIND_SERVER: u32 = 2;
+/// the OTLP status code of a failed span
+const STATUS_CODE_ERROR: u32 = 2;
+
+/// whether an exporter is installed, checked before recording anything
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// where finished spans go while an exporter is installed
+static SINK: Mutex<Option<SyncSender<FinishedSpan>>> = Mutex::new(None);
+/// the spans dropped since the exporter was installed as too many were waiting
+static DROPPED_SPANS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// the open spans of this thread, innermost last
+    static OPEN_SPANS: RefCell<Vec<TraceContext>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The identity of a span, propagated from clients in the W3C `traceparent` format
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceContext {
+    /// the trace the span belongs to
+    pub trace_id: u128,
+    /// the span itself
+    pub span_id: u64,
+}
+
+impl TraceContext {
+    /// Parse a `traceparent` like `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let (version, trace_id, span_id) = (parts.next()?, parts.next()?, parts.next()?);
+        if version != "00" || trace_id.len() != 32 || span_id.len() != 16 {
+            return None;
+        }
+        let context = TraceContext {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            span_id: u64::from_str_radix(span_id, 16).ok()?,
+        };
+        // all zero IDs are invalid
+        if context.trace_id == 0 || context.span_id == 0 {
+            return None;
+        }
+        Some(context)
+    }
+}
+
+impl fmt::Display for TraceContext {
+    /// format as a sampled `traceparent`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "00-{:032x}-{:016x}-01", self.trace_id, self.span_id)
+    }
+}
+
+/** A unit of work recorded while an `OtlpExporter` is installed, finished when dropped.
+Spans opened while another one is open on the same thread are its children.
+# Example
+```
+use kvs::trace;
+
+let mut span = trace::span("kvs.example");
+span.attribute("kvs.key", "key1");
+```
+ */
+pub struct Span {
+    inner: Option<OpenSpan>,
+    /// a span is closed on the thread which opened it
+    _not_send: PhantomData<*const ()>,
+}
+
+struct OpenSpan {
+    context: TraceContext,
+    parent_span_id: Option<u64>,
+    name: &'static str,
+    kind: u32,
+    start: SystemTime,
+    started: Instant,
+    attributes: Vec<(&'static str, String)>,
+    error: Option<String>,
+}
+
+struct FinishedSpan {
+    span: OpenSpan,
+    end: SystemTime,
+}
+
+/// Open a span which is a child of the innermost open span of this thread, if any.
+pub fn span(name: &'static str) -> Span {
+    open(name, None, SPAN_KIND_INTERNAL)
+}
+
+/// Open a span serving a request, as a child of the span of the client if it sent one.
+pub fn server_span(name: &'static str, parent: Option<TraceContext>) -> Span {
+    open(name, parent, SPAN_KIND_SERVER)
+}
+
+/// Open a span which is a child of `parent`, e.g. of a span of the thread handing work over
+/// to this one, or of the innermost open span of this thread if it is None.
+pub fn child_span(name: &'static str, parent: Option<TraceContext>) -> Span {
+    open(name, parent, SPAN_KIND_INTERNAL)
+}
+
+/// The innermost open span of this thread.
+pub fn current() -> Option<TraceContext> {
+    OPEN_SPANS.with(|spans| spans.borrow().last().copied())
+}
+
+fn open(name: &'static str, parent: Option<TraceContext>, kind: u32) -> Span {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Span {
+            inner: None,
+            _not_send: PhantomData,
+        };
+    }
+    let parent = parent.or_else(current);
+    let context = TraceContext {
+        trace_id: parent.map(|parent| parent.trace_id).unwrap_or_else(|| {
+            // an ID of zero is invalid
+            (u128::from(random_u64()) << 64 | u128::from(random_u64())).max(1)
+        }),
+        span_id: random_u64().max(1),
+    };
+    OPEN_SPANS.with(|spans| spans.borrow_mut().push(context));
+    Span {
+        inner: Some(OpenSpan {
+            context,
+            parent_span_id: parent.map(|parent| parent.span_id),
+            name,
+            kind,
+            start: SystemTime::now(),
+            started: Instant::now(),
+            attributes: Vec::new(),
+            error: None,
+        }),
+        _not_send: PhantomData,
+    }
+}
+
+impl Span {
+    /// The identity of the span, if it is recorded.
+    pub fn context(&self) -> Option<TraceContext> {
+        self.inner.as_ref().map(|span| span.context)
+    }
+
+    /// Describe the span with an attribute.
+    pub fn attribute(&mut self, key: &'static str, value: impl ToString) {
+        if let Some(span) = &mut self.inner {
+            span.attributes.push((key, value.to_string()));
+        }
+    }
+
+    /// Mark the span as failed.
+    pub fn error(&mut self, message: impl ToString) {
+        if let Some(span) = &mut self.inner {
+            span.error = Some(message.to_string());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(span) = self.inner.take() {
+            OPEN_SPANS.with(|spans| {
+                let mut spans = spans.borrow_mut();
+                if let Some(index) = spans.iter().rposition(|open| *open == span.context) {
+                    spans.remove(index);
+                }
+            });
+            let end = span.start + span.started.elapsed();
+            if let Some(sink) = SINK.lock().unwrap().as_ref() {
+                // a slow collector must not hold requests back or fill the memory
+                if let Err(TrySendError::Full(_)) = sink.try_send(FinishedSpan { span, end }) {
+                    DROPPED_SPANS.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+/** Exports the spans recorded in this process to an OpenTelemetry collector, e.g. Jaeger or
+Tempo, with OTLP over HTTP in its JSON encoding. Only one exporter is installed at a time;
+dropping it exports the remaining spans and stops recording. Spans finished while too many
+wait for a slow collector are dropped, see `dropped_spans`.
+# Example
+```no_run
+use kvs::trace::OtlpExporter;
+
+let exporter = OtlpExporter::install("http://127.0.0.1:4318", "kvs-server").unwrap();
+```
+ */
+pub struct OtlpExporter {
+    handle: Option<JoinHandle<()>>,
+}
+
+impl OtlpExporter {
+    /// Send spans to the collector at `endpoint`, like `http://127.0.0.1:4318`, whose path
+    /// defaults to `/v1/traces`, on behalf of the service `service_name`.
+    pub fn install(endpoint: &str, service_name: &str) -> Result<Self> {
+        let target = HttpTarget::parse(endpoint)?;
+        let mut sink = SINK.lock().unwrap();
+        if sink.is_some() {
+            return Err(KVStoreError::CommonStringError(
+                "an exporter is installed already".to_owned(),
+            ));
+        }
+        let (sender, receiver) = mpsc::sync_channel(MAX_QUEUED_SPANS);
+        DROPPED_SPANS.store(0, Ordering::Relaxed);
+        let service_name = service_name.to_owned();
+        let handle = thread::spawn(move || export_loop(receiver, target, service_name));
+        *sink = Some(sender);
+        ENABLED.store(true, Ordering::SeqCst);
+        Ok(OtlpExporter {
+            handle: Some(handle),
+        })
+    }
+
+    /// the number of spans dropped since the exporter was installed, as too many were
+    /// waiting to be exported
+    pub fn dropped_spans(&self) -> u64 {
+        DROPPED_SPANS.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for OtlpExporter {
+    fn drop(&mut self) {
+        ENABLED.store(false, Ordering::SeqCst);
+        // closing the channel lets the exporter finish the spans sent so far
+        SINK.lock().unwrap().take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn export_loop(receiver: Receiver<FinishedSpan>, target: HttpTarget, service_name: String) {
+    let mut batch = Vec::new();
+    let mut reported = 0;
+    loop {
+        let deadline = Instant::now() + EXPORT_INTERVAL;
+        let closed = loop {
+            match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(span) => {
+                    batch.push(span);
+                    if batch.len() >= EXPORT_BATCH_SIZE {
+                        break false;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => break false,
+                Err(RecvTimeoutError::Disconnected) => break true,
+            }
+        };
+        if !batch.is_empty() {
+            let body = encode(&batch, &service_name);
+            match target.post(&body) {
+                Ok(()) => debug!("Exported {} spans", batch.len()),
+                Err(err) => {
+                    warn!("Can not export {} spans: {}", batch.len(), err);
+                    // once the exporter is dropped, the collector is not waited for again
+                    if !ENABLED.load(Ordering::SeqCst) {
+                        let left = receiver.try_iter().count();
+                        warn!("Dropped the {} spans left to export", left);
+                        return;
+                    }
+                }
+            }
+            batch.clear();
+        }
+        let dropped = DROPPED_SPANS.load(Ordering::Relaxed);
+        if dropped > reported {
+            warn!(
+                "Dropped {} spans waiting for the collector",
+                dropped - reported
+            );
+            reported = dropped;
+        }
+        if closed {
+            return;
+        }
+    }
+}
+
+/// encode spans as an OTLP `ExportTraceServiceRequest` in JSON
+fn encode(batch: &[FinishedSpan], service_name: &str) -> Vec<u8> {
+    let spans: Vec<Value> = batch
+        .iter()
+        .map(|finished| {
+            let span = &finished.span;
+            let mut value = json!({
+                "traceId": format!("{:032x}", span.context.trace_id),
+                "spanId": format!("{:016x}", span.context.span_id),
+                "name": span.name,
+                "kind": span.kind,
+                "startTimeUnixNano": unix_nanos(span.start).to_string(),
+                "endTimeUnixNano": unix_nanos(finished.end).to_string(),
+                "attributes": span.attributes.iter().map(|(key, value)| json!({
+                    "key": key,
+                    "value": {"stringValue": value},
+                })).collect::<Vec<_>>(),
+            });
+            if let Some(parent_span_id) = span.parent_span_id {
+                value["parentSpanId"] = json!(format!("{:016x}", parent_span_id));
+            }
+            if let Some(message) = &span.error {
+                value["status"] = json!({"code": STATUS_CODE_ERROR, "message": message});
+            }
+            value
+        })
+        .collect();
+    let request = json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": service_name}}],
+            },
+            "scopeSpans": [{
+                "scope": {"name": "kvs", "version": env!("CARGO_PKG_VERSION")},
+                "spans": spans,
+            }],
+        }],
+    });
+    serde_json::to_vec(&request).expect("spans encode to JSON")
+}
+
+/// the collector spans are posted to
+struct HttpTarget {
+    host: String,
+    path: String,
+}
+
+impl HttpTarget {
+    fn parse(endpoint: &str) -> Result<Self> {
+        let rest = endpoint.strip_prefix("http://").ok_or_else(|| {
+            KVStoreError::CommonStringError(format!(
+                "the OTLP endpoint {} is not an http:// URL",
+                endpoint
+            ))
+        })?;
+        let (host, path) = match rest.find('/') {
+            Some(index) if index + 1 < rest.len() => (&rest[..index], &rest[index..]),
+            Some(index) => (&rest[..index], "/v1/traces"),
+            None => (rest, "/v1/traces"),
+        };
+        Ok(HttpTarget {
+            host: host.to_owned(),
+            path: path.to_owned(),
+        })
+    }
+
+    /// connect to the first address of the host which answers in time
+    fn connect(&self) -> Result<TcpStream> {
+        let mut last_err = None;
+        for addr in self.host.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, EXPORT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(match last_err {
+            Some(err) => err.into(),
+            None => KVStoreError::CommonStringError(format!("{} has no address", self.host)),
+        })
+    }
+
+    fn post(&self, body: &[u8]) -> Result<()> {
+        let mut stream = self.connect()?;
+        stream.set_read_timeout(Some(EXPORT_TIMEOUT))?;
+        stream.set_write_timeout(Some(EXPORT_TIMEOUT))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.path,
+            self.host,
+            body.len()
+        )?;
+        stream.write_all(body)?;
+        stream.flush()?;
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status)?;
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(KVStoreError::CommonStringError(format!(
+                "the collector answered {:?}",
+                status.trim()
+            ))),
+        }
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_nanos())
+        .unwrap_or(0)
+}
@@ -0,0 +1,35 @@
+use crate::integrity;
+use crate::Result;
+use serde::{Deserialize, Serialize};
+
+/// A transformation of the values a `GET` or `SCAN` reads, applied by the server so only
+/// the part of a value the client needs is sent, see `Request::TRANSFORM`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Transform {
+    /// the field of a JSON value at a JSON pointer like `/user/emails/0`, as JSON text;
+    /// values without the field are read as missing
+    JsonPointer(String),
+    /// the given number of characters of the value from a character offset on, fewer past
+    /// the end of the value
+    Substring(usize, usize),
+}
+
+impl Transform {
+    /// Transform the value of `key`, checking and stripping the checksum of a value written
+    /// by a client with `Client::integrity` first. Return None if the value lacks the part.
+    pub(crate) fn apply(&self, key: &str, value: String) -> Result<Option<String>> {
+        let value = integrity::open(key, value)?;
+        match self {
+            Transform::JsonPointer(pointer) => {
+                let document: serde_json::Value = serde_json::from_str(&value)?;
+                match document.pointer(pointer) {
+                    Some(field) => Ok(Some(field.to_string())),
+                    None => Ok(None),
+                }
+            }
+            Transform::Substring(offset, length) => {
+                Ok(Some(value.chars().skip(*offset).take(*length).collect()))
+            }
+        }
+    }
+}
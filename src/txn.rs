@@ -0,0 +1,146 @@
+use crate::locks::KeyLockManager;
+use crate::watch::WatchHub;
+use crate::{KVStoreError, KvsEngine, KvsReader, Result};
+use log::warn;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// how long a commit waits for other commits writing the same keys
+const COMMIT_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the writes of a transaction by stored key, the last one of each key, None removing it
+#[derive(Default)]
+pub(crate) struct WriteBatch {
+    writes: BTreeMap<String, Option<String>>,
+}
+
+impl WriteBatch {
+    pub(crate) fn set(&mut self, key: String, value: String) {
+        self.writes.insert(key, Some(value));
+    }
+
+    pub(crate) fn remove(&mut self, key: String) {
+        self.writes.insert(key, None);
+    }
+
+    /// the value the batch writes to a key, Some(None) if it removes it
+    pub(crate) fn get(&self, key: &str) -> Option<Option<&String>> {
+        self.writes.get(key).map(Option::as_ref)
+    }
+}
+
+/// A transaction of a connection, begun by `Request::BEGIN`. It reads the keys as of the
+/// revision it began at, failing with `TransactionConflict` if a key it reads was written
+/// since, and buffers its writes until they are applied together by `commit`.
+pub(crate) struct Transaction {
+    revision: u64,
+    writes: WriteBatch,
+}
+
+impl Transaction {
+    pub(crate) fn begin(watch_hub: &WatchHub) -> Self {
+        Transaction {
+            revision: watch_hub.revision(),
+            writes: WriteBatch::default(),
+        }
+    }
+
+    /// the value of a stored key as of the revision the transaction began at, with its writes
+    pub(crate) fn get<E: KvsReader>(
+        &self,
+        engine: &E,
+        watch_hub: &WatchHub,
+        key: &str,
+    ) -> Result<Option<String>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.cloned());
+        }
+        // no write can come between the check and the read
+        let guard = watch_hub.lock();
+        if guard.changed_since(key, self.revision)? {
+            return Err(KVStoreError::TransactionConflict(key.to_owned()));
+        }
+        engine.get(key.to_owned())
+    }
+
+    pub(crate) fn set(&mut self, key: String, value: String) {
+        self.writes.set(key, value);
+    }
+
+    /// remove a stored key, which must exist as `get` reads it
+    pub(crate) fn remove<E: KvsReader>(
+        &mut self,
+        engine: &E,
+        watch_hub: &WatchHub,
+        key: String,
+    ) -> Result<()> {
+        if self.get(engine, watch_hub, &key)?.is_none() {
+            return Err(KVStoreError::KeyNotFound);
+        }
+        self.writes.remove(key);
+        Ok(())
+    }
+
+    /// Apply the writes with all other writes held back, unless a key they write was
+    /// written since the transaction began, in which case none is applied.
+    /// The keys are locked first, so commits of other keys are not held back while
+    /// this one waits for a commit of the same keys.
+    /// If a write fails, e.g. with `QuotaExceeded`, the writes applied before it are undone
+    /// and none is published to the watchers.
+    pub(crate) fn commit<E: KvsEngine>(
+        self,
+        engine: &E,
+        watch_hub: &WatchHub,
+        locks: &KeyLockManager,
+    ) -> Result<()> {
+        let keys: Vec<String> = self.writes.writes.keys().cloned().collect();
+        let _locks = locks.try_lock_keys(&keys, COMMIT_LOCK_TIMEOUT)?;
+        let mut guard = watch_hub.lock();
+        for key in self.writes.writes.keys() {
+            if guard.changed_since(key, self.revision)? {
+                return Err(KVStoreError::TransactionConflict(key.clone()));
+            }
+        }
+        // the writes applied so far with the values they replaced
+        let mut applied = Vec::new();
+        for (key, value) in self.writes.writes {
+            let result = engine.get(key.clone()).and_then(|previous| {
+                match value.clone() {
+                    Some(value) => engine.set(key.clone(), value),
+                    None => engine.remove(key.clone()),
+                }
+                .map(|()| previous)
+            });
+            match result {
+                Ok(previous) => applied.push((key, value, previous)),
+                // a key the transaction set and removed again
+                Err(KVStoreError::KeyNotFound) => {}
+                Err(err) => {
+                    undo(engine, applied);
+                    return Err(err);
+                }
+            }
+        }
+        for (key, value, _) in applied {
+            guard.publish(key, value);
+        }
+        Ok(())
+    }
+}
+
+/// Restore the values the writes of a failed commit replaced, the last write first.
+/// A crash before the commit completes can still leave some of its writes applied.
+fn undo<E: KvsEngine>(engine: &E, applied: Vec<(String, Option<String>, Option<String>)>) {
+    for (key, _, previous) in applied.into_iter().rev() {
+        let result = match previous {
+            Some(previous) => engine.set(key.clone(), previous),
+            None => engine.remove(key.clone()),
+        };
+        if let Err(err) = result {
+            warn!(
+                "Unable to undo the write of {} by a failed commit: {}",
+                key, err
+            );
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use crate::{KVStoreError, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// the number of events a server keeps for watchers which reconnect
+pub const DEFAULT_WATCH_HISTORY: usize = 10_000;
+
+/// a change of a key, numbered by a server-wide revision
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WatchEvent {
+    /// the revision of the write, increasing by one for every write the server applies
+    pub revision: u64,
+    /// the changed key
+    pub key: String,
+    /// the new value, or None if the key was removed
+    pub value: Option<String>,
+}
+
+/// numbers the writes of a server and fans them out to watchers
+pub(crate) struct WatchHub {
+    inner: Mutex<HubState>,
+}
+
+struct HubState {
+    revision: u64,
+    capacity: usize,
+    history: VecDeque<WatchEvent>,
+    watchers: Vec<Watcher>,
+}
+
+/// How far a watcher may fall behind, see `KvServer::slow_clients`: it is dropped once the
+/// events queued for it took more than `max_backlog` bytes for longer than `timeout`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SlowClientLimit {
+    pub(crate) max_backlog: usize,
+    pub(crate) timeout: Duration,
+}
+
+/// the events queued for a watcher, shared with its receiver
+#[derive(Default)]
+struct Backlog {
+    bytes: AtomicUsize,
+    /// whether the watcher was dropped for falling behind
+    dropped: AtomicBool,
+}
+
+struct Watcher {
+    prefix: String,
+    sender: Sender<WatchEvent>,
+    backlog: Arc<Backlog>,
+    limit: Option<SlowClientLimit>,
+    /// when the backlog went over the limit, if it is over it
+    over_since: Option<Instant>,
+}
+
+impl Watcher {
+    fn new(prefix: String, limit: Option<SlowClientLimit>) -> (Watcher, WatchReceiver) {
+        let (sender, receiver) = mpsc::channel();
+        let backlog = Arc::new(Backlog::default());
+        let watcher = Watcher {
+            prefix,
+            sender,
+            backlog: Arc::clone(&backlog),
+            limit,
+            over_since: None,
+        };
+        (watcher, WatchReceiver { receiver, backlog })
+    }
+
+    /// queue an event for the watcher, returning false once it is gone or fell behind
+    fn send(&mut self, event: &WatchEvent) -> bool {
+        let bytes = self.backlog.bytes.fetch_add(size(event), Ordering::SeqCst) + size(event);
+        if let Some(limit) = self.limit {
+            if bytes <= limit.max_backlog {
+                self.over_since = None;
+            } else if self.over_since.get_or_insert_with(Instant::now).elapsed() >= limit.timeout {
+                self.backlog.dropped.store(true, Ordering::SeqCst);
+                return false;
+            }
+        }
+        self.sender.send(event.clone()).is_ok()
+    }
+}
+
+/// The events of a watch as they happen. It ends when the watcher is dropped for falling
+/// behind, see `fell_behind`.
+pub(crate) struct WatchReceiver {
+    receiver: Receiver<WatchEvent>,
+    backlog: Arc<Backlog>,
+}
+
+impl WatchReceiver {
+    /// whether the events stopped as the watcher fell behind reading them
+    pub(crate) fn fell_behind(&self) -> bool {
+        self.backlog.dropped.load(Ordering::SeqCst)
+    }
+}
+
+impl Iterator for WatchReceiver {
+    type Item = WatchEvent;
+
+    fn next(&mut self) -> Option<WatchEvent> {
+        let event = self.receiver.recv().ok()?;
+        self.backlog.bytes.fetch_sub(size(&event), Ordering::SeqCst);
+        Some(event)
+    }
+}
+
+/// the bytes an event takes in the backlog of a watcher
+fn size(event: &WatchEvent) -> usize {
+    event.key.len() + event.value.as_ref().map_or(0, String::len)
+}
+
+impl WatchHub {
+    pub(crate) fn new(capacity: usize) -> Self {
+        WatchHub {
+            inner: Mutex::new(HubState {
+                revision: 0,
+                capacity,
+                history: VecDeque::with_capacity(capacity),
+                watchers: Vec::new(),
+            }),
+        }
+    }
+
+    /// Apply a write and publish it under the next revision if it succeeds.
+    /// Writes are serialized here so that revisions follow the order the engine applied them in.
+    pub(crate) fn apply<F>(&self, key: String, value: Option<String>, write: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        self.lock().apply(key, value, write)
+    }
+
+    /// Hold back all other writes until the guard is dropped, e.g. to apply several atomically.
+    pub(crate) fn lock(&self) -> HubGuard<'_> {
+        HubGuard(self.inner.lock().unwrap())
+    }
+
+    /// the revision of the last write
+    pub(crate) fn revision(&self) -> u64 {
+        self.inner.lock().unwrap().revision
+    }
+
+    /// Subscribe to the changes of keys starting with `prefix` from now on.
+    /// `read` runs while writes are held back, so its result and the received changes
+    /// together cover every write exactly once. Return it with the revision it reflects.
+    pub(crate) fn subscribe<T, F>(&self, prefix: String, read: F) -> Result<(u64, T, WatchReceiver)>
+    where
+        F: FnOnce() -> Result<T>,
+    {
+        let mut state = self.inner.lock().unwrap();
+        let snapshot = read()?;
+        let (watcher, receiver) = Watcher::new(prefix, None);
+        state.watchers.push(watcher);
+        Ok((state.revision, snapshot, receiver))
+    }
+
+    /// Subscribe to the changes of keys starting with `prefix` after `since_revision`.
+    /// Return the retained events after it and a receiver for all later ones, which ends if
+    /// the watcher falls behind `limit`.
+    pub(crate) fn watch(
+        &self,
+        prefix: String,
+        since_revision: u64,
+        limit: Option<SlowClientLimit>,
+    ) -> Result<(Vec<WatchEvent>, WatchReceiver)> {
+        let mut state = self.inner.lock().unwrap();
+        if since_revision > state.revision {
+            return Err(KVStoreError::FutureRevision(since_revision, state.revision));
+        }
+        let oldest = state
+            .history
+            .front()
+            .map(|event| event.revision)
+            .unwrap_or(state.revision + 1);
+        if since_revision + 1 < oldest {
+            return Err(KVStoreError::CompactedRevision(since_revision, oldest));
+        }
+
+        let backlog = state
+            .history
+            .iter()
+            .filter(|event| event.revision > since_revision && event.key.starts_with(&prefix))
+            .cloned()
+            .collect();
+        let (watcher, receiver) = Watcher::new(prefix, limit);
+        state.watchers.push(watcher);
+        Ok((backlog, receiver))
+    }
+}
+
+/// the writes of a server held back by `WatchHub::lock`
+pub(crate) struct HubGuard<'a>(MutexGuard<'a, HubState>);
+
+impl HubGuard<'_> {
+    /// Apply a write and publish it under the next revision if it succeeds.
+    pub(crate) fn apply<F>(&mut self, key: String, value: Option<String>, write: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        write()?;
+        self.publish(key, value);
+        Ok(())
+    }
+
+    /// Publish a write the engine already applied under the next revision, e.g. once all the
+    /// writes of a commit are applied.
+    pub(crate) fn publish(&mut self, key: String, value: Option<String>) {
+        let state = &mut self.0;
+        state.revision += 1;
+        let event = WatchEvent {
+            revision: state.revision,
+            key,
+            value,
+        };
+        state
+            .watchers
+            .retain_mut(|watcher| !event.key.starts_with(&watcher.prefix) || watcher.send(&event));
+        if state.capacity > 0 {
+            if state.history.len() == state.capacity {
+                state.history.pop_front();
+            }
+            state.history.push_back(event);
+        }
+    }
+
+    /// the revision of the last write
+    pub(crate) fn revision(&self) -> u64 {
+        self.0.revision
+    }
+
+    /// Whether `key` was written after `revision`, or an error if the events after it are
+    /// no longer retained.
+    pub(crate) fn changed_since(&self, key: &str, revision: u64) -> Result<bool> {
+        let state = &self.0;
+        if revision == state.revision {
+            return Ok(false);
+        }
+        let oldest = state
+            .history
+            .front()
+            .map(|event| event.revision)
+            .unwrap_or(state.revision + 1);
+        if revision + 1 < oldest {
+            return Err(KVStoreError::CompactedRevision(revision, oldest));
+        }
+        Ok(state
+            .history
+            .iter()
+            .rev()
+            .take_while(|event| event.revision > revision)
+            .any(|event| event.key == key))
+    }
+}
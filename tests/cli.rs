@@ -1,7 +1,8 @@
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -20,28 +21,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +53,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +92,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +124,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +135,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +146,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +158,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -178,15 +180,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,15 +200,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -217,19 +221,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +242,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +250,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +258,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +266,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +274,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +282,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +290,15 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["scan", "key*", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("key1 value2\nkey2 value3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,30 +311,92 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("Key not found"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["topkeys", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("reads:\nkey1 1\nwrites:\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--output", "json", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("{\"key\":\"key2\",\"value\":\"value3\"}\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "key*", "--output", "json", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("[{\"key\":\"key2\",\"value\":\"value3\"}]\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["scan", "key*", "--output", "table", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("KEY   VALUE\nkey2  value3\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--output", "json", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr("{\"error\":\"Key not found\"}\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "db1", "--db", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["dbstats", "--db", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("database 1: 1 keys, 7 bytes\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["flush", "--db", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("value3"));
     sender.send(()).unwrap();
     handle.join().unwrap();
 }
@@ -334,4 +409,577 @@ fn cli_access_server_kvs_engine() {
 #[test]
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
-}
\ No newline at end of file
+}
+
+// `kvs-server --check-config` validates the configuration file without serving
+#[test]
+fn cli_check_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("kvs.toml");
+    let check = |config: &str| {
+        fs::write(&config_path, config).unwrap();
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(["--config", config_path.to_str().unwrap(), "--check-config"])
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    check(
+        r#"
+        listen = ["127.0.0.1:4006", "127.0.0.1:4007"]
+        engine = "kvs"
+
+        [thread_pool]
+        kind = "rayon"
+        threads = 4
+
+        [limits]
+        max_disk_size = 1048576
+
+        [log]
+        level = "warn"
+        "#,
+    )
+    .success()
+    .stdout(contains("configuration is valid"));
+    check("").success();
+
+    check("engine = \"rocksdb\"")
+        .failure()
+        .stderr(contains("rocksdb"));
+    check("listen = [\"localhost\"]")
+        .failure()
+        .stderr(contains("localhost"));
+    check("threads = 4").failure().stderr(contains("threads"));
+    check("[thread_pool]\nthreads = 0").failure();
+    check("[log]\nlevel = \"loud\"")
+        .failure()
+        .stderr(contains("loud"));
+    check("[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"")
+        .failure()
+        .stderr(contains("tls"));
+    check("disabled_commands = [\"flush\", \"CLIENTKILL\"]").success();
+    check("disabled_commands = [\"FLUSHALL\"]")
+        .failure()
+        .stderr(contains("FLUSHALL"));
+    check("[access]\nallow = [\"10.0.0.0/8\", \"::1\"]").success();
+    check("[access]\ndeny = [\"10.0.0.0/40\"]")
+        .failure()
+        .stderr(contains("10.0.0.0/40"));
+
+    // flags override the file
+    fs::write(&config_path, "engine = \"rocksdb\"").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", config_path.to_str().unwrap(), "--check-config"])
+        .args(["--engine", "kvs"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    // nothing was opened or served
+    assert!(!temp_dir.path().join("kvs").exists());
+}
+
+// `kvs-server --config` serves on every address of the file
+#[test]
+fn cli_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config_path,
+        r#"
+        listen = ["127.0.0.1:4008", "127.0.0.1:4009"]
+        data_dir = "data"
+
+        [thread_pool]
+        kind = "naive"
+        threads = 2
+        "#,
+    )
+    .unwrap();
+    fs::create_dir(temp_dir.path().join("data")).unwrap();
+
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--config", config_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+    assert!(temp_dir.path().join("data").join("kvs").exists());
+}
+
+// `KVS_*` variables override the configuration file and flags override them
+#[test]
+fn cli_config_env() {
+    let temp_dir = TempDir::new().unwrap();
+    let config_path = temp_dir.path().join("kvs.toml");
+    fs::write(&config_path, "engine = \"kvs\"\n[log]\nlevel = \"loud\"").unwrap();
+    let check = |envs: &[(&str, &str)], args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(["--config", config_path.to_str().unwrap(), "--check-config"])
+            .args(args)
+            .envs(envs.iter().copied())
+            .current_dir(&temp_dir)
+            .assert()
+    };
+
+    check(&[], &[]).failure().stderr(contains("loud"));
+    check(&[("KVS_LOG_LEVEL", "debug")], &[]).success();
+    check(
+        &[("KVS_LOG_LEVEL", "debug"), ("KVS_ENGINE", "rocksdb")],
+        &[],
+    )
+    .failure()
+    .stderr(contains("rocksdb"));
+    check(
+        &[("KVS_LOG_LEVEL", "debug"), ("KVS_ENGINE", "rocksdb")],
+        &["--engine", "sled"],
+    )
+    .success();
+    check(
+        &[
+            ("KVS_LOG_LEVEL", "debug"),
+            ("KVS_LISTEN", "127.0.0.1:4010, 127.0.0.1:4011"),
+            ("KVS_THREAD_POOL_KIND", "rayon"),
+            ("KVS_THREAD_POOL_THREADS", "3"),
+            ("KVS_LIMITS_MAX_DISK_SIZE", "1048576"),
+        ],
+        &[],
+    )
+    .success();
+    check(
+        &[
+            ("KVS_LOG_LEVEL", "debug"),
+            ("KVS_THREAD_POOL_THREADS", "many"),
+        ],
+        &[],
+    )
+    .failure()
+    .stderr(contains("KVS_THREAD_POOL_THREADS"));
+    check(
+        &[("KVS_LOG_LEVEL", "debug"), ("KVS_TLS_CERT", "cert.pem")],
+        &[],
+    )
+    .failure()
+    .stderr(contains("KVS_TLS_KEY"));
+    check(
+        &[("KVS_LOG_LEVEL", "debug"), ("KVS_THREAD_POOL_SIZ", "4")],
+        &[],
+    )
+    .failure()
+    .stderr(contains("KVS_THREAD_POOL_SIZ"));
+
+    // `--set` overrides any option, over the variables
+    check(&[("KVS_LOG_LEVEL", "loud")], &["--set", "log.level=debug"]).success();
+    check(
+        &[],
+        &[
+            "--set",
+            "log.level=debug",
+            "--set",
+            "thread_pool.threads=4",
+            "--set",
+            r#"listen=["127.0.0.1:4010", "127.0.0.1:4011"]"#,
+            "--set",
+            "limits.max_disk_size=1048576",
+        ],
+    )
+    .success();
+    check(
+        &[],
+        &["--set", "log.level=debug", "--set", "thread_pool.threads=0"],
+    )
+    .failure()
+    .stderr(contains("thread_pool.threads"));
+    check(
+        &[],
+        &["--set", "log.level=debug", "--set", "thread_pool.size=4"],
+    )
+    .failure()
+    .stderr(contains("size"));
+    check(
+        &[],
+        &["--set", "log.level=debug", "--set", "tls.cert=cert.pem"],
+    )
+    .failure()
+    .stderr(contains("key"));
+}
+
+// `kvs-server --daemonize` serves in the background until `kvs-server stop`
+#[test]
+fn cli_daemonize() {
+    let temp_dir = TempDir::new().unwrap();
+    let pid_path = temp_dir.path().join("kvs-server.pid");
+    let log_path = temp_dir.path().join("kvs-server.log");
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4012", "--daemonize"])
+        .args(["--pid-file", pid_path.to_str().unwrap()])
+        .args(["--log-file", log_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    thread::sleep(Duration::from_secs(1));
+    let pid = fs::read_to_string(&pid_path).expect("unable to read the PID file");
+    assert!(pid.trim().parse::<u32>().is_ok());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4012"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    // a second server refuses to take over the PID file
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4013"])
+        .args(["--pid-file", pid_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("already running"));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["stop", "--pid-file", pid_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("stopped"));
+    assert!(!pid_path.exists());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4012"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let log = fs::read_to_string(&log_path).expect("unable to read the log file");
+    assert!(log.contains(env!("CARGO_PKG_VERSION")));
+    assert!(log.contains("Stopped"));
+
+    // without a running server there is nothing to stop
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["stop", "--pid-file", pid_path.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
+// `kvs-server` serves on the socket passed by systemd socket activation instead of binding
+#[cfg(unix)]
+#[test]
+fn cli_socket_activation() {
+    use std::net::TcpListener;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::process::CommandExt;
+
+    let temp_dir = TempDir::new().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let fd = listener.as_raw_fd();
+
+    // the shell sets LISTEN_PID to its own PID, which the server keeps by exec
+    let mut server = Command::new("sh");
+    server
+        .arg("-c")
+        .arg("LISTEN_PID=$$ LISTEN_FDS=1 exec \"$0\" --engine kvs --addr 127.0.0.1:4014")
+        .arg(assert_cmd::cargo::cargo_bin("kvs-server"))
+        .current_dir(&temp_dir);
+    // SAFETY: dup2 and fcntl are async-signal-safe
+    unsafe {
+        server.pre_exec(move || {
+            // dup2 leaves close-on-exec set if the socket is descriptor 3 already
+            let result = if fd == 3 {
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, 3)
+            };
+            if result < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = server.spawn().unwrap();
+    drop(listener);
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("value1"));
+    // the configured address is not bound
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4014"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
+}
+
+// `kvs-server --take-over` takes the listener over from a running server, which then exits
+#[cfg(unix)]
+#[test]
+fn cli_take_over() {
+    let temp_dir = TempDir::new().unwrap();
+    let socket_path = temp_dir.path().join("upgrade.sock");
+    let pid_path = temp_dir.path().join("kvs-server.pid");
+    let addr = "127.0.0.1:4015";
+    let start_server = |take_over: bool| {
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(["--engine", "kvs", "--addr", addr])
+            .args(["--upgrade-socket", socket_path.to_str().unwrap()])
+            .args(["--pid-file", pid_path.to_str().unwrap()])
+            .current_dir(&temp_dir);
+        if take_over {
+            cmd.arg("--take-over");
+        }
+        cmd.spawn().unwrap()
+    };
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd.assert()
+    };
+
+    let mut old = start_server(false);
+    thread::sleep(Duration::from_secs(1));
+    client(&["set", "key1", "value1"]).success();
+
+    let mut new = start_server(true);
+    let mut exited = None;
+    for _ in 0..50 {
+        exited = old.try_wait().unwrap();
+        if exited.is_some() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(exited.expect("the old server did not exit").success());
+    thread::sleep(Duration::from_secs(1));
+
+    client(&["get", "key1"])
+        .success()
+        .stdout(contains("value1"));
+    client(&["set", "key2", "value2"]).success();
+    let pid = fs::read_to_string(&pid_path).unwrap();
+    assert_eq!(pid.trim(), new.id().to_string());
+
+    new.kill().expect("server exited before killed");
+    new.wait().expect("unable to wait for server");
+}
+
+// `kvs-server` closes the store cleanly when it is stopped by a signal
+#[cfg(unix)]
+#[test]
+fn cli_close_on_stop() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4176";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    // SAFETY: kill only sends a signal to the server
+    unsafe { libc::kill(child.id() as i32, libc::SIGTERM) };
+    assert!(child.wait().unwrap().success());
+    assert!(temp_dir.path().join("kvs/clean_shutdown.json").exists());
+}
+
+// `kvs-client repl` runs the commands of its input over one connection per server
+#[test]
+fn cli_repl() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut children: Vec<_> = ["127.0.0.1:4121", "127.0.0.1:4122"]
+        .iter()
+        .enumerate()
+        .map(|(i, addr)| {
+            let data_dir = temp_dir.path().join(i.to_string());
+            fs::create_dir(&data_dir).unwrap();
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(["--engine", "kvs", "--addr", addr])
+                .current_dir(&data_dir)
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    thread::sleep(Duration::from_secs(1));
+
+    assert_cmd::Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["repl", "--addr", "127.0.0.1:4121"])
+        .env("HOME", temp_dir.path())
+        .write_stdin(
+            "set key1 \"two words\"\n\
+             get key1\n\
+             get key2\n\
+             rm key2\n\
+             select 1\n\
+             get key1\n\
+             connect 127.0.0.1:4122\n\
+             set key2 value2\n\
+             scan key*\n\
+             servers\n\
+             use 1\n\
+             select 0\n\
+             scan key*\n\
+             frobnicate\n\
+             quit\n\
+             get key1\n",
+        )
+        .assert()
+        .success()
+        .stdout(
+            "OK\n\
+             \"two words\"\n\
+             (nil)\n\
+             (error) Key not found\n\
+             OK\n\
+             (nil)\n\
+             connected to 127.0.0.1:4122\n\
+             OK\n\
+             1) \"key2\" => \"value2\"\n \
+             1) 127.0.0.1:4121 database 1\n\
+             *2) 127.0.0.1:4122 database 0\n\
+             using 127.0.0.1:4121\n\
+             OK\n\
+             1) \"key1\" => \"two words\"\n\
+             (error) unknown command frobnicate, see help\n",
+        );
+    let history = fs::read_to_string(temp_dir.path().join(".kvs_history")).unwrap();
+    assert!(history.contains("connect 127.0.0.1:4122"));
+
+    for child in children.iter_mut() {
+        child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
+    }
+}
+
+// `kvs-client watch` prints the retained writes of a prefix and then new ones as they happen
+#[test]
+fn cli_watch() {
+    let addr = "127.0.0.1:4123";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", addr])
+            .assert()
+            .success();
+    };
+    client(&["set", "user:1", "a"]);
+    client(&["set", "other", "b"]);
+    client(&["rm", "user:1"]);
+
+    let mut watch = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "user:", "--output", "json", "--addr", addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(watch.stdout.take().unwrap()).lines();
+    let mut next_line = || lines.next().unwrap().unwrap();
+    assert_eq!(next_line(), r#"{"key":"user:1","revision":1,"value":"a"}"#);
+    assert_eq!(next_line(), r#"{"key":"user:1","revision":3,"value":null}"#);
+    client(&["set", "user:2", "c"]);
+    assert_eq!(next_line(), r#"{"key":"user:2","revision":4,"value":"c"}"#);
+
+    watch.kill().expect("watch exited before killed");
+    watch.wait().expect("unable to wait for watch");
+    server.kill().expect("server exited before killed");
+    server.wait().expect("unable to wait for server");
+}
+
+// `kvs verify` should print a report and fail on a corrupted store
+#[test]
+fn cli_verify() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = kvs::KvStore::open(temp_dir.path()).unwrap();
+        kvs::KvsWriter::set(&store, "key".to_owned(), "value".to_owned()).unwrap();
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("\"live_keys\": 1"));
+
+    fs::write(temp_dir.path().join("data_7.txt"), "").unwrap();
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["verify", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .code(1)
+        .stdout(contains("\"kind\": \"dangling_file\""));
+}
+
+// `kvs trim` should compact a store and print its disk usage
+#[test]
+fn cli_trim() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = kvs::KvStore::open(temp_dir.path()).unwrap();
+        for iter in 0..50 {
+            kvs::KvsWriter::set(&store, "key".to_owned(), iter.to_string()).unwrap();
+        }
+    }
+    Command::cargo_bin("kvs")
+        .unwrap()
+        .args(["trim", temp_dir.path().to_str().unwrap()])
+        .assert()
+        .success()
+        .stdout(contains("\"disk_usage_after\""));
+}
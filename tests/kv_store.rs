@@ -1,6 +1,16 @@
-use kvs::{KvStore, KvsEngine, Result};
-use std::sync::{Arc, Barrier};
+use kvs::thread_pool::CancellationToken;
+use kvs::{
+    ArchiveStats, BackupManifest, BackupTarget, Command, CompactionDecision, CompactionPolicy,
+    DirBackupTarget, KVStoreError, KeyPrefix, KvStore, KvsEngine, KvsReader, KvsWriter,
+    MigratingEngine, PlacementPolicy, PrefixStats, QuotaPolicy, RangeSize, ReadOnlyEngine, Result,
+    S3BackupTarget, SledKvsEngine, SnapshotEngine, TypedEngine, VerifyProblem,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{mpsc, Arc, Barrier, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -208,4 +218,2363 @@ fn concurrent_get() -> Result<()> {
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// Should reject writes beyond the disk quota and accept them again after removing keys
+#[test]
+fn disk_quota() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .max_disk_size(512)
+        .open(temp_dir.path())?;
+
+    let mut stored = 0;
+    loop {
+        match store.set(format!("key{}", stored), "value".to_owned()) {
+            Ok(()) => stored += 1,
+            Err(KVStoreError::QuotaExceeded) => break,
+            Err(err) => return Err(err),
+        }
+    }
+    assert!(stored > 0);
+    let stats = store.stats();
+    assert_eq!(stats.live_keys, stored);
+    assert!(stats.disk_usage <= 512);
+
+    // compaction reclaims the removed keys, less the header of the new data file
+    store.remove("key0".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.set(format!("key{}", stored), "value".to_owned())?;
+    assert!(store.stats().disk_usage <= 512);
+
+    // Open from disk again and check the usage is recovered
+    drop(store);
+    let store = KvStore::builder()
+        .max_disk_size(512)
+        .open(temp_dir.path())?;
+    assert!(store.stats().disk_usage <= 512);
+    assert!(matches!(
+        store.set("another".to_owned(), "value".to_owned()),
+        Err(KVStoreError::QuotaExceeded)
+    ));
+
+    Ok(())
+}
+
+// Should evict the least recently used keys instead of rejecting writes
+#[test]
+fn lru_eviction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .max_disk_size(512)
+        .quota_policy(QuotaPolicy::EvictLru)
+        .open(temp_dir.path())?;
+
+    store.set("hot".to_owned(), "value".to_owned())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+        assert_eq!(store.get("hot".to_owned())?, Some("value".to_owned()));
+    }
+
+    let stats = store.stats();
+    assert!(stats.evicted_keys > 0);
+    assert!(stats.disk_usage <= 512);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key99".to_owned())?, Some("value".to_owned()));
+
+    // Open from disk again and check evicted keys stay evicted
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("hot".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Should count the bytes read and written, the records appended and the work of compactions
+#[test]
+fn io_accounting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::UselessSize(1024))
+        .open(temp_dir.path())?;
+    let stats = store.stats();
+    assert_eq!(stats.bytes_written, 0);
+    assert_eq!(stats.records_appended, 0);
+
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.remove("key".to_owned())?;
+    let stats = store.stats();
+    assert_eq!(stats.records_appended, 2);
+    assert!(stats.bytes_written > 0 && stats.bytes_written < stats.disk_usage);
+    assert_eq!(stats.bytes_read, 0);
+    assert_eq!(stats.fsyncs, 0);
+
+    // a read reads the record the last write appended
+    store.set("key".to_owned(), "value".to_owned())?;
+    let record = store.stats().bytes_written - stats.bytes_written;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.stats().bytes_read, record);
+
+    // rewriting a key until compaction runs copies its live record once
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    let stats = store.stats();
+    assert_eq!(stats.records_appended, 103);
+    assert!(stats.compaction_bytes_copied > 0);
+    assert!(stats.fsyncs > 0);
+    assert!(stats
+        .metrics()
+        .contains(&("fsyncs".to_owned(), kvs::Metric::Counter(stats.fsyncs))));
+    Ok(())
+}
+
+// Should collect the sizes of the keys and values written and recount the live ones on compaction
+#[test]
+fn size_distribution() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::UselessSize(1024))
+        .open(temp_dir.path())?;
+    store.set("a".to_owned(), "x".repeat(10))?;
+    store.set("bbb".to_owned(), "y".repeat(100))?;
+    let stats = store.stats();
+    assert_eq!(stats.key_sizes.count, 2);
+    assert_eq!(stats.key_sizes.sum, 4);
+    assert_eq!(stats.value_sizes.max, 100);
+    assert_eq!(stats.value_sizes.percentile(50.0), 16);
+    assert_eq!(stats.value_sizes.percentile(100.0), 128);
+    assert!(stats
+        .metrics()
+        .contains(&("value_sizes.max".to_owned(), kvs::Metric::Gauge(100.0))));
+
+    // compaction only counts the live values again
+    while store.stats().compaction_bytes_copied == 0 {
+        store.set("bbb".to_owned(), "z".repeat(200))?;
+    }
+    let stats = store.stats();
+    assert_eq!(stats.key_sizes.count, 2);
+    assert_eq!(stats.value_sizes.sum, 210);
+    drop(store);
+
+    // reopening counts the sizes in the data files
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().value_sizes, stats.value_sizes);
+    Ok(())
+}
+
+// Should account the live keys and bytes of each key prefix
+#[test]
+fn usage_by_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("tenant1/a".to_owned(), "value".to_owned())?;
+    store.set("tenant1/b".to_owned(), "value".to_owned())?;
+    store.set("tenant1/b".to_owned(), "a longer value".to_owned())?;
+    store.set("tenant2/a".to_owned(), "value".to_owned())?;
+    store.set("tenant2/b".to_owned(), "value".to_owned())?;
+    store.remove("tenant2/b".to_owned())?;
+    store.set("shared".to_owned(), "value".to_owned())?;
+    assert!(store.stats().prefixes.is_empty());
+    drop(store);
+
+    let store = KvStore::builder()
+        .usage_prefix(KeyPrefix::Delimiter('/'))
+        .open(temp_dir.path())?;
+    let prefixes = store.stats().prefixes;
+    assert_eq!(
+        prefixes.keys().collect::<Vec<_>>(),
+        vec!["", "tenant1/", "tenant2/"]
+    );
+    assert_eq!(prefixes["tenant1/"].live_keys, 2);
+    assert_eq!(prefixes["tenant2/"].live_keys, 1);
+    assert_eq!(prefixes[""].live_keys, 1);
+    // the bytes of the current values only
+    assert_eq!(
+        prefixes["tenant1/"].live_bytes,
+        2 * prefixes["tenant2/"].live_bytes + "a longer value".len() as u64 - "value".len() as u64
+    );
+
+    store.remove("tenant2/a".to_owned())?;
+    assert_eq!(store.stats().prefixes.get("tenant2/"), None);
+    store.set("tenant2/c".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        store.stats().prefixes["tenant2/"],
+        PrefixStats {
+            live_keys: 1,
+            live_bytes: prefixes["tenant2/"].live_bytes,
+        }
+    );
+
+    assert_eq!(KeyPrefix::Length(3).of("tenant1/a"), "ten");
+    assert_eq!(KeyPrefix::Length(3).of("ab"), "ab");
+    assert_eq!(KeyPrefix::Delimiter(':').of("user:1:name"), "user:");
+
+    Ok(())
+}
+
+// Should spread data files over several directories and recover from all of them
+#[test]
+fn multiple_data_dirs() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let extra_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .data_dir(extra_dir.path())
+            .placement_policy(PlacementPolicy::RoundRobin)
+            .open(temp_dir.path())
+    };
+    let has_data_files = |dir: &TempDir| {
+        WalkDir::new(dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.file_name().to_string_lossy().starts_with("data_"))
+    };
+
+    let store = open()?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    assert!(has_data_files(&temp_dir));
+    assert!(has_data_files(&extra_dir));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = open()?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("9".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should move keys which are not read any more to the cold tier during compaction
+#[test]
+fn cold_tier() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cold_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .cold_tier(cold_dir.path(), Duration::from_millis(200))
+            .open(temp_dir.path())
+    };
+
+    let store = open()?;
+    for key_id in 0..10 {
+        store.set(format!("cold{}", key_id), "value".to_owned())?;
+    }
+    thread::sleep(Duration::from_millis(300));
+    for iter in 0..10 {
+        for key_id in 0..10 {
+            store.set(format!("hot{}", key_id), format!("{}", iter))?;
+        }
+    }
+
+    let stats = store.stats();
+    let cold_tier = stats.cold_tier.expect("cold tier is configured");
+    assert_eq!(cold_tier.live_keys, 10);
+    assert!(cold_tier.data_files > 0);
+    assert_eq!(stats.hot_tier.live_keys, 10);
+
+    // Open from disk again and check the keys not read since stay cold
+    drop(store);
+    let store = open()?;
+    for iter in 0..10 {
+        for key_id in 0..10 {
+            store.set(format!("hot{}", key_id), format!("{}", iter))?;
+        }
+    }
+    let stats = store.stats();
+    assert_eq!(
+        stats.cold_tier.expect("cold tier is configured").live_keys,
+        10
+    );
+    assert_eq!(stats.hot_tier.live_keys, 10);
+    for key_id in 0..10 {
+        assert_eq!(
+            store.get(format!("cold{}", key_id))?,
+            Some("value".to_owned())
+        );
+        assert_eq!(store.get(format!("hot{}", key_id))?, Some("9".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should scan the key/value pairs matching a glob pattern in key order
+#[test]
+fn scan_match() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for user in ["alice", "bob", "carol"] {
+        store.set(format!("user:{}:profile", user), user.to_owned())?;
+        store.set(format!("user:{}:settings", user), user.to_owned())?;
+    }
+    store.set("users".to_owned(), "all".to_owned())?;
+    store.remove("user:bob:settings".to_owned())?;
+
+    let keys = |pattern: &str| -> Result<Vec<String>> {
+        Ok(store
+            .scan_match(pattern.to_owned())?
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect())
+    };
+    assert_eq!(
+        keys("user:*:profile")?,
+        vec![
+            "user:alice:profile",
+            "user:bob:profile",
+            "user:carol:profile"
+        ]
+    );
+    assert_eq!(
+        keys("user:[a-b]*")?,
+        vec![
+            "user:alice:profile",
+            "user:alice:settings",
+            "user:bob:profile"
+        ]
+    );
+    assert_eq!(keys("user?")?, vec!["users"]);
+    assert_eq!(keys("user:[^a]????:*s")?, vec!["user:carol:settings"]);
+    assert!(keys("nothing*")?.is_empty());
+    assert_eq!(
+        store.scan_match("user:alice:p*".to_owned())?,
+        vec![("user:alice:profile".to_owned(), "alice".to_owned())]
+    );
+
+    Ok(())
+}
+
+// Should stop a cancelled scan or backup without a result
+#[test]
+fn cancelled_scan_and_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let target = DirBackupTarget::new(backup_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+
+    let cancel = CancellationToken::new();
+    assert_eq!(
+        store
+            .scan_match_cancellable("key*".to_owned(), &cancel)?
+            .len(),
+        100
+    );
+    cancel.cancel();
+    assert!(matches!(
+        store.scan_match_cancellable("key*".to_owned(), &cancel),
+        Err(KVStoreError::Cancelled)
+    ));
+    assert!(matches!(
+        store.backup_cancellable(&target, &cancel),
+        Err(KVStoreError::Cancelled)
+    ));
+    assert!(matches!(
+        store.backup_incremental_cancellable(&target, &cancel),
+        Err(KVStoreError::Cancelled)
+    ));
+    assert!(BackupManifest::list(&target)?.is_empty());
+    // writes are not held back after a cancelled backup
+    store.set("key0".to_owned(), "changed".to_owned())?;
+    Ok(())
+}
+
+// Should restore the state of a backup into a new directory
+#[test]
+fn backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let target = DirBackupTarget::new(backup_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id % 100), format!("{}", key_id))?;
+    }
+    store.remove("key0".to_owned())?;
+    let manifest = store.backup(&target)?;
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.set("new".to_owned(), "value".to_owned())?;
+
+    assert_eq!(BackupManifest::list(&target)?, vec![manifest.clone()]);
+    let second = store.backup(&target)?;
+    assert!(second.id > manifest.id);
+
+    let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+    let restored = KvStore::restore(&target, &manifest.id, restore_dir.path())?;
+    assert_eq!(restored.get("key0".to_owned())?, None);
+    assert_eq!(restored.get("key1".to_owned())?, Some("901".to_owned()));
+    assert_eq!(restored.get("key99".to_owned())?, Some("999".to_owned()));
+    assert_eq!(restored.get("new".to_owned())?, None);
+
+    // A directory which already holds data is not overwritten
+    assert!(KvStore::restore(&target, &second.id, restore_dir.path()).is_err());
+    match KvStore::restore(&target, "missing", temp_dir.path()) {
+        Err(KVStoreError::BackupNotFound(id)) => assert_eq!(id, "missing"),
+        _ => panic!("restoring a missing backup should fail"),
+    }
+
+    Ok(())
+}
+
+// an S3-compatible object store keeping one bucket in memory, which lists two keys a page
+// and passes on the authorization header of every request
+fn start_object_store(bucket: &str) -> (String, mpsc::Receiver<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let bucket_path = format!("/{}", bucket);
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut objects: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+        let mut parts: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+        let decode = |text: &str| {
+            let mut decoded = Vec::new();
+            let bytes = text.as_bytes();
+            let mut index = 0;
+            while index < bytes.len() {
+                if bytes[index] == b'%' {
+                    let hex = std::str::from_utf8(&bytes[index + 1..index + 3]).unwrap();
+                    decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                    index += 3;
+                } else {
+                    decoded.push(bytes[index]);
+                    index += 1;
+                }
+            }
+            String::from_utf8(decoded).unwrap()
+        };
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut words = request_line.split_whitespace();
+            let method = words.next().unwrap().to_owned();
+            let target = words.next().unwrap().to_owned();
+            let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+            let query: HashMap<String, String> = query
+                .split('&')
+                .filter(|pair| !pair.is_empty())
+                .map(|pair| {
+                    let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                    (decode(key), decode(value))
+                })
+                .collect();
+            let mut content_length = 0;
+            let mut authorization = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                let (name, value) = line.split_once(':').unwrap();
+                match name.to_lowercase().as_str() {
+                    "content-length" => content_length = value.trim().parse().unwrap(),
+                    "authorization" => authorization = value.trim().to_owned(),
+                    _ => {}
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            sender.send(authorization).unwrap();
+
+            let name = path
+                .strip_prefix(&bucket_path)
+                .and_then(|rest| rest.strip_prefix('/'))
+                .map(decode);
+            let (status, answer) = match (method.as_str(), name) {
+                ("GET", None) => {
+                    let prefix = query.get("prefix").cloned().unwrap_or_default();
+                    let after = query.get("continuation-token").cloned().unwrap_or_default();
+                    let keys: Vec<&String> = objects
+                        .keys()
+                        .filter(|key| key.starts_with(&prefix) && **key > after)
+                        .collect();
+                    let mut answer = String::from("<ListBucketResult>");
+                    for key in keys.iter().take(2) {
+                        answer.push_str(&format!("<Key>{}</Key>", key));
+                    }
+                    if keys.len() > 2 {
+                        answer.push_str(&format!(
+                            "<IsTruncated>true</IsTruncated><NextContinuationToken>{}</NextContinuationToken>",
+                            keys[1]
+                        ));
+                    }
+                    answer.push_str("</ListBucketResult>");
+                    ("200 OK", answer.into_bytes())
+                }
+                ("GET", Some(name)) => match objects.get(&name) {
+                    Some(data) => ("200 OK", data.clone()),
+                    None => (
+                        "404 Not Found",
+                        b"<Error><Message>no such key</Message></Error>".to_vec(),
+                    ),
+                },
+                ("PUT", Some(name)) => match query.get("partNumber") {
+                    Some(number) => {
+                        parts.insert(number.parse().unwrap(), body);
+                        ("200 OK", Vec::new())
+                    }
+                    None => {
+                        objects.insert(name, body);
+                        ("200 OK", Vec::new())
+                    }
+                },
+                ("POST", Some(_)) if query.contains_key("uploads") => {
+                    parts.clear();
+                    ("200 OK", b"<InitiateMultipartUploadResult><UploadId>upload1</UploadId></InitiateMultipartUploadResult>".to_vec())
+                }
+                ("POST", Some(name)) => {
+                    objects.insert(name, parts.values().flatten().copied().collect());
+                    ("200 OK", b"<CompleteMultipartUploadResult/>".to_vec())
+                }
+                _ => ("400 Bad Request", Vec::new()),
+            };
+            // list answers are chunked like those of S3
+            if answer.starts_with(b"<ListBucketResult>") {
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n",
+                    status,
+                    answer.len()
+                )
+                .unwrap();
+                stream.write_all(&answer).unwrap();
+                stream.write_all(b"\r\n0\r\n\r\n").unwrap();
+            } else {
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n",
+                    status,
+                    answer.len()
+                )
+                .unwrap();
+                stream.write_all(&answer).unwrap();
+            }
+        }
+    });
+    (endpoint, receiver)
+}
+
+// Should back up into and restore from an S3-compatible object store
+#[test]
+fn backup_to_object_store() -> Result<()> {
+    let (endpoint, authorizations) = start_object_store("backups");
+    assert!(S3BackupTarget::new("https://s3.amazonaws.com", "backups").is_err());
+    let target = S3BackupTarget::new(&endpoint, "backups")?
+        .credentials("AKIDEXAMPLE", "secret")
+        .region("eu-west-1");
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first = store.backup(&target)?;
+    // a value larger than a part is uploaded in parts
+    let large = "x".repeat(9 * 1024 * 1024);
+    store.set("large".to_owned(), large.clone())?;
+    let second = store.backup_incremental(&target)?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let third = store.backup_incremental(&target)?;
+    assert_eq!(
+        BackupManifest::list(&target)?,
+        vec![first.clone(), second, third.clone()]
+    );
+    for authorization in authorizations.try_iter() {
+        assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"));
+        assert!(authorization.contains("/eu-west-1/s3/aws4_request"));
+    }
+
+    let restore = |id: &str| -> Result<(TempDir, KvStore)> {
+        let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+        let store = KvStore::restore(&target, id, restore_dir.path())?;
+        Ok((restore_dir, store))
+    };
+    let (_restore_dir, restored) = restore(&first.id)?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(restored.get("large".to_owned())?, None);
+    let (_restore_dir, restored) = restore(&third.id)?;
+    assert_eq!(restored.get("large".to_owned())?, Some(large));
+    assert_eq!(restored.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(matches!(
+        KvStore::restore(&target, "missing", TempDir::new().unwrap().path()),
+        Err(KVStoreError::BackupNotFound(_))
+    ));
+
+    Ok(())
+}
+
+// a target whose uploads wait until they are let through
+struct GatedTarget {
+    inner: DirBackupTarget,
+    started: Mutex<mpsc::Sender<()>>,
+    gate: Mutex<mpsc::Receiver<()>>,
+}
+
+impl BackupTarget for GatedTarget {
+    fn put(&self, name: &str, data: &mut dyn Read) -> Result<()> {
+        if !name.ends_with("MANIFEST") {
+            let _ = self.started.lock().unwrap().send(());
+            let _ = self.gate.lock().unwrap().recv();
+        }
+        self.inner.put(name, data)
+    }
+
+    fn get(&self, name: &str) -> Result<Box<dyn Read>> {
+        self.inner.get(name)
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        self.inner.list(prefix)
+    }
+}
+
+// Should take writes while the files of a backup are uploaded
+#[test]
+fn write_during_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let (started_sender, started) = mpsc::channel();
+    let (gate, gate_receiver) = mpsc::channel();
+    let target = GatedTarget {
+        inner: DirBackupTarget::new(backup_dir.path())?,
+        started: Mutex::new(started_sender),
+        gate: Mutex::new(gate_receiver),
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+
+    let manifest = thread::scope(|scope| -> Result<BackupManifest> {
+        let backing_up = store.clone();
+        let target = &target;
+        let backup = scope.spawn(move || backing_up.backup(target));
+        started.recv().unwrap();
+        let (written_sender, written) = mpsc::channel();
+        let writer = store.clone();
+        scope.spawn(move || {
+            writer.set("key2".to_owned(), "value2".to_owned()).unwrap();
+            written_sender.send(()).unwrap();
+        });
+        let wrote = written.recv_timeout(Duration::from_secs(5));
+        drop(gate);
+        assert!(wrote.is_ok(), "the write waited for the upload");
+        backup.join().unwrap()
+    })?;
+
+    let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+    let restored = KvStore::restore(&target, &manifest.id, restore_dir.path())?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(restored.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// Should copy only new data in incremental backups and restore any backup of the chain
+#[test]
+fn incremental_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let target = DirBackupTarget::new(backup_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let full = store.backup_incremental(&target)?;
+    assert_eq!(full.parent, None);
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let first = store.backup_incremental(&target)?;
+    assert_eq!(first.parent, Some(full.id.clone()));
+    assert_eq!(first.files.len(), 1);
+    assert_eq!(first.files[0].offset, full.files[0].length);
+
+    // Compaction replaces the data files backed up so far
+    for key_id in 0..1000 {
+        store.set("key3".to_owned(), format!("{}", key_id))?;
+    }
+    store.remove("key1".to_owned())?;
+    let second = store.backup_incremental(&target)?;
+    let unchanged = store.backup_incremental(&target)?;
+    assert!(unchanged.files.is_empty());
+    assert_eq!(second.chain(&target)?.len(), 3);
+
+    let restore = |id: &str| -> Result<(TempDir, KvStore)> {
+        let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+        let store = KvStore::restore(&target, id, restore_dir.path())?;
+        Ok((restore_dir, store))
+    };
+    let (_restore_dir, restored) = restore(&first.id)?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(restored.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(restored.get("key3".to_owned())?, None);
+    for id in [&second.id, &unchanged.id] {
+        let (_restore_dir, restored) = restore(id)?;
+        assert_eq!(restored.get("key1".to_owned())?, None);
+        assert_eq!(restored.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(restored.get("key3".to_owned())?, Some("999".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should restore the exact state as of a write from the retained backups
+#[test]
+fn restore_to_sequence() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary backup directory");
+    let target = DirBackupTarget::new(backup_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..10 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    let before_delete = store.stats().sequence;
+    assert_eq!(before_delete, 10);
+    for key_id in 0..10 {
+        store.remove(format!("key{}", key_id))?;
+    }
+    store.set("key0".to_owned(), "again".to_owned())?;
+    store.backup(&target)?;
+
+    let restore_to = |sequence: u64| -> Result<(TempDir, KvStore)> {
+        let restore_dir = TempDir::new().expect("unable to create temporary restore directory");
+        let store = KvStore::restore_to(&target, sequence, restore_dir.path())?;
+        Ok((restore_dir, store))
+    };
+    let (_restore_dir, restored) = restore_to(before_delete)?;
+    assert_eq!(restored.stats().sequence, before_delete);
+    assert_eq!(restored.stats().live_keys, 10);
+    assert_eq!(restored.get("key0".to_owned())?, Some("value".to_owned()));
+    let (_restore_dir, restored) = restore_to(before_delete + 3)?;
+    assert_eq!(restored.stats().live_keys, 7);
+    assert_eq!(restored.get("key3".to_owned())?, Some("value".to_owned()));
+    assert_eq!(restored.get("key2".to_owned())?, None);
+    // The restored store continues with the following sequence numbers
+    restored.set("key2".to_owned(), "new".to_owned())?;
+    assert_eq!(restored.stats().sequence, before_delete + 4);
+
+    // Compaction drops the single writes before it, and backups can not go beyond their last write
+    for key_id in 0..1000 {
+        store.set("key1".to_owned(), format!("{}", key_id))?;
+    }
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats().sequence, 1021);
+    store.backup_incremental(&target)?;
+    assert!(restore_to(before_delete).is_ok());
+    let (_restore_dir, restored) = restore_to(1020)?;
+    assert_eq!(restored.get("key1".to_owned())?, Some("998".to_owned()));
+    match restore_to(1022) {
+        Err(KVStoreError::SequenceNotRetained(1022)) => {}
+        _ => panic!("restoring beyond the last backup should fail"),
+    }
+
+    Ok(())
+}
+
+// Should keep removed keys in the trash until their retention expires
+#[test]
+fn trash_bin() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .trash_retention(Duration::from_millis(500))
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key1".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.stats().trashed_keys, 2);
+
+    store.restore_key("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert!(matches!(
+        store.restore_key("key1".to_owned()),
+        Err(KVStoreError::KeyNotFound)
+    ));
+    assert!(matches!(
+        store.restore_key("key3".to_owned()),
+        Err(KVStoreError::KeyNotFound)
+    ));
+
+    // The trash survives compaction and reopening
+    for iter in 0..100 {
+        store.set("key3".to_owned(), format!("{}", iter))?;
+    }
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.stats().trashed_keys, 1);
+    assert_eq!(store.get("key2".to_owned())?, None);
+    store.restore_key("key2".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Expired keys can not be restored and are purged by compaction
+    store.remove("key2".to_owned())?;
+    thread::sleep(Duration::from_millis(600));
+    assert!(store.restore_key("key2".to_owned()).is_err());
+    for iter in 0..100 {
+        store.set("key3".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(store.stats().trashed_keys, 0);
+
+    // Without a retention removed keys are gone at once
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    store.remove("key1".to_owned())?;
+    assert!(store.restore_key("key1".to_owned()).is_err());
+    assert_eq!(store.stats().trashed_keys, 0);
+
+    Ok(())
+}
+
+// Should compact when the configured compaction policy triggers
+#[test]
+fn compaction_policies() -> Result<()> {
+    let overwrite = |store: &KvStore, times: u32| -> Result<()> {
+        for iter in 0..times {
+            store.set("key1".to_owned(), format!("{}", iter))?;
+        }
+        Ok(())
+    };
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::UselessSize(10_000))
+        .open(temp_dir.path())?;
+    overwrite(&store, 100)?;
+    assert!(store.stats().useless_size > 1024);
+    overwrite(&store, 500)?;
+    assert!(store.stats().useless_size <= 10_000);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::GarbageRatio(200))
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    overwrite(&store, 100)?;
+    let stats = store.stats();
+    assert!(stats.useless_size * 100 <= (stats.disk_usage - stats.useless_size) * 200);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::Idle(Duration::from_millis(300)))
+        .open(temp_dir.path())?;
+    overwrite(&store, 500)?;
+    assert!(store.stats().useless_size > 1024);
+    thread::sleep(Duration::from_millis(800));
+    assert_eq!(store.stats().useless_size, 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("499".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::Interval(Duration::from_millis(200)))
+        .open(temp_dir.path())?;
+    overwrite(&store, 500)?;
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(store.stats().useless_size, 0);
+
+    for policy in [
+        CompactionPolicy::Interval(Duration::ZERO),
+        CompactionPolicy::Idle(Duration::ZERO),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let opened = KvStore::builder()
+            .compaction_policy(policy)
+            .open(temp_dir.path());
+        assert!(matches!(opened, Err(KVStoreError::InvalidConfig(_))));
+    }
+
+    Ok(())
+}
+
+// Should keep all data when compaction copies data files on several threads
+#[test]
+fn multi_threaded_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .compaction_threads(4)
+            .compaction_policy(CompactionPolicy::UselessSize(4 * 1024))
+            .trash_retention(Duration::from_secs(60))
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+
+    for iter in 0..20 {
+        for key_id in 0..200 {
+            store.set(format!("key{}", key_id), format!("{}-{}", key_id, iter))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), format!("{}-last", key_id))?;
+    }
+    store.remove("key1".to_owned())?;
+    assert!(store.stats().hot_tier.data_files > 2);
+
+    drop(store);
+    let store = open()?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    for key_id in 2..200 {
+        assert_eq!(
+            store.get(format!("key{}", key_id))?,
+            Some(format!("{}-last", key_id))
+        );
+    }
+    store.restore_key("key1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("1-last".to_owned()));
+
+    Ok(())
+}
+
+// Should delay writes while compaction is behind
+#[test]
+fn write_stall() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::Idle(Duration::from_millis(300)))
+        .write_stall(1024, Duration::from_millis(50))
+        .open(temp_dir.path())?;
+
+    for iter in 0..50 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    assert!(!store.stats().write_stalled);
+    for iter in 0..200 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+        if store.stats().useless_size > 2048 {
+            break;
+        }
+    }
+    let stats = store.stats();
+    assert!(stats.write_stalled);
+    assert!(stats.stalled_writes > 0);
+    let now = Instant::now();
+    store.set("key1".to_owned(), "value".to_owned())?;
+    assert!(now.elapsed() >= Duration::from_millis(50));
+
+    // Writes run at full speed again once compaction caught up in an idle moment
+    thread::sleep(Duration::from_millis(600));
+    let stats = store.stats();
+    assert_eq!(stats.useless_size, 0);
+    assert!(!stats.write_stalled);
+
+    Ok(())
+}
+
+// Should ignore the data files of an interrupted compaction
+#[test]
+fn interrupted_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let manifests = |dir: &TempDir| {
+        WalkDir::new(dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with("MANIFEST-"))
+            .count()
+    };
+    assert_eq!(manifests(&temp_dir), 1);
+
+    // A compaction which crashed before writing its manifest leaves a data file behind
+    let leftover = temp_dir.path().join("data_1000.txt");
+    std::fs::write(&leftover, r#"{"SET":["key2","stale"]}"#)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!leftover.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("99".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(manifests(&temp_dir), 1);
+
+    Ok(())
+}
+
+// Should refuse to open a store written by a newer format or with unsupported options
+#[test]
+fn manifest_format_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let manifest_path = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.to_string_lossy().contains("MANIFEST-"))
+        .expect("no manifest");
+    let manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(&manifest_path)?)?;
+    assert_eq!(manifest["format_version"], 3);
+    assert_eq!(manifest["options"]["codec"], "json");
+
+    let mut future = manifest.clone();
+    future["format_version"] = 99.into();
+    future["segments"] = serde_json::json!([{ "number": 0 }]);
+    std::fs::write(&manifest_path, serde_json::to_vec(&future)?)?;
+    match KvStore::open(temp_dir.path()) {
+        Err(KVStoreError::IncompatibleFormat(99, 3)) => {}
+        other => panic!("unexpected result {:?}", other.map(|_| ())),
+    }
+
+    let mut compressed = manifest.clone();
+    compressed["options"]["compression"] = "lz4".into();
+    std::fs::write(&manifest_path, serde_json::to_vec(&compressed)?)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KVStoreError::UnsupportedStoreOption(..))
+    ));
+
+    std::fs::write(&manifest_path, serde_json::to_vec(&manifest)?)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Should give each store a UUID which stays with its data files
+#[test]
+fn instance_id() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::UselessSize(1024))
+        .open(temp_dir.path())?;
+    let instance_id = store.instance_id();
+    assert_eq!(instance_id.len(), 36);
+    assert_eq!(&instance_id[14..15], "4");
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    assert!(store.stats().compaction_bytes_copied > 0);
+    assert_eq!(store.instance_id(), instance_id);
+    drop(store);
+
+    assert_eq!(KvStore::open(temp_dir.path())?.instance_id(), instance_id);
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert_ne!(KvStore::open(other_dir.path())?.instance_id(), instance_id);
+    Ok(())
+}
+
+// Should keep accepting only the replication source first accepted, also after a restart
+#[test]
+fn replication_source() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::UselessSize(1024))
+        .open(temp_dir.path())?;
+    assert_eq!(store.replication_source(), None);
+    store.accept_replication_source("primary")?;
+    store.accept_replication_source("primary")?;
+    let unrelated = |store: &KvStore| match store.accept_replication_source("other") {
+        Err(KVStoreError::UnrelatedStore(expected, found)) => {
+            assert_eq!((expected.as_str(), found.as_str()), ("primary", "other"))
+        }
+        result => panic!("another source was accepted: {:?}", result),
+    };
+    unrelated(&store);
+    for i in 0..100 {
+        store.set("key".to_owned(), format!("value{}", i))?;
+    }
+    assert!(store.stats().compaction_bytes_copied > 0);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.replication_source(), Some("primary".to_owned()));
+    unrelated(&store);
+    Ok(())
+}
+
+// Should serve a frozen store read-only, leaving its directory untouched
+#[test]
+fn snapshot_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let missing = temp_dir.path().join("missing");
+    assert!(SnapshotEngine::open(&missing).is_err());
+    assert!(!missing.exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{:03}", i), format!("value{}", i))?;
+    }
+    store.remove("key050".to_owned())?;
+    let instance_id = store.instance_id();
+    store.close()?;
+    let files = || -> Result<Vec<(String, u64)>> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(temp_dir.path())? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            files.push((name, entry.metadata()?.len()));
+        }
+        files.sort();
+        Ok(files)
+    };
+    let before = files()?;
+
+    let snapshot = SnapshotEngine::open(temp_dir.path())?;
+    assert_eq!(
+        snapshot.get("key007".to_owned())?,
+        Some("value7".to_owned())
+    );
+    assert_eq!(snapshot.get("key050".to_owned())?, None);
+    let pairs = snapshot.scan_match("key09*".to_owned())?;
+    let keys: Vec<&str> = pairs.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys.len(), 10);
+    assert_eq!(keys[0], "key090");
+    assert_eq!(pairs[9].1, "value99");
+    assert_eq!(snapshot.instance_id(), Some(instance_id));
+    drop(snapshot);
+    assert_eq!(files()?, before);
+
+    // the store opens as it was closed
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key007".to_owned())?, Some("value7".to_owned()));
+    Ok(())
+}
+
+// Should record a clean close and cut records torn by a crash off the log when it is missing
+#[test]
+fn clean_close() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let marker = temp_dir.path().join("clean_shutdown.json");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.close()?;
+    assert!(marker.exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!marker.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+
+    // a crash tore the last record, or left zeros where it should have been
+    let log_file = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.to_string_lossy().contains("data_"))
+        .max()
+        .expect("no data file");
+    for torn in [&br#"{"SET":["key2","val"#[..], &[0; 64][..]] {
+        let mut file = std::fs::OpenOptions::new().append(true).open(&log_file)?;
+        std::io::Write::write_all(&mut file, torn)?;
+        drop(file);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        store.close()?;
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should open stores of the first format and rewrite them in the newest one on upgrade
+#[test]
+fn upgrade_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_file = |number: u64| temp_dir.path().join(format!("data_{}.txt", number));
+    let format_version = || -> Result<u64> {
+        let manifest_path = std::fs::read_dir(temp_dir.path())?
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.to_string_lossy().contains("MANIFEST-"))
+            .expect("no manifest");
+        let manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(manifest_path)?)?;
+        Ok(manifest["format_version"].as_u64().unwrap())
+    };
+
+    // a store of the first format has no manifest and no data file headers
+    std::fs::write(
+        data_file(0),
+        r#"{"SET":["key1","value1"]}{"SET":["key2","value2"]}{"RM":"key1"}"#,
+    )?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    assert_eq!(format_version()?, 1);
+
+    KvStore::upgrade(temp_dir.path())?;
+    assert_eq!(format_version()?, 3);
+    assert!(!data_file(0).exists());
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with(".txt") {
+            assert!(std::fs::read_to_string(&path)?.starts_with(r#"{"format_version":3}"#));
+        }
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.stats().sequence, 4);
+
+    Ok(())
+}
+
+// Should detect records which do not match their index entries in paranoid mode
+#[test]
+fn paranoid_checks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .paranoid_checks(true)
+        .open(temp_dir.path())?;
+    for iter in 0..200 {
+        store.set(format!("key{}", iter % 10), format!("value{}", iter))?;
+    }
+    for iter in 190..200 {
+        assert_eq!(
+            store.get(format!("key{}", iter % 10))?,
+            Some(format!("value{}", iter))
+        );
+    }
+
+    // overwrite the key of the last record with another key of the same length
+    let data_file = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .filter(|path| path.to_string_lossy().ends_with(".txt"))
+        .max()
+        .expect("no data file");
+    let data = std::fs::read_to_string(&data_file)?;
+    let corrupted = data.replacen(r#"["key9","value199"]"#, r#"["kez9","value199"]"#, 1);
+    assert_ne!(data, corrupted);
+    std::fs::write(&data_file, corrupted)?;
+
+    assert!(matches!(
+        store.get("key9".to_owned()),
+        Err(KVStoreError::CorruptedRecord(..))
+    ));
+    assert_eq!(store.get("key8".to_owned())?, Some("value198".to_owned()));
+
+    Ok(())
+}
+
+// Should report where a data file is corrupted, with a stable code
+#[test]
+fn errors_with_context() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..10 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+    drop(store);
+
+    let data_file = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .filter(|path| path.to_string_lossy().ends_with(".txt"))
+        .max()
+        .expect("no data file");
+    let length = std::fs::metadata(&data_file)?.len();
+    let mut file = std::fs::OpenOptions::new().append(true).open(&data_file)?;
+    std::io::Write::write_all(&mut file, b"{\"SET\":garbage")?;
+    drop(file);
+
+    let err = match KvStore::open(temp_dir.path()) {
+        Err(err) => err,
+        Ok(_) => panic!("opening a corrupted store should fail"),
+    };
+    assert_eq!(err.code(), 1002);
+    assert!(matches!(err.root(), KVStoreError::Serde(_)));
+    let context = err.contexts().next().expect("no context");
+    assert_eq!(context.operation, "recover");
+    assert_eq!(context.file.as_deref(), Some(data_file.as_path()));
+    assert_eq!(context.offset, Some(length));
+    assert!(std::error::Error::source(&err).is_some());
+    assert!(err.to_string().starts_with("recover in "), "{}", err);
+    Ok(())
+}
+
+// Should write data files with direct I/O which read back like buffered ones
+#[test]
+fn direct_io() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .direct_io(true)
+        .compaction_policy(CompactionPolicy::UselessSize(64 * 1024))
+        .open(temp_dir.path())?;
+    let value = "x".repeat(3000);
+    for iter in 0..100 {
+        store.set(format!("key{}", iter % 20), format!("{}{}", value, iter))?;
+    }
+    for iter in 80..100 {
+        assert_eq!(
+            store.get(format!("key{}", iter % 20))?,
+            Some(format!("{}{}", value, iter))
+        );
+    }
+    drop(store);
+
+    // the padding of partial blocks is cut off again
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.to_string_lossy().ends_with(".txt") {
+            assert!(!std::fs::read(&path)?.contains(&0));
+        }
+    }
+
+    let store = KvStore::builder().direct_io(true).open(temp_dir.path())?;
+    store.set("key0".to_owned(), "value0".to_owned())?;
+    for iter in 81..100 {
+        assert_eq!(
+            store.get(format!("key{}", iter % 20))?,
+            Some(format!("{}{}", value, iter))
+        );
+    }
+    assert_eq!(store.get("key0".to_owned())?, Some("value0".to_owned()));
+
+    Ok(())
+}
+
+// Should get many keys at once in the order they were asked for
+#[test]
+fn get_many() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for iter in 0..300 {
+        store.set(format!("key{}", iter % 100), format!("value{}", iter))?;
+    }
+    store.remove("key7".to_owned())?;
+
+    let keys: Vec<String> = (0..110).rev().map(|iter| format!("key{}", iter)).collect();
+    let values = store.get_many(keys)?;
+    for (iter, value) in (0..110).rev().zip(values) {
+        let expected = match iter {
+            7 | 100.. => None,
+            _ => Some(format!("value{}", iter + 200)),
+        };
+        assert_eq!(value, expected);
+    }
+    assert_eq!(store.scan_match("key9?".to_owned())?.len(), 10);
+
+    Ok(())
+}
+
+// Should return the values of batched reads across data files in the order asked for, whether
+// the batch reads most of a file and reads it ahead or only a few records of it
+#[test]
+fn batched_reads_across_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .data_dir(other_dir.path())
+        .preallocate(4096)
+        .open(temp_dir.path())?;
+    let value = |iter: usize| format!("{}{}", iter, "v".repeat(iter % 7 * 10));
+    for iter in 0..400 {
+        store.set(format!("key{:03}", iter), value(iter))?;
+    }
+    assert!(store.stats().hot_tier.data_files > 4);
+
+    // every key, interleaving the files: the spans are dense enough to read ahead
+    let dense: Vec<usize> = (0..400).map(|iter| iter * 7 % 400).rev().collect();
+    // two keys far apart in the first file: the records are read without read ahead
+    let sparse: Vec<usize> = vec![60, 399, 0];
+    for order in [dense, sparse] {
+        let keys = order.iter().map(|iter| format!("key{:03}", iter)).collect();
+        let values = store.get_many(keys)?;
+        let expected: Vec<Option<String>> = order.iter().map(|&iter| Some(value(iter))).collect();
+        assert_eq!(values, expected);
+    }
+
+    let pairs = store.scan_match("key1*".to_owned())?;
+    assert_eq!(pairs.len(), 100);
+    for (iter, (key, found)) in (100..200).zip(pairs) {
+        assert_eq!(key, format!("key{:03}", iter));
+        assert_eq!(found, value(iter));
+    }
+
+    Ok(())
+}
+
+// Should continue the log in a new preallocated file once the current one is full
+#[test]
+fn preallocate() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_files = || -> Vec<std::fs::Metadata> {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".txt"))
+            .map(|entry| entry.metadata().unwrap())
+            .collect()
+    };
+    let value = "x".repeat(500);
+
+    let store = KvStore::builder()
+        .preallocate(64 * 1024)
+        .open(temp_dir.path())?;
+    for iter in 0..200 {
+        store.set(format!("key{}", iter), format!("{}{}", value, iter))?;
+    }
+    let files = data_files();
+    assert_eq!(files.len(), 2);
+    // the files only grow as far as they are written, the active one has its space reserved
+    let active = files.iter().min_by_key(|metadata| metadata.len()).unwrap();
+    assert!(active.len() < 64 * 1024);
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::fs::MetadataExt;
+        assert!(active.blocks() * 512 >= 64 * 1024);
+    }
+    drop(store);
+
+    let store = KvStore::builder()
+        .preallocate(64 * 1024)
+        .open(temp_dir.path())?;
+    assert_eq!(store.stats().sequence, 200);
+    for iter in 0..200 {
+        assert_eq!(
+            store.get(format!("key{}", iter))?,
+            Some(format!("{}{}", value, iter))
+        );
+    }
+
+    Ok(())
+}
+
+// Should report the progress of recovery while opening
+#[test]
+fn recovery_progress() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder().preallocate(1024).open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set(format!("key{}", iter), "value".to_owned())?;
+    }
+    drop(store);
+
+    let progress = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let reported = Arc::clone(&progress);
+    KvStore::builder()
+        .on_progress(move |done, total, keys| reported.lock().unwrap().push((done, total, keys)))
+        .open(temp_dir.path())?;
+
+    let progress = progress.lock().unwrap();
+    let total = progress[0].1;
+    assert!(total > 1);
+    assert_eq!(progress.len(), total);
+    for (iter, (done, files, _)) in progress.iter().enumerate() {
+        assert_eq!((*done, *files), (iter + 1, total));
+    }
+    assert!(progress.windows(2).all(|pair| pair[0].2 <= pair[1].2));
+    assert_eq!(progress.last().unwrap().2, 100);
+
+    Ok(())
+}
+
+/// count the keys matching a pattern with nothing but the reads of an engine
+fn count_matches<E: KvsReader>(engine: &E, pattern: &str) -> Result<usize> {
+    Ok(engine.scan_match(pattern.to_owned())?.len())
+}
+
+// Should serve the reads of a store through the read-only wrapper
+#[test]
+fn read_only_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("user:2".to_owned(), "bob".to_owned())?;
+    store.set("order:1".to_owned(), "book".to_owned())?;
+
+    let reader = ReadOnlyEngine::new(store.clone());
+    assert_eq!(reader.get("user:1".to_owned())?, Some("alice".to_owned()));
+    assert_eq!(reader.get("user:3".to_owned())?, None);
+    assert_eq!(count_matches(&reader, "user:*")?, 2);
+
+    // writes through the store are visible to the reader
+    store.remove("user:2".to_owned())?;
+    assert_eq!(count_matches(&reader, "user:*")?, 1);
+    assert_eq!(count_matches(&store, "*")?, 2);
+
+    Ok(())
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, PartialEq)]
+struct User {
+    name: String,
+    age: u32,
+}
+
+/// store users by ID in an engine of strings, with a plain string key beside them
+fn store_users<E: KvsEngine>(engine: E) -> Result<()> {
+    let users: TypedEngine<E, u64, User> = TypedEngine::new(engine.clone());
+    let alice = User {
+        name: "alice".to_owned(),
+        age: 30,
+    };
+    users.set(1, alice)?;
+    users.set(
+        2,
+        User {
+            name: "bob".to_owned(),
+            age: 25,
+        },
+    )?;
+    assert_eq!(
+        users.get(1)?.map(|user| user.name),
+        Some("alice".to_owned())
+    );
+    assert_eq!(users.get(3)?, None);
+    users.remove(2)?;
+    assert!(matches!(users.remove(2), Err(KVStoreError::KeyNotFound)));
+
+    // strings are stored as they are, readable through the engine of strings
+    engine.set("motd".to_owned(), "hello".to_owned())?;
+    let strings: TypedEngine<E, String, String> = TypedEngine::new(engine.clone());
+    assert_eq!(strings.get("motd".to_owned())?, Some("hello".to_owned()));
+    assert_eq!(
+        engine.get("1".to_owned())?,
+        Some(r#"{"name":"alice","age":30}"#.to_owned())
+    );
+    assert_eq!(
+        users.scan_match("[0-9]".to_owned())?,
+        vec![(
+            1,
+            User {
+                name: "alice".to_owned(),
+                age: 30
+            }
+        )]
+    );
+
+    Ok(())
+}
+
+// Should store typed keys and values in both engines
+#[test]
+fn typed_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    store_users(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    store_users(SledKvsEngine::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+// Should iterate a snapshot of the store while writes and compactions go on
+#[test]
+fn snapshot_iter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let data_file = |number: u64| temp_dir.path().join(format!("data_{}.txt", number));
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::UselessSize(4 * 1024))
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{:03}", key_id), "old".to_owned())?;
+    }
+
+    let mut snapshot = store.iter()?;
+    assert_eq!(snapshot.size_hint(), (100, Some(100)));
+    assert_eq!(
+        snapshot.next().transpose()?,
+        Some(("key000".to_owned(), "old".to_owned()))
+    );
+    // enough overwrites to compact away the data file the snapshot reads
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{:03}", key_id), format!("new{}", iter))?;
+        }
+    }
+    store.remove("key050".to_owned())?;
+    store.set("key100".to_owned(), "new".to_owned())?;
+    assert!(data_file(0).exists());
+
+    let pairs = snapshot.collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 99);
+    assert!(pairs.iter().all(|(_, value)| value == "old"));
+    assert_eq!(pairs[49].0, "key050");
+    assert!(!data_file(0).exists());
+
+    let pairs = store.iter()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 100);
+    assert_eq!(pairs[0], ("key000".to_owned(), "new9".to_owned()));
+    assert_eq!(pairs[99], ("key100".to_owned(), "new".to_owned()));
+
+    Ok(())
+}
+
+// Should scan ranges of keys in both directions and find the first and last key
+#[test]
+fn reverse_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.first_key_value()?, None);
+    assert_eq!(store.last_key_value()?, None);
+    assert_eq!(store.iter()?.next_back().transpose()?, None);
+
+    for minute in [5, 1, 3, 4, 2] {
+        store.set(format!("log:{:02}", minute), format!("entry{}", minute))?;
+    }
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+
+    let keys = |iter: &mut dyn Iterator<Item = Result<(String, String)>>| -> Result<Vec<String>> {
+        iter.map(|pair| pair.map(|(key, _)| key)).collect()
+    };
+    assert_eq!(
+        keys(&mut store.scan("log:02".."log:05")?)?,
+        vec!["log:02", "log:03", "log:04"]
+    );
+    assert_eq!(
+        keys(&mut store.scan("log:".."log;")?.rev())?,
+        vec!["log:05", "log:04", "log:03", "log:02", "log:01"]
+    );
+    assert_eq!(
+        store.scan("log:".."log;")?.next_back().transpose()?,
+        Some(("log:05".to_owned(), "entry5".to_owned()))
+    );
+    let start = "log:04".to_owned();
+    assert_eq!(store.scan(start.as_str()..)?.count(), 3);
+
+    assert_eq!(
+        store.first_key_value()?,
+        Some(("log:01".to_owned(), "entry1".to_owned()))
+    );
+    assert_eq!(
+        store.last_key_value()?,
+        Some(("user:1".to_owned(), "alice".to_owned()))
+    );
+    store.remove("user:1".to_owned())?;
+    assert_eq!(
+        store.last_key_value()?,
+        Some(("log:05".to_owned(), "entry5".to_owned()))
+    );
+
+    Ok(())
+}
+
+// Should estimate the size of key ranges without reading values
+#[test]
+fn approximate_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.approximate_size(..), RangeSize::default());
+    for key_id in 0..10 {
+        store.set(format!("a{}", key_id), "x".repeat(100))?;
+        store.set(format!("b{}", key_id), "x".repeat(10))?;
+    }
+
+    let a = store.approximate_size("a".."b");
+    let b = store.approximate_size("b"..);
+    assert_eq!((a.live_keys, b.live_keys), (10, 10));
+    assert!(a.live_bytes >= 1000 && b.live_bytes >= 100);
+    assert!(a.live_bytes > b.live_bytes);
+    let all = store.approximate_size(..);
+    assert_eq!(all.live_keys, 20);
+    assert_eq!(all.live_bytes, a.live_bytes + b.live_bytes);
+
+    // overwritten values only count once
+    store.set("a0".to_owned(), "x".to_owned())?;
+    store.remove("a1".to_owned())?;
+    let shrunk = store.approximate_size("a".."b");
+    assert_eq!(shrunk.live_keys, 9);
+    assert!(shrunk.live_bytes < a.live_bytes - 190);
+
+    Ok(())
+}
+
+// Should keep, drop or rewrite the records compaction copies as the filter decides
+#[test]
+fn compaction_filter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .compaction_policy(CompactionPolicy::UselessSize(4 * 1024))
+            .compaction_filter(|key: &str, value: &str| {
+                if key.starts_with("tmp:") {
+                    CompactionDecision::Remove
+                } else if key.starts_with("upper:") {
+                    CompactionDecision::ChangeValue(value.to_uppercase())
+                } else {
+                    CompactionDecision::Keep
+                }
+            })
+            .paranoid_checks(true)
+            .open(temp_dir.path())
+    };
+    let store = open()?;
+    for key_id in 0..10 {
+        store.set(format!("tmp:{}", key_id), "value".to_owned())?;
+        store.set(format!("upper:{}", key_id), "value".to_owned())?;
+        store.set(format!("keep:{}", key_id), "value".to_owned())?;
+    }
+    assert_eq!(store.get("tmp:1".to_owned())?, Some("value".to_owned()));
+
+    // overwrite a key until a compaction runs
+    for iter in 0..200 {
+        store.set("counter".to_owned(), format!("{}", iter))?;
+    }
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("tmp:1".to_owned())?, None);
+        assert_eq!(store.get("upper:1".to_owned())?, Some("VALUE".to_owned()));
+        assert_eq!(store.get("keep:1".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("counter".to_owned())?, Some("199".to_owned()));
+        assert_eq!(store.scan_match("tmp:*".to_owned())?, vec![]);
+        Ok(())
+    };
+    check(&store)?;
+    assert_eq!(store.stats().live_keys, 21);
+    drop(store);
+
+    check(&open()?)?;
+
+    Ok(())
+}
+
+#[test]
+fn event_streams() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for event_id in 1..=12 {
+        let sequence = store.append_event("orders".to_owned(), format!("order {}", event_id))?;
+        assert_eq!(sequence, event_id);
+    }
+    assert_eq!(
+        store.append_event("payments".to_owned(), "paid".to_owned())?,
+        1
+    );
+    // a key sorting between the events is no event of the stream
+    store.set("orders~".to_owned(), "value".to_owned())?;
+
+    let events = store.read_events("orders", 9, 10)?;
+    assert_eq!(
+        events,
+        (9..=12)
+            .map(|event_id| (event_id, format!("order {}", event_id)))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(store.read_events("orders", 0, 2)?.len(), 2);
+    assert_eq!(
+        store.read_events("payments", 1, 10)?,
+        vec![(1, "paid".to_owned())]
+    );
+    assert!(store.read_events("refunds", 1, 10)?.is_empty());
+    assert!(store
+        .append_event("bad\u{0}".to_owned(), "x".to_owned())
+        .is_err());
+    drop(store);
+
+    // the sequences go on after a restart
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.append_event("orders".to_owned(), "order 13".to_owned())?,
+        13
+    );
+    assert_eq!(
+        store.read_events("orders", 12, 10)?,
+        vec![(12, "order 12".to_owned()), (13, "order 13".to_owned())]
+    );
+    // events written as plain keys, e.g. by replication, move the sequence on as well
+    store.set(format!("orders\u{0}{:020}", 20), "order 20".to_owned())?;
+    assert_eq!(
+        store.append_event("orders".to_owned(), "order 21".to_owned())?,
+        21
+    );
+    assert_eq!(
+        store.read_events("orders", 13, 2)?,
+        vec![(13, "order 13".to_owned()), (20, "order 20".to_owned())]
+    );
+
+    Ok(())
+}
+
+// Should read the live events of a stream from its start once the older ones were removed,
+// also after a restart and once a retention dropped them
+#[test]
+fn read_events_after_removals() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .compaction_policy(CompactionPolicy::UselessSize(4 * 1024))
+            .retention(0, "metrics:", Duration::from_millis(500))
+            .open(temp_dir.path())
+    };
+    let event = |sequence: u64| (sequence, format!("order {}", sequence));
+    let store = open()?;
+    for sequence in 1..=1000 {
+        store.append_event("orders".to_owned(), event(sequence).1)?;
+    }
+    // newest first, so the first live event only moves on with the last removal
+    for sequence in (1..=990).rev() {
+        store.remove(format!("orders\u{0}{:020}", sequence))?;
+    }
+    assert_eq!(
+        store.read_events("orders", 1, 10)?,
+        (991..=1000).map(event).collect::<Vec<_>>()
+    );
+    drop(store);
+
+    let store = open()?;
+    assert_eq!(
+        store.read_events("orders", 1, 3)?,
+        (991..=993).map(event).collect::<Vec<_>>()
+    );
+
+    for _ in 0..5 {
+        store.append_event("metrics:cpu".to_owned(), "sample".to_owned())?;
+    }
+    thread::sleep(Duration::from_millis(600));
+    store.append_event("metrics:cpu".to_owned(), "recent".to_owned())?;
+    // overwrite a key until a compaction drops the expired events
+    for iter in 0..200 {
+        store.set("counter".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(
+        store.read_events("metrics:cpu", 1, 10)?,
+        vec![(6, "recent".to_owned())]
+    );
+
+    // a stream without live events goes on with the next sequence
+    for sequence in 991..=1000 {
+        store.remove(format!("orders\u{0}{:020}", sequence))?;
+    }
+    assert!(store.read_events("orders", 1, 10)?.is_empty());
+    assert_eq!(
+        store.append_event("orders".to_owned(), event(1001).1)?,
+        1001
+    );
+    assert_eq!(store.read_events("orders", 1, 10)?, vec![event(1001)]);
+
+    Ok(())
+}
+
+#[test]
+fn json_patches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut document = serde_json::json!({ "count": 0, "tags": [], "padding": "x".repeat(1000) });
+    store.set("doc".to_owned(), document.to_string())?;
+    for count in 1..=40 {
+        document["count"] = count.into();
+        store.set_json_field(
+            "doc".to_owned(),
+            document.to_string(),
+            "/count",
+            &count.to_string(),
+        )?;
+        if count % 10 == 0 {
+            document["tags"].as_array_mut().unwrap().push(count.into());
+            store.set_json_field(
+                "doc".to_owned(),
+                document.to_string(),
+                "/tags/-",
+                &count.to_string(),
+            )?;
+        }
+    }
+    let expected = Some(document.to_string());
+    assert_eq!(store.get("doc".to_owned())?, expected);
+    assert_eq!(
+        store.get_many(vec!["doc".to_owned()])?,
+        vec![expected.clone()]
+    );
+    assert_eq!(
+        store.iter()?.collect::<Result<Vec<_>>>()?,
+        vec![("doc".to_owned(), document.to_string())]
+    );
+    // only the changes are written, with the whole document every few of them
+    assert!(store.stats().disk_usage < 10 * 1024);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("doc".to_owned())?, expected);
+    drop(store);
+    assert!(KvStore::verify(temp_dir.path())?.is_ok());
+
+    // compaction folds the changes into the whole document
+    KvStore::trim(temp_dir.path())?;
+    for entry in WalkDir::new(temp_dir.path()) {
+        let path = entry.unwrap().into_path();
+        if path.to_string_lossy().ends_with(".txt") {
+            assert!(!std::fs::read_to_string(&path)?.contains("PATCH"));
+        }
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("doc".to_owned())?, expected);
+
+    Ok(())
+}
+
+#[test]
+fn retention() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = || {
+        KvStore::builder()
+            .compaction_policy(CompactionPolicy::UselessSize(4 * 1024))
+            .retention(0, "metrics:", Duration::from_millis(500))
+            .open(temp_dir.path())
+    };
+    // overwrite a key until a compaction runs
+    let compact = |store: &KvStore| -> Result<()> {
+        for iter in 0..200 {
+            store.set("counter".to_owned(), format!("{}", iter))?;
+        }
+        Ok(())
+    };
+    let store = open()?;
+    for key_id in 0..10 {
+        store.set(format!("metrics:{}", key_id), "value".to_owned())?;
+        store.set(format!("users:{}", key_id), "value".to_owned())?;
+    }
+    // the same key in another database
+    store.set("\u{0}1:metrics:0".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(600));
+    store.set("metrics:new".to_owned(), "value".to_owned())?;
+    compact(&store)?;
+
+    assert_eq!(store.get("metrics:1".to_owned())?, None);
+    assert_eq!(
+        store.get("metrics:new".to_owned())?,
+        Some("value".to_owned())
+    );
+    assert_eq!(store.get("users:1".to_owned())?, Some("value".to_owned()));
+    assert_eq!(
+        store.get("\u{0}1:metrics:0".to_owned())?,
+        Some("value".to_owned())
+    );
+    assert_eq!(store.stats().expired_keys, 10);
+    drop(store);
+
+    // the write times survive the restart, so the recent key is neither kept forever nor
+    // dropped before its time
+    let store = open()?;
+    compact(&store)?;
+    assert_eq!(
+        store.get("metrics:new".to_owned())?,
+        Some("value".to_owned())
+    );
+    thread::sleep(Duration::from_millis(600));
+    compact(&store)?;
+    assert_eq!(store.get("metrics:new".to_owned())?, None);
+    assert_eq!(store.get("users:1".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.stats().expired_keys, 1);
+
+    Ok(())
+}
+
+// Should describe the options of every data file and the created databases in the manifest
+#[test]
+fn manifest_entries() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let manifest_path = || {
+        std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.to_string_lossy().contains("MANIFEST-"))
+            .expect("no manifest")
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.create_namespace(3)?;
+    store.create_namespace(1)?;
+    store.create_namespace(3)?;
+    assert_eq!(store.namespaces(), vec![3, 1]);
+    drop(store);
+
+    let manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(manifest_path())?)?;
+    assert_eq!(
+        manifest["entries"][0],
+        serde_json::json!({"SetOption": {"option": "codec", "value": "json", "first_file": 0}})
+    );
+    assert_eq!(
+        manifest["entries"][2],
+        serde_json::json!({"CreateNamespace": {"database": 3, "sequence": 1}})
+    );
+
+    // the databases survive reopening and compaction
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::UselessSize(1024))
+        .open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("value{}", iter))?;
+    }
+    assert_eq!(store.namespaces(), vec![3, 1]);
+    drop(store);
+
+    // a data file written with a codec this version lacks can not be read
+    let path = manifest_path();
+    let mut manifest: serde_json::Value = serde_json::from_slice(&std::fs::read(&path)?)?;
+    let last_file = manifest["log_files"][0].clone();
+    manifest["entries"]
+        .as_array_mut()
+        .unwrap()
+        .push(serde_json::json!(
+            {"SetOption": {"option": "codec", "value": "msgpack", "first_file": last_file}}
+        ));
+    std::fs::write(&path, serde_json::to_vec(&manifest)?)?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KVStoreError::UnsupportedStoreOption(..))
+    ));
+
+    Ok(())
+}
+
+// Should repair index entries pointing at records of other keys by reading the data files again,
+// skipping records which can not be decoded
+#[test]
+fn read_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::UselessSize(u64::MAX))
+        .open(temp_dir.path())?;
+    for iter in 0..30 {
+        store.set(format!("key{}", iter % 10), format!("value{}", iter))?;
+    }
+    store.set("lonely".to_owned(), "value".to_owned())?;
+
+    // overwrite keys of records with other keys of the same length
+    let data_file = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .filter(|path| path.to_string_lossy().ends_with(".txt"))
+        .max()
+        .expect("no data file");
+    let data = std::fs::read_to_string(&data_file)?;
+    // a record which can not be decoded anymore is skipped when looking up other keys
+    let corrupted = data
+        .replacen(r#"["key5","value15"]"#, r#"["key5"!"value15"]"#, 1)
+        .replacen(r#"["key9","value29"]"#, r#"["kez9","value29"]"#, 1)
+        .replacen(r#"["key8","value28"]"#, r#"["kez8","value28"]"#, 1)
+        .replacen(r#"["lonely","value"]"#, r#"["lonelz","value"]"#, 1);
+    std::fs::write(&data_file, corrupted)?;
+
+    assert_eq!(store.get("key9".to_owned())?, Some("value19".to_owned()));
+    assert_eq!(store.stats().corrupted_reads, 1);
+    assert_eq!(store.get("key9".to_owned())?, Some("value19".to_owned()));
+    assert_eq!(
+        store.get_many(vec!["key7".to_owned(), "key8".to_owned()])?,
+        vec![Some("value27".to_owned()), Some("value18".to_owned())]
+    );
+    assert_eq!(store.stats().corrupted_reads, 2);
+
+    // without another record of the key there is nothing to repair it with
+    match store.get("lonely".to_owned()) {
+        Err(err) => assert!(matches!(err.root(), KVStoreError::CorruptedRecord(..))),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(store.stats().corrupted_reads, 3);
+
+    Ok(())
+}
+
+// Should keep the write time of a record an index entry is repaired to, so its retention holds
+#[test]
+fn read_repair_retention() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::UselessSize(4 * 1024))
+        .retention(0, "metrics:", Duration::from_millis(500))
+        .open(temp_dir.path())?;
+    store.set("metrics:a".to_owned(), "old1".to_owned())?;
+    store.set("metrics:a".to_owned(), "old2".to_owned())?;
+    thread::sleep(Duration::from_millis(600));
+
+    let data_file = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .filter(|path| path.to_string_lossy().ends_with(".txt"))
+        .max()
+        .expect("no data file");
+    let data = std::fs::read_to_string(&data_file)?;
+    let corrupted = data.replacen(r#"["metrics:a","old2"]"#, r#"["metrics:z","old2"]"#, 1);
+    std::fs::write(&data_file, corrupted)?;
+    assert_eq!(store.get("metrics:a".to_owned())?, Some("old1".to_owned()));
+
+    // overwrite a key until a compaction runs
+    for iter in 0..200 {
+        store.set("counter".to_owned(), format!("{}", iter))?;
+    }
+    assert_eq!(store.get("metrics:a".to_owned())?, None);
+    assert_eq!(store.stats().expired_keys, 1);
+
+    Ok(())
+}
+
+// Should find corrupted records in sealed data files, repairing or quarantining their keys
+#[test]
+fn scrub() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::UselessSize(u64::MAX))
+        .preallocate(512)
+        .open(temp_dir.path())?;
+    for iter in 0..30 {
+        store.set(format!("key{}", iter % 10), format!("value{}", iter))?;
+    }
+    store.set("lonely".to_owned(), "value".to_owned())?;
+    for iter in 30..40 {
+        store.set(format!("key{}", iter % 10), format!("value{}", iter))?;
+    }
+    store.scrub()?;
+    let stats = store.stats();
+    assert!(stats.scrubbed_files > 1);
+    assert!(stats.scrubbed_records >= 30);
+    assert_eq!((stats.scrub_errors, stats.quarantined_keys), (0, 0));
+
+    // overwrite keys of records with other keys of the same length
+    let data_files: Vec<_> = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .filter(|path| path.to_string_lossy().ends_with(".txt"))
+        .collect();
+    for data_file in data_files {
+        let data = std::fs::read_to_string(&data_file)?;
+        let corrupted = data
+            .replacen(r#"["lonely","value"]"#, r#"["lonelz","value"]"#, 1)
+            .replacen(r#"["key1","value1"]"#, r#"["kez1","value1"]"#, 1);
+        std::fs::write(&data_file, corrupted)?;
+    }
+
+    store.scrub()?;
+    let stats = store.stats();
+    assert_eq!((stats.scrub_errors, stats.quarantined_keys), (1, 1));
+    match store.get("lonely".to_owned()) {
+        Err(err) => assert!(matches!(err, KVStoreError::CorruptedRecord(..))),
+        other => panic!("unexpected result {:?}", other),
+    }
+    // the corrupted record of key1 is no longer its current value
+    assert_eq!(store.get("key1".to_owned())?, Some("value31".to_owned()));
+
+    // writing a quarantined key releases it
+    store.set("lonely".to_owned(), "fresh".to_owned())?;
+    assert_eq!(store.get("lonely".to_owned())?, Some("fresh".to_owned()));
+    assert_eq!(store.stats().quarantined_keys, 0);
+
+    Ok(())
+}
+
+// Should scrub in the background
+#[test]
+fn background_scrub() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .preallocate(256)
+        .scrub_interval(Duration::from_millis(20))
+        .open(temp_dir.path())?;
+    for iter in 0..20 {
+        store.set(format!("key{}", iter), format!("value{}", iter))?;
+    }
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while store.stats().scrubbed_files < 3 {
+        assert!(Instant::now() < deadline, "the scrubber did not run");
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(store.stats().scrub_errors, 0);
+    Ok(())
+}
+
+// Should verify a store offline and report its problems
+#[test]
+fn verify_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::UselessSize(256))
+        .open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set(format!("key{}", iter % 10), format!("value{}", iter))?;
+    }
+    store.remove("key0".to_owned())?;
+    drop(store);
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(report.is_ok(), "{:?}", report);
+    assert_eq!(report.live_keys, 9);
+    assert!(report.records >= 9);
+
+    // a leftover of a compaction and a record cut short by a crash
+    std::fs::write(temp_dir.path().join("data_999.txt"), "")?;
+    let last_file = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.into_path())
+        .filter(|path| path.to_string_lossy().ends_with(".txt") && !path.ends_with("data_999.txt"))
+        .max_by_key(|path| std::fs::metadata(path).map(|meta| meta.len()).unwrap_or(0))
+        .unwrap();
+    let mut data = std::fs::read(&last_file)?;
+    data.extend_from_slice(br#"{"SET":["key1","#);
+    std::fs::write(&last_file, data)?;
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(!report.is_ok());
+    assert!(report
+        .problems
+        .contains(&VerifyProblem::DanglingFile { file: 999 }));
+    assert!(report
+        .problems
+        .iter()
+        .any(|problem| matches!(problem, VerifyProblem::BadRecord { .. })));
+
+    assert!(KvStore::verify(temp_dir.path().join("missing")).is_err());
+    Ok(())
+}
+
+// Should compact a store into a single data file and report the space reclaimed
+#[test]
+fn trim_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .compaction_policy(CompactionPolicy::UselessSize(1024))
+        .compaction_threads(4)
+        .open(temp_dir.path())?;
+    // keys written once stay in the files of the first compaction, so later compactions
+    // copy from several data files with several jobs
+    for iter in 0..10 {
+        store.set(format!("static{}", iter), "value".to_owned())?;
+    }
+    for iter in 0..300 {
+        store.set(format!("key{}", iter % 10), format!("value{}", iter))?;
+    }
+    drop(store);
+
+    let report = KvStore::trim(temp_dir.path())?;
+    assert!(report.data_files_before > 2);
+    // the compacted data file and an empty log file
+    assert_eq!(report.data_files_after, 2);
+    assert!(report.disk_usage_after < report.disk_usage_before);
+    assert!(KvStore::verify(temp_dir.path())?.is_ok());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value293".to_owned()));
+    Ok(())
+}
+
+/// wait until the store archived all of its sealed log files
+fn wait_for_archive(store: &KvStore) -> ArchiveStats {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        let stats = store.stats().archive.unwrap();
+        if stats.pending_files == 0 || Instant::now() > deadline {
+            return stats;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+// Should archive each log file once it is sealed, also across compactions, and the sealed
+// log files which were not archived when the store is opened
+#[test]
+fn archive_log_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let archive_dir = TempDir::new().expect("unable to create temporary archive directory");
+    let target = DirBackupTarget::new(archive_dir.path())?;
+    let store = KvStore::builder()
+        .preallocate(8 * 1024)
+        .compaction_policy(CompactionPolicy::UselessSize(16 * 1024))
+        .archive_to(target.clone())
+        .open(temp_dir.path())?;
+    let value = |iter: usize| format!("value-{:04}-{}", iter, "x".repeat(200));
+    // each key is written twice and never again, so a compaction output holds other records
+    // than the log files it replaced
+    let mut written = Vec::new();
+    for iter in 0..600 {
+        let key = format!("key{:04}", iter / 2);
+        store.set(key.clone(), value(iter))?;
+        written.push((key, value(iter)));
+    }
+    assert!(store.stats().compaction_bytes_copied > 0);
+
+    let stats = wait_for_archive(&store);
+    assert_eq!(stats.pending_files, 0);
+    assert_eq!(stats.last_error, None);
+    assert!(stats.archived_files > 2);
+    let names = target.list("wal/")?;
+    assert_eq!(names.len() as u64, stats.archived_files);
+    // replaying the archive in order gives back every write but the ones of the log file still
+    // written, so it holds the sealed log files and nothing else
+    let mut replayed = Vec::new();
+    for name in &names {
+        let mut data = Vec::new();
+        target.get(name)?.read_to_end(&mut data)?;
+        // the header, the records and the rest of the preallocated space
+        let records = serde_json::Deserializer::from_slice(&data)
+            .into_iter::<serde_json::Value>()
+            .skip(1)
+            .map_while(|record| record.ok());
+        for record in records {
+            match serde_json::from_value(record)? {
+                Command::SET(key, value) => replayed.push((key, value)),
+                record => panic!("unexpected record {:?}", record),
+            }
+        }
+    }
+    assert!((500..600).contains(&replayed.len()), "{}", replayed.len());
+    assert_eq!(replayed[..], written[..replayed.len()]);
+    drop(store);
+
+    // a store opened with an empty archive archives its sealed log files first
+    let archive_dir = TempDir::new().expect("unable to create temporary archive directory");
+    let target = DirBackupTarget::new(archive_dir.path())?;
+    let store = KvStore::builder()
+        .preallocate(8 * 1024)
+        .compaction_policy(CompactionPolicy::UselessSize(16 * 1024))
+        .archive_to(target.clone())
+        .open(temp_dir.path())?;
+    let stats = wait_for_archive(&store);
+    assert_eq!(stats.pending_files, 0);
+    assert_eq!(target.list("wal/")?.len() as u64, stats.archived_files);
+    assert_eq!(store.get("key0299".to_owned())?, Some(value(599)));
+    Ok(())
+}
+
+// Should list keys in batches and backfill a migration batch by batch
+#[test]
+fn backfill_in_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old = KvStore::open(temp_dir.path().join("kvs"))?;
+    let new = SledKvsEngine::open(temp_dir.path().join("sled"))?;
+    for iter in 0..10000 {
+        old.set(format!("key{:05}", iter * 2), format!("value{}", iter))?;
+    }
+    // leftovers between and after the keys of the old engine
+    for iter in 0..3000 {
+        new.set(format!("key{:05}", iter * 7 + 1), "leftover".to_owned())?;
+    }
+    new.set("zzz".to_owned(), "leftover".to_owned())?;
+
+    let first = old.scan_keys(None, 3)?;
+    assert_eq!(first, vec!["key00000", "key00002", "key00004"]);
+    assert_eq!(
+        old.scan_keys(first.last(), 2)?,
+        vec!["key00006", "key00008"]
+    );
+    assert_eq!(new.scan_keys(Some(&"key20994".to_owned()), 5)?, vec!["zzz"]);
+
+    let engine = MigratingEngine::new(old.clone(), new.clone());
+    assert_eq!(engine.backfill()?, 10000);
+    let expected: Vec<String> = (0..10000)
+        .map(|iter| format!("key{:05}", iter * 2))
+        .collect();
+    assert_eq!(new.scan_keys(None, 20000)?, expected);
+    assert_eq!(
+        new.get("key19998".to_owned())?,
+        Some("value9999".to_owned())
+    );
+    Ok(())
+}
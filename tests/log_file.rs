@@ -0,0 +1,58 @@
+use kvs::{RollingFile, Rotation};
+use std::fs;
+use std::io::Write;
+use tempfile::TempDir;
+
+// Rolling by size keeps the newest files up to the retention count
+#[test]
+fn roll_by_size() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.log");
+    let mut log = RollingFile::open(&path, Rotation::Size(100), 2).unwrap();
+    for i in 0..10 {
+        // 50 bytes each, two lines per file
+        log.write_all(format!("{:049}\n", i).as_bytes()).unwrap();
+    }
+    log.flush().unwrap();
+
+    let read = |name: &str| fs::read_to_string(temp_dir.path().join(name)).unwrap();
+    assert_eq!(read("kvs.log"), format!("{:049}\n{:049}\n", 8, 9));
+    assert_eq!(read("kvs.log.1"), format!("{:049}\n{:049}\n", 6, 7));
+    assert_eq!(read("kvs.log.2"), format!("{:049}\n{:049}\n", 4, 5));
+    assert!(!temp_dir.path().join("kvs.log.3").exists());
+
+    // reopening continues the current file
+    drop(log);
+    let mut log = RollingFile::open(&path, Rotation::Size(100), 2).unwrap();
+    log.write_all(b"10\n").unwrap();
+    assert_eq!(read("kvs.log.1"), format!("{:049}\n{:049}\n", 8, 9));
+    assert_eq!(read("kvs.log"), "10\n");
+}
+
+// Without retention the old file is dropped, and a record larger than the limit still gets written
+#[test]
+fn roll_without_retention() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.log");
+    let mut log = RollingFile::open(&path, Rotation::Size(10), 0).unwrap();
+    log.write_all(b"first\n").unwrap();
+    log.write_all(b"a line longer than the limit\n").unwrap();
+    assert_eq!(
+        fs::read_to_string(&path).unwrap(),
+        "a line longer than the limit\n"
+    );
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+}
+
+// A file which is never rolled keeps everything
+#[test]
+fn never_roll() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.log");
+    let mut log = RollingFile::open(&path, Rotation::Never, 3).unwrap();
+    for _ in 0..100 {
+        log.write_all(b"0123456789").unwrap();
+    }
+    assert_eq!(fs::metadata(&path).unwrap().len(), 1000);
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 1);
+}
@@ -0,0 +1,221 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{Client, Drain, KvServer, KvStore, Request, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+struct MemcachedClient {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl MemcachedClient {
+    fn connect(addr: &str) -> MemcachedClient {
+        let stream = TcpStream::connect(addr).expect("unable to connect");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        MemcachedClient {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+
+    /// send `request` and read the reply up to and including the line `last`
+    fn call(&mut self, request: &str, last: &str) -> String {
+        self.writer.write_all(request.as_bytes()).unwrap();
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            assert!(!line.is_empty(), "connection closed after {:?}", reply);
+            reply.push_str(&line);
+            if line.trim_end() == last || line.starts_with("ERROR") || line.contains("_ERROR") {
+                return reply;
+            }
+        }
+    }
+
+    fn line(&mut self, request: &str) -> String {
+        self.writer.write_all(request.as_bytes()).unwrap();
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        line
+    }
+}
+
+fn start_server(kvs_addr: &str, memcached_addr: &str) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path()).unwrap(),
+        SharedQueueThreadPool::new(4).unwrap(),
+        Arc::new(AtomicBool::new(false)),
+    )
+    .memcached_listeners(vec![TcpListener::bind(memcached_addr).unwrap()]);
+    let kvs_addr = kvs_addr.to_owned();
+    thread::spawn(move || server.serve(&kvs_addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    temp_dir
+}
+
+// Should store, read and delete items like memcached, sharing the keys of the kvs protocol
+#[test]
+fn memcached_commands() -> Result<()> {
+    let kvs_addr = "127.0.0.1:4113";
+    let _temp_dir = start_server(kvs_addr, "127.0.0.1:4114");
+    let mut client = MemcachedClient::connect("127.0.0.1:4114");
+
+    assert_eq!(client.line("set key1 0 0 6\r\nvalue1\r\n"), "STORED\r\n");
+    assert_eq!(client.line("set key2 42 0 2\r\nv2\r\n"), "STORED\r\n");
+    assert_eq!(
+        client.call("get key1 key2 missing\r\n", "END"),
+        "VALUE key1 0 6\r\nvalue1\r\nVALUE key2 42 2\r\nv2\r\nEND\r\n"
+    );
+    // items without flags and exptime are plain values
+    assert_eq!(
+        Client::new(kvs_addr)?.request(&Request::GET("key1".to_owned()))?,
+        Some("value1".to_owned())
+    );
+    Client::new(kvs_addr)?.request(&Request::SET("key3".to_owned(), "kvs".to_owned()))?;
+    assert_eq!(
+        client.call("get key3\r\n", "END"),
+        "VALUE key3 0 3\r\nkvs\r\nEND\r\n"
+    );
+
+    assert_eq!(client.line("add key1 0 0 1\r\nx\r\n"), "NOT_STORED\r\n");
+    assert_eq!(client.line("add key4 0 0 1\r\nx\r\n"), "STORED\r\n");
+    assert_eq!(client.line("replace key5 0 0 1\r\nx\r\n"), "NOT_STORED\r\n");
+    assert_eq!(client.line("replace key4 0 0 1\r\ny\r\n"), "STORED\r\n");
+    assert_eq!(
+        client.call("get key4\r\n", "END"),
+        "VALUE key4 0 1\r\ny\r\nEND\r\n"
+    );
+
+    assert_eq!(client.line("delete key4\r\n"), "DELETED\r\n");
+    assert_eq!(client.line("delete key4\r\n"), "NOT_FOUND\r\n");
+    // no reply, the next command gets the next reply
+    client
+        .writer
+        .write_all(b"set key5 0 0 1 noreply\r\nz\r\n")?;
+    assert_eq!(
+        client.call("get key5\r\n", "END"),
+        "VALUE key5 0 1\r\nz\r\nEND\r\n"
+    );
+
+    assert_eq!(client.line("bogus\r\n"), "ERROR\r\n");
+    assert_eq!(
+        client.line("set key6 0 0 1\r\nxyz\r\n"),
+        "CLIENT_ERROR bad data chunk\r\n"
+    );
+    assert!(client.line("version\r\n").starts_with("VERSION "));
+    Ok(())
+}
+
+// Should expire items after their exptime
+#[test]
+fn memcached_exptime() -> Result<()> {
+    let kvs_addr = "127.0.0.1:4115";
+    let _temp_dir = start_server(kvs_addr, "127.0.0.1:4116");
+    let mut client = MemcachedClient::connect("127.0.0.1:4116");
+
+    assert_eq!(client.line("set short 0 1 1\r\na\r\n"), "STORED\r\n");
+    assert_eq!(client.line("set long 0 3600 1\r\nb\r\n"), "STORED\r\n");
+    assert_eq!(client.line("set past 0 -1 1\r\nc\r\n"), "STORED\r\n");
+    assert_eq!(client.call("get past\r\n", "END"), "END\r\n");
+    assert_eq!(
+        client.call("get short\r\n", "END"),
+        "VALUE short 0 1\r\na\r\nEND\r\n"
+    );
+
+    thread::sleep(Duration::from_millis(2100));
+    assert_eq!(
+        client.call("get short long\r\n", "END"),
+        "VALUE long 0 1\r\nb\r\nEND\r\n"
+    );
+    // the expired item was removed from the store
+    assert_eq!(
+        Client::new(kvs_addr)?.request(&Request::GET("short".to_owned()))?,
+        None
+    );
+    // and an expired item may be added again
+    assert_eq!(client.line("set gone 0 1 1\r\nd\r\n"), "STORED\r\n");
+    thread::sleep(Duration::from_millis(2100));
+    assert_eq!(client.line("add gone 0 0 1\r\ne\r\n"), "STORED\r\n");
+    assert_eq!(client.line("delete short\r\n"), "NOT_FOUND\r\n");
+    Ok(())
+}
+
+// Should refuse the commands doing the same as the disabled requests of the kvs protocol
+#[test]
+fn memcached_disabled_commands() -> Result<()> {
+    let kvs_addr = "127.0.0.1:4181";
+    let memcached_addr = "127.0.0.1:4182";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+        Arc::new(AtomicBool::new(false)),
+    )
+    .memcached_listeners(vec![TcpListener::bind(memcached_addr)?])
+    .disable_commands(&["SET", "RM", "FLUSH"]);
+    let addr = kvs_addr.to_owned();
+    thread::spawn(move || server.serve(&addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    let mut client = MemcachedClient::connect(memcached_addr);
+
+    for command in ["set", "add", "replace"] {
+        let reply = client.line(&format!("{} key 0 0 5\r\nvalue\r\n", command));
+        assert!(reply.starts_with("SERVER_ERROR"), "{}", reply);
+    }
+    let reply = client.line("delete key\r\n");
+    assert!(reply.starts_with("SERVER_ERROR"), "{}", reply);
+    // the data blocks were read, so the connection stays in sync
+    assert_eq!(client.call("get key\r\n", "END"), "END\r\n");
+    assert_eq!(
+        Client::new(kvs_addr)?.request(&Request::GET("key".to_owned()))?,
+        None
+    );
+    Ok(())
+}
+
+// Should serve the connections open before a drain for its grace period and no others
+#[test]
+fn memcached_draining() -> Result<()> {
+    let memcached_addr = "127.0.0.1:4186";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let drain = Drain::default();
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+        Arc::new(AtomicBool::new(false)),
+    )
+    .memcached_listeners(vec![TcpListener::bind(memcached_addr)?])
+    .drain(drain.clone());
+    thread::spawn(move || server.serve(&"127.0.0.1:4187".to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut open = MemcachedClient::connect(memcached_addr);
+    assert_eq!(open.line("set key 0 0 5\r\nvalue\r\n"), "STORED\r\n");
+    drain.start(Duration::from_millis(500));
+    assert_eq!(open.line("set key 0 0 6\r\nvalue2\r\n"), "STORED\r\n");
+    let mut new = MemcachedClient::connect(memcached_addr);
+    let reply = new.line("set key 0 0 6\r\nvalue3\r\n");
+    assert!(reply.starts_with("SERVER_ERROR"), "{}", reply);
+    // the data block was read, so the connection stays in sync
+    let reply = new.line("get key\r\n");
+    assert!(reply.starts_with("SERVER_ERROR"), "{}", reply);
+    thread::sleep(Duration::from_millis(600));
+    let reply = open.line("delete key\r\n");
+    assert!(reply.starts_with("SERVER_ERROR"), "{}", reply);
+
+    drain.resume();
+    assert_eq!(
+        new.call("get key\r\n", "END"),
+        "VALUE key 0 6\r\nvalue2\r\nEND\r\n"
+    );
+    Ok(())
+}
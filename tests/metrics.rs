@@ -0,0 +1,116 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{Client, KvServer, KvStore, Metric, Request, Result, StatsdSink};
+use std::net::UdpSocket;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn bind_statsd() -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").expect("unable to bind a UDP socket");
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    socket
+}
+
+fn receive(socket: &UdpSocket) -> Vec<String> {
+    let mut buf = [0; 65536];
+    let len = socket.recv(&mut buf).expect("no datagram received");
+    String::from_utf8_lossy(&buf[..len])
+        .lines()
+        .map(str::to_owned)
+        .collect()
+}
+
+// Counters are sent as their increase since the last flush, gauges as they are
+#[test]
+fn statsd_counter_deltas() -> Result<()> {
+    let statsd = bind_statsd();
+    let addr = statsd.local_addr()?.to_string();
+    let mut total = 0;
+    let sink = StatsdSink::start(&addr, "kvs", Duration::from_millis(100), move || {
+        total += 5;
+        vec![
+            ("requests".to_owned(), Metric::Counter(total)),
+            ("live_keys".to_owned(), Metric::Gauge(2.0)),
+        ]
+    })?;
+    assert_eq!(
+        receive(&statsd),
+        vec!["kvs.requests:5|c", "kvs.live_keys:2|g"]
+    );
+    assert_eq!(
+        receive(&statsd),
+        vec!["kvs.requests:5|c", "kvs.live_keys:2|g"]
+    );
+    drop(sink);
+    Ok(())
+}
+
+// Metrics which do not fit one datagram are split over several
+#[test]
+fn statsd_splits_datagrams() -> Result<()> {
+    let statsd = bind_statsd();
+    let addr = statsd.local_addr()?.to_string();
+    let sink = StatsdSink::start(&addr, "", Duration::from_secs(60), || {
+        (0..200)
+            .map(|i| (format!("gauge{}", i), Metric::Gauge(i as f64)))
+            .collect()
+    })?;
+    // dropping flushes
+    drop(sink);
+    let mut lines = Vec::new();
+    while lines.len() < 200 {
+        let datagram = receive(&statsd);
+        assert!(datagram.iter().map(|line| line.len() + 1).sum::<usize>() <= 1433);
+        lines.extend(datagram);
+    }
+    assert_eq!(lines[0], "gauge0:0|g");
+    assert_eq!(lines[199], "gauge199:199|g");
+    Ok(())
+}
+
+// The server pushes its request counts and the stats of its engine
+#[test]
+fn push_server_metrics() -> Result<()> {
+    let statsd = bind_statsd();
+    let statsd_addr = statsd.local_addr()?.to_string();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4110";
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        Arc::new(AtomicBool::new(false)),
+    );
+    let sink = server.push_metrics(&statsd_addr, "kvs", Duration::from_secs(60))?;
+    thread::spawn(move || server.serve(&addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    Client::new(addr)?.request(&Request::SET("key1".to_owned(), "value1".to_owned()))?;
+    Client::new(addr)?.request(&Request::GET("key1".to_owned()))?;
+    assert!(Client::new(addr)?
+        .request(&Request::RM("key2".to_owned()))
+        .is_err());
+    drop(sink);
+
+    let lines = receive(&statsd);
+    for line in [
+        "kvs.connections:3|c",
+        "kvs.errors:1|c",
+        "kvs.requests.set:1|c",
+        "kvs.requests.get:1|c",
+        "kvs.requests.rm:1|c",
+        "kvs.engine.live_keys:1|g",
+        "kvs.engine.writes:1|c",
+    ] {
+        assert!(
+            lines.iter().any(|sent| sent == line),
+            "{} not in {:?}",
+            line,
+            lines
+        );
+    }
+    Ok(())
+}
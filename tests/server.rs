@@ -0,0 +1,2201 @@
+use kvs::thread_pool::{CancellationToken, SharedQueueThreadPool, ThreadPool};
+use kvs::{
+    AccessList, Client, Drain, ErrorContext, FailoverTask, IdempotencyToken, KVStoreError, KvProxy,
+    KvServer, KvStore, KvsReader, KvsWriter, Metric, MigratingEngine, MultiplexClient, Replicator,
+    Request, Reshard, Response, Result, RetryingClient, SledKvsEngine, SnapshotEngine, Transform,
+    WatchEvent,
+};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn start_server(addr: &str) -> TempDir {
+    start_replicated_server(addr, None).0
+}
+
+fn start_replicated_server(addr: &str, remote: Option<&str>) -> (TempDir, Option<Replicator>) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path().join("data")).unwrap();
+    let mut server = KvServer::new(
+        engine,
+        SharedQueueThreadPool::new(4).unwrap(),
+        Arc::new(AtomicBool::new(false)),
+    );
+    let replicator = remote.map(|remote| {
+        server
+            .replicate_to(remote, temp_dir.path().join("replication"))
+            .unwrap()
+    });
+    let addr = addr.to_owned();
+    thread::spawn(move || server.serve(&addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    (temp_dir, replicator)
+}
+
+/// start a server caching the responses of retryable requests
+fn start_dedup_server(addr: &str) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path()).unwrap(),
+        SharedQueueThreadPool::new(2).unwrap(),
+        Arc::new(AtomicBool::new(false)),
+    )
+    .dedup_cache(100, Duration::from_secs(60));
+    let addr = addr.to_owned();
+    thread::spawn(move || server.serve(&addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    temp_dir
+}
+
+fn wait_for(addr: &str, key: &str, expected: Option<&str>) -> Result<()> {
+    for _ in 0..100 {
+        if request(addr, Request::GET(key.to_owned()))?.as_deref() == expected {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    panic!("{} did not become {:?} on {}", key, expected, addr);
+}
+
+fn request(addr: &str, request: Request) -> Result<Option<String>> {
+    Client::new(addr)?.request(&request)
+}
+
+fn event(revision: u64, key: &str, value: Option<&str>) -> WatchEvent {
+    WatchEvent {
+        revision,
+        key: key.to_owned(),
+        value: value.map(str::to_owned),
+    }
+}
+
+// Should replay the events after a revision and then stream new ones
+#[test]
+fn watch_from_revision() -> Result<()> {
+    let addr = "127.0.0.1:4101";
+    let _temp_dir = start_server(addr);
+
+    request(addr, Request::SET("user:1".to_owned(), "a".to_owned()))?;
+    request(addr, Request::SET("other".to_owned(), "b".to_owned()))?;
+    request(addr, Request::RM("user:1".to_owned()))?;
+
+    let mut watcher = Client::new(addr)?.watch("user:", 0)?;
+    assert_eq!(watcher.next().unwrap()?, event(1, "user:1", Some("a")));
+    assert_eq!(watcher.next().unwrap()?, event(3, "user:1", None));
+
+    request(addr, Request::SET("user:2".to_owned(), "c".to_owned()))?;
+    assert_eq!(watcher.next().unwrap()?, event(4, "user:2", Some("c")));
+
+    // Reconnect from the last seen revision and miss nothing
+    drop(watcher);
+    request(addr, Request::SET("user:3".to_owned(), "d".to_owned()))?;
+    let mut watcher = Client::new(addr)?.watch("user:", 4)?;
+    assert_eq!(watcher.next().unwrap()?, event(5, "user:3", Some("d")));
+
+    // Failed writes do not produce events
+    assert!(request(addr, Request::RM("user:1".to_owned())).is_err());
+    request(addr, Request::SET("user:4".to_owned(), "e".to_owned()))?;
+    assert_eq!(watcher.next().unwrap()?, event(6, "user:4", Some("e")));
+
+    Ok(())
+}
+
+// Should reject watching from a revision the server has not reached
+#[test]
+fn watch_future_revision() -> Result<()> {
+    let addr = "127.0.0.1:4102";
+    let _temp_dir = start_server(addr);
+
+    let mut watcher = Client::new(addr)?.watch("", 10)?;
+    assert!(watcher.next().unwrap().is_err());
+    assert!(watcher.next().is_none());
+
+    Ok(())
+}
+
+// Should push the writes of a server to a remote server in order
+#[test]
+fn replicate_to_remote() -> Result<()> {
+    let remote = "127.0.0.1:4103";
+    let primary = "127.0.0.1:4104";
+    let _remote_dir = start_server(remote);
+    let (_primary_dir, replicator) = start_replicated_server(primary, Some(remote));
+    let replicator = replicator.unwrap();
+
+    request(
+        primary,
+        Request::SET("key1".to_owned(), "value1".to_owned()),
+    )?;
+    request(
+        primary,
+        Request::SET("key2".to_owned(), "value2".to_owned()),
+    )?;
+    request(
+        primary,
+        Request::SET("key1".to_owned(), "value3".to_owned()),
+    )?;
+    request(primary, Request::RM("key2".to_owned()))?;
+
+    wait_for(remote, "key1", Some("value3"))?;
+    wait_for(remote, "key2", None)?;
+    for _ in 0..100 {
+        if replicator.stats().replicated_events == 4 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let stats = replicator.stats();
+    assert_eq!(stats.replicated_events, 4);
+    assert_eq!(stats.pending_events, 0);
+    assert_eq!(stats.pending_bytes, 0);
+    assert_eq!(stats.last_error, None);
+
+    Ok(())
+}
+
+// Should spool writes while the remote is down and push them once it is up
+#[test]
+fn replicate_to_unavailable_remote() -> Result<()> {
+    let remote = "127.0.0.1:4105";
+    let primary = "127.0.0.1:4106";
+    let (_primary_dir, replicator) = start_replicated_server(primary, Some(remote));
+    let replicator = replicator.unwrap();
+
+    request(
+        primary,
+        Request::SET("key1".to_owned(), "value1".to_owned()),
+    )?;
+    thread::sleep(Duration::from_millis(300));
+    let stats = replicator.stats();
+    assert_eq!(stats.pending_events, 1);
+    assert!(stats.pending_bytes > 0);
+    assert!(stats.last_error.is_some());
+
+    let _remote_dir = start_server(remote);
+    wait_for(remote, "key1", Some("value1"))?;
+
+    Ok(())
+}
+
+// Should cut the write a crash tore off the end of the spool and push the others
+#[test]
+fn replicate_torn_spool() -> Result<()> {
+    let remote = "127.0.0.1:4185";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let spool_dir = temp_dir.path().join("replication");
+    fs::create_dir_all(&spool_dir)?;
+    let record = r#"{"at_millis":0,"event":{"revision":1,"key":"key1","value":"value1"}}"#;
+    let torn = r#"{"at_millis":0,"event":{"revision":2,"ke"#;
+    fs::write(
+        spool_dir.join("replication.spool"),
+        format!("{}{}", record, torn),
+    )?;
+    fs::write(spool_dir.join("replication.pos"), "0")?;
+
+    let server = KvServer::new(
+        KvStore::open(temp_dir.path().join("data"))?,
+        SharedQueueThreadPool::new(4)?,
+        Arc::new(AtomicBool::new(false)),
+    );
+    let replicator = server.replicate_to(remote, spool_dir.clone())?;
+    let stats = replicator.stats();
+    assert_eq!(stats.pending_events, 1);
+    assert_eq!(stats.pending_bytes, record.len() as u64);
+    assert_eq!(
+        fs::metadata(spool_dir.join("replication.spool"))?.len(),
+        record.len() as u64
+    );
+
+    let _remote_dir = start_server(remote);
+    wait_for(remote, "key1", Some("value1"))?;
+    Ok(())
+}
+
+// Should install a snapshot of the existing keys on a new remote before streaming writes
+#[test]
+fn replicate_snapshot_to_new_remote() -> Result<()> {
+    let remote = "127.0.0.1:4107";
+    let primary = "127.0.0.1:4108";
+    let _remote_dir = start_server(remote);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path().join("data"))?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    let mut server = KvServer::new(
+        engine,
+        SharedQueueThreadPool::new(4)?,
+        Arc::new(AtomicBool::new(false)),
+    );
+    let replicator = server.replicate_to(remote, temp_dir.path().join("replication"))?;
+    assert_eq!(replicator.stats().snapshot_keys, 1);
+    thread::spawn(move || server.serve(&primary.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    request(
+        primary,
+        Request::SET("key3".to_owned(), "value3".to_owned()),
+    )?;
+    wait_for(remote, "key3", Some("value3"))?;
+    assert_eq!(
+        request(remote, Request::GET("key1".to_owned()))?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(request(remote, Request::GET("key2".to_owned()))?, None);
+
+    Ok(())
+}
+
+// Should report the most read and most written keys, hottest first
+#[test]
+fn top_keys() -> Result<()> {
+    let addr = "127.0.0.1:4111";
+    let _temp_dir = start_server(addr);
+
+    for i in 0..3 {
+        request(addr, Request::SET(format!("key{}", i), "value".to_owned()))?;
+    }
+    request(addr, Request::SET("key1".to_owned(), "value".to_owned()))?;
+    for _ in 0..5 {
+        request(addr, Request::GET("key2".to_owned()))?;
+    }
+    for _ in 0..2 {
+        request(addr, Request::GET("key0".to_owned()))?;
+    }
+    // misses count too, they may be a hotspot as well
+    request(addr, Request::GET("missing".to_owned()))?;
+
+    let top = Client::new(addr)?.top_keys(2)?;
+    assert_eq!(
+        top.reads,
+        vec![("key2".to_owned(), 5), ("key0".to_owned(), 2)]
+    );
+    assert_eq!(
+        top.writes,
+        vec![("key1".to_owned(), 2), ("key0".to_owned(), 1)]
+    );
+    assert_eq!(Client::new(addr)?.top_keys(10)?.reads.len(), 3);
+
+    Ok(())
+}
+
+// Should keep the keys of logical databases apart, with a database selected per connection
+#[test]
+fn logical_databases() -> Result<()> {
+    let addr = "127.0.0.1:4112";
+    let _temp_dir = start_server(addr);
+
+    // a connection serves any number of requests
+    let mut db0 = Client::new(addr)?;
+    db0.request(&Request::SET("key1".to_owned(), "db0".to_owned()))?;
+    db0.request(&Request::SET("key2".to_owned(), "db0".to_owned()))?;
+    let mut db1 = Client::new(addr)?;
+    db1.select(1)?;
+    let mut watcher = Client::new(addr)?;
+    watcher.select(1)?;
+    let mut watcher = watcher.watch("", 2)?;
+    db1.request(&Request::SET("key1".to_owned(), "db1".to_owned()))?;
+
+    assert_eq!(
+        db0.request(&Request::GET("key1".to_owned()))?,
+        Some("db0".to_owned())
+    );
+    assert_eq!(
+        db1.request(&Request::GET("key1".to_owned()))?,
+        Some("db1".to_owned())
+    );
+    assert_eq!(db1.request(&Request::GET("key2".to_owned()))?, None);
+    assert_eq!(
+        db1.scan_match("*")?,
+        vec![("key1".to_owned(), "db1".to_owned())]
+    );
+    assert_eq!(db0.scan_match("*")?.len(), 2);
+    assert_eq!(watcher.next().unwrap()?, event(3, "key1", Some("db1")));
+
+    let stats = db0.database_stats()?;
+    assert_eq!((stats.database, stats.keys, stats.bytes), (0, 2, 14));
+    db0.request(&Request::FLUSH)?;
+    assert_eq!(db0.database_stats()?.keys, 0);
+    assert_eq!(db1.database_stats()?.keys, 1);
+
+    assert!(db1.select(16).is_err());
+    assert!(db0
+        .request(&Request::SET("\u{0}1:key1".to_owned(), "db0".to_owned()))
+        .is_err());
+    db1.select(0)?;
+    assert_eq!(db1.request(&Request::GET("key1".to_owned()))?, None);
+
+    Ok(())
+}
+
+// Should answer pipelined requests in order, failing only the requests which fail
+#[test]
+fn pipeline() -> Result<()> {
+    let addr = "127.0.0.1:4124";
+    let _temp_dir = start_server(addr);
+
+    let mut client = Client::new(addr)?;
+    let results = client
+        .pipeline()
+        .set("key1", "value1")
+        .get("key1")
+        .remove("key2")
+        .remove("key1")
+        .get("key1")
+        .execute()?;
+    assert_eq!(results.len(), 5);
+    assert_eq!(results[0].as_ref().unwrap(), &None);
+    assert_eq!(results[1].as_ref().unwrap().as_deref(), Some("value1"));
+    assert!(results[2].is_err());
+    assert!(results[3].is_ok());
+    assert_eq!(results[4].as_ref().unwrap(), &None);
+
+    // more requests than fit one window, on the same connection afterwards
+    let mut pipeline = client.pipeline();
+    for i in 0..1000 {
+        pipeline = pipeline.set(&format!("key{}", i), &i.to_string());
+    }
+    assert!(pipeline.execute()?.iter().all(|result| result.is_ok()));
+    assert_eq!(
+        client
+            .request(&Request::GET("key999".to_owned()))?
+            .as_deref(),
+        Some("999")
+    );
+
+    Ok(())
+}
+
+// Should answer the requests of many threads over one connection
+#[test]
+fn multiplexed_connection() -> Result<()> {
+    let addr = "127.0.0.1:4125";
+    let _temp_dir = start_server(addr);
+
+    let client = Arc::new(MultiplexClient::new(addr)?);
+    let handles: Vec<_> = (0..8)
+        .map(|thread| {
+            let client = Arc::clone(&client);
+            thread::spawn(move || -> Result<()> {
+                for i in 0..50 {
+                    let key = format!("key{}-{}", thread, i);
+                    client.request(&Request::SET(key.clone(), i.to_string()))?;
+                    let value = client.request(&Request::GET(key))?;
+                    assert_eq!(value, Some(i.to_string()));
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(client.scan_match("key3-*")?.len(), 50);
+    assert!(client.request(&Request::RM("missing".to_owned())).is_err());
+
+    // a database selected applies to all later requests
+    client.request(&Request::SELECT(1))?;
+    assert_eq!(client.request(&Request::GET("key0-0".to_owned()))?, None);
+    assert!(client.request(&Request::WATCH(String::new(), 0)).is_err());
+
+    Ok(())
+}
+
+// Should agree on compression in the handshake and carry large values either way
+#[test]
+fn frame_compression() -> Result<()> {
+    let addr = "127.0.0.1:4126";
+    let uncompressed_addr = "127.0.0.1:4127";
+    let _temp_dir = start_server(addr);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        Arc::new(AtomicBool::new(false)),
+    )
+    .compression(false);
+    thread::spawn(move || server.serve(&uncompressed_addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    // the server replies with the offered flags it accepts, then waits for frames
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"KVS\x01\x01")?;
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply)?;
+    assert_eq!(&reply, b"KVS\x01\x01");
+    let mut stream = TcpStream::connect(uncompressed_addr)?;
+    stream.write_all(b"KVS\x01\x01")?;
+    stream.read_exact(&mut reply)?;
+    assert_eq!(&reply, b"KVS\x01\x00");
+    // a client without the handshake is disconnected
+    let mut stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(b"{\"GET\":\"key\"}")?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply)?;
+    assert!(reply.is_empty());
+    // a frame claiming a payload it does not send, and a compressed one claiming a size its
+    // bytes can not decompress to, are dropped with the connection
+    let compressed = (1u32 << 31 | 8).to_be_bytes();
+    let claims: [&[u8]; 2] = [
+        &(200u32 << 20).to_be_bytes(),
+        &[&compressed[..], &(200u32 << 20).to_le_bytes(), &[0; 4]].concat(),
+    ];
+    for claim in claims {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        stream.write_all(b"KVS\x01\x01")?;
+        let mut reply = [0; 5];
+        stream.read_exact(&mut reply)?;
+        stream.write_all(claim)?;
+        stream.shutdown(std::net::Shutdown::Write)?;
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply)?;
+        assert!(reply.is_empty());
+    }
+
+    let value = "compressible ".repeat(100_000);
+    for addr in [addr, uncompressed_addr] {
+        let mut client = Client::new(addr)?;
+        client.request(&Request::SET("large".to_owned(), value.clone()))?;
+        assert_eq!(
+            client
+                .request(&Request::GET("large".to_owned()))?
+                .as_deref(),
+            Some(value.as_str())
+        );
+    }
+
+    Ok(())
+}
+
+// Should apply a retried request once if the server caches responses
+#[test]
+fn retried_requests() -> Result<()> {
+    let addr = "127.0.0.1:4128";
+    let uncached_addr = "127.0.0.1:4129";
+    let _temp_dir = start_dedup_server(addr);
+    let _uncached_temp_dir = start_server(uncached_addr);
+
+    let retryable = |client_id: &str, request_id, request| {
+        Request::RETRYABLE(client_id.to_owned(), request_id, Box::new(request))
+    };
+    for addr in [addr, uncached_addr] {
+        request(addr, Request::SET("key".to_owned(), "value".to_owned()))?;
+    }
+    // the retry is answered from the cache, over a new connection
+    let remove = retryable("c1", 1, Request::RM("key".to_owned()));
+    assert_eq!(request(addr, remove.clone())?, None);
+    assert_eq!(request(addr, remove)?, None);
+    // another request id, or another client, runs again
+    assert!(request(addr, retryable("c1", 2, Request::RM("key".to_owned()))).is_err());
+    assert!(request(addr, retryable("c2", 1, Request::RM("key".to_owned()))).is_err());
+    // without the cache a retry runs again
+    let remove = retryable("c1", 1, Request::RM("key".to_owned()));
+    assert_eq!(request(uncached_addr, remove.clone())?, None);
+    assert!(request(uncached_addr, remove).is_err());
+    assert!(request(addr, retryable("c1", 3, Request::WATCH(String::new(), 0))).is_err());
+
+    let mut client = RetryingClient::new(addr, "c3").retries(1);
+    client.request(&Request::SET("key".to_owned(), "1".to_owned()))?;
+    assert_eq!(
+        client.request(&Request::GET("key".to_owned()))?,
+        Some("1".to_owned())
+    );
+    Ok(())
+}
+
+// Should apply a write retried with its token once, on any connection
+#[test]
+fn idempotent_writes() -> Result<()> {
+    let addr = "127.0.0.1:4130";
+    let _temp_dir = start_dedup_server(addr);
+
+    let remove = IdempotencyToken::generate();
+    let set = IdempotencyToken::generate();
+    assert_ne!(remove, set);
+    Client::new(addr)?.set_idempotent(&set, "key", "1")?;
+    Client::new(addr)?.remove_idempotent(&remove, "key")?;
+    // the retries neither restore the key nor fail to remove it again
+    Client::new(addr)?.set_idempotent(&set, "key", "1")?;
+    Client::new(addr)?.remove_idempotent(&remove, "key")?;
+    assert_eq!(request(addr, Request::GET("key".to_owned()))?, None);
+    // a new token is a new write
+    let mut client = Client::new(addr)?;
+    client.set_idempotent(&IdempotencyToken::generate(), "key", "2")?;
+    assert!(client
+        .remove_idempotent(&IdempotencyToken::generate(), "missing")
+        .is_err());
+    assert_eq!(
+        client.request(&Request::GET("key".to_owned()))?,
+        Some("2".to_owned())
+    );
+    Ok(())
+}
+
+// Should buffer the writes of a transaction and read the keys as of its beginning
+#[test]
+fn transactions() -> Result<()> {
+    let addr = "127.0.0.1:4131";
+    let _temp_dir = start_server(addr);
+    let get = |client: &mut Client, key: &str| client.request(&Request::GET(key.to_owned()));
+    let mut other = Client::new(addr)?;
+    other.request(&Request::SET("a".to_owned(), "1".to_owned()))?;
+    other.request(&Request::SET("b".to_owned(), "2".to_owned()))?;
+
+    let mut client = Client::new(addr)?;
+    assert!(client.commit().is_err());
+    client.begin()?;
+    assert!(client.begin().is_err());
+    client.request(&Request::SET("a".to_owned(), "10".to_owned()))?;
+    client.request(&Request::RM("b".to_owned()))?;
+    client.request(&Request::SET("c".to_owned(), "30".to_owned()))?;
+    assert_eq!(get(&mut client, "a")?, Some("10".to_owned()));
+    assert_eq!(get(&mut client, "b")?, None);
+    assert!(client.request(&Request::RM("b".to_owned())).is_err());
+    assert!(client.scan_match("*").is_err());
+    // nothing is applied before the commit
+    assert_eq!(get(&mut other, "a")?, Some("1".to_owned()));
+    assert_eq!(get(&mut other, "c")?, None);
+    client.commit()?;
+    assert_eq!(get(&mut other, "a")?, Some("10".to_owned()));
+    assert_eq!(get(&mut other, "b")?, None);
+    assert_eq!(get(&mut other, "c")?, Some("30".to_owned()));
+
+    client.begin()?;
+    client.request(&Request::SET("a".to_owned(), "rolled back".to_owned()))?;
+    client.rollback()?;
+    assert_eq!(get(&mut client, "a")?, Some("10".to_owned()));
+
+    // a key written by another client since the transaction began can not be read
+    client.begin()?;
+    other.request(&Request::SET("c".to_owned(), "31".to_owned()))?;
+    assert_eq!(get(&mut client, "a")?, Some("10".to_owned()));
+    assert!(get(&mut client, "c").is_err());
+    client.rollback()?;
+    // nor written, and the commit applies none of the writes
+    client.begin()?;
+    client.request(&Request::SET("a".to_owned(), "11".to_owned()))?;
+    client.request(&Request::SET("c".to_owned(), "32".to_owned()))?;
+    other.request(&Request::SET("c".to_owned(), "33".to_owned()))?;
+    assert!(client.commit().is_err());
+    assert_eq!(get(&mut client, "a")?, Some("10".to_owned()));
+    assert_eq!(get(&mut client, "c")?, Some("33".to_owned()));
+    Ok(())
+}
+
+// Should apply none of the writes of a transaction whose commit fails partway
+#[test]
+fn failed_commit() -> Result<()> {
+    let addr = "127.0.0.1:4180";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::builder()
+        .max_disk_size(1024)
+        .open(temp_dir.path())?;
+    let mut server = KvServer::new(
+        store,
+        SharedQueueThreadPool::new(4)?,
+        Arc::new(AtomicBool::new(false)),
+    );
+    thread::spawn(move || server.serve(&addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = Client::new(addr)?;
+    client.request(&Request::SET("a".to_owned(), "1".to_owned()))?;
+    client.request(&Request::SET("b".to_owned(), "2".to_owned()))?;
+    client.begin()?;
+    // the keys are written in order, so the last write exceeds the quota
+    client.request(&Request::SET("a".to_owned(), "10".to_owned()))?;
+    client.request(&Request::RM("b".to_owned()))?;
+    client.request(&Request::SET("c".to_owned(), "3".to_owned()))?;
+    client.request(&Request::SET("d".to_owned(), "x".repeat(2048)))?;
+    let err = client.commit().unwrap_err();
+    assert_eq!(err.code(), KVStoreError::QuotaExceeded.code());
+    for (key, value) in [("a", Some("1")), ("b", Some("2")), ("c", None), ("d", None)] {
+        assert_eq!(
+            client.request(&Request::GET(key.to_owned()))?,
+            value.map(str::to_owned)
+        );
+    }
+    Ok(())
+}
+
+// Should apply each of concurrent read-modify-write transactions on the same key once
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    let addr = "127.0.0.1:4132";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(8)?,
+        Arc::new(AtomicBool::new(false)),
+    );
+    let metrics = server.metrics();
+    thread::spawn(move || server.serve(&addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    request(addr, Request::SET("counter".to_owned(), "0".to_owned()))?;
+
+    let increments: Vec<_> = (0..4)
+        .map(|_| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = Client::new(addr)?;
+                for _ in 0..10 {
+                    loop {
+                        client.begin()?;
+                        let counter: u64 = match client.request(&Request::GET("counter".to_owned()))
+                        {
+                            Ok(counter) => counter.unwrap().parse().unwrap(),
+                            Err(_) => {
+                                client.rollback()?;
+                                continue;
+                            }
+                        };
+                        let counter = (counter + 1).to_string();
+                        client.request(&Request::SET("counter".to_owned(), counter))?;
+                        if client.commit().is_ok() {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for increment in increments {
+        increment.join().unwrap()?;
+    }
+    assert_eq!(
+        request(addr, Request::GET("counter".to_owned()))?,
+        Some("40".to_owned())
+    );
+    let names: Vec<String> = metrics
+        .metrics()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    assert!(names.contains(&"lock_waits".to_owned()));
+    assert!(names.contains(&"lock_timeouts".to_owned()));
+    Ok(())
+}
+
+/// an engine whose reads of the key `panic` panic
+#[derive(Clone)]
+struct PanickingEngine(KvStore);
+
+impl KvsWriter for PanickingEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+}
+
+impl KvsReader for PanickingEngine {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if key == "panic" {
+            panic!("reading {}", key);
+        }
+        self.0.get(key)
+    }
+
+    fn scan_match(&self, pattern: String) -> Result<Vec<(String, String)>> {
+        self.0.scan_match(pattern)
+    }
+}
+
+// Should answer a request whose handler panicked with an error and count the panic
+#[test]
+fn handler_panic() -> Result<()> {
+    let addr = "127.0.0.1:4133";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvServer::new(
+        PanickingEngine(KvStore::open(temp_dir.path())?),
+        SharedQueueThreadPool::new(2)?,
+        Arc::new(AtomicBool::new(false)),
+    );
+    let metrics = server.metrics();
+    thread::spawn(move || server.serve(&addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = Client::new(addr)?;
+    client.request(&Request::SET("key".to_owned(), "value".to_owned()))?;
+    let err = client
+        .request(&Request::GET("panic".to_owned()))
+        .unwrap_err();
+    assert!(err.to_string().contains("reading panic"), "{}", err);
+    // the connection is closed, the server keeps serving others
+    assert!(client.request(&Request::GET("key".to_owned())).is_err());
+    assert_eq!(
+        request(addr, Request::GET("key".to_owned()))?,
+        Some("value".to_owned())
+    );
+    let panics = metrics
+        .metrics()
+        .into_iter()
+        .find(|(name, _)| name == "panics")
+        .map(|(_, metric)| metric);
+    assert_eq!(panics, Some(Metric::Counter(1)));
+    Ok(())
+}
+
+// Should serve requests on the pools of their classes, keeping the session of the connection
+#[test]
+fn request_pools() -> Result<()> {
+    let addr = "127.0.0.1:4134";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+        Arc::new(AtomicBool::new(false)),
+    )
+    .request_pools(
+        SharedQueueThreadPool::new(2)?,
+        SharedQueueThreadPool::new(1)?,
+        SharedQueueThreadPool::new(1)?,
+    );
+    thread::spawn(move || server.serve(&addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = Client::new(addr)?;
+    client.select(1)?;
+    client.request(&Request::SET("key".to_owned(), "1".to_owned()))?;
+    client.begin()?;
+    client.request(&Request::SET("key".to_owned(), "2".to_owned()))?;
+    assert_eq!(
+        client.request(&Request::GET("key".to_owned()))?,
+        Some("2".to_owned())
+    );
+    client.commit()?;
+    assert_eq!(
+        client.scan_match("*")?,
+        vec![("key".to_owned(), "2".to_owned())]
+    );
+    assert_eq!(client.database_stats()?.database, 1);
+    // the key is in database 1 only
+    assert_eq!(request(addr, Request::GET("key".to_owned()))?, None);
+    Ok(())
+}
+
+/// an engine whose scans run until they are cancelled, recording that they were
+#[derive(Clone)]
+struct EndlessScanEngine {
+    store: KvStore,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl KvsWriter for EndlessScanEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.store.set(key, value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.store.remove(key)
+    }
+}
+
+impl KvsReader for EndlessScanEngine {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.store.get(key)
+    }
+
+    fn scan_match(&self, pattern: String) -> Result<Vec<(String, String)>> {
+        self.store.scan_match(pattern)
+    }
+
+    fn scan_match_cancellable(
+        &self,
+        pattern: String,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<(String, String)>> {
+        for _ in 0..1000 {
+            if cancel.is_cancelled() {
+                self.cancelled.store(true, Ordering::SeqCst);
+                return cancel.check().map(|_| Vec::new());
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.store.scan_match(pattern)
+    }
+}
+
+// Should cancel the scan of a client which closed its connection
+#[test]
+fn cancel_orphaned_scan() -> Result<()> {
+    let addr = "127.0.0.1:4135";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let engine = EndlessScanEngine {
+        store: KvStore::open(temp_dir.path())?,
+        cancelled: Arc::clone(&cancelled),
+    };
+    let mut server = KvServer::new(
+        engine,
+        SharedQueueThreadPool::new(2)?,
+        Arc::new(AtomicBool::new(false)),
+    )
+    .request_pools(
+        SharedQueueThreadPool::new(1)?,
+        SharedQueueThreadPool::new(1)?,
+        SharedQueueThreadPool::new(1)?,
+    );
+    thread::spawn(move || server.serve(&addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"KVS\x01\x00")?;
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply)?;
+    let scan = serde_json::to_vec(&Request::SCAN("*".to_owned()))?;
+    stream.write_all(&(scan.len() as u32).to_be_bytes())?;
+    stream.write_all(&scan)?;
+    thread::sleep(Duration::from_millis(200));
+    drop(stream);
+
+    for _ in 0..50 {
+        if cancelled.load(Ordering::SeqCst) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(cancelled.load(Ordering::SeqCst));
+    Ok(())
+}
+
+// Should reject requests whose deadline passed and stop scans running out of time
+#[test]
+fn request_deadlines() -> Result<()> {
+    let addr = "127.0.0.1:4136";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let engine = EndlessScanEngine {
+        store: KvStore::open(temp_dir.path())?,
+        cancelled: Arc::clone(&cancelled),
+    };
+    let mut server = KvServer::new(
+        engine,
+        SharedQueueThreadPool::new(2)?,
+        Arc::new(AtomicBool::new(false)),
+    );
+    thread::spawn(move || server.serve(&addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = Client::new(addr)?.timeout(Duration::from_secs(5));
+    client.request(&Request::SET("key".to_owned(), "value".to_owned()))?;
+    assert_eq!(
+        client.request(&Request::GET("key".to_owned()))?,
+        Some("value".to_owned())
+    );
+
+    let err = Client::new(addr)?
+        .timeout(Duration::ZERO)
+        .request(&Request::GET("key".to_owned()))
+        .unwrap_err();
+    assert!(err.to_string().contains("deadline"), "{}", err);
+
+    let start = std::time::Instant::now();
+    let err = Client::new(addr)?
+        .timeout(Duration::from_millis(200))
+        .scan_match("*")
+        .unwrap_err();
+    assert!(err.to_string().contains("deadline"), "{}", err);
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(cancelled.load(Ordering::SeqCst));
+    Ok(())
+}
+
+/// an engine whose reads of the key `slow` take 200ms
+#[derive(Clone)]
+struct SlowReadEngine(KvStore);
+
+impl KvsWriter for SlowReadEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.0.remove(key)
+    }
+}
+
+impl KvsReader for SlowReadEngine {
+    fn get(&self, key: String) -> Result<Option<String>> {
+        if key == "slow" {
+            thread::sleep(Duration::from_millis(200));
+        }
+        self.0.get(key)
+    }
+
+    fn scan_match(&self, pattern: String) -> Result<Vec<(String, String)>> {
+        self.0.scan_match(pattern)
+    }
+}
+
+// Should shed scans while requests wait too long for a thread, but not commits
+#[test]
+fn shed_under_overload() -> Result<()> {
+    let addr = "127.0.0.1:4137";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvServer::new(
+        SlowReadEngine(KvStore::open(temp_dir.path())?),
+        SharedQueueThreadPool::new(8)?,
+        Arc::new(AtomicBool::new(false)),
+    )
+    .request_pools(
+        SharedQueueThreadPool::new(1)?,
+        SharedQueueThreadPool::new(1)?,
+        SharedQueueThreadPool::new(1)?,
+    )
+    .admission_control(Duration::from_millis(50));
+    let metrics = server.metrics();
+    thread::spawn(move || server.serve(&addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    assert_eq!(Client::new(addr)?.scan_match("*")?, vec![]);
+    // the reads queue up behind each other on the single read thread
+    let readers: Vec<_> = (0..4)
+        .map(|_| thread::spawn(move || request(addr, Request::GET("slow".to_owned()))))
+        .collect();
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    let err = Client::new(addr)?.scan_match("*").unwrap_err();
+    assert!(err.to_string().contains("overloaded"), "{}", err);
+    let err = request(addr, Request::COMMIT).unwrap_err();
+    assert!(!err.to_string().contains("overloaded"), "{}", err);
+    let shed = metrics
+        .metrics()
+        .into_iter()
+        .find(|(name, _)| name == "shed")
+        .map(|(_, metric)| metric);
+    assert_eq!(shed, Some(Metric::Counter(1)));
+
+    // the wait is forgotten once the queues are idle
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(Client::new(addr)?.scan_match("*")?, vec![]);
+    Ok(())
+}
+
+// Should classify errors the same on both sides of a connection
+#[test]
+fn error_classification() -> Result<()> {
+    let addr = "127.0.0.1:4138";
+    let _temp_dir = start_server(addr);
+
+    let err = request(addr, Request::RM("missing".to_owned())).unwrap_err();
+    assert!(matches!(err, KVStoreError::Remote(_)));
+    assert_eq!(err.code(), KVStoreError::KeyNotFound.code());
+    assert_eq!(err.to_string(), "Key not found");
+    assert!(!err.is_retryable() && !err.is_transient() && !err.is_corruption());
+
+    request(addr, Request::SET("key".to_owned(), "1".to_owned()))?;
+    let mut client = Client::new(addr)?;
+    client.begin()?;
+    request(addr, Request::SET("key".to_owned(), "2".to_owned()))?;
+    let err = client.request(&Request::GET("key".to_owned())).unwrap_err();
+    assert_eq!(err.code(), 4004);
+    assert!(err.is_transient() && !err.is_retryable());
+
+    let reset = KVStoreError::Io(std::io::Error::from(std::io::ErrorKind::ConnectionReset));
+    assert!(reset.is_retryable() && reset.is_transient());
+    let denied = KVStoreError::Io(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+    assert!(!denied.is_retryable());
+    assert!(KVStoreError::Overloaded.is_retryable());
+    assert!(KVStoreError::CorruptedRecord(1, 0, "bad".to_owned()).is_corruption());
+    let undecodable = serde_json::from_str::<Request>("{")
+        .map_err(KVStoreError::from)
+        .unwrap_err();
+    assert!(!undecodable.is_corruption());
+    let in_file = KVStoreError::Context(
+        ErrorContext::new("read").file("data_1.txt"),
+        Box::new(undecodable),
+    );
+    assert!(in_file.is_corruption());
+    Ok(())
+}
+
+// Should verify values written with a checksum when they are read back
+#[test]
+fn value_integrity() -> Result<()> {
+    let addr = "127.0.0.1:4139";
+    let _temp_dir = start_server(addr);
+
+    let mut client = Client::new(addr)?.integrity(true);
+    client.request(&Request::SET("a".to_owned(), "value".to_owned()))?;
+    assert_eq!(
+        client.request(&Request::GET("a".to_owned()))?,
+        Some("value".to_owned())
+    );
+    let sealed = request(addr, Request::GET("a".to_owned()))?.unwrap();
+    assert_ne!(sealed, "value");
+
+    // values written without a checksum are read as they are
+    request(addr, Request::SET("plain".to_owned(), "old".to_owned()))?;
+    assert_eq!(
+        client.request(&Request::GET("plain".to_owned()))?,
+        Some("old".to_owned())
+    );
+
+    // a value changed on its way or moved to another key is detected
+    request(
+        addr,
+        Request::SET("a".to_owned(), sealed.replace("value", "valve")),
+    )?;
+    request(addr, Request::SET("b".to_owned(), sealed))?;
+    for key in ["a", "b"] {
+        let err = client.request(&Request::GET(key.to_owned())).unwrap_err();
+        assert!(matches!(err, KVStoreError::IntegrityMismatch(_)), "{}", err);
+        assert!(err.is_corruption());
+    }
+    let results = client.pipeline().get("b").get("plain").execute()?;
+    assert!(results[0].is_err());
+    assert_eq!(results[1].as_ref().unwrap(), &Some("old".to_owned()));
+    assert!(client.scan_match("*").is_err());
+
+    client.request(&Request::SET("b".to_owned(), "fixed".to_owned()))?;
+    assert_eq!(
+        client.scan_match("[bp]*")?,
+        vec![
+            ("b".to_owned(), "fixed".to_owned()),
+            ("plain".to_owned(), "old".to_owned())
+        ]
+    );
+    Ok(())
+}
+
+// Should copy all keys of a running server to a new one before it serves
+#[test]
+fn bootstrap_from_server() -> Result<()> {
+    let source = "127.0.0.1:4140";
+    let standby = "127.0.0.1:4141";
+    let _source_dir = start_server(source);
+    for i in 0..2500 {
+        request(source, Request::SET(format!("key{}", i), i.to_string()))?;
+    }
+    request(source, Request::RM("key7".to_owned()))?;
+    let mut client = Client::new(source)?;
+    client.select(3)?;
+    client.request(&Request::SET("key1".to_owned(), "db3".to_owned()))?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    engine.set("stale".to_owned(), "value".to_owned())?;
+    let mut server = KvServer::new(
+        engine,
+        SharedQueueThreadPool::new(4)?,
+        Arc::new(AtomicBool::new(false)),
+    );
+    assert_eq!(server.bootstrap_from(source)?, 2502);
+    thread::spawn(move || server.serve(&standby.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    assert_eq!(
+        request(standby, Request::GET("key2499".to_owned()))?,
+        Some("2499".to_owned())
+    );
+    assert_eq!(request(standby, Request::GET("key7".to_owned()))?, None);
+    assert_eq!(request(standby, Request::GET("stale".to_owned()))?, None);
+    let mut client = Client::new(standby)?;
+    client.select(3)?;
+    assert_eq!(
+        client.request(&Request::GET("key1".to_owned()))?,
+        Some("db3".to_owned())
+    );
+    assert_eq!(Client::new(standby)?.scan_match("*")?.len(), 2499);
+
+    // the checkpoint covers the keys of all databases
+    let mut client = Client::new(source)?;
+    let mut pairs = 0;
+    client.checkpoint(|batch| {
+        pairs += batch.len();
+        Ok(())
+    })?;
+    assert_eq!(pairs, 2500);
+    Ok(())
+}
+
+// Should write to both engines of a migration and read from the new one after a cutover
+#[test]
+fn migrate_engine() -> Result<()> {
+    let addr = "127.0.0.1:4142";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let old = KvStore::open(temp_dir.path().join("kvs"))?;
+    old.set("before".to_owned(), "old".to_owned())?;
+    old.set("removed".to_owned(), "old".to_owned())?;
+    let new = SledKvsEngine::open(temp_dir.path().join("sled"))?;
+    new.set("leftover".to_owned(), "new".to_owned())?;
+    let engine = MigratingEngine::new(old.clone(), new.clone());
+    let mut server = KvServer::new(
+        engine.clone(),
+        SharedQueueThreadPool::new(4)?,
+        Arc::new(AtomicBool::new(false)),
+    );
+    thread::spawn(move || server.serve(&addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    request(addr, Request::SET("during".to_owned(), "both".to_owned()))?;
+    request(addr, Request::RM("removed".to_owned()))?;
+    assert_eq!(new.get("during".to_owned())?, Some("both".to_owned()));
+    assert_eq!(old.get("removed".to_owned())?, None);
+    // reads still go to the old engine
+    assert_eq!(request(addr, Request::GET("leftover".to_owned()))?, None);
+    assert_eq!(new.get("before".to_owned())?, None);
+
+    Client::new(addr)?.cutover()?;
+    assert!(engine.is_cut_over());
+    assert_eq!(
+        request(addr, Request::GET("before".to_owned()))?,
+        Some("old".to_owned())
+    );
+    assert_eq!(request(addr, Request::GET("leftover".to_owned()))?, None);
+    assert_eq!(
+        Client::new(addr)?.scan_match("*")?,
+        vec![
+            ("before".to_owned(), "old".to_owned()),
+            ("during".to_owned(), "both".to_owned())
+        ]
+    );
+    // the old engine still follows, so the migration can be rolled back
+    request(addr, Request::SET("after".to_owned(), "both".to_owned()))?;
+    assert_eq!(old.get("after".to_owned())?, Some("both".to_owned()));
+
+    let other = "127.0.0.1:4143";
+    let _other_dir = start_server(other);
+    let err = Client::new(other)?.cutover().unwrap_err();
+    assert_eq!(err.code(), KVStoreError::NotMigrating.code());
+    Ok(())
+}
+
+// Should serve the reads of a snapshot and answer every write with an error
+#[test]
+fn serve_snapshot() -> Result<()> {
+    let addr = "127.0.0.1:4177";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.close()?;
+    let mut server = KvServer::read_only(
+        SnapshotEngine::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+        Arc::new(AtomicBool::new(false)),
+    );
+    thread::spawn(move || server.serve(&addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    assert_eq!(
+        request(addr, Request::GET("key".to_owned()))?,
+        Some("value".to_owned())
+    );
+    for write in [
+        Request::SET("key".to_owned(), "other".to_owned()),
+        Request::RM("key".to_owned()),
+    ] {
+        let err = request(addr, write).unwrap_err();
+        assert_eq!(err.code(), KVStoreError::ReadOnlySnapshot.code());
+    }
+    assert_eq!(
+        request(addr, Request::GET("key".to_owned()))?,
+        Some("value".to_owned())
+    );
+    Ok(())
+}
+
+// Should route keys to shards by their hash, merge scans and follow changes of the shards
+#[test]
+fn proxy_shards() -> Result<()> {
+    let shards = vec![
+        "127.0.0.1:4144".to_owned(),
+        "127.0.0.1:4145".to_owned(),
+        "127.0.0.1:4146".to_owned(),
+    ];
+    let _dirs: Vec<TempDir> = shards.iter().map(|shard| start_server(shard)).collect();
+    let addr = "127.0.0.1:4147";
+    let proxy = KvProxy::new(shards.clone(), Arc::new(AtomicBool::new(false)))?;
+    let serving = proxy.clone();
+    thread::spawn(move || serving.serve(addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = Client::new(addr)?;
+    let keys: Vec<String> = (0..30).map(|i| format!("key{:02}", i)).collect();
+    for key in &keys {
+        client.request(&Request::SET(key.clone(), format!("{}-value", key)))?;
+    }
+    for key in &keys {
+        assert_eq!(
+            request(&proxy.shard_of(key), Request::GET(key.clone()))?,
+            Some(format!("{}-value", key))
+        );
+    }
+    for shard in &shards {
+        assert!(!Client::new(shard)?.scan_match("*")?.is_empty());
+    }
+    let pairs = client.scan_match("key*")?;
+    let scanned: Vec<&String> = pairs.iter().map(|(key, _)| key).collect();
+    assert_eq!(scanned, keys.iter().collect::<Vec<_>>());
+    assert_eq!(client.database_stats()?.keys, 30);
+    assert!(client.request(&Request::BEGIN).is_err());
+
+    client.select(1)?;
+    assert_eq!(client.request(&Request::GET(keys[0].clone()))?, None);
+    client.select(0)?;
+
+    // keys of the removed shard are not moved by the proxy
+    proxy.set_shards(shards[..2].to_vec())?;
+    for key in &keys {
+        let expected = (request(&shards[2], Request::GET(key.clone()))?.is_none())
+            .then(|| format!("{}-value", key));
+        assert_eq!(client.request(&Request::GET(key.clone()))?, expected);
+    }
+    Ok(())
+}
+
+// Should split the keys of a shard by range into another one and merge them back
+#[test]
+fn split_and_merge_shards() -> Result<()> {
+    let source = "127.0.0.1:4148";
+    let target = "127.0.0.1:4149";
+    let _source_dir = start_server(source);
+    let _target_dir = start_server(target);
+    let keys: Vec<String> = ('a'..='z').map(|c| c.to_string()).collect();
+    let mut client = Client::new(source)?;
+    for key in &keys {
+        client.request(&Request::SET(key.clone(), key.to_uppercase()))?;
+    }
+    client.select(1)?;
+    client.request(&Request::SET("x".to_owned(), "in 1".to_owned()))?;
+
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let reported = Arc::clone(&reports);
+    let progress = Reshard::split(source, target, "m")
+        .on_progress(move |progress| reported.lock().unwrap().push(progress.clone()))
+        .run()?;
+    assert_eq!(progress.scanned_keys, 27);
+    assert_eq!(progress.copied_keys, 15);
+    assert_eq!(progress.removed_keys, 15);
+    assert_eq!(reports.lock().unwrap().last(), Some(&progress));
+
+    let scanned = |addr: &str| -> Result<Vec<String>> {
+        let pairs = Client::new(addr)?.scan_match("*")?;
+        Ok(pairs.into_iter().map(|(key, _)| key).collect())
+    };
+    assert_eq!(scanned(source)?, keys[..12].to_vec());
+    assert_eq!(scanned(target)?, keys[12..].to_vec());
+    assert_eq!(
+        request(target, Request::GET("m".to_owned()))?,
+        Some("M".to_owned())
+    );
+    let mut other_database = Client::new(target)?;
+    other_database.select(1)?;
+    assert_eq!(
+        other_database.request(&Request::GET("x".to_owned()))?,
+        Some("in 1".to_owned())
+    );
+
+    let progress = Reshard::merge(target, source).run()?;
+    assert_eq!(progress.removed_keys, 15);
+    assert_eq!(scanned(source)?, keys);
+    assert!(scanned(target)?.is_empty());
+    client.select(1)?;
+    assert_eq!(
+        client.request(&Request::GET("x".to_owned()))?,
+        Some("in 1".to_owned())
+    );
+    Ok(())
+}
+
+/// start a server at `listen` holding the shard at `addr` of a cluster of `shards`
+fn start_cluster_server(listen: &str, shards: &[&str], addr: &str) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let shards = shards.iter().map(|shard| shard.to_string()).collect();
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path()).unwrap(),
+        SharedQueueThreadPool::new(2).unwrap(),
+        Arc::new(AtomicBool::new(false)),
+    )
+    .cluster(shards, addr);
+    let listen = listen.to_owned();
+    thread::spawn(move || server.serve(&listen).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    temp_dir
+}
+
+// Should route keys to the shards of a cluster, refreshing a stale topology when a shard
+// answers that another one serves a key
+#[test]
+fn cluster_client() -> Result<()> {
+    let first = "127.0.0.1:4150";
+    let second = "127.0.0.1:4151";
+    let _first_dir = start_cluster_server(first, &[first, second], first);
+    let _second_dir = start_cluster_server(second, &[first, second], second);
+    // a seed which still knows the cluster with one shard
+    let seed = "127.0.0.1:4152";
+    let _seed_dir = start_cluster_server(seed, &[first], first);
+
+    let mut client = Client::connect_cluster(&["127.0.0.1:4153", seed])?;
+    assert_eq!(client.shards(), [first.to_owned()]);
+    let keys: Vec<String> = (0..20).map(|i| format!("key{}", i)).collect();
+    for key in &keys {
+        client.request(&Request::SET(key.clone(), key.to_uppercase()))?;
+    }
+    assert_eq!(client.shards(), [first.to_owned(), second.to_owned()]);
+    for key in &keys {
+        assert_eq!(
+            client.request(&Request::GET(key.clone()))?,
+            Some(key.to_uppercase())
+        );
+    }
+    let on_first = Client::new(first)?.scan_match("*")?.len();
+    let on_second = Client::new(second)?.scan_match("*")?.len();
+    assert!(on_first > 0 && on_second > 0);
+    assert_eq!(on_first + on_second, keys.len());
+    assert_eq!(client.scan_match("key*")?.len(), keys.len());
+
+    let mut moved = None;
+    for key in &keys {
+        if let Err(err) = request(first, Request::GET(key.clone())) {
+            moved = Some(err);
+            break;
+        }
+    }
+    assert_eq!(
+        moved.map(|err| err.code()),
+        Some(KVStoreError::Moved(String::new()).code())
+    );
+    assert_eq!(
+        Client::new(first)?.topology()?,
+        vec![first.to_owned(), second.to_owned()]
+    );
+    Ok(())
+}
+
+// Should answer a read with a consistency token once the server applied the writes of the
+// token, also on a replica
+#[test]
+fn read_your_writes() -> Result<()> {
+    let replica = "127.0.0.1:4154";
+    let primary = "127.0.0.1:4155";
+    let _replica_dir = start_server(replica);
+    let (_primary_dir, _replicator) = start_replicated_server(primary, Some(replica));
+
+    let mut writer = Client::new(primary)?.read_your_writes(0);
+    writer.request(&Request::SET("key".to_owned(), "first".to_owned()))?;
+    let first = writer.token().unwrap();
+    assert!(first > 0);
+    writer.request(&Request::SET("key".to_owned(), "second".to_owned()))?;
+    let token = writer.token().unwrap();
+    assert!(token > first);
+
+    let mut reader = Client::new(replica)?.read_your_writes(token);
+    assert_eq!(
+        reader.request(&Request::GET("key".to_owned()))?,
+        Some("second".to_owned())
+    );
+    assert!(reader.token().unwrap() >= token);
+
+    let mut ahead = Client::new(primary)?
+        .read_your_writes(token + 1000)
+        .timeout(Duration::from_millis(200));
+    let err = ahead.request(&Request::GET("key".to_owned())).unwrap_err();
+    assert_eq!(err.code(), KVStoreError::NotCaughtUp(0, 0).code());
+    assert!(err.is_retryable());
+    Ok(())
+}
+
+// Should reject the writes of clients on a follower, naming its leader, while applying the
+// writes replicated from the leader
+#[test]
+fn read_only_follower() -> Result<()> {
+    let follower = "127.0.0.1:4156";
+    let leader = "127.0.0.1:4157";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path()).unwrap(),
+        SharedQueueThreadPool::new(2).unwrap(),
+        Arc::new(AtomicBool::new(false)),
+    )
+    .follower(leader);
+    thread::spawn(move || server.serve(&follower.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    let (_leader_dir, _replicator) = start_replicated_server(leader, Some(follower));
+
+    let mut client = Client::new(follower)?;
+    for request in [
+        Request::SET("key".to_owned(), "value".to_owned()),
+        Request::RM("key".to_owned()),
+        Request::FLUSH,
+        Request::DEADLINE(1000, Box::new(Request::RM("key".to_owned()))),
+        // writes wrapped like replicated ones, without the handshake of a replicator
+        Request::BATCH(vec![WatchEvent {
+            revision: 0,
+            key: "key".to_owned(),
+            value: Some("value".to_owned()),
+        }]),
+    ] {
+        let err = client.request(&request).unwrap_err();
+        assert_eq!(
+            err.code(),
+            KVStoreError::ReadOnlyReplica(String::new()).code()
+        );
+        assert!(err.to_string().contains(leader), "{}", err);
+    }
+    // each connection holds a thread of the pool, which the replicated writes need
+    drop(client);
+
+    let mut writer = Client::new(leader)?.read_your_writes(0);
+    writer.request(&Request::SET("key".to_owned(), "value".to_owned()))?;
+    let mut reader = Client::new(follower)?.read_your_writes(writer.token().unwrap());
+    assert_eq!(
+        reader.request(&Request::GET("key".to_owned()))?,
+        Some("value".to_owned())
+    );
+    Ok(())
+}
+
+/// start a follower of `leader`, promoting itself after `failover` without a lease renewal
+fn start_follower(
+    addr: &str,
+    leader: &str,
+    failover: Option<Duration>,
+) -> (TempDir, Option<FailoverTask>) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path()).unwrap(),
+        SharedQueueThreadPool::new(4).unwrap(),
+        Arc::new(AtomicBool::new(false)),
+    )
+    .follower(leader);
+    let failover = failover.map(|timeout| server.failover_after(timeout));
+    let addr = addr.to_owned();
+    thread::spawn(move || server.serve(&addr).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    (temp_dir, failover)
+}
+
+// Should promote a follower only once the lease of its leader expired, fencing the old
+// leader off so the two never take writes at the same time
+#[test]
+fn promote_follower() -> Result<()> {
+    let leader = "127.0.0.1:4158";
+    let follower = "127.0.0.1:4159";
+    let (_follower_dir, _) = start_follower(follower, leader, None);
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvServer::new(
+        KvStore::open(leader_dir.path().join("data")).unwrap(),
+        SharedQueueThreadPool::new(4).unwrap(),
+        Arc::new(AtomicBool::new(false)),
+    );
+    let _replicator = server.replicate_to(follower, leader_dir.path().join("replication"))?;
+    let _lease = server.leader_lease(follower, Duration::from_millis(600));
+    thread::spawn(move || server.serve(&leader.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = Client::new(leader)?;
+    client.request(&Request::SET("key".to_owned(), "first".to_owned()))?;
+    let epoch = Client::new(follower)?.promote()?;
+    assert_eq!(epoch, 1);
+    let err = client
+        .request(&Request::SET("key".to_owned(), "second".to_owned()))
+        .unwrap_err();
+    assert!(
+        [
+            KVStoreError::LeaseExpired.code(),
+            KVStoreError::ReadOnlyReplica(String::new()).code()
+        ]
+        .contains(&err.code()),
+        "{}",
+        err
+    );
+
+    let mut promoted = Client::new(follower)?;
+    assert_eq!(
+        promoted.request(&Request::GET("key".to_owned()))?,
+        Some("first".to_owned())
+    );
+    promoted.request(&Request::SET("key".to_owned(), "third".to_owned()))?;
+    assert_eq!(promoted.promote()?, 1);
+    // the writes replicated by the old leader are refused
+    promoted.request(&Request::REPLICATE(String::new()))?;
+    let stale = Request::BATCH(vec![WatchEvent {
+        revision: 7,
+        key: "key".to_owned(),
+        value: Some("stale".to_owned()),
+    }]);
+    let err = promoted.request(&stale).unwrap_err();
+    assert_eq!(err.code(), KVStoreError::Fenced(0).code());
+    drop(promoted);
+
+    // the old leader follows the promoted server once its renewal is refused
+    thread::sleep(Duration::from_millis(500));
+    let err = client
+        .request(&Request::SET("key".to_owned(), "second".to_owned()))
+        .unwrap_err();
+    assert_eq!(
+        err.code(),
+        KVStoreError::ReadOnlyReplica(String::new()).code()
+    );
+    assert!(err.to_string().contains(follower), "{}", err);
+    Ok(())
+}
+
+// Should refuse the commit of a transaction begun on a leader fenced off since
+#[test]
+fn fenced_leader_transaction() -> Result<()> {
+    let leader = "127.0.0.1:4178";
+    let follower = "127.0.0.1:4179";
+    let (_follower_dir, _) = start_follower(follower, leader, None);
+    let leader_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvServer::new(
+        KvStore::open(leader_dir.path().join("data")).unwrap(),
+        SharedQueueThreadPool::new(4).unwrap(),
+        Arc::new(AtomicBool::new(false)),
+    );
+    let _replicator = server.replicate_to(follower, leader_dir.path().join("replication"))?;
+    let _lease = server.leader_lease(follower, Duration::from_millis(600));
+    thread::spawn(move || server.serve(&leader.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = Client::new(leader)?;
+    client.request(&Request::BEGIN)?;
+    client.request(&Request::SET("key".to_owned(), "buffered".to_owned()))?;
+    Client::new(follower)?.promote()?;
+    let err = client.request(&Request::COMMIT).unwrap_err();
+    assert!(
+        [
+            KVStoreError::LeaseExpired.code(),
+            KVStoreError::ReadOnlyReplica(String::new()).code()
+        ]
+        .contains(&err.code()),
+        "{}",
+        err
+    );
+    client.request(&Request::ROLLBACK)?;
+    assert_eq!(client.request(&Request::GET("key".to_owned()))?, None);
+    Ok(())
+}
+
+// Should promote a follower whose leader did not renew its lease in time
+#[test]
+fn automatic_failover() -> Result<()> {
+    let follower = "127.0.0.1:4160";
+    // a leader which never comes up
+    let leader = "127.0.0.1:4161";
+    let (_follower_dir, _failover) =
+        start_follower(follower, leader, Some(Duration::from_millis(1500)));
+
+    let mut client = Client::new(follower)?;
+    let err = client
+        .request(&Request::SET("key".to_owned(), "value".to_owned()))
+        .unwrap_err();
+    assert_eq!(
+        err.code(),
+        KVStoreError::ReadOnlyReplica(String::new()).code()
+    );
+    thread::sleep(Duration::from_millis(1500));
+    client.request(&Request::SET("key".to_owned(), "value".to_owned()))?;
+    assert_eq!(client.promote()?, 1);
+    Ok(())
+}
+
+// Should set, get and count the bits of bitmaps on the server
+#[test]
+fn bitmap_operations() -> Result<()> {
+    let addr = "127.0.0.1:4162";
+    let _temp_dir = start_server(addr);
+
+    let mut client = Client::new(addr)?;
+    assert!(!client.getbit("seen", 7)?);
+    assert_eq!(client.bitcount("seen")?, 0);
+    for offset in [0, 7, 1_000_000] {
+        assert!(!client.setbit("seen", offset, true)?);
+    }
+    assert!(client.setbit("seen", 7, true)?);
+    assert!(client.getbit("seen", 1_000_000)?);
+    assert!(!client.getbit("seen", 1)?);
+    assert!(!client.getbit("seen", 2_000_000)?);
+    assert_eq!(client.bitcount("seen")?, 3);
+    assert!(client.setbit("seen", 0, false)?);
+    assert_eq!(client.bitcount("seen")?, 2);
+
+    // the bitmap is stored base64 encoded, bit 0 first
+    client.setbit("small", 1, true)?;
+    assert_eq!(
+        client.request(&Request::GET("small".to_owned()))?,
+        Some("QA==".to_owned())
+    );
+
+    client.request(&Request::SET("text".to_owned(), "not base64!".to_owned()))?;
+    let err = client.bitcount("text").unwrap_err();
+    assert_eq!(err.code(), KVStoreError::NotABitmap(String::new()).code());
+    assert!(client.setbit("seen", u64::MAX, true).is_err());
+    Ok(())
+}
+
+// Should estimate the distinct elements added to HyperLogLog sketches on the server
+#[test]
+fn hyperloglog_counting() -> Result<()> {
+    let addr = "127.0.0.1:4163";
+    let _temp_dir = start_server(addr);
+
+    let mut client = Client::new(addr)?;
+    assert_eq!(client.pfcount(&["visitors"])?, 0);
+    assert!(client.pfadd("visitors", &["alice", "bob"])?);
+    assert!(!client.pfadd("visitors", &["alice"])?);
+    assert_eq!(client.pfcount(&["visitors"])?, 2);
+
+    let monday: Vec<String> = (0..10_000).map(|id| format!("user:{}", id)).collect();
+    let tuesday: Vec<String> = (5_000..15_000).map(|id| format!("user:{}", id)).collect();
+    for (key, users) in [("monday", &monday), ("tuesday", &tuesday)] {
+        for chunk in users.chunks(1000) {
+            let chunk: Vec<&str> = chunk.iter().map(String::as_str).collect();
+            client.pfadd(key, &chunk)?;
+        }
+    }
+    let within = |count: u64, expected: f64| (count as f64 - expected).abs() / expected < 0.05;
+    let count = client.pfcount(&["monday"])?;
+    assert!(within(count, 10_000.0), "{}", count);
+    let count = client.pfcount(&["monday", "tuesday"])?;
+    assert!(within(count, 15_000.0), "{}", count);
+    let sketch = client.request(&Request::GET("monday".to_owned()))?.unwrap();
+    assert!(sketch.len() < 6 * 1024);
+
+    client.request(&Request::SET("text".to_owned(), "plain".to_owned()))?;
+    let err = client.pfadd("text", &["x"]).unwrap_err();
+    assert_eq!(
+        err.code(),
+        KVStoreError::NotAHyperLogLog(String::new()).code()
+    );
+    Ok(())
+}
+
+// Should read and patch byte ranges of values on the server
+#[test]
+fn value_ranges() -> Result<()> {
+    let addr = "127.0.0.1:4164";
+    let _temp_dir = start_server(addr);
+
+    let mut client = Client::new(addr)?;
+    client.request(&Request::SET(
+        "greeting".to_owned(),
+        "Hello World".to_owned(),
+    ))?;
+    assert_eq!(client.getrange("greeting", 6, 5)?, "World");
+    assert_eq!(client.getrange("greeting", 6, 100)?, "World");
+    assert_eq!(client.getrange("greeting", 100, 5)?, "");
+    assert_eq!(client.getrange("missing", 0, 5)?, "");
+
+    assert_eq!(client.setrange("greeting", 6, "Redis")?, 11);
+    assert_eq!(client.setrange("greeting", 11, "!")?, 12);
+    assert_eq!(
+        client.request(&Request::GET("greeting".to_owned()))?,
+        Some("Hello Redis!".to_owned())
+    );
+    // a missing value is padded with zero bytes up to the offset
+    assert_eq!(client.setrange("padded", 2, "ab")?, 4);
+    assert_eq!(
+        client.request(&Request::GET("padded".to_owned()))?,
+        Some("\0\0ab".to_owned())
+    );
+    assert_eq!(client.setrange("empty", 3, "")?, 0);
+    assert_eq!(client.request(&Request::GET("empty".to_owned()))?, None);
+
+    // ranges must not split a character
+    client.request(&Request::SET("utf8".to_owned(), "héllo".to_owned()))?;
+    assert_eq!(client.getrange("utf8", 1, 2)?, "é");
+    assert!(client.getrange("utf8", 2, 2).is_err());
+    assert!(client.setrange("utf8", 2, "x").is_err());
+    assert!(client.setrange("huge", u64::MAX - 1, "x").is_err());
+    Ok(())
+}
+
+// Should send only the part of the values a client asks for
+#[test]
+fn transform_values() -> Result<()> {
+    let addr = "127.0.0.1:4165";
+    let _temp_dir = start_server(addr);
+
+    let mut client = Client::new(addr)?;
+    let document = r#"{"name":"alice","emails":["a@example.com","b@example.com"],"age":30}"#;
+    client.request(&Request::SET("user:1".to_owned(), document.to_owned()))?;
+    client.request(&Request::SET(
+        "user:2".to_owned(),
+        r#"{"name":"bob"}"#.to_owned(),
+    ))?;
+    let pointer = |pointer: &str| Transform::JsonPointer(pointer.to_owned());
+
+    assert_eq!(
+        client.get_transformed("user:1", pointer("/emails/1"))?,
+        Some("\"b@example.com\"".to_owned())
+    );
+    assert_eq!(
+        client.get_transformed("user:1", pointer("/age"))?,
+        Some("30".to_owned())
+    );
+    assert_eq!(client.get_transformed("user:1", pointer("/missing"))?, None);
+    assert_eq!(client.get_transformed("user:3", pointer("/name"))?, None);
+    assert_eq!(
+        client.get_transformed("user:1", Transform::Substring(2, 4))?,
+        Some("name".to_owned())
+    );
+    assert_eq!(
+        client.scan_transformed("user:*", pointer("/emails/0"))?,
+        vec![("user:1".to_owned(), "\"a@example.com\"".to_owned())]
+    );
+
+    // values written with a checksum are checked before they are transformed
+    let mut sealed = Client::new(addr)?.integrity(true);
+    sealed.request(&Request::SET(
+        "user:4".to_owned(),
+        r#"{"name":"carol"}"#.to_owned(),
+    ))?;
+    assert_eq!(
+        client.get_transformed("user:4", pointer("/name"))?,
+        Some("\"carol\"".to_owned())
+    );
+
+    client.request(&Request::SET("text".to_owned(), "not json".to_owned()))?;
+    assert!(client.get_transformed("text", pointer("/name")).is_err());
+    let set = Request::SET("user:1".to_owned(), "x".to_owned());
+    assert!(client
+        .request(&Request::TRANSFORM(pointer("/name"), Box::new(set)))
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn client_list_and_kill() -> Result<()> {
+    let addr = "127.0.0.1:4167";
+    let _temp_dir = start_server(addr);
+
+    let mut admin = Client::new(addr)?;
+    let mut other = Client::new(addr)?;
+    other.select(2)?;
+    for key_id in 0..3 {
+        other.request(&Request::SET(format!("key{}", key_id), "value".to_owned()))?;
+    }
+
+    let clients = admin.client_list()?;
+    assert_eq!(clients.len(), 2);
+    let me = &clients[0];
+    assert_eq!(me.last_command.as_deref(), Some("CLIENTLIST"));
+    assert_eq!(me.commands, 1);
+    assert_eq!(me.database, 0);
+    let them = &clients[1];
+    assert!(them.id > me.id);
+    assert_eq!(them.last_command.as_deref(), Some("SET"));
+    assert_eq!(them.commands, 4);
+    assert_eq!(them.database, 2);
+    assert!(them.bytes_in > 0 && them.bytes_out > 0);
+    assert!(them.addr.starts_with("127.0.0.1:"));
+    assert!(them.age >= them.idle);
+
+    admin.client_kill(them.id)?;
+    assert!(other.request(&Request::GET("key0".to_owned())).is_err());
+    // the connection is gone once its handler noticed
+    let start = Instant::now();
+    while admin.client_list()?.len() > 1 {
+        assert!(start.elapsed() < Duration::from_secs(5));
+        thread::sleep(Duration::from_millis(10));
+    }
+    match admin.client_kill(them.id) {
+        Err(err) => assert_eq!(err.code(), KVStoreError::ClientNotFound(0).code()),
+        Ok(()) => panic!("killed a connection twice"),
+    }
+    Ok(())
+}
+
+#[test]
+fn json_documents() -> Result<()> {
+    let addr = "127.0.0.1:4166";
+    let _temp_dir = start_server(addr);
+
+    let mut client = Client::new(addr)?;
+    client.json_set("user:1", "/name", r#""alice""#)?;
+    client.json_set("user:1", "/emails", "[]")?;
+    client.json_set("user:1", "/emails/-", r#""a@example.com""#)?;
+    client.json_set("user:1", "/emails/1", r#""b@example.com""#)?;
+    client.json_set("user:1", "/emails/0", r#""c@example.com""#)?;
+    client.json_set("user:1", "/address", r#"{"city":"Oslo"}"#)?;
+    client.json_set("user:1", "/address/city", r#""Bergen""#)?;
+
+    assert_eq!(
+        client.json_get("user:1", "/name")?,
+        Some(r#""alice""#.to_owned())
+    );
+    assert_eq!(
+        client.json_get("user:1", "/emails")?,
+        Some(r#"["c@example.com","b@example.com"]"#.to_owned())
+    );
+    assert_eq!(
+        client.json_get("user:1", "/address/city")?,
+        Some(r#""Bergen""#.to_owned())
+    );
+    assert_eq!(client.json_get("user:1", "/missing")?, None);
+    assert_eq!(client.json_get("user:2", "/name")?, None);
+    let document: serde_json::Value =
+        serde_json::from_str(&client.request(&Request::GET("user:1".to_owned()))?.unwrap())?;
+    assert_eq!(
+        document,
+        serde_json::json!({
+            "name": "alice",
+            "emails": ["c@example.com", "b@example.com"],
+            "address": { "city": "Bergen" },
+        })
+    );
+
+    // the empty pointer replaces the whole document
+    client.json_set("user:1", "", r#"{"name":"bob"}"#)?;
+    assert_eq!(
+        client.json_get("user:1", "")?,
+        Some(r#"{"name":"bob"}"#.to_owned())
+    );
+
+    // the parent of the field must exist and the field must be JSON
+    assert!(client.json_set("user:1", "/a/b", "1").is_err());
+    assert!(client.json_set("user:1", "/emails/5", "1").is_err());
+    assert!(client.json_set("user:1", "/name", "not json").is_err());
+    client.request(&Request::SET("text".to_owned(), "not json".to_owned()))?;
+    assert!(client.json_set("text", "/name", "1").is_err());
+    assert!(client.json_get("text", "/name").is_err());
+    Ok(())
+}
+
+#[test]
+fn access_list_refuses_connections() -> Result<()> {
+    let addr = "127.0.0.1:4168";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let access = AccessList::new(&["10.0.0.0/8"], &[])?;
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        Arc::new(AtomicBool::new(false)),
+    )
+    .access_list(access.clone());
+    let metrics = server.metrics();
+    thread::spawn(move || server.serve(&addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    // the connection is closed as soon as it is accepted
+    assert!(request(addr, Request::GET("key".to_owned())).is_err());
+    let refused = metrics
+        .metrics()
+        .into_iter()
+        .find(|(name, _)| name == "refused_connections")
+        .map(|(_, metric)| metric);
+    assert_eq!(refused, Some(Metric::Counter(1)));
+
+    // the rules reloaded apply to the next connections
+    access.reload(&["10.0.0.0/8", "127.0.0.0/8"], &[])?;
+    request(addr, Request::SET("key".to_owned(), "value".to_owned()))?;
+    assert_eq!(
+        request(addr, Request::GET("key".to_owned()))?,
+        Some("value".to_owned())
+    );
+    access.reload(&[], &["127.0.0.1"])?;
+    assert!(request(addr, Request::GET("key".to_owned())).is_err());
+
+    // rules which are not CIDRs are rejected and the old ones kept
+    assert!(access.reload(&["127.0.0.0/33"], &[]).is_err());
+    assert!(access.reload(&["localhost"], &[]).is_err());
+    assert!(!access.is_allowed("127.0.0.1".parse().unwrap()));
+    assert!(access.is_allowed("::ffff:10.0.0.1".parse().unwrap()));
+    Ok(())
+}
+
+#[test]
+fn disabled_commands() -> Result<()> {
+    let addr = "127.0.0.1:4169";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+        Arc::new(AtomicBool::new(false)),
+    )
+    .disable_commands(&["flush", "WATCH"]);
+    thread::spawn(move || server.serve(&addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = Client::new(addr)?;
+    client.request(&Request::SET("key".to_owned(), "value".to_owned()))?;
+    let err = client.request(&Request::FLUSH).unwrap_err();
+    assert!(
+        err.to_string().contains("FLUSH command is disabled"),
+        "{}",
+        err
+    );
+    let err = client
+        .request(&Request::DEADLINE(1000, Box::new(Request::FLUSH)))
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("FLUSH command is disabled"),
+        "{}",
+        err
+    );
+    assert!(MultiplexClient::new(addr)?
+        .request(&Request::FLUSH)
+        .is_err());
+    client.begin()?;
+    assert!(client.request(&Request::FLUSH).is_err());
+    client.commit()?;
+    assert_eq!(
+        client.request(&Request::GET("key".to_owned()))?,
+        Some("value".to_owned())
+    );
+
+    let err = Client::new(addr)?
+        .watch("", 0)?
+        .next()
+        .unwrap()
+        .unwrap_err();
+    assert!(
+        err.to_string().contains("WATCH command is disabled"),
+        "{}",
+        err
+    );
+    Ok(())
+}
+
+#[test]
+fn slow_clients_are_disconnected() -> Result<()> {
+    let addr = "127.0.0.1:4170";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+        Arc::new(AtomicBool::new(false)),
+    )
+    .slow_clients(64 * 1024, Duration::from_millis(300));
+    let metrics = server.metrics();
+    thread::spawn(move || server.serve(&addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+    let slow_clients = || {
+        metrics
+            .metrics()
+            .into_iter()
+            .find(|(name, _)| name == "slow_clients")
+            .map(|(_, metric)| metric)
+    };
+    let wait_for_slow_clients = |expected: u64| {
+        for _ in 0..100 {
+            if slow_clients() == Some(Metric::Counter(expected)) {
+                return;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("{:?} slow clients, expected {}", slow_clients(), expected);
+    };
+
+    // a watcher not reading its events
+    let mut watcher = Client::new(addr)?.watch("", 0)?;
+    let mut client = Client::new(addr)?;
+    let value = "v".repeat(256 * 1024);
+    for i in 0..40 {
+        client.request(&Request::SET(format!("key{}", i), value.clone()))?;
+        thread::sleep(Duration::from_millis(20));
+    }
+    wait_for_slow_clients(1);
+    let mut events = 0;
+    while let Some(Ok(_)) = watcher.next() {
+        events += 1;
+    }
+    assert!(events < 40, "{} events", events);
+
+    // a client not reading the response of a large scan
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"KVS\x01\x00")?;
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply)?;
+    let scan = serde_json::to_vec(&Request::SCAN("*".to_owned()))?;
+    stream.write_all(&(scan.len() as u32).to_be_bytes())?;
+    stream.write_all(&scan)?;
+    wait_for_slow_clients(2);
+
+    // the clients reading their responses are served
+    assert_eq!(
+        client.request(&Request::GET("key0".to_owned()))?,
+        Some(value)
+    );
+    Ok(())
+}
+
+#[test]
+fn escaped_keys_and_values() -> Result<()> {
+    let addr = "127.0.0.1:4171";
+    let _temp_dir = start_server(addr);
+    let mut client = Client::new(addr)?;
+    let pairs = [
+        ("plain", "value"),
+        ("quoted \"key\"", "back\\slash"),
+        ("line\nbreak", "tab\tseparated"),
+        ("ключ", "значение \u{1f600}"),
+    ];
+    for (key, value) in pairs {
+        client.request(&Request::SET(key.to_owned(), value.to_owned()))?;
+    }
+    for (key, value) in pairs {
+        assert_eq!(
+            client.request(&Request::GET(key.to_owned()))?.as_deref(),
+            Some(value)
+        );
+    }
+    // a large frame between small ones
+    let large = "x".repeat(1 << 20);
+    client.request(&Request::SET("large".to_owned(), large.clone()))?;
+    client.request(&Request::RM("quoted \"key\"".to_owned()))?;
+    assert_eq!(
+        client.request(&Request::GET("large".to_owned()))?,
+        Some(large)
+    );
+    assert_eq!(
+        client.request(&Request::GET("quoted \"key\"".to_owned()))?,
+        None
+    );
+    assert_eq!(
+        client.request(&Request::GET("ключ".to_owned()))?.as_deref(),
+        Some("значение \u{1f600}")
+    );
+    Ok(())
+}
+
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let addr = "127.0.0.1:4172";
+    let _temp_dir = start_server(addr);
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"KVS\x01\x00")?;
+    let mut reply = [0; 5];
+    stream.read_exact(&mut reply)?;
+
+    // the requests are sent at once, the responses come in their order
+    let mut requests = Vec::new();
+    for i in 0..100 {
+        requests.push(Request::SET(format!("key{}", i), format!("value{}", i)));
+        requests.push(Request::GET(format!("key{}", i)));
+    }
+    requests.push(Request::SCAN("key9*".to_owned()));
+    let mut frames = Vec::new();
+    for request in &requests {
+        let payload = serde_json::to_vec(request)?;
+        frames.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frames.extend_from_slice(&payload);
+    }
+    stream.write_all(&frames)?;
+    let mut read_response = || -> Result<Response> {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header)?;
+        let mut payload = vec![0; u32::from_be_bytes(header) as usize];
+        stream.read_exact(&mut payload)?;
+        Ok(serde_json::from_slice(&payload)?)
+    };
+    for i in 0..100 {
+        assert!(matches!(read_response()?, Response::Ok(None)));
+        match read_response()? {
+            Response::Ok(Some(value)) => assert_eq!(value, format!("value{}", i)),
+            response => panic!("unexpected response {:?}", response),
+        }
+    }
+    match read_response()? {
+        Response::Pairs(pairs) => assert_eq!(pairs.len(), 11),
+        response => panic!("unexpected response {:?}", response),
+    }
+    Ok(())
+}
+
+#[test]
+fn draining_server() -> Result<()> {
+    let addr = "127.0.0.1:4173";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let drain = Drain::default();
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(4)?,
+        Arc::new(AtomicBool::new(false)),
+    )
+    .drain(drain.clone());
+    thread::spawn(move || server.serve(&addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut open = Client::new(addr)?;
+    assert_eq!(open.health()?, "serving");
+    open.drain(Duration::from_millis(500))?;
+    assert!(drain.is_draining());
+
+    // the connection open before the drain is served for its grace period
+    open.request(&Request::SET("key".to_owned(), "value".to_owned()))?;
+    let mut new = Client::new(addr)?;
+    assert_eq!(new.health()?, "draining");
+    let err = new.request(&Request::GET("key".to_owned())).unwrap_err();
+    assert_eq!(err.code(), KVStoreError::Draining.code());
+    assert!(err.is_retryable());
+    thread::sleep(Duration::from_millis(600));
+    let err = open.request(&Request::GET("key".to_owned())).unwrap_err();
+    assert_eq!(err.code(), KVStoreError::Draining.code());
+    assert_eq!(open.health()?, "draining");
+
+    drain.resume();
+    assert_eq!(new.health()?, "serving");
+    assert_eq!(
+        new.request(&Request::GET("key".to_owned()))?,
+        Some("value".to_owned())
+    );
+    Ok(())
+}
+
+// Should tell the stores of replicating servers apart and refuse the writes of unrelated ones
+#[test]
+fn replication_identity() -> Result<()> {
+    let remote = "127.0.0.1:4174";
+    let primary = "127.0.0.1:4175";
+    let _remote_dir = start_server(remote);
+    let (_primary_dir, replicator) = start_replicated_server(primary, Some(remote));
+    let replicator = replicator.unwrap();
+    request(primary, Request::SET("key".to_owned(), "value".to_owned()))?;
+    wait_for(remote, "key", Some("value"))?;
+
+    let primary_info = Client::new(primary)?.info()?;
+    let mut client = Client::new(remote)?;
+    let remote_info = client.info()?;
+    let remote_id = remote_info.instance_id.clone().unwrap();
+    assert_eq!(remote_id.len(), 36);
+    assert_ne!(primary_info.instance_id, remote_info.instance_id);
+    assert_eq!(remote_info.replicating_from, primary_info.instance_id);
+    assert_eq!(remote_info.health, "serving");
+    assert_eq!(
+        replicator.stats().remote_instance_id,
+        Some(remote_id.clone())
+    );
+
+    // the handshake of the primary passes again, the one of another store does not
+    let source = primary_info.instance_id.unwrap();
+    assert_eq!(
+        client.request(&Request::REPLICATE(source))?,
+        Some(remote_id)
+    );
+    let err = client
+        .request(&Request::REPLICATE("another store".to_owned()))
+        .unwrap_err();
+    assert_eq!(
+        err.code(),
+        KVStoreError::UnrelatedStore(String::new(), String::new()).code()
+    );
+
+    // replicated writes need the handshake on their own connection
+    let batch = Request::BATCH(vec![WatchEvent {
+        revision: 0,
+        key: "key".to_owned(),
+        value: Some("forged".to_owned()),
+    }]);
+    let err = Client::new(remote)?.request(&batch).unwrap_err();
+    assert_eq!(err.code(), KVStoreError::NoReplicationHandshake.code());
+    assert_eq!(
+        client.request(&Request::GET("key".to_owned()))?,
+        Some("value".to_owned())
+    );
+    Ok(())
+}
@@ -1,5 +1,7 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use kvs::thread_pool::*;
 use kvs::Result;
@@ -67,4 +69,239 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
-}
\ No newline at end of file
+}
+#[test]
+fn shared_queue_thread_pool_supervised_panic_task() -> Result<()> {
+    const TASK_NUM: u64 = 100;
+
+    let pool = SharedQueueThreadPool::supervised(4)?;
+    for _ in 0..TASK_NUM {
+        pool.spawn(move || {
+            panic_control::disable_hook_in_current_thread();
+
+            panic!();
+        })
+    }
+    for _ in 0..100 {
+        if pool.replaced_workers() == TASK_NUM {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(pool.replaced_workers(), TASK_NUM);
+
+    spawn_counter(pool)
+}
+
+#[test]
+fn rayon_thread_pool_supervised() -> Result<()> {
+    let pool = RayonThreadPool::supervised(4)?;
+    assert_eq!(pool.replaced_workers(), 0);
+    spawn_counter(pool)
+}
+
+fn panic_handler<P: ThreadPool>() -> Result<()> {
+    const TASK_NUM: usize = 10;
+
+    let pool = P::new(2)?;
+    let panics = Arc::new(Mutex::new(Vec::new()));
+    let handled = Arc::clone(&panics);
+    pool.set_panic_handler(Box::new(move |panic| {
+        handled.lock().unwrap().push(panic.message.unwrap());
+    }));
+    for i in 0..TASK_NUM {
+        pool.spawn(move || {
+            panic_control::disable_hook_in_current_thread();
+
+            panic!("job {}", i);
+        })
+    }
+    for _ in 0..100 {
+        if panics.lock().unwrap().len() == TASK_NUM {
+            break;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    let mut panics = panics.lock().unwrap().clone();
+    panics.sort();
+    assert_eq!(panics.len(), TASK_NUM);
+    assert_eq!(panics[3], "job 3");
+
+    spawn_counter(pool)
+}
+
+#[test]
+fn naive_thread_pool_panic_handler() -> Result<()> {
+    panic_handler::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_panic_handler() -> Result<()> {
+    panic_handler::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_panic_handler() -> Result<()> {
+    panic_handler::<RayonThreadPool>()
+}
+
+fn labelled_threads<P: ThreadPool>() -> Result<()> {
+    let pool: P = ThreadPoolBuilder::new(2).label("kv-test").build()?;
+    assert_eq!(pool.label(), Some("kv-test"));
+    let (sender, receiver) = std::sync::mpsc::channel();
+    pool.set_panic_handler(Box::new(move |panic| sender.send(panic).unwrap()));
+    pool.spawn(|| {
+        panic_control::disable_hook_in_current_thread();
+
+        panic!("labelled");
+    });
+    let panic = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(panic.pool.as_deref(), Some("kv-test"));
+    assert!(panic.thread.unwrap().starts_with("kv-test"));
+    spawn_counter(pool)
+}
+
+#[test]
+fn naive_thread_pool_labelled_threads() -> Result<()> {
+    labelled_threads::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_labelled_threads() -> Result<()> {
+    labelled_threads::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_labelled_threads() -> Result<()> {
+    labelled_threads::<RayonThreadPool>()
+}
+
+#[cfg(target_os = "linux")]
+fn pinned_threads<P: ThreadPool>() -> Result<()> {
+    let pool: P = ThreadPoolBuilder::new(2)
+        .affinity(CpuAffinity::Cores(vec![0]))
+        .build()?;
+    let (sender, receiver) = std::sync::mpsc::channel();
+    pool.spawn(move || {
+        let status = std::fs::read_to_string("/proc/thread-self/status").unwrap();
+        let cores = status
+            .lines()
+            .find_map(|line| line.strip_prefix("Cpus_allowed_list:"))
+            .map(|cores| cores.trim().to_owned());
+        sender.send(cores).unwrap();
+    });
+    let cores = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(cores.as_deref(), Some("0"));
+    spawn_counter(pool)
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn naive_thread_pool_pinned_threads() -> Result<()> {
+    pinned_threads::<NaiveThreadPool>()
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn shared_queue_thread_pool_pinned_threads() -> Result<()> {
+    pinned_threads::<SharedQueueThreadPool>()
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn rayon_thread_pool_pinned_threads() -> Result<()> {
+    pinned_threads::<RayonThreadPool>()
+}
+
+#[test]
+fn parse_cpu_affinity() {
+    assert_eq!(
+        "spread".parse::<CpuAffinity>().unwrap(),
+        CpuAffinity::Spread
+    );
+    assert_eq!(
+        "compact".parse::<CpuAffinity>().unwrap(),
+        CpuAffinity::Compact
+    );
+    assert_eq!(
+        "0, 2,4".parse::<CpuAffinity>().unwrap(),
+        CpuAffinity::Cores(vec![0, 2, 4])
+    );
+    assert!("0,x".parse::<CpuAffinity>().is_err());
+    assert!("".parse::<CpuAffinity>().is_err());
+}
+
+fn cancellable_jobs<P: ThreadPool>() -> Result<()> {
+    let pool = P::new(2)?;
+    let (started, is_started) = std::sync::mpsc::channel();
+    let (stopped, is_stopped) = std::sync::mpsc::channel();
+    let token = pool.spawn_cancellable(move |cancel| {
+        started.send(()).unwrap();
+        while cancel.check().is_ok() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        stopped.send(()).unwrap();
+    });
+    is_started.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(!token.is_cancelled());
+    token.cancel();
+    is_stopped.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(token.is_cancelled());
+    spawn_counter(pool)
+}
+
+#[test]
+fn naive_thread_pool_cancellable_jobs() -> Result<()> {
+    cancellable_jobs::<NaiveThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_cancellable_jobs() -> Result<()> {
+    cancellable_jobs::<SharedQueueThreadPool>()
+}
+
+#[test]
+fn rayon_thread_pool_cancellable_jobs() -> Result<()> {
+    cancellable_jobs::<RayonThreadPool>()
+}
+
+#[test]
+fn shared_queue_thread_pool_skips_cancelled_jobs() -> Result<()> {
+    let pool = SharedQueueThreadPool::new(1)?;
+    let (release, released) = std::sync::mpsc::channel::<()>();
+    pool.spawn(move || {
+        let _ = released.recv();
+    });
+    let ran = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&ran);
+    let token = pool.spawn_cancellable(move |_| {
+        counter.fetch_add(1, Ordering::SeqCst);
+    });
+    token.cancel();
+    drop(release);
+    let wg = WaitGroup::new();
+    let done = wg.clone();
+    pool.spawn(move || drop(done));
+    wg.wait();
+    assert_eq!(ran.load(Ordering::SeqCst), 0);
+    Ok(())
+}
+
+#[test]
+fn cancellation_token_deadline() {
+    let token = CancellationToken::new();
+    let expiring = token.with_deadline(std::time::Instant::now() + Duration::from_millis(100));
+    assert!(!expiring.is_cancelled());
+    assert!(expiring.remaining().unwrap() <= Duration::from_millis(100));
+    assert!(token.remaining().is_none());
+    thread::sleep(Duration::from_millis(150));
+    assert!(matches!(
+        expiring.check(),
+        Err(kvs::KVStoreError::DeadlineExceeded)
+    ));
+    assert!(!token.is_cancelled());
+
+    let later = token.with_deadline(std::time::Instant::now() + Duration::from_secs(60));
+    token.cancel();
+    assert!(matches!(later.check(), Err(kvs::KVStoreError::Cancelled)));
+}
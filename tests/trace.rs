@@ -0,0 +1,152 @@
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::trace::{self, OtlpExporter, TraceContext};
+use kvs::{Client, KvServer, KvStore, Request, Result};
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::atomic::AtomicBool;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// only one exporter can be installed at a time
+static EXPORTER: Mutex<()> = Mutex::new(());
+
+// a collector which answers every export with 200 and passes the bodies on
+fn start_collector() -> (String, mpsc::Receiver<Value>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            sender.send(serde_json::from_slice(&body).unwrap()).unwrap();
+        }
+    });
+    (endpoint, receiver)
+}
+
+fn spans(export: &Value) -> Vec<Value> {
+    export["resourceSpans"][0]["scopeSpans"][0]["spans"]
+        .as_array()
+        .unwrap()
+        .clone()
+}
+
+// Requests carrying a traceparent are exported as children of the client span,
+// with the engine work as their children
+#[test]
+fn export_request_spans() -> Result<()> {
+    let _installed = EXPORTER.lock().unwrap_or_else(|err| err.into_inner());
+    let (endpoint, exports) = start_collector();
+    let exporter = OtlpExporter::install(&endpoint, "kvs-test")?;
+    assert!(OtlpExporter::install(&endpoint, "kvs-test").is_err());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4109";
+    let mut server = KvServer::new(
+        KvStore::open(temp_dir.path())?,
+        SharedQueueThreadPool::new(2)?,
+        Arc::new(AtomicBool::new(false)),
+    );
+    thread::spawn(move || server.serve(&addr.to_owned()).unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let client_span =
+        TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+            .expect("a valid traceparent");
+    assert_eq!(
+        client_span.to_string(),
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+    );
+    Client::new(addr)?
+        .trace_parent(client_span)
+        .request(&Request::SET("key1".to_owned(), "value1".to_owned()))?;
+    // an untraced request starts a trace of its own
+    Client::new(addr)?.request(&Request::GET("key1".to_owned()))?;
+    {
+        let mut local = trace::span("kvs.test");
+        local.attribute("kvs.key", "key1");
+        assert_eq!(trace::current(), local.context());
+    }
+    assert_eq!(trace::current(), None);
+    thread::sleep(Duration::from_millis(200));
+    // exports what is left
+    drop(exporter);
+
+    let mut all = Vec::new();
+    while let Ok(export) = exports.recv_timeout(Duration::from_secs(1)) {
+        assert_eq!(
+            export["resourceSpans"][0]["resource"]["attributes"][0]["value"]["stringValue"],
+            "kvs-test"
+        );
+        all.extend(spans(&export));
+    }
+    let find = |name: &str, trace_id: &str| {
+        all.iter()
+            .find(|span| span["name"] == name && span["traceId"] == trace_id)
+            .unwrap_or_else(|| panic!("no {} span in {:?}", name, all))
+            .clone()
+    };
+
+    let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+    let request = find("kvs.request", trace_id);
+    assert_eq!(request["parentSpanId"], "00f067aa0ba902b7");
+    assert_eq!(request["kind"], 2);
+    assert_eq!(request["attributes"][0]["value"]["stringValue"], "SET");
+    let set = find("kvs.engine.set", trace_id);
+    assert_eq!(set["parentSpanId"], request["spanId"]);
+
+    let get = all
+        .iter()
+        .find(|span| span["name"] == "kvs.engine.get")
+        .expect("a get span");
+    assert_ne!(get["traceId"], trace_id);
+    let local = all
+        .iter()
+        .find(|span| span["name"] == "kvs.test")
+        .expect("a local span");
+    assert!(local.get("parentSpanId").is_none());
+    Ok(())
+}
+
+// Spans finished while a collector which never answers holds the exporter back are dropped
+// instead of queueing up, and dropping the exporter does not wait for the collector long
+#[test]
+fn drop_spans_of_stalled_collector() -> Result<()> {
+    let _installed = EXPORTER.lock().unwrap_or_else(|err| err.into_inner());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let endpoint = format!("http://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        // accept the exports but never read or answer them
+        let streams: Vec<_> = listener.incoming().collect();
+        drop(streams);
+    });
+    let exporter = OtlpExporter::install(&endpoint, "kvs-test")?;
+    for _ in 0..20_000 {
+        trace::span("kvs.test");
+    }
+    assert!(exporter.dropped_spans() > 0);
+    let dropping = Instant::now();
+    drop(exporter);
+    assert!(dropping.elapsed() < Duration::from_secs(20));
+    Ok(())
+}